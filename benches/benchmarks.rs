@@ -2,7 +2,7 @@ use std::time::Duration;
 
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 
-use youshallnotpass::TokenBucket;
+use youshallnotpass::{RateLimiter, TokenBucket};
 
 pub fn tokenbucket_consume(c: &mut Criterion) {
     let bucket = TokenBucket::new(10, Duration::from_secs(600));
@@ -11,5 +11,21 @@ pub fn tokenbucket_consume(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, tokenbucket_consume);
+pub fn ratelimiter_consume(c: &mut Criterion) {
+    let limiter = RateLimiter::configure()
+        .limit(1_usize, 10, Duration::from_secs(600))
+        .done();
+    c.bench_function("RateLimiter::consume(1)", |b| {
+        b.iter(|| limiter.consume(black_box(&1), black_box(1)))
+    });
+
+    let limiter = RateLimiter::configure()
+        .limit(1_usize, 10, Duration::from_secs(600))
+        .done_dense();
+    c.bench_function("DenseRateLimiter::consume(1)", |b| {
+        b.iter(|| limiter.consume(black_box(1), black_box(1)))
+    });
+}
+
+criterion_group!(benches, tokenbucket_consume, ratelimiter_consume);
 criterion_main!(benches);
@@ -0,0 +1,140 @@
+//! Derive macros for the `youshallnotpass` crate.
+//!
+//! The macros are re-exported by `youshallnotpass` itself behind the `derive`
+//! feature, and are not meant to be used directly.
+
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Error, Fields, Path};
+
+/// Derive `youshallnotpass::RateLimitKey` for a struct or an enum.
+///
+/// See the documentation of the trait for the encoding and the supported
+/// `#[rate_limit_key(...)]` attributes.
+#[proc_macro_derive(RateLimitKey, attributes(rate_limit_key))]
+pub fn derive_rate_limit_key(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(mut input: DeriveInput) -> syn::Result<TokenStream> {
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let (pattern, encode) = encode_fields(&data.fields)?;
+            quote! {
+                let Self #pattern = self;
+                #encode
+            }
+        }
+        Data::Enum(data) => {
+            let arms = data
+                .variants
+                .iter()
+                .enumerate()
+                .map(|(index, variant)| {
+                    let ident = &variant.ident;
+                    let index = index as u32;
+                    let (pattern, encode) = encode_fields(&variant.fields)?;
+                    Ok(quote! {
+                        Self::#ident #pattern => {
+                            ::youshallnotpass::RateLimitKey::encode_key(&#index, buf);
+                            #encode
+                        }
+                    })
+                })
+                .collect::<syn::Result<Vec<_>>>()?;
+            quote! {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+        Data::Union(_) => {
+            return Err(Error::new_spanned(
+                &input.ident,
+                "RateLimitKey cannot be derived for unions",
+            ))
+        }
+    };
+
+    let params: Vec<_> = input
+        .generics
+        .type_params()
+        .map(|param| param.ident.clone())
+        .collect();
+    let where_clause = input.generics.make_where_clause();
+    for param in params {
+        where_clause
+            .predicates
+            .push(parse_quote!(#param: ::youshallnotpass::RateLimitKey));
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::youshallnotpass::RateLimitKey for #ident #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn encode_key(&self, buf: &mut ::std::vec::Vec<u8>) {
+                #body
+            }
+        }
+    })
+}
+
+/// Return a pattern binding the `fields`, and the code encoding them.
+fn encode_fields(fields: &Fields) -> syn::Result<(TokenStream, TokenStream)> {
+    let mut bindings = Vec::new();
+    let mut encode = Vec::new();
+    for (index, field) in fields.iter().enumerate() {
+        let binding = match &field.ident {
+            Some(ident) => ident.clone(),
+            None => format_ident!("field{}", index),
+        };
+        match parse_attributes(field)? {
+            Attribute::Skip => {}
+            Attribute::Normalize(path) => encode.push(quote! {
+                ::youshallnotpass::RateLimitKey::encode_key(&#path(#binding), buf);
+            }),
+            Attribute::None => encode.push(quote! {
+                ::youshallnotpass::RateLimitKey::encode_key(#binding, buf);
+            }),
+        }
+        bindings.push(binding);
+    }
+
+    let pattern = match fields {
+        Fields::Named(_) => quote!({ #(#bindings),* }),
+        Fields::Unnamed(_) => quote!(( #(#bindings),* )),
+        Fields::Unit => quote!(),
+    };
+    Ok((pattern, quote!(#(#encode)*)))
+}
+
+enum Attribute {
+    None,
+    Skip,
+    Normalize(Path),
+}
+
+fn parse_attributes(field: &syn::Field) -> syn::Result<Attribute> {
+    let mut result = Attribute::None;
+    for attr in &field.attrs {
+        if !attr.path().is_ident("rate_limit_key") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                result = Attribute::Skip;
+                Ok(())
+            } else if meta.path.is_ident("normalize") {
+                result = Attribute::Normalize(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `skip` or `normalize = path`"))
+            }
+        })?;
+    }
+    Ok(result)
+}
@@ -0,0 +1,182 @@
+use std::time::Duration;
+
+use crate::clock::Clock;
+use crate::error::Error;
+use crate::token_bucket::TokenBucket;
+
+/// The limit an event (`key`) is charged against, as reported by
+/// [`RateLimiter::consume_detailed()`].
+///
+/// [`RateLimiter::consume_detailed()`]: crate::RateLimiter::consume_detailed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Usage {
+    limit: usize,
+    remaining: usize,
+    reset: Duration,
+}
+
+impl Usage {
+    /// Return the usage of an event without a limit, which is as if its bucket
+    /// were infinitely large.
+    pub(crate) fn unlimited() -> Usage {
+        Usage {
+            limit: usize::MAX,
+            remaining: usize::MAX,
+            reset: Duration::ZERO,
+        }
+    }
+
+    /// Return the usage of the strictest of the `buckets`, i.e. the one with
+    /// the fewest tokens left, and the longest to refill of such.
+    pub(crate) fn strictest<'b, C: Clock + 'b>(
+        buckets: impl IntoIterator<Item = &'b TokenBucket<C>>,
+    ) -> Usage {
+        buckets
+            .into_iter()
+            .map(Usage::of)
+            .min_by_key(|usage| (usage.remaining, std::cmp::Reverse(usage.reset)))
+            .unwrap_or_else(Usage::unlimited)
+    }
+
+    fn of<C: Clock>(bucket: &TokenBucket<C>) -> Usage {
+        match (bucket.available(), bucket.time_to_full()) {
+            (Ok(availability), Ok(reset)) => Usage {
+                limit: bucket.quota().burst(),
+                remaining: availability.tokens(),
+                reset,
+            },
+            _ => Usage::blocked(),
+        }
+    }
+
+    /// Return the usage of a blocked event, whose bucket is never refilled.
+    pub(crate) fn blocked() -> Usage {
+        Usage {
+            limit: 0,
+            remaining: 0,
+            reset: Duration::MAX,
+        }
+    }
+
+    /// Return the names and the values of the `RateLimit-*` response headers
+    /// describing the usage, with the reset time in seconds. An event without
+    /// a limit has none of them, and a blocked one has no reset time.
+    #[cfg(any(feature = "poem", feature = "tide"))]
+    pub(crate) fn headers(&self) -> impl Iterator<Item = (&'static str, u64)> {
+        let limited = self.limit != usize::MAX;
+        let reset = (self.reset != Duration::MAX).then(|| seconds(self.reset));
+        [
+            ("ratelimit-limit", Some(self.limit as u64)),
+            ("ratelimit-remaining", Some(self.remaining as u64)),
+            ("ratelimit-reset", reset),
+        ]
+        .into_iter()
+        .filter_map(move |(name, value)| Some((name, value.filter(|_| limited)?)))
+    }
+}
+
+/// Return the number of seconds in the `duration`, rounded up, as expected by
+/// the `Retry-After` and `RateLimit-Reset` response headers.
+#[cfg(any(feature = "poem", feature = "tide"))]
+pub(crate) fn seconds(duration: Duration) -> u64 {
+    duration.as_secs() + (duration.subsec_nanos() > 0) as u64
+}
+
+/// Details of an event admitted by [`RateLimiter::consume_detailed()`], e.g.
+/// to be reported via `RateLimit-*` response headers.
+///
+/// With several limits applied to an event, e.g. stacked or global ones, the
+/// details are of the strictest of them, i.e. the one with the fewest tokens
+/// left. An event without a limit is reported as if its bucket were infinitely
+/// large, i.e. with both the limit and the remaining tokens of `usize::MAX`.
+///
+/// [`RateLimiter::consume_detailed()`]: crate::RateLimiter::consume_detailed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allowance {
+    usage: Usage,
+}
+
+impl Allowance {
+    pub(crate) fn new(usage: Usage) -> Self {
+        Allowance { usage }
+    }
+
+    #[cfg(any(feature = "poem", feature = "tide"))]
+    pub(crate) fn usage(&self) -> &Usage {
+        &self.usage
+    }
+
+    /// Return the maximum number of tokens the bucket holds.
+    pub fn limit(&self) -> usize {
+        self.usage.limit
+    }
+
+    /// Return the number of tokens left in the bucket after the consumption.
+    pub fn remaining(&self) -> usize {
+        self.usage.remaining
+    }
+
+    /// Return how long it takes to refill the bucket up to its limit, or
+    /// [`Duration::MAX`] if it's blocked.
+    pub fn reset(&self) -> Duration {
+        self.usage.reset
+    }
+}
+
+/// Details of an event rejected by [`RateLimiter::consume_detailed()`], along
+/// with the reason of the rejection.
+///
+/// Same as [`Allowance`], but the tokens remaining are the ones available, as
+/// a rejection consumes nothing.
+///
+/// [`RateLimiter::consume_detailed()`]: crate::RateLimiter::consume_detailed
+#[derive(Debug, PartialEq, Eq)]
+pub struct Denial {
+    error: Error,
+    usage: Usage,
+}
+
+impl Denial {
+    pub(crate) fn new(error: Error, usage: Usage) -> Self {
+        Denial { error, usage }
+    }
+
+    #[cfg(any(feature = "poem", feature = "tide"))]
+    pub(crate) fn usage(&self) -> &Usage {
+        &self.usage
+    }
+
+    /// Return the reason of the rejection.
+    pub fn error(&self) -> &Error {
+        &self.error
+    }
+
+    /// Return the reason of the rejection, discarding the details.
+    pub fn into_error(self) -> Error {
+        self.error
+    }
+
+    /// Return the maximum number of tokens the bucket holds.
+    pub fn limit(&self) -> usize {
+        self.usage.limit
+    }
+
+    /// Return the number of tokens available in the bucket.
+    pub fn remaining(&self) -> usize {
+        self.usage.remaining
+    }
+
+    /// Return how long it takes to refill the bucket up to its limit, or
+    /// [`Duration::MAX`] if it's blocked.
+    pub fn reset(&self) -> Duration {
+        self.usage.reset
+    }
+}
+
+impl std::fmt::Display for Denial {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for Denial {}
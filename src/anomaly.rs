@@ -0,0 +1,279 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::clock::Clock;
+use crate::default_policy;
+
+/// Callback invoked with a key and the fraction of its requests rejected.
+pub(crate) type AnomalyCallback<K> = Arc<dyn Fn(&K, f64) + Send + Sync>;
+
+/// Tracker of keys that chronically hit their limits.
+///
+/// For every key, the detector counts requests and rejections over the last
+/// `windows` periods of `window` length, and invokes the callback once the
+/// fraction of rejected requests reaches the `threshold`. The callback is not
+/// invoked again for the key until the fraction drops below the threshold.
+///
+/// The keys are spread across shards, the same way the buckets of the
+/// default policy are, so that recording a request locks out only the keys of
+/// a single shard, and the callback is invoked once the shard is unlocked.
+/// Histories of keys idle for all the `windows` are pruned as new keys come.
+pub(crate) struct AnomalyDetector<K, C, S = RandomState> {
+    window: Duration,
+    windows: usize,
+    threshold: f64,
+    callback: AnomalyCallback<K>,
+    shards: Box<[Mutex<Shard<K, S>>]>,
+    /// Picks the shard of a key, independently of the hashers of the shards.
+    hasher: S,
+    /// The index of the window idle histories have been pruned at last.
+    pruned_at: Mutex<u128>,
+    epoch: Instant,
+    clock: C,
+}
+
+/// Requests and rejections of a single key over the recent windows.
+#[derive(Default)]
+struct History {
+    /// Counters of the recent windows, from the oldest to the newest.
+    windows: Vec<Window>,
+    /// Whether the key is currently above the threshold.
+    flagged: bool,
+}
+
+struct Window {
+    index: u128,
+    requests: u64,
+    rejected: u64,
+}
+
+type Shard<K, S> = HashMap<K, History, S>;
+
+impl<K, C: Clone, S: Clone> Clone for AnomalyDetector<K, C, S> {
+    /// Clone the configuration of the detector, but not the histories.
+    fn clone(&self) -> Self {
+        AnomalyDetector {
+            window: self.window,
+            windows: self.windows,
+            threshold: self.threshold,
+            callback: Arc::clone(&self.callback),
+            shards: (0..self.shards.len())
+                .map(|_| Mutex::new(HashMap::with_hasher(self.hasher.clone())))
+                .collect(),
+            hasher: self.hasher.clone(),
+            pruned_at: Mutex::new(*self.pruned_at.lock().unwrap()),
+            epoch: self.epoch,
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+impl<K, C: Clock> AnomalyDetector<K, C> {
+    pub(crate) fn new(
+        window: Duration,
+        windows: usize,
+        threshold: f64,
+        callback: AnomalyCallback<K>,
+        clock: C,
+    ) -> Self {
+        AnomalyDetector::with_hasher(
+            (window, windows, threshold),
+            callback,
+            clock,
+            RandomState::new(),
+        )
+    }
+}
+
+impl<K, C: Clock, S: Clone> AnomalyDetector<K, C, S> {
+    pub(crate) fn with_hasher(
+        (window, windows, threshold): (Duration, usize, f64),
+        callback: AnomalyCallback<K>,
+        clock: C,
+        hasher: S,
+    ) -> Self {
+        AnomalyDetector {
+            window,
+            windows,
+            threshold,
+            callback,
+            shards: (0..default_policy::shards())
+                .map(|_| Mutex::new(HashMap::with_hasher(hasher.clone())))
+                .collect(),
+            hasher,
+            pruned_at: Mutex::new(0),
+            epoch: clock.now(),
+            clock,
+        }
+    }
+}
+
+impl<K: Eq + Hash, C: Clock, S: BuildHasher> AnomalyDetector<K, C, S> {
+    /// Record a request for `key`, and whether it has been `rejected`. The
+    /// `key` is looked up by its borrowed form, which is converted into an
+    /// owned one via `to_owned` only for a key seen first, or once the
+    /// callback is invoked for it.
+    ///
+    /// The callback is invoked once the shard of the key is unlocked, so that
+    /// a slow callback doesn't stall requests of other keys.
+    pub(crate) fn record<Q>(&self, key: &Q, to_owned: fn(&Q) -> K, rejected: bool)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let index = self.index();
+        let fraction = {
+            let mut shard = self.shard(key).lock().unwrap();
+            if !shard.contains_key(key) {
+                // other shards are locked while pruning, so the shard of the
+                // key mustn't be locked yet
+                drop(shard);
+                self.prune(index);
+                shard = self.shard(key).lock().unwrap();
+            }
+            let history = match shard.get_mut(key) {
+                Some(history) => history,
+                None => shard.entry(to_owned(key)).or_default(),
+            };
+            self.update(history, index, rejected)
+        };
+        if let Some(fraction) = fraction {
+            (self.callback)(&to_owned(key), fraction);
+        }
+    }
+
+    /// Forget histories of all keys.
+    pub(crate) fn reset(&mut self) {
+        for shard in self.shards.iter_mut() {
+            shard.get_mut().unwrap().clear();
+        }
+    }
+
+    /// Forget histories of keys idle for all the windows, at most once per as
+    /// many windows, so that the cost of going through every key is amortized.
+    fn prune(&self, index: u128) {
+        let mut pruned_at = self.pruned_at.lock().unwrap();
+        if index.saturating_sub(*pruned_at) < self.windows as u128 {
+            return;
+        }
+        let oldest = self.oldest(index);
+        for shard in self.shards.iter() {
+            shard.lock().unwrap().retain(|_, history| {
+                history
+                    .windows
+                    .last()
+                    .is_some_and(|window| window.index >= oldest)
+            });
+        }
+        *pruned_at = index;
+    }
+
+    /// Return the index of the current window.
+    fn index(&self) -> u128 {
+        let now = self.clock.now();
+        now.saturating_duration_since(self.epoch).as_nanos() / self.window.as_nanos().max(1)
+    }
+
+    /// Return the index of the oldest window still counted at `index`.
+    fn oldest(&self, index: u128) -> u128 {
+        index.saturating_sub(self.windows.saturating_sub(1) as u128)
+    }
+
+    /// Update the `history` with a new request, and return the fraction of
+    /// rejected requests if it has just reached the threshold.
+    fn update(&self, history: &mut History, index: u128, rejected: bool) -> Option<f64> {
+        let oldest = self.oldest(index);
+        history.windows.retain(|window| window.index >= oldest);
+        match history.windows.last_mut() {
+            Some(window) if window.index == index => {
+                window.requests += 1;
+                window.rejected += rejected as u64;
+            }
+            _ => history.windows.push(Window {
+                index,
+                requests: 1,
+                rejected: rejected as u64,
+            }),
+        }
+
+        let (requests, rejected) = history
+            .windows
+            .iter()
+            .fold((0, 0), |(requests, rejected), window| {
+                (requests + window.requests, rejected + window.rejected)
+            });
+        let fraction = rejected as f64 / requests as f64;
+
+        let crossed = fraction >= self.threshold && !history.flagged;
+        history.flagged = fraction >= self.threshold;
+        crossed.then_some(fraction)
+    }
+
+    /// Return the shard the `key` belongs to.
+    #[inline]
+    fn shard<Q>(&self, key: &Q) -> &Mutex<Shard<K, S>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash,
+    {
+        // the lowest and the highest bits are used by the shards themselves
+        let hash = self.hasher.hash_one(key) >> 32;
+        &self.shards[hash as usize & (self.shards.len() - 1)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl<K, C, S> AnomalyDetector<K, C, S> {
+        fn len(&self) -> usize {
+            self.shards
+                .iter()
+                .map(|shard| shard.lock().unwrap().len())
+                .sum()
+        }
+    }
+
+    #[test]
+    fn prune() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let flagged = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&flagged);
+        let detector = AnomalyDetector::new(
+            Duration::from_secs(1),
+            2,
+            0.5,
+            Arc::new(move |key: &String, _| recorded.lock().unwrap().push(key.clone())),
+            &clock,
+        );
+
+        detector.record("A", str::to_string, true);
+        detector.record("B", str::to_string, false);
+        assert_eq!(*flagged.lock().unwrap(), vec!["A".to_string()]);
+        assert_eq!(detector.len(), 2);
+
+        // keys are kept as long as any of their windows is counted
+        *now.lock().unwrap() += Duration::from_secs(1);
+        detector.record("B", str::to_string, false);
+        detector.record("C", str::to_string, false);
+        assert_eq!(detector.len(), 3);
+
+        // and are forgotten once they have been idle for all the windows
+        *now.lock().unwrap() += Duration::from_secs(1);
+        detector.record("D", str::to_string, false);
+        assert_eq!(detector.len(), 3);
+        *now.lock().unwrap() += Duration::from_secs(2);
+        detector.record("E", str::to_string, false);
+        assert_eq!(detector.len(), 1);
+
+        // a forgotten key starts afresh
+        detector.record("A", str::to_string, true);
+        assert_eq!(flagged.lock().unwrap().len(), 2);
+    }
+}
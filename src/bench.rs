@@ -0,0 +1,247 @@
+//! Standardized workloads for comparing rate limiter implementations.
+//!
+//! The module is available behind the `bench` feature. It runs the same
+//! workload against different implementations, so that their throughput and
+//! decision latency can be compared apples-to-apples, and can be used from
+//! downstream benchmarks to measure custom setups the same way.
+//!
+//! ```
+//! use youshallnotpass::bench::{self, Workload};
+//!
+//! let workload = Workload::contended(4, 1_000);
+//! for report in bench::compare(&workload) {
+//!     println!("{}", report);
+//! }
+//! ```
+
+use std::time::{Duration, Instant};
+
+use crate::{KeyedRateLimiter, RateLimiter, TokenBucket};
+
+/// Parameters of a workload to run against an implementation.
+#[derive(Debug, Clone)]
+pub struct Workload {
+    /// Number of threads issuing decisions concurrently.
+    pub threads: usize,
+
+    /// Number of decisions each thread makes.
+    pub iterations: usize,
+
+    /// Number of tokens requested by each decision.
+    pub tokens: usize,
+}
+
+impl Workload {
+    /// A workload issuing `iterations` decisions from a single thread.
+    pub fn single_thread(iterations: usize) -> Self {
+        Workload {
+            threads: 1,
+            iterations,
+            tokens: 1,
+        }
+    }
+
+    /// A workload issuing `iterations` decisions from each of `threads`
+    /// threads hitting the same limiter.
+    pub fn contended(threads: usize, iterations: usize) -> Self {
+        Workload {
+            threads,
+            iterations,
+            tokens: 1,
+        }
+    }
+}
+
+/// Results of running a [`Workload`] against an implementation.
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// Name of the measured implementation.
+    pub name: String,
+
+    /// Number of threads the workload was run with.
+    pub threads: usize,
+
+    /// Total number of decisions made.
+    pub decisions: usize,
+
+    /// Number of decisions that allowed the request.
+    pub allowed: usize,
+
+    /// Wall-clock time it took to run the workload.
+    pub elapsed: Duration,
+
+    /// Latency of individual decisions, sorted in ascending order.
+    latencies: Vec<Duration>,
+}
+
+impl Report {
+    /// Number of decisions made per second, across all threads.
+    pub fn throughput(&self) -> f64 {
+        self.decisions as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Latency of a single decision at the given `percentile` (from 0 to 100).
+    pub fn latency(&self, percentile: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (percentile.clamp(0.0, 100.0) / 100.0 * (self.latencies.len() - 1) as f64)
+            .round() as usize;
+        self.latencies[rank]
+    }
+
+    /// Mean latency of a single decision.
+    pub fn mean_latency(&self) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        self.latencies.iter().sum::<Duration>() / self.latencies.len() as u32
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({} threads): {:.0} decisions/s, mean {:?}, p50 {:?}, p99 {:?}, max {:?}",
+            self.name,
+            self.threads,
+            self.throughput(),
+            self.mean_latency(),
+            self.latency(50.0),
+            self.latency(99.0),
+            self.latency(100.0),
+        )
+    }
+}
+
+/// Run a `workload` against an arbitrary implementation.
+///
+/// The `decide` function is called for every decision with the number of
+/// requested tokens, and must return whether the request is allowed. It's
+/// called concurrently from all the workload threads.
+pub fn run<F>(name: &str, workload: &Workload, decide: F) -> Report
+where
+    F: Fn(usize) -> bool + Sync,
+{
+    let started_at = Instant::now();
+    let results: Vec<(usize, Vec<Duration>)> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..workload.threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut allowed = 0;
+                    let mut latencies = Vec::with_capacity(workload.iterations);
+                    for _ in 0..workload.iterations {
+                        let decided_at = Instant::now();
+                        let is_allowed = decide(workload.tokens);
+                        latencies.push(decided_at.elapsed());
+                        allowed += is_allowed as usize;
+                    }
+                    (allowed, latencies)
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+    });
+    let elapsed = started_at.elapsed();
+
+    let mut allowed = 0;
+    let mut latencies = Vec::with_capacity(workload.threads * workload.iterations);
+    for (thread_allowed, thread_latencies) in results {
+        allowed += thread_allowed;
+        latencies.extend(thread_latencies);
+    }
+    latencies.sort_unstable();
+
+    Report {
+        name: name.to_string(),
+        threads: workload.threads,
+        decisions: latencies.len(),
+        allowed,
+        elapsed,
+        latencies,
+    }
+}
+
+/// Run a `workload` against every implementation available in this crate.
+///
+/// Every implementation is configured with the same generous policy, so
+/// that both allowed and rejected decisions are exercised.
+pub fn compare(workload: &Workload) -> Vec<Report> {
+    let limit = workload.threads * workload.iterations / 2;
+    let interval = Duration::from_secs(60);
+
+    let bucket = TokenBucket::new(limit, interval);
+    let limiter = RateLimiter::configure().limit(0, limit, interval).done();
+    let dense = RateLimiter::configure()
+        .limit(0usize, limit, interval)
+        .done_dense();
+    let keyed = KeyedRateLimiter::new(limit, interval);
+    let default = RateLimiter::configure()
+        .default_limit(limit, interval)
+        .done();
+
+    vec![
+        run("TokenBucket", workload, |tokens| {
+            bucket.consume(tokens).is_ok()
+        }),
+        run("RateLimiter", workload, |tokens| {
+            limiter.consume(&0, tokens).is_ok()
+        }),
+        run("DenseRateLimiter", workload, |tokens| {
+            dense.consume(0, tokens).is_ok()
+        }),
+        run("KeyedRateLimiter", workload, |tokens| {
+            keyed.consume(&0, tokens).is_ok()
+        }),
+        // keys without a policy of their own share the sharded default one
+        run("RateLimiter (default policy)", workload, |tokens| {
+            default.consume(&0, tokens).is_ok()
+        }),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_single_thread() {
+        let bucket = TokenBucket::new(10, Duration::from_secs(60));
+        let report = run("TokenBucket", &Workload::single_thread(15), |tokens| {
+            bucket.consume(tokens).is_ok()
+        });
+
+        assert_eq!(report.threads, 1);
+        assert_eq!(report.decisions, 15);
+        assert_eq!(report.allowed, 10);
+        assert!(report.latency(0.0) <= report.latency(50.0));
+        assert!(report.latency(50.0) <= report.latency(100.0));
+    }
+
+    #[test]
+    fn run_contended() {
+        let bucket = TokenBucket::new(10, Duration::from_secs(60));
+        let report = run("TokenBucket", &Workload::contended(4, 5), |tokens| {
+            bucket.consume(tokens).is_ok()
+        });
+
+        assert_eq!(report.threads, 4);
+        assert_eq!(report.decisions, 20);
+        assert_eq!(report.allowed, 10);
+    }
+
+    #[test]
+    fn compare_all() {
+        let reports = compare(&Workload::contended(2, 10));
+
+        assert_eq!(reports.len(), 5);
+        for report in reports {
+            assert_eq!(report.decisions, 20);
+            assert_eq!(report.allowed, 10);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+/// Whether a key bypasses its buckets by being always allowed, or always
+/// blocked, see [`RateLimiterBuilder::always_allow`].
+///
+/// [`RateLimiterBuilder::always_allow`]: crate::RateLimiterBuilder::always_allow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Bypass {
+    Allow,
+    Block,
+}
+
+/// Keys bypassing their buckets, which may be changed at runtime.
+pub(crate) struct Bypasses<K, S = RandomState> {
+    /// Whether any key is listed, so that rate limiters without such keys
+    /// don't pay for the lock.
+    listed: AtomicBool,
+    keys: RwLock<HashMap<K, Bypass, S>>,
+}
+
+impl<K, S> Bypasses<K, S> {
+    pub(crate) fn with_hasher(hasher: S) -> Self {
+        Bypasses {
+            listed: AtomicBool::new(false),
+            keys: RwLock::new(HashMap::with_hasher(hasher)),
+        }
+    }
+}
+
+impl<K: Eq + Hash, S: BuildHasher> Bypasses<K, S> {
+    /// Return whether `key` is always allowed or blocked, if it's either.
+    #[inline]
+    pub(crate) fn get<Q>(&self, key: &Q) -> Option<Bypass>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        if !self.listed.load(Ordering::Acquire) {
+            return None;
+        }
+        self.keys.read().unwrap().get(key).copied()
+    }
+
+    /// Set whether `key` is always allowed or blocked.
+    pub(crate) fn insert(&self, key: K, bypass: Bypass) {
+        self.keys.write().unwrap().insert(key, bypass);
+        self.listed.store(true, Ordering::Release);
+    }
+
+    /// Let `key` be limited by its buckets again.
+    pub(crate) fn remove<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.keys.write().unwrap().remove(key);
+    }
+
+    /// Rename every key with `f`.
+    pub(crate) fn rekey<F: FnMut(K) -> K>(&mut self, mut f: F)
+    where
+        S: Clone,
+    {
+        let keys = self.keys.get_mut().unwrap();
+        let mut rekeyed = HashMap::with_capacity_and_hasher(keys.len(), keys.hasher().clone());
+        rekeyed.extend(keys.drain().map(|(key, bypass)| (f(key), bypass)));
+        *keys = rekeyed;
+    }
+}
+
+impl<K: Clone, S: Clone> Clone for Bypasses<K, S> {
+    fn clone(&self) -> Self {
+        Bypasses {
+            listed: AtomicBool::new(self.listed.load(Ordering::Acquire)),
+            keys: RwLock::new(self.keys.read().unwrap().clone()),
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A source of monotonic time used by rate limiters to refill their tokens.
+///
+/// The default [`MonotonicClock`] is backed by [`Instant::now()`]. Any
+/// `Fn() -> Instant` closure is a clock too, which comes handy to control the
+/// time in tests.
+pub trait Clock {
+    /// Return the current moment in time.
+    fn now(&self) -> Instant;
+}
+
+/// The system monotonic clock, i.e. [`Instant::now()`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MonotonicClock;
+
+impl Clock for MonotonicClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl<F: Fn() -> Instant> Clock for F {
+    #[inline]
+    fn now(&self) -> Instant {
+        self()
+    }
+}
+
+/// A clock that only moves when told to, meant for tests.
+///
+/// Clones of the clock share the same time, so a clone can be handed over to
+/// a rate limiter, while the original one is used to advance the time.
+///
+/// ```
+/// use std::time::Duration;
+/// use youshallnotpass::{Clock, ManualClock};
+///
+/// let clock = ManualClock::new();
+/// let start = clock.now();
+///
+/// clock.clone().advance(Duration::from_secs(5));
+/// assert_eq!(clock.now() - start, Duration::from_secs(5));
+/// ```
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl ManualClock {
+    /// Create a new [`ManualClock`] set to the current moment in time.
+    pub fn new() -> Self {
+        ManualClock {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Move the clock forward by the given `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+
+        let clone = clock.clone();
+        clone.advance(Duration::from_secs(1));
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(clock.now(), start + Duration::from_millis(1500));
+        assert_eq!(clone.now(), clock.now());
+    }
+}
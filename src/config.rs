@@ -0,0 +1,413 @@
+use std::path::Path;
+use std::time::Duration;
+
+use serde::de::{DeserializeOwned, Deserializer, Error as _};
+use serde::Deserialize;
+
+use crate::error::ConfigError;
+use crate::quota::{parse_interval, Quota};
+use crate::rate_limiter::{RateLimiter, RateLimiterBuilder};
+
+/// Format of a configuration file read by [`RateLimiterBuilder::from_config`].
+///
+/// Every format is available with the feature of the same name only, i.e.
+/// `toml`, `yaml`, or `json`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    /// [TOML](https://toml.io), with the `.toml` extension.
+    #[cfg(feature = "toml")]
+    Toml,
+
+    /// [YAML](https://yaml.org), with either the `.yaml` or `.yml` extension.
+    #[cfg(feature = "yaml")]
+    Yaml,
+
+    /// [JSON](https://www.json.org), with the `.json` extension.
+    #[cfg(feature = "json")]
+    Json,
+}
+
+impl ConfigFormat {
+    /// Return the format of a file at `path` by its extension, or `None` if
+    /// it's unknown, or its feature is disabled.
+    pub fn from_path(path: &Path) -> Option<ConfigFormat> {
+        match path.extension()?.to_str()? {
+            #[cfg(feature = "toml")]
+            "toml" => Some(ConfigFormat::Toml),
+            #[cfg(feature = "yaml")]
+            "yaml" | "yml" => Some(ConfigFormat::Yaml),
+            #[cfg(feature = "json")]
+            "json" => Some(ConfigFormat::Json),
+            _ => None,
+        }
+    }
+
+    fn parse<K: DeserializeOwned>(self, config: &str) -> Result<Config<K>, ConfigError> {
+        let invalid = |error: &dyn std::fmt::Display| ConfigError::Invalid(error.to_string());
+        match self {
+            #[cfg(feature = "toml")]
+            ConfigFormat::Toml => toml::from_str(config).map_err(|error| invalid(&error)),
+            #[cfg(feature = "yaml")]
+            ConfigFormat::Yaml => serde_yaml::from_str(config).map_err(|error| invalid(&error)),
+            #[cfg(feature = "json")]
+            ConfigFormat::Json => serde_json::from_str(config).map_err(|error| invalid(&error)),
+        }
+    }
+}
+
+/// Limiting policies of a configuration file, see
+/// [`RateLimiterBuilder::from_config`].
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config<K> {
+    #[serde(default = "Vec::new")]
+    limits: Vec<KeyPolicy<K>>,
+    default: Option<Policy>,
+    global: Option<Policy>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyPolicy<K> {
+    key: K,
+    #[serde(default, deserialize_with = "rate")]
+    rate: Option<Quota>,
+    limit: Option<usize>,
+    #[serde(default, deserialize_with = "interval")]
+    interval: Option<Duration>,
+    burst: Option<usize>,
+}
+
+impl<K> KeyPolicy<K> {
+    fn into_parts(self) -> (K, Policy) {
+        let policy = Policy {
+            rate: self.rate,
+            limit: self.limit,
+            interval: self.interval,
+            burst: self.burst,
+        };
+        (self.key, policy)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Policy {
+    #[serde(default, deserialize_with = "rate")]
+    rate: Option<Quota>,
+    limit: Option<usize>,
+    #[serde(default, deserialize_with = "interval")]
+    interval: Option<Duration>,
+    burst: Option<usize>,
+}
+
+impl Policy {
+    /// Returns the quota of the policy, which is set either by a rate, or by
+    /// a limit and an interval. The `name` of the policy is reported if it's
+    /// set by neither.
+    fn quota(&self, name: &str) -> Result<Quota, ConfigError> {
+        let quota = match (self.rate, self.limit, self.interval) {
+            (Some(rate), None, None) => rate,
+            (None, Some(limit), Some(interval)) => Quota::new(limit, interval),
+            _ => {
+                return Err(ConfigError::Invalid(format!(
+                    "{} must set either a rate, or a limit and an interval",
+                    name
+                )))
+            }
+        };
+        Ok(match self.burst {
+            Some(burst) => quota.allow_burst(burst),
+            None => quota,
+        })
+    }
+}
+
+/// An interval given either in seconds, which may be fractional, or as a spec
+/// of the `[<count>]<unit>` form, e.g. `5m`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Interval {
+    Seconds(f64),
+    Spec(String),
+}
+
+fn interval<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    let interval = match Interval::deserialize(deserializer)? {
+        Interval::Seconds(seconds) => {
+            Duration::try_from_secs_f64(seconds).map_err(D::Error::custom)?
+        }
+        Interval::Spec(spec) => parse_interval(&spec).map_err(D::Error::custom)?,
+    };
+    Ok(Some(interval))
+}
+
+/// Deserializes a rate spec, e.g. `100/min`, into a [`Quota`].
+fn rate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Quota>, D::Error> {
+    let spec = String::deserialize(deserializer)?;
+    spec.parse().map(Some).map_err(D::Error::custom)
+}
+
+impl<K> RateLimiterBuilder<K> {
+    /// Constructs a new `RateLimiterBuilder` object with the limiting policies
+    /// of a configuration file at `path`.
+    ///
+    /// The format of the file is picked by its extension, see
+    /// [`ConfigFormat`]. The file lists policies of keys under `limits`, and
+    /// optionally the default and the global policies:
+    ///
+    /// ```toml
+    /// [[limits]]
+    /// key = "login"
+    /// limit = 5
+    /// interval = 60     # seconds, may be fractional, or a spec such as "1m"
+    /// burst = 2         # optional, same as the limit by default
+    ///
+    /// [default]
+    /// rate = "100/s"    # same as a limit and an interval, see Quota::from_str
+    ///
+    /// [global]
+    /// limit = 2000
+    /// interval = "1s"
+    /// ```
+    ///
+    /// The policies are set same as via [`quota`], [`default_quota`] and
+    /// [`global_quota`], so more of them can be set before the rate limiter is
+    /// constructed, and several ones set for the same key are stacked unless
+    /// the rate limiter is constructed via [`try_done`].
+    ///
+    /// Available with any of the `toml`, `yaml`, and `json` features only.
+    ///
+    /// [`quota`]: RateLimiterBuilder::quota
+    /// [`default_quota`]: RateLimiterBuilder::default_quota
+    /// [`global_quota`]: RateLimiterBuilder::global_quota
+    /// [`try_done`]: RateLimiterBuilder::try_done
+    pub fn from_config(path: impl AsRef<Path>) -> Result<Self, ConfigError>
+    where
+        K: DeserializeOwned + Clone,
+    {
+        let path = path.as_ref();
+        let format = ConfigFormat::from_path(path)
+            .ok_or_else(|| ConfigError::UnknownFormat(path.to_path_buf()))?;
+        let config = std::fs::read_to_string(path).map_err(ConfigError::Io)?;
+        Self::from_config_str(&config, format)
+    }
+
+    /// Constructs a new `RateLimiterBuilder` object with the limiting policies
+    /// of a `config` in a given `format`.
+    ///
+    /// Same as [`from_config`], but the configuration is already read, e.g.
+    /// from an environment variable.
+    ///
+    /// [`from_config`]: RateLimiterBuilder::from_config
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #[cfg(feature = "toml")] {
+    /// use youshallnotpass::{ConfigFormat, RateLimiterBuilder};
+    ///
+    /// let config = r#"
+    ///     [[limits]]
+    ///     key = "login"
+    ///     limit = 2
+    ///     interval = 60
+    /// "#;
+    /// let limiter = RateLimiterBuilder::<String>::from_config_str(config, ConfigFormat::Toml)
+    ///     .unwrap()
+    ///     .done();
+    ///
+    /// assert!(limiter.consume("login", 2).is_ok());
+    /// assert!(limiter.consume("login", 1).is_err());
+    /// # }
+    /// ```
+    pub fn from_config_str(config: &str, format: ConfigFormat) -> Result<Self, ConfigError>
+    where
+        K: DeserializeOwned + Clone,
+    {
+        RateLimiter::configure().config(config, format)
+    }
+}
+
+impl<K, C, S> RateLimiterBuilder<K, C, S> {
+    /// Sets the limiting policies of a `config` in a given `format`.
+    ///
+    /// Same as [`from_config_str`], but for a builder constructed otherwise,
+    /// e.g. with a custom clock.
+    ///
+    /// [`from_config_str`]: RateLimiterBuilder::from_config_str
+    pub fn config(self, config: &str, format: ConfigFormat) -> Result<Self, ConfigError>
+    where
+        K: DeserializeOwned + Clone,
+    {
+        let config: Config<K> = format.parse(config)?;
+        let mut builder = self;
+        for (index, policy) in config.limits.into_iter().enumerate() {
+            let (key, policy) = policy.into_parts();
+            let quota = policy.quota(&format!("limits[{}]", index))?;
+            builder = builder.quota(key, quota);
+        }
+        if let Some(policy) = config.default {
+            builder = builder.default_quota(policy.quota("default")?);
+        }
+        if let Some(policy) = config.global {
+            builder = builder.global_quota(policy.quota("global")?);
+        }
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn toml() {
+        use crate::error::Error;
+        use std::sync::Mutex;
+        use std::time::Instant;
+
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let config = r#"
+            [[limits]]
+            key = "A"
+            limit = 2
+            interval = 1
+
+            [[limits]]
+            key = "A"
+            rate = "3/min"
+
+            [[limits]]
+            key = "B"
+            limit = 60
+            interval = "1m"
+            burst = 1
+
+            [default]
+            limit = 1
+            interval = 0.5
+
+            [global]
+            limit = 10
+            interval = 60
+        "#;
+        let limiter = RateLimiter::with_clock(&clock)
+            .config(config, ConfigFormat::Toml)
+            .unwrap()
+            .done();
+
+        // several policies of a key are stacked
+        assert_eq!(limiter.consume("A", 2), Ok(()));
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(limiter.consume("A", 1), Ok(()));
+        assert_eq!(
+            limiter.consume("A", 1),
+            Err(Error::RetryAfter(Duration::from_secs(19)))
+        );
+
+        assert_eq!(limiter.consume("B", 1), Ok(()));
+        assert_eq!(
+            limiter.consume("B", 1),
+            Err(Error::RetryAfter(Duration::from_secs(1)))
+        );
+
+        assert_eq!(limiter.consume("C", 1), Ok(()));
+        assert_eq!(
+            limiter.consume("C", 1),
+            Err(Error::RetryAfter(Duration::from_millis(500)))
+        );
+
+        // the global policy caps all the keys together
+        for key in ["D", "E", "F", "G", "H"] {
+            assert_eq!(limiter.consume(key, 1), Ok(()));
+        }
+        assert!(limiter.consume("I", 1).is_err());
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn yaml() {
+        let config = "
+            limits:
+              - key: 1
+                rate: 1/min
+        ";
+        let limiter = RateLimiterBuilder::<u32>::from_config_str(config, ConfigFormat::Yaml)
+            .unwrap()
+            .done();
+        assert_eq!(limiter.consume(&1, 1), Ok(()));
+        assert!(limiter.consume(&1, 1).is_err());
+        assert_eq!(limiter.consume(&2, 100), Ok(()));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json() {
+        let config = r#"{"limits": [{"key": "A", "limit": 1, "interval": 60}]}"#;
+        let limiter = RateLimiterBuilder::<String>::from_config_str(config, ConfigFormat::Json)
+            .unwrap()
+            .done();
+        assert_eq!(limiter.consume("A", 1), Ok(()));
+        assert!(limiter.consume("A", 1).is_err());
+
+        // typos are rejected rather than ignored
+        let config = r#"{"limits": [{"key": "A", "limits": 1, "interval": 60}]}"#;
+        assert!(matches!(
+            RateLimiterBuilder::<String>::from_config_str(config, ConfigFormat::Json),
+            Err(ConfigError::Invalid(_))
+        ));
+        for policy in [
+            r#"{"key": "A", "limit": 1, "interval": -1}"#,
+            r#"{"key": "A", "limit": 1, "interval": "1 fortnight"}"#,
+            r#"{"key": "A", "rate": "1 per minute"}"#,
+            r#"{"key": "A", "rate": "1/min", "limit": 1}"#,
+            r#"{"key": "A", "limit": 1}"#,
+        ] {
+            let config = format!(r#"{{"limits": [{}]}}"#, policy);
+            assert!(matches!(
+                RateLimiterBuilder::<String>::from_config_str(&config, ConfigFormat::Json),
+                Err(ConfigError::Invalid(_))
+            ));
+        }
+        let config = r#"{"global": {"burst": 1}}"#;
+        assert_eq!(
+            RateLimiterBuilder::<String>::from_config_str(config, ConfigFormat::Json)
+                .err()
+                .map(|error| error.to_string()),
+            Some(
+                "Invalid configuration: global must set either a rate, or a limit and an interval"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn from_config() {
+        let path = std::env::temp_dir().join("youshallnotpass-config.ini");
+        assert!(matches!(
+            RateLimiterBuilder::<String>::from_config(&path),
+            Err(ConfigError::UnknownFormat(unknown)) if unknown == path
+        ));
+
+        #[cfg(feature = "json")]
+        {
+            let path = std::env::temp_dir().join("youshallnotpass-missing.json");
+            assert!(matches!(
+                RateLimiterBuilder::<String>::from_config(path),
+                Err(ConfigError::Io(_))
+            ));
+
+            let path = std::env::temp_dir().join("youshallnotpass-config.json");
+            std::fs::write(&path, r#"{"default": {"limit": 1, "interval": 60}}"#).unwrap();
+            let limiter = RateLimiterBuilder::<String>::from_config(&path)
+                .unwrap()
+                .done();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(limiter.consume("A", 1), Ok(()));
+            assert!(limiter.consume("A", 1).is_err());
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+
+use crate::clock::{Clock, MonotonicClock};
+use crate::costs::Costs;
+use crate::error::Error;
+use crate::rate_limiter::RateLimiter;
+
+/// Maps metadata of a request to the number of tokens it costs.
+type CostFn<M> = Arc<dyn Fn(&M) -> usize + Send + Sync>;
+
+/// A [`RateLimiter`] charging requests by their metadata (`M`), e.g. the size
+/// of a payload or the complexity of a query, rather than by a number of
+/// tokens computed at every call site.
+///
+/// Every key is charged by the same cost function, unless it has one of its
+/// own, and either of them can be replaced while the limiter is in use.
+/// Requests cost a single token each until there is a cost function for them.
+///
+/// ```
+/// use std::time::Duration;
+/// use youshallnotpass::{CostedRateLimiter, RateLimiter};
+///
+/// struct Upload {
+///     bytes: usize,
+/// }
+///
+/// let limiter = RateLimiter::configure()
+///     .limit("uploads", 10, Duration::from_secs(1))
+///     .done();
+/// let limiter = CostedRateLimiter::new(limiter);
+/// // a token per started kilobyte
+/// limiter.set_key_cost("uploads", |upload: &Upload| upload.bytes.div_ceil(1024));
+///
+/// assert!(limiter.consume_with(&"uploads", &Upload { bytes: 8 * 1024 }).is_ok());
+/// assert!(limiter.consume_with(&"uploads", &Upload { bytes: 4 * 1024 }).is_err());
+/// ```
+pub struct CostedRateLimiter<K, M, C = MonotonicClock, S = RandomState> {
+    limiter: RateLimiter<K, C, S>,
+    costs: Costs<K, Option<CostFn<M>>, S>,
+}
+
+impl<K: Eq + Hash, M, C: Clock, S: BuildHasher + Clone> CostedRateLimiter<K, M, C, S> {
+    /// Charge requests to the `limiter` by their metadata, each costing a
+    /// token until a cost function is set.
+    pub fn new(limiter: RateLimiter<K, C, S>) -> Self {
+        CostedRateLimiter {
+            costs: Costs::with_hasher(limiter.hasher().clone()),
+            limiter,
+        }
+    }
+}
+
+impl<K, M, C, S> CostedRateLimiter<K, M, C, S> {
+    /// Return the rate limiter the requests are charged to.
+    pub fn limiter(&self) -> &RateLimiter<K, C, S> {
+        &self.limiter
+    }
+}
+
+impl<K: Eq + Hash, M, C: Clock, S: BuildHasher> CostedRateLimiter<K, M, C, S> {
+    /// Set the function computing the cost of a request from its metadata,
+    /// unless overridden for the policy of a key.
+    pub fn set_cost<F>(&self, cost: F)
+    where
+        F: Fn(&M) -> usize + Send + Sync + 'static,
+    {
+        self.costs.update(|shared| *shared = Some(Arc::new(cost)));
+    }
+
+    /// Set the function computing the cost of a request from its metadata for
+    /// the policy of the `key`, overriding the one set for all the policies.
+    pub fn set_key_cost<F>(&self, key: K, cost: F)
+    where
+        F: Fn(&M) -> usize + Send + Sync + 'static,
+    {
+        self.costs
+            .update_key(key, |overridden| *overridden = Some(Arc::new(cost)));
+    }
+
+    /// Remove the function set for the policy of the `key`, so that the one
+    /// set for all the policies applies again.
+    pub fn remove_key_cost<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.costs.remove_key(key);
+    }
+
+    /// Return the number of tokens a request described by `meta` costs for
+    /// the `key`.
+    pub fn cost<Q>(&self, key: &Q, meta: &M) -> usize
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        // the function is called once the costs are unlocked, so that it may
+        // change them
+        self.costs
+            .find(key, Option::clone)
+            .map_or(1, |cost| cost(meta))
+    }
+
+    /// Try to consume the tokens a request described by `meta` costs from the
+    /// bucket for a given event (`key`).
+    ///
+    /// Same as [`RateLimiter::consume`], for the number of tokens computed by
+    /// the cost function of the `key`.
+    pub fn consume_with<Q>(&self, key: &Q, meta: &M) -> Result<(), Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        let tokens = self.cost(key, meta);
+        self.limiter.consume(key, tokens)
+    }
+
+    /// Same as [`RateLimiter::check`], for the number of tokens computed by
+    /// the cost function of the `key`.
+    pub fn check_with<Q>(&self, key: &Q, meta: &M) -> Result<(), Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        let tokens = self.cost(key, meta);
+        self.limiter.check(key, tokens)
+    }
+
+    /// Same as [`RateLimiter::refund`], for the number of tokens computed by
+    /// the cost function of the `key`.
+    ///
+    /// The tokens are computed by the current cost function, so a refund
+    /// following a change of the function returns a different number of
+    /// tokens than consumed.
+    pub fn refund_with<Q>(&self, key: &Q, meta: &M)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        let tokens = self.cost(key, meta);
+        self.limiter.refund(key, tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn consume_with() {
+        let clock = ManualClock::new();
+        let limiter = RateLimiter::with_clock(clock.clone())
+            .limit("A", 10, Duration::from_secs(1))
+            .default_limit(4, Duration::from_secs(1))
+            .done();
+        let limiter = CostedRateLimiter::new(limiter);
+
+        // every request costs a token until a cost function is set
+        assert_eq!(limiter.cost(&"A", &100), 1);
+        limiter.set_cost(|size: &usize| *size);
+        limiter.set_key_cost("B", |size: &usize| size / 2);
+        assert_eq!(limiter.cost(&"A", &4), 4);
+        assert_eq!(limiter.cost(&"B", &4), 2);
+
+        assert_eq!(limiter.consume_with(&"A", &6), Ok(()));
+        assert!(limiter.check_with(&"A", &5).is_err());
+        assert_eq!(limiter.consume_with(&"A", &4), Ok(()));
+        assert_eq!(
+            limiter.consume_with(&"A", &1),
+            Err(Error::RetryAfter(Duration::from_millis(100)))
+        );
+        limiter.refund_with(&"A", &1);
+        assert_eq!(limiter.consume_with(&"A", &1), Ok(()));
+
+        // keys limited by the default policy are charged the same way
+        assert_eq!(limiter.consume_with(&"B", &8), Ok(()));
+        assert!(limiter.consume_with(&"B", &2).is_err());
+        assert!(limiter.consume_with(&"C", &5).is_err());
+
+        clock.advance(Duration::from_secs(1));
+        limiter.remove_key_cost("B");
+        assert_eq!(limiter.consume_with(&"B", &4), Ok(()));
+        assert!(limiter.consume_with(&"B", &1).is_err());
+        assert_eq!(
+            limiter.consume_with(&"A", &usize::MAX),
+            Err(Error::ExceedsCapacity)
+        );
+    }
+
+    #[test]
+    fn reentrant_cost() {
+        let limiter = RateLimiter::configure()
+            .limit("A", 10, Duration::from_secs(1))
+            .done();
+        let limiter = Arc::new(CostedRateLimiter::new(limiter));
+
+        // a cost function may change the costs, e.g. to charge only once
+        let weak = Arc::downgrade(&limiter);
+        limiter.set_key_cost("A", move |size: &usize| {
+            weak.upgrade().unwrap().remove_key_cost("A");
+            *size
+        });
+        assert_eq!(limiter.cost(&"A", &4), 4);
+        assert_eq!(limiter.cost(&"A", &4), 1);
+    }
+}
@@ -0,0 +1,79 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::RwLock;
+
+/// Costs of requests set for all the policies of a rate limiter, some of
+/// which are overridden for the policies of specific keys, see
+/// [`CostedRateLimiter`] and [`Exchange`].
+///
+/// The costs are looked up far more often than changed, so they are kept
+/// behind a single lock shared by readers.
+///
+/// [`CostedRateLimiter`]: crate::CostedRateLimiter
+/// [`Exchange`]: crate::Exchange
+pub(crate) struct Costs<K, V, S> {
+    table: RwLock<Table<K, V, S>>,
+}
+
+struct Table<K, V, S> {
+    shared: V,
+    overrides: HashMap<K, V, S>,
+}
+
+impl<K, V: Default, S> Costs<K, V, S> {
+    pub(crate) fn with_hasher(hasher: S) -> Self {
+        Costs {
+            table: RwLock::new(Table {
+                shared: V::default(),
+                overrides: HashMap::with_hasher(hasher),
+            }),
+        }
+    }
+}
+
+impl<K: Eq + Hash, V: Default, S: BuildHasher> Costs<K, V, S> {
+    /// Change the costs set for all the policies via `f`.
+    pub(crate) fn update(&self, f: impl FnOnce(&mut V)) {
+        f(&mut self.table.write().unwrap().shared);
+    }
+
+    /// Change the costs overridden for the policy of the `key` via `f`,
+    /// starting with the default ones if there are none yet.
+    pub(crate) fn update_key(&self, key: K, f: impl FnOnce(&mut V)) {
+        f(self
+            .table
+            .write()
+            .unwrap()
+            .overrides
+            .entry(key)
+            .or_default());
+    }
+
+    /// Forget the costs overridden for the policy of the `key`.
+    pub(crate) fn remove_key<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.table.write().unwrap().overrides.remove(key);
+    }
+
+    /// Return what `f` finds in the costs overridden for the policy of the
+    /// `key`, falling back to the ones set for all the policies.
+    ///
+    /// The costs are locked while `f` runs, so it must not call back into
+    /// them, e.g. it should clone a function out rather than call it.
+    pub(crate) fn find<Q, T>(&self, key: &Q, f: impl Fn(&V) -> Option<T>) -> Option<T>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let table = self.table.read().unwrap();
+        table
+            .overrides
+            .get(key)
+            .and_then(&f)
+            .or_else(|| f(&table.shared))
+    }
+}
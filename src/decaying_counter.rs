@@ -0,0 +1,262 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, MonotonicClock};
+use crate::error::Error;
+
+/// Implementation of an exponentially decaying counter.
+///
+/// Unlike [`TokenBucket`](crate::TokenBucket), which enforces a strict quota,
+/// the counter keeps a *score* that every request adds to, and that decays
+/// exponentially over time: after each `half_life` period the score is halved.
+/// Requests are rejected while they would push the score above the configured
+/// `threshold`.
+///
+/// This makes the counter a cheap way to score abusive behaviour: occasional
+/// bursts are forgiven quickly, while sustained pressure keeps the score high.
+///
+/// ```
+/// use std::time::Duration;
+/// use youshallnotpass::{DecayingCounter, Error};
+///
+/// // create a counter that admits a score of up to 3, halved every 10 seconds
+/// let counter = DecayingCounter::new(3, Duration::from_secs(10));
+/// assert!(counter.consume(1).is_ok());
+/// assert!(counter.consume(2).is_ok());
+/// assert!(matches!(counter.consume(1), Err(Error::RetryAfter(duration))));
+/// ```
+pub struct DecayingCounter<C = MonotonicClock> {
+    threshold: f64,
+    half_life: Duration,
+    state: Mutex<Option<(f64, Instant)>>,
+    created_at: Instant,
+    clock: C,
+}
+
+/// A score too low to matter, which is treated as zero, since the score only
+/// approaches zero asymptotically. Otherwise a request for the whole threshold
+/// could never be admitted once anything has been consumed.
+const NEGLIGIBLE: f64 = 1e-6;
+
+impl DecayingCounter {
+    /// Create a new [`DecayingCounter`] admitting a score of up to `threshold`,
+    /// which is halved every `half_life` period of time.
+    ///
+    /// Specifying the `threshold` (or `half_life`) of 0 has a meaning of
+    /// blocking a given entity: every request is rejected with
+    /// [`Error::Blocked`].
+    pub fn new(threshold: usize, half_life: Duration) -> Self {
+        DecayingCounter::with_clock(threshold, half_life, MonotonicClock)
+    }
+}
+
+impl<C: Clock> DecayingCounter<C> {
+    /// Same as [`DecayingCounter::new()`], but allows to override the internal
+    /// clock, which is mainly useful in tests along with a [`ManualClock`](crate::ManualClock).
+    pub fn with_clock(threshold: usize, half_life: Duration, clock: C) -> Self {
+        DecayingCounter {
+            threshold: threshold as f64,
+            half_life,
+            state: Mutex::new(None),
+            created_at: clock.now(),
+            clock,
+        }
+    }
+
+    /// Try to add the specified number of `tokens` to the score.
+    ///
+    /// If the decayed score plus `tokens` does not exceed the threshold, the
+    /// tokens are added and `Ok(())` is returned.
+    ///
+    /// Otherwise, the score is *not* modified, and [`Error::RetryAfter`] is
+    /// returned. The error specifies how much time it takes for the score to
+    /// decay enough to admit the same number of tokens.
+    ///
+    /// Requests to a counter with the threshold of 0 result in [`Error::Blocked`],
+    /// while requests for more tokens than the threshold can never be admitted,
+    /// and result in [`Error::ExceedsCapacity`].
+    pub fn consume(&self, tokens: usize) -> Result<(), Error> {
+        self.validate(tokens)?;
+
+        let now = self.clock.now();
+        let mut lock = self.state.lock().unwrap();
+
+        let score = self.score(*lock, now);
+        self.delay(score, tokens)?;
+        *lock = Some((score + tokens as f64, now));
+        Ok(())
+    }
+
+    /// Check whether the specified number of `tokens` can be added to the
+    /// score, without adding them.
+    ///
+    /// See [`DecayingCounter::consume()`] for details.
+    pub fn check(&self, tokens: usize) -> Result<(), Error> {
+        self.validate(tokens)?;
+
+        let now = self.clock.now();
+        let score = self.score(*self.state.lock().unwrap(), now);
+        self.delay(score, tokens)
+    }
+
+    /// Return how long the counter hasn't admitted any requests, or has
+    /// existed if it has never admitted one.
+    ///
+    /// Unlike a [`TokenBucket`](crate::TokenBucket), rejected requests don't
+    /// count as a use, as they don't add to the score.
+    pub fn idle_for(&self) -> Duration {
+        let updated_at = match *self.state.lock().unwrap() {
+            Some((_, updated_at)) => updated_at,
+            None => self.created_at,
+        };
+        self.clock.now().saturating_duration_since(updated_at)
+    }
+
+    /// Return the error a request for `tokens` is rejected with regardless of
+    /// the score, if it is.
+    fn validate(&self, tokens: usize) -> Result<(), Error> {
+        if self.half_life.is_zero() || self.threshold == 0.0 {
+            return Err(Error::Blocked);
+        }
+        if tokens as f64 > self.threshold {
+            return Err(Error::ExceedsCapacity);
+        }
+        Ok(())
+    }
+
+    /// Return the error a request for `tokens` is rejected with at the given
+    /// `score`, if it is.
+    fn delay(&self, score: f64, tokens: usize) -> Result<(), Error> {
+        let tokens = tokens as f64;
+        if score + tokens <= self.threshold {
+            return Ok(());
+        }
+
+        // the score must decay down to (threshold - tokens), which takes
+        // log2(score / (threshold - tokens)) half-lives, or down to half of
+        // the negligible one, for a request of the whole threshold
+        let target = (self.threshold - tokens).max(NEGLIGIBLE / 2.0);
+        let half_lives = (score / target).log2();
+        let delay = Duration::try_from_secs_f64(self.half_life.as_secs_f64() * half_lives)
+            .unwrap_or(Duration::MAX);
+        Err(Error::RetryAfter(delay.max(Duration::from_nanos(1))))
+    }
+
+    /// Return the current score, with the decay up to the moment `now` applied.
+    fn score(&self, state: Option<(f64, Instant)>, now: Instant) -> f64 {
+        let score = match state {
+            Some((score, updated_at)) => {
+                let elapsed = now.saturating_duration_since(updated_at);
+                score * 0.5f64.powf(elapsed.as_secs_f64() / self.half_life.as_secs_f64())
+            }
+            None => 0.0,
+        };
+        if score < NEGLIGIBLE {
+            0.0
+        } else {
+            score
+        }
+    }
+}
+
+impl<C: Clone> Clone for DecayingCounter<C> {
+    /// Clone the counter along with its score.
+    fn clone(&self) -> Self {
+        DecayingCounter {
+            threshold: self.threshold,
+            half_life: self.half_life,
+            state: Mutex::new(*self.state.lock().unwrap()),
+            created_at: self.created_at,
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new() {
+        let counter = DecayingCounter::new(3, Duration::from_secs(60));
+
+        assert_eq!(counter.consume(1), Ok(()));
+        assert_eq!(counter.consume(1), Ok(()));
+        assert_eq!(counter.consume(1), Ok(()));
+        // we don't mock time in this test case, so checking the retry-after delay would be unreliable
+        assert!(matches!(counter.consume(1), Err(Error::RetryAfter(_))));
+    }
+
+    #[test]
+    fn blocked() {
+        let counter = DecayingCounter::new(0, Duration::from_secs(60));
+        assert_eq!(counter.consume(1), Err(Error::Blocked));
+
+        let counter = DecayingCounter::new(3, Duration::from_secs(0));
+        assert_eq!(counter.consume(1), Err(Error::Blocked));
+
+        // a request exceeding the threshold can never be admitted
+        let counter = DecayingCounter::new(3, Duration::from_secs(60));
+        assert_eq!(counter.consume(4), Err(Error::ExceedsCapacity));
+        assert_eq!(counter.consume(3), Ok(()));
+    }
+
+    #[test]
+    fn decay() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let counter = DecayingCounter::with_clock(4, Duration::from_secs(1), &clock);
+
+        assert_eq!(counter.consume(4), Ok(()));
+        // the score of 4 must decay to 2, which takes one half-life
+        assert_eq!(
+            counter.consume(2),
+            Err(Error::RetryAfter(Duration::from_secs(1)))
+        );
+        // the score of 4 must decay to 3, which takes log2(4/3) half-lives
+        assert_eq!(
+            counter.consume(1),
+            Err(Error::RetryAfter(Duration::from_nanos(415_037_499)))
+        );
+
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(counter.consume(2), Ok(()));
+        assert_eq!(
+            counter.consume(2),
+            Err(Error::RetryAfter(Duration::from_secs(1)))
+        );
+
+        // the score keeps decaying while the counter is idle
+        *now.lock().unwrap() += Duration::from_secs(2);
+        assert_eq!(counter.consume(3), Ok(()));
+        assert_eq!(
+            counter.consume(1),
+            Err(Error::RetryAfter(Duration::from_nanos(415_037_499)))
+        );
+    }
+
+    #[test]
+    fn whole_threshold() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let counter = DecayingCounter::with_clock(3, Duration::from_secs(10), &clock);
+
+        // the score of 1 must decay to a negligible one, which takes about 21
+        // half-lives, rather than forever
+        assert_eq!(counter.consume(1), Ok(()));
+        let Err(Error::RetryAfter(delay)) = counter.consume(3) else {
+            panic!("a request of the whole threshold must wait");
+        };
+        assert!(delay > Duration::from_secs(200) && delay < Duration::from_secs(220));
+        assert_eq!(counter.check(3), Err(Error::RetryAfter(delay)));
+
+        *now.lock().unwrap() += delay;
+        assert_eq!(counter.check(3), Ok(()));
+        assert_eq!(counter.consume(3), Ok(()));
+
+        // delays too long to be represented saturate
+        let counter = DecayingCounter::with_clock(3, Duration::MAX, &clock);
+        assert_eq!(counter.consume(1), Ok(()));
+        assert_eq!(counter.consume(3), Err(Error::RetryAfter(Duration::MAX)));
+    }
+}
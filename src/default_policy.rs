@@ -0,0 +1,305 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::Entry;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::clock::Clock;
+use crate::limiter::Limiter;
+use crate::quota::Quota;
+use crate::token_bucket::TokenBucket;
+
+/// Function cloning a key, captured where `K: Clone` is known.
+pub(crate) type CloneKey<K> = fn(&K) -> K;
+
+/// Limits on the buckets kept by a [`DefaultPolicy`].
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Eviction {
+    /// The maximum number of buckets; the least recently used ones are
+    /// evicted once it's reached.
+    pub(crate) max_keys: Option<usize>,
+    /// For how long a bucket may stay idle before it's evicted.
+    pub(crate) ttl: Option<Duration>,
+}
+
+/// A fallback policy for keys without an explicit limit.
+///
+/// Every key gets its own bucket, created on first use, which is a
+/// [`TokenBucket`] unless another [`Limiter`] is given as `B`. The buckets are
+/// shared, so that permits and speculations issued by them don't borrow the
+/// map they are stored in.
+///
+/// The buckets are spread across shards, each behind its own lock, so that
+/// creating buckets for new keys doesn't serialize lookups of other keys.
+pub(crate) struct DefaultPolicy<K, C, S = RandomState, B = TokenBucket<C>> {
+    quota: Quota,
+    shards: Box<[RwLock<Shard<K, B, S>>]>,
+    /// Picks the shard of a key, independently of the hashers of the shards.
+    hasher: S,
+    /// The number of buckets across all the shards.
+    len: AtomicUsize,
+    /// Captured at build time, so that the rate limiter requires neither
+    /// `K: Clone` nor `C: Clone` to look buckets up.
+    clone_key: CloneKey<K>,
+    new_bucket: fn(Quota, &C) -> B,
+    eviction: Eviction,
+    /// The last time idle buckets have been evicted.
+    swept_at: Mutex<Instant>,
+    clock: C,
+}
+
+type Shard<K, B, S> = HashMap<K, Arc<B>, S>;
+
+/// Return the number of shards, which is a power of two, so that contention
+/// stays low with every core looking buckets up.
+pub(crate) fn shards() -> usize {
+    let cores = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    (cores * 4).next_power_of_two()
+}
+
+impl<K: Eq + Hash, C: Clock, B: Limiter> DefaultPolicy<K, C, RandomState, B> {
+    pub(crate) fn new(
+        quota: Quota,
+        clone_key: CloneKey<K>,
+        new_bucket: fn(Quota, &C) -> B,
+        clock: C,
+    ) -> Self {
+        DefaultPolicy::with_hasher(quota, clone_key, new_bucket, clock, RandomState::new())
+    }
+}
+
+impl<K: Eq + Hash, C: Clock, S: BuildHasher, B: Limiter> DefaultPolicy<K, C, S, B> {
+    pub(crate) fn with_hasher(
+        quota: Quota,
+        clone_key: CloneKey<K>,
+        new_bucket: fn(Quota, &C) -> B,
+        clock: C,
+        hasher: S,
+    ) -> Self
+    where
+        S: Clone,
+    {
+        DefaultPolicy {
+            quota,
+            shards: (0..shards())
+                .map(|_| RwLock::new(HashMap::with_hasher(hasher.clone())))
+                .collect(),
+            hasher,
+            len: AtomicUsize::new(0),
+            clone_key,
+            new_bucket,
+            eviction: Eviction::default(),
+            swept_at: Mutex::new(clock.now()),
+            clock,
+        }
+    }
+
+    /// Return the limits on the buckets kept, which are enforced as new keys
+    /// come.
+    pub(crate) fn eviction_mut(&mut self) -> &mut Eviction {
+        &mut self.eviction
+    }
+
+    /// Return the bucket for `key`, creating it if the key is seen first.
+    pub(crate) fn bucket(&self, key: &K) -> Arc<B> {
+        self.bucket_borrowed(key, self.clone_key)
+    }
+
+    /// Same as [`DefaultPolicy::bucket`], but looks the `key` up by its
+    /// borrowed form, which is converted into an owned one via `to_owned`
+    /// only for a key seen first.
+    pub(crate) fn bucket_borrowed<Q>(&self, key: &Q, to_owned: fn(&Q) -> K) -> Arc<B>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let shard = self.shard(key);
+        if let Some(bucket) = shard.read().unwrap().get(key) {
+            return Arc::clone(bucket);
+        }
+        // other shards are locked while evicting, so the shard of the key
+        // mustn't be locked yet
+        self.evict();
+        let mut shard = shard.write().unwrap();
+        if let Some(bucket) = shard.get(key) {
+            return Arc::clone(bucket);
+        }
+        let bucket = Arc::new((self.new_bucket)(self.quota, &self.clock));
+        shard.insert(to_owned(key), Arc::clone(&bucket));
+        self.len.fetch_add(1, Ordering::Relaxed);
+        bucket
+    }
+
+    /// Make room for a new bucket, according to the eviction limits.
+    ///
+    /// The shards are locked one at a time, so new buckets may be created
+    /// concurrently, and the limits are approximate.
+    fn evict(&self) {
+        if let Some(ttl) = self.eviction.ttl {
+            let now = self.clock.now();
+            let mut swept_at = self.swept_at.lock().unwrap();
+            if now.saturating_duration_since(*swept_at) >= ttl {
+                self.retain(|bucket| bucket.idle_for() < ttl);
+                *swept_at = now;
+            }
+        }
+
+        let len = self.len.load(Ordering::Relaxed);
+        let max_keys = match self.eviction.max_keys {
+            Some(max_keys) if len >= max_keys => max_keys.max(1),
+            _ => return,
+        };
+        // An eighth of the buckets is evicted at once, so that the cost of
+        // finding the least recently used ones is amortized.
+        let mut idle: Vec<Duration> = Vec::with_capacity(len);
+        for shard in self.shards.iter() {
+            idle.extend(
+                shard
+                    .read()
+                    .unwrap()
+                    .values()
+                    .map(|bucket| bucket.idle_for()),
+            );
+        }
+        let count = (len + 1 + max_keys / 8 - max_keys).min(idle.len());
+        if count == 0 {
+            return;
+        }
+        let (_, &mut threshold, _) = idle.select_nth_unstable_by(count - 1, |a, b| b.cmp(a));
+        let mut evicted = 0;
+        self.retain(|bucket| {
+            let evict = evicted < count && bucket.idle_for() >= threshold;
+            evicted += usize::from(evict);
+            !evict
+        });
+    }
+
+    /// Keep only the buckets for which `f` returns `true`.
+    fn retain(&self, mut f: impl FnMut(&B) -> bool) {
+        for shard in self.shards.iter() {
+            let mut shard = shard.write().unwrap();
+            let len = shard.len();
+            shard.retain(|_, bucket| f(bucket));
+            self.len.fetch_sub(len - shard.len(), Ordering::Relaxed);
+        }
+    }
+
+    /// Return the shard the `key` belongs to.
+    #[inline]
+    fn shard<Q>(&self, key: &Q) -> &RwLock<Shard<K, B, S>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash,
+    {
+        &self.shards[self.shard_index(key)]
+    }
+
+    #[inline]
+    fn shard_index<Q>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash,
+    {
+        // the lowest and the highest bits are used by the shards themselves
+        let hash = self.hasher.hash_one(key) >> 32;
+        hash as usize & (self.shards.len() - 1)
+    }
+
+    /// Return a bucket of a key seen first, without storing it.
+    pub(crate) fn fresh(&self) -> B {
+        (self.new_bucket)(self.quota, &self.clock)
+    }
+
+    /// Return the number of keys seen so far.
+    pub(crate) fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    /// Return the bucket for `key`, if the key has been seen before.
+    pub(crate) fn get<Q>(&self, key: &Q) -> Option<Arc<B>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.shard(key).read().unwrap().get(key).map(Arc::clone)
+    }
+
+    /// Forget the bucket for `key`, e.g. once the key gets its own policy.
+    pub(crate) fn remove(&self, key: &K) {
+        if self.shard(key).write().unwrap().remove(key).is_some() {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Return every key seen so far, along with its bucket.
+    pub(crate) fn buckets(&self) -> Vec<(K, Arc<B>)> {
+        let mut buckets = Vec::with_capacity(self.len());
+        for shard in self.shards.iter() {
+            buckets.extend(
+                shard
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .map(|(key, bucket)| ((self.clone_key)(key), Arc::clone(bucket))),
+            );
+        }
+        buckets
+    }
+}
+
+impl<K: Eq + Hash, C: Clock, S: BuildHasher> DefaultPolicy<K, C, S> {
+    /// Rename the keys by `f`, merging the buckets of colliding keys.
+    pub(crate) fn rekey<F: FnMut(K) -> K>(&mut self, mut f: F) {
+        let mut buckets = Vec::with_capacity(*self.len.get_mut());
+        for shard in self.shards.iter_mut() {
+            buckets.extend(shard.get_mut().unwrap().drain());
+        }
+        let mut len = 0;
+        for (key, bucket) in buckets {
+            let key = f(key);
+            let index = self.shard_index(&key);
+            match self.shards[index].get_mut().unwrap().entry(key) {
+                Entry::Occupied(entry) => entry.get().absorb(&bucket),
+                Entry::Vacant(entry) => {
+                    entry.insert(bucket);
+                    len += 1;
+                }
+            }
+        }
+        *self.len.get_mut() = len;
+    }
+}
+
+impl<K, C, S, B> DefaultPolicy<K, C, S, B> {
+    pub(crate) fn quota(&self) -> &Quota {
+        &self.quota
+    }
+}
+
+impl<K: Clone, C: Clone, S: Clone, B: Clone> Clone for DefaultPolicy<K, C, S, B> {
+    /// Clone the policy along with the state of every bucket.
+    fn clone(&self) -> Self {
+        let shards = self.shards.iter().map(|shard| {
+            let mut shard = shard.read().unwrap().clone();
+            for bucket in shard.values_mut() {
+                *bucket = Arc::new(B::clone(bucket));
+            }
+            RwLock::new(shard)
+        });
+        DefaultPolicy {
+            quota: self.quota,
+            shards: shards.collect(),
+            hasher: self.hasher.clone(),
+            len: AtomicUsize::new(self.len.load(Ordering::Relaxed)),
+            clone_key: self.clone_key,
+            new_bucket: self.new_bucket,
+            eviction: self.eviction,
+            swept_at: Mutex::new(*self.swept_at.lock().unwrap()),
+            clock: self.clock.clone(),
+        }
+    }
+}
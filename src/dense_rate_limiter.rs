@@ -0,0 +1,230 @@
+use std::hash::Hash;
+
+use crate::anomaly::AnomalyDetector;
+use crate::clock::{Clock, MonotonicClock};
+use crate::error::Error;
+use crate::jitter::Jitter;
+use crate::TokenBucket;
+
+/// Rate limiter for keys that are small integers, e.g. enum discriminants.
+///
+/// Unlike [`RateLimiter`](crate::RateLimiter), which looks buckets up in a
+/// hash map, the dense rate limiter stores them in an array indexed by the
+/// key itself, so no hashing is involved unless anomaly detection is set up.
+/// The array is as long as the largest key, hence keys are expected to be
+/// small.
+///
+/// The dense rate limiter is constructed with the same builder as the
+/// [`RateLimiter`](crate::RateLimiter), via
+/// [`RateLimiterBuilder::done_dense`](crate::RateLimiterBuilder::done_dense).
+///
+/// ```
+/// use std::time::Duration;
+/// use youshallnotpass::{Error, RateLimiter};
+///
+/// #[derive(Clone, Copy, PartialEq, Eq, Hash)]
+/// enum Endpoint {
+///     Login,
+///     Search,
+/// }
+///
+/// impl From<Endpoint> for usize {
+///     fn from(endpoint: Endpoint) -> usize {
+///         endpoint as usize
+///     }
+/// }
+///
+/// let limiter = RateLimiter::configure()
+///     .limit(Endpoint::Login, 1, Duration::from_secs(60))
+///     .done_dense();
+///
+/// assert_eq!(limiter.consume(Endpoint::Login, 1), Ok(()));
+/// assert!(matches!(limiter.consume(Endpoint::Login, 1), Err(Error::RetryAfter(_))));
+/// assert_eq!(limiter.consume(Endpoint::Search, 1), Ok(()));
+/// ```
+pub struct DenseRateLimiter<K, C = MonotonicClock> {
+    buckets: Vec<Option<TokenBucket<C>>>,
+    jitter: Option<Jitter>,
+    anomalies: Option<AnomalyDetector<K, C>>,
+}
+
+impl<K, C> DenseRateLimiter<K, C> {
+    pub(crate) fn new(
+        buckets: Vec<Option<TokenBucket<C>>>,
+        jitter: Option<Jitter>,
+        anomalies: Option<AnomalyDetector<K, C>>,
+    ) -> Self {
+        DenseRateLimiter {
+            buckets,
+            jitter,
+            anomalies,
+        }
+    }
+}
+
+impl<K: Copy + Into<usize> + Eq + Hash, C: Clock> DenseRateLimiter<K, C> {
+    /// Tries to consume the specified number of `tokens` from the bucket for a
+    /// given event (`key`).
+    ///
+    /// Same as [`RateLimiter::consume`](crate::RateLimiter::consume): if no
+    /// `limit` is set for the `key`, the function always succeeds.
+    #[inline]
+    pub fn consume(&self, key: K, tokens: usize) -> Result<(), Error> {
+        self.consume_remaining(key, tokens).map(|_| ())
+    }
+
+    /// Same as [`consume`], but returns the number of tokens remaining in the
+    /// bucket for a given event (`key`) after the consumption, or `None` if no
+    /// `limit` is set for the `key`.
+    ///
+    /// [`consume`]: DenseRateLimiter::consume
+    #[inline]
+    pub fn consume_remaining(&self, key: K, tokens: usize) -> Result<Option<u64>, Error> {
+        match self.bucket(key) {
+            Some(bucket) => {
+                let result = bucket.consume_remaining(tokens);
+                if let Some(anomalies) = &self.anomalies {
+                    anomalies.record(&key, |key| *key, result.is_err());
+                }
+                result.map(Some).map_err(|error| match &self.jitter {
+                    Some(jitter) => jitter.apply(error),
+                    None => error,
+                })
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Checks whether the specified number of `tokens` can be consumed from the
+    /// bucket for a given event (`key`), without consuming them.
+    ///
+    /// See [`TokenBucket::check`] for details.
+    pub fn check(&self, key: K, tokens: usize) -> Result<(), Error> {
+        self.bucket(key)
+            .map(|bucket| bucket.check(tokens))
+            .unwrap_or(Ok(()))
+    }
+
+    /// Returns the specified number of `tokens` back to the bucket for a given
+    /// event (`key`).
+    ///
+    /// See [`TokenBucket::refund`] for details.
+    pub fn refund(&self, key: K, tokens: usize) {
+        if let Some(bucket) = self.bucket(key) {
+            bucket.refund(tokens);
+        }
+    }
+
+    /// Refills the bucket for a given event (`key`) up to its capacity.
+    ///
+    /// See [`TokenBucket::reset`] for details.
+    pub fn reset(&self, key: K) {
+        if let Some(bucket) = self.bucket(key) {
+            bucket.reset();
+        }
+    }
+
+    #[inline]
+    fn bucket(&self, key: K) -> Option<&TokenBucket<C>> {
+        self.buckets.get(key.into())?.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use crate::{BuildError, RateLimiter, RateLimiterBuilder};
+
+    #[test]
+    fn consume() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit(1_u8, 2, Duration::from_secs(2))
+            .limit(3_u8, 0, Duration::from_secs(2))
+            .done_dense();
+
+        assert_eq!(limiter.consume_remaining(1, 1), Ok(Some(1)));
+        assert_eq!(limiter.consume_remaining(1, 1), Ok(Some(0)));
+        assert_eq!(
+            limiter.consume(1, 1),
+            Err(Error::RetryAfter(Duration::from_secs(1)))
+        );
+        assert_eq!(limiter.consume(1, 3), Err(Error::ExceedsCapacity));
+        assert_eq!(limiter.consume(3, 1), Err(Error::Blocked));
+
+        // keys without a policy, either within or beyond the array, are never
+        // limited
+        assert_eq!(limiter.consume_remaining(0, 100), Ok(None));
+        assert_eq!(limiter.consume_remaining(2, 100), Ok(None));
+        assert_eq!(limiter.consume_remaining(200, 100), Ok(None));
+
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(limiter.check(1, 1), Ok(()));
+        assert_eq!(limiter.consume(1, 1), Ok(()));
+        assert!(limiter.check(1, 1).is_err());
+
+        limiter.refund(1, 1);
+        assert_eq!(limiter.consume(1, 1), Ok(()));
+        limiter.reset(1);
+        assert_eq!(limiter.consume_remaining(1, 2), Ok(Some(0)));
+    }
+
+    #[test]
+    fn shadowed() {
+        let limiter = RateLimiter::configure()
+            .limit(0_usize, 1, Duration::from_secs(60))
+            .limit(0_usize, 2, Duration::from_secs(60))
+            .done_dense();
+
+        // the last policy wins, unlike for the hash map based limiter that
+        // stacks them
+        assert_eq!(limiter.consume(0, 2), Ok(()));
+    }
+
+    #[test]
+    fn try_done_dense() {
+        let configure = || RateLimiter::configure().limit(1_usize, 1, Duration::from_secs(1));
+        assert!(configure()
+            .group([2, 3], 1, Duration::from_secs(1))
+            .try_done_dense()
+            .is_err());
+        assert!(configure()
+            .group([2], 1, Duration::from_secs(1))
+            .try_done_dense()
+            .is_ok());
+
+        let unsupported = |builder: RateLimiterBuilder<usize>| builder.try_done_dense().err();
+        let cases = [
+            (configure().always_allow(2), "always_allow"),
+            (configure().always_block(2), "always_block"),
+            (configure().pattern(2, 1, Duration::from_secs(1)), "pattern"),
+            (
+                configure().default_limit(1, Duration::from_secs(1)),
+                "default_limit",
+            ),
+            (
+                configure().global_limit(1, Duration::from_secs(1)),
+                "global_limit",
+            ),
+            (configure().track_stats(), "track_stats"),
+            (configure().normalize(|key| key % 2), "normalize"),
+        ];
+        for (builder, option) in cases {
+            assert_eq!(unsupported(builder), Some(BuildError::Unsupported(option)));
+        }
+        assert_eq!(
+            unsupported(configure().limit(1, 2, Duration::from_secs(1))),
+            Some(BuildError::DuplicateKey(1))
+        );
+
+        // unlike the fallible one, the infallible builder panics
+        let builder = configure().global_limit(1, Duration::from_secs(1));
+        let built = panic::catch_unwind(AssertUnwindSafe(|| builder.done_dense()));
+        assert!(built.is_err());
+    }
+}
@@ -1,13 +1,28 @@
 use std::time::Duration;
 
 /// Error type describing various possible conditions for why requests are rejected.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Error {
     /// The corresponding entity is completely blocked. New attempts will also result in failures.
     Blocked,
 
     /// The configured rate-limit has been exceeded. New attempts might succeed after the specified delay.
     RetryAfter(Duration),
+
+    /// The request costs more tokens than the bucket can ever hold. New attempts will also result in failures.
+    ExceedsCapacity,
+}
+
+impl Error {
+    /// Return the error of the two that rejects a request for longer, e.g.
+    /// when several limits reject it at once.
+    pub(crate) fn strictest(self, other: Error) -> Error {
+        match (self, other) {
+            (Error::Blocked, _) | (_, Error::Blocked) => Error::Blocked,
+            (Error::ExceedsCapacity, _) | (_, Error::ExceedsCapacity) => Error::ExceedsCapacity,
+            (Error::RetryAfter(a), Error::RetryAfter(b)) => Error::RetryAfter(a.max(b)),
+        }
+    }
 }
 
 impl std::fmt::Display for Error {
@@ -17,8 +32,156 @@ impl std::fmt::Display for Error {
             Error::RetryAfter(duration) => {
                 write!(f, "Retry after {:.1} seconds", duration.as_secs_f64())
             }
+            Error::ExceedsCapacity => write!(f, "Request exceeds capacity"),
         }
     }
 }
 
 impl std::error::Error for Error {}
+
+/// Error type describing why the limiting policies set via a
+/// [`RateLimiterBuilder`](crate::RateLimiterBuilder) are rejected by
+/// [`RateLimiterBuilder::try_done()`](crate::RateLimiterBuilder::try_done).
+///
+/// Policies without a key, i.e. the default, the global, and the regular
+/// expression ones, are reported with `None` instead of a key.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BuildError<K> {
+    /// Several policies were set for the key. Unlike `try_done()`, `done()`
+    /// stacks them on top of each other.
+    DuplicateKey(K),
+
+    /// The policy blocks every request, since either its limit or its
+    /// interval is zero, while blocking hasn't been set explicitly via
+    /// [`Quota::blocked()`](crate::Quota::blocked).
+    ZeroQuota(Option<K>),
+
+    /// The interval of the policy, or the time it takes to accumulate its
+    /// burst, exceeds ~584 years, and would be capped.
+    IntervalOverflow(Option<K>),
+
+    /// The option, named after the builder method setting it, isn't supported
+    /// by the [`DenseRateLimiter`](crate::DenseRateLimiter), see
+    /// [`RateLimiterBuilder::try_done_dense()`](crate::RateLimiterBuilder::try_done_dense).
+    Unsupported(&'static str),
+}
+
+impl<K: std::fmt::Debug> std::fmt::Display for BuildError<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let policy = |key: &Option<K>| match key {
+            Some(key) => format!("Policy for {:?}", key),
+            None => "Policy without a key".to_string(),
+        };
+        match self {
+            BuildError::DuplicateKey(key) => write!(f, "Several policies are set for {:?}", key),
+            BuildError::ZeroQuota(key) => write!(f, "{} blocks every request", policy(key)),
+            BuildError::IntervalOverflow(key) => {
+                write!(f, "{} has an interval too long", policy(key))
+            }
+            BuildError::Unsupported(option) => {
+                write!(f, "{} is not supported by the dense rate limiter", option)
+            }
+        }
+    }
+}
+
+impl<K: std::fmt::Debug> std::error::Error for BuildError<K> {}
+
+/// Error type describing why limiting policies cannot be read from a
+/// configuration, see
+/// [`RateLimiterBuilder::from_config()`](crate::RateLimiterBuilder::from_config).
+#[cfg(any(feature = "toml", feature = "yaml", feature = "json"))]
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The configuration file cannot be read.
+    Io(std::io::Error),
+
+    /// The format of the configuration file is unknown by its extension, or
+    /// its feature is disabled.
+    UnknownFormat(std::path::PathBuf),
+
+    /// The configuration is malformed, e.g. a field is missing, unknown, or
+    /// of a wrong type.
+    Invalid(String),
+}
+
+#[cfg(any(feature = "toml", feature = "yaml", feature = "json"))]
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(error) => write!(f, "Cannot read configuration: {}", error),
+            ConfigError::UnknownFormat(path) => {
+                write!(f, "Unknown configuration format of {}", path.display())
+            }
+            ConfigError::Invalid(message) => write!(f, "Invalid configuration: {}", message),
+        }
+    }
+}
+
+#[cfg(any(feature = "toml", feature = "yaml", feature = "json"))]
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// Error type describing why a rate spec, such as `"10/s"`, cannot be parsed
+/// into a [`Quota`](crate::Quota).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseRateError {
+    /// The spec isn't of the `<tokens>/<interval>` form.
+    Malformed,
+
+    /// The number of tokens isn't a non-negative integer.
+    InvalidTokens,
+
+    /// The interval isn't of the `[<count>]<unit>` form with a known unit,
+    /// e.g. `5m`, or is too long to be represented.
+    InvalidInterval,
+}
+
+impl std::fmt::Display for ParseRateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseRateError::Malformed => write!(f, "Rate is not of the <tokens>/<interval> form"),
+            ParseRateError::InvalidTokens => write!(f, "Number of tokens is not an integer"),
+            ParseRateError::InvalidInterval => {
+                write!(f, "Interval is not of the [<count>]<unit> form")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseRateError {}
+
+/// Error type describing why a serialized [`TokenBucket`](crate::TokenBucket) cannot be decoded.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input is shorter than the encoding requires.
+    Truncated,
+
+    /// The input was produced by an unknown version of the encoding.
+    UnsupportedVersion(u8),
+
+    /// The input was produced with an unknown clock mode.
+    UnsupportedClockMode(u8),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "Encoded bucket is truncated"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported encoding version {}", version)
+            }
+            DecodeError::UnsupportedClockMode(mode) => {
+                write!(f, "Unsupported clock mode {}", mode)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
@@ -0,0 +1,241 @@
+use std::borrow::Borrow;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use crate::clock::Clock;
+use crate::default_policy;
+
+/// Temporary bans of keys that repeatedly hit their limits, see
+/// [`RateLimiterBuilder::escalate_after`].
+///
+/// For every key, the rejections over the last `window` are tracked, and the
+/// key is banned for `ban` once there are `violations` of them.
+///
+/// The keys are spread across shards, the same way the buckets of the
+/// default policy are, so that recording a rejection locks out only the keys
+/// of a single shard. Bans are checked under a shared lock.
+///
+/// [`RateLimiterBuilder::escalate_after`]: crate::RateLimiterBuilder::escalate_after
+pub(crate) struct Escalation<K, C, S> {
+    violations: usize,
+    window: Duration,
+    ban: Duration,
+    /// Whether any key has ever been banned, so that rate limiters without
+    /// bans don't pay for the lock.
+    banned: AtomicBool,
+    shards: Box<[RwLock<Shard<K, S>>]>,
+    /// Picks the shard of a key, independently of the hashers of the shards.
+    hasher: S,
+    clock: C,
+}
+
+/// Recent rejections of a single key, and its ban, if any.
+#[derive(Clone, Default)]
+struct Offender {
+    /// Times of the rejections within the window, from the oldest to the
+    /// newest.
+    rejections: VecDeque<Instant>,
+    banned_until: Option<Instant>,
+}
+
+type Shard<K, S> = HashMap<K, Offender, S>;
+
+impl Offender {
+    /// Return whether the offender is neither banned nor rejected within the
+    /// `window` at `now`, and thus may be forgotten.
+    fn expired(&self, now: Instant, window: Duration) -> bool {
+        self.banned_until.is_none_or(|until| until <= now)
+            && self
+                .rejections
+                .back()
+                .is_none_or(|&rejected| now.saturating_duration_since(rejected) >= window)
+    }
+}
+
+impl<K, C, S: Clone> Escalation<K, C, S> {
+    pub(crate) fn with_hasher(
+        (violations, window, ban): (usize, Duration, Duration),
+        clock: C,
+        hasher: S,
+    ) -> Self {
+        Escalation {
+            violations,
+            window,
+            ban,
+            banned: AtomicBool::new(false),
+            shards: (0..default_policy::shards())
+                .map(|_| RwLock::new(HashMap::with_hasher(hasher.clone())))
+                .collect(),
+            hasher,
+            clock,
+        }
+    }
+}
+
+impl<K: Eq + Hash, C: Clock, S: BuildHasher> Escalation<K, C, S> {
+    /// Return whether `key` is currently banned.
+    #[inline]
+    pub(crate) fn banned<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        if !self.banned.load(Ordering::Acquire) {
+            return false;
+        }
+        let shard = self.shard(key).read().unwrap();
+        shard
+            .get(key)
+            .and_then(|offender| offender.banned_until)
+            .is_some_and(|until| self.clock.now() < until)
+    }
+
+    /// Record a rejection of `key`, and ban it if that's one too many. The
+    /// `key` is looked up by its borrowed form, which is converted into an
+    /// owned one via `to_owned` only for a key rejected first.
+    ///
+    /// Offenders neither banned nor rejected within the window are pruned
+    /// from the shard of a key rejected first, right before the shard would
+    /// grow, so that only the keys rejected recently are kept, and the cost
+    /// of pruning is amortized.
+    pub(crate) fn record<Q>(&self, key: &Q, to_owned: fn(&Q) -> K)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let now = self.clock.now();
+        let mut shard = self.shard(key).write().unwrap();
+        let offender = match shard.get_mut(key) {
+            Some(offender) => offender,
+            None => {
+                if shard.len() == shard.capacity() {
+                    shard.retain(|_, offender| !offender.expired(now, self.window));
+                }
+                shard.entry(to_owned(key)).or_default()
+            }
+        };
+        while offender
+            .rejections
+            .front()
+            .is_some_and(|&rejected| now.saturating_duration_since(rejected) >= self.window)
+        {
+            offender.rejections.pop_front();
+        }
+        offender.rejections.push_back(now);
+
+        if offender.rejections.len() >= self.violations {
+            offender.rejections.clear();
+            offender.banned_until = Some(now + self.ban);
+            self.banned.store(true, Ordering::Release);
+        }
+    }
+
+    /// Lift the ban of `key`, and forget its rejections.
+    pub(crate) fn pardon<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.shard(key).write().unwrap().remove(key);
+    }
+
+    /// Rename every key with `f`, merging the rejections and keeping the
+    /// longest ban of the keys that are renamed to the same one.
+    pub(crate) fn rekey<F: FnMut(K) -> K>(&mut self, mut f: F) {
+        let offenders: Vec<_> = self
+            .shards
+            .iter_mut()
+            .flat_map(|shard| shard.get_mut().unwrap().drain())
+            .collect();
+        for (key, offender) in offenders {
+            let key = f(key);
+            let index = self.shard_index(&key);
+            let shard = self.shards[index].get_mut().unwrap();
+            let merged = shard.entry(key).or_default();
+            merged.rejections.extend(offender.rejections);
+            merged.rejections.make_contiguous().sort();
+            merged.banned_until = merged.banned_until.max(offender.banned_until);
+        }
+    }
+
+    /// Return the shard the `key` belongs to.
+    #[inline]
+    fn shard<Q>(&self, key: &Q) -> &RwLock<Shard<K, S>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash,
+    {
+        &self.shards[self.shard_index(key)]
+    }
+
+    #[inline]
+    fn shard_index<Q>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash,
+    {
+        // the lowest and the highest bits are used by the shards themselves
+        let hash = self.hasher.hash_one(key) >> 32;
+        hash as usize & (self.shards.len() - 1)
+    }
+}
+
+impl<K: Clone, C: Clone, S: Clone> Clone for Escalation<K, C, S> {
+    fn clone(&self) -> Self {
+        Escalation {
+            violations: self.violations,
+            window: self.window,
+            ban: self.ban,
+            banned: AtomicBool::new(self.banned.load(Ordering::Acquire)),
+            shards: self
+                .shards
+                .iter()
+                .map(|shard| RwLock::new(shard.read().unwrap().clone()))
+                .collect(),
+            hasher: self.hasher.clone(),
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::hash_map::RandomState;
+    use std::sync::Mutex;
+
+    #[test]
+    fn prune() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let escalation = Escalation::with_hasher(
+            (2, Duration::from_secs(10), Duration::from_secs(60)),
+            &clock,
+            RandomState::new(),
+        );
+        let len = || {
+            escalation
+                .shards
+                .iter()
+                .map(|shard| shard.read().unwrap().len())
+                .sum::<usize>()
+        };
+
+        escalation.record("A", str::to_string);
+        escalation.record("B", str::to_string);
+        escalation.record("B", str::to_string);
+        assert!(escalation.banned("B"));
+
+        // offenders that are neither banned nor rejected within the window are
+        // forgotten as the shards fill up with new ones
+        *now.lock().unwrap() += Duration::from_secs(10);
+        for key in 0..10_000 {
+            escalation.record(key.to_string().as_str(), str::to_string);
+        }
+        assert_eq!(len(), 10_001);
+        assert!(escalation.banned("B"));
+    }
+}
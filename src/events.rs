@@ -0,0 +1,164 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::SystemTime;
+
+use crate::error::Error;
+
+/// A request rejected by a rate limiter, see
+/// [`RateLimiterBuilder::event_sink`].
+///
+/// [`RateLimiterBuilder::event_sink`]: crate::RateLimiterBuilder::event_sink
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RejectionEvent<K> {
+    key: K,
+    timestamp: SystemTime,
+    tokens: usize,
+    reason: Error,
+}
+
+impl<K> RejectionEvent<K> {
+    /// Return the key the request has been made for.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Return the wall-clock time the request has been rejected at.
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    /// Return the number of tokens requested.
+    pub fn tokens(&self) -> usize {
+        self.tokens
+    }
+
+    /// Return the error the request has been rejected with.
+    pub fn reason(&self) -> &Error {
+        &self.reason
+    }
+}
+
+/// A receiver of the requests rejected by a rate limiter, e.g. to keep an
+/// audit trail of throttled and blocked entities.
+///
+/// Events are received on a dedicated thread, in the order they're queued,
+/// so a sink is free to block, e.g. on I/O. Any closure taking an event is a
+/// sink.
+pub trait EventSink<K>: Send + 'static {
+    /// Receive a rejected request.
+    fn receive(&mut self, event: RejectionEvent<K>);
+}
+
+impl<K, F> EventSink<K> for F
+where
+    F: FnMut(RejectionEvent<K>) + Send + 'static,
+{
+    fn receive(&mut self, event: RejectionEvent<K>) {
+        self(event)
+    }
+}
+
+/// Bounded queue of rejection events, drained into an [`EventSink`] by a
+/// dedicated thread.
+///
+/// Events are never waited to be queued, so that a slow sink doesn't stall
+/// consuming functions, and the ones not fitting into the queue are dropped.
+pub(crate) struct EventQueue<K> {
+    sender: SyncSender<RejectionEvent<K>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl<K: Send + 'static> EventQueue<K> {
+    /// Spawn a thread passing up to `capacity` queued events to the `sink`.
+    pub(crate) fn spawn<E: EventSink<K>>(mut sink: E, capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        thread::Builder::new()
+            .name("youshallnotpass-events".to_string())
+            .spawn(move || {
+                // the thread exits once every rate limiter sharing the queue
+                // is dropped
+                for event in receiver {
+                    sink.receive(event);
+                }
+            })
+            .expect("failed to spawn a thread for the event sink");
+        EventQueue {
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl<K> EventQueue<K> {
+    /// Queue a request for `tokens` of `key` rejected with `reason`.
+    pub(crate) fn push(&self, key: K, tokens: usize, reason: &Error) {
+        let event = RejectionEvent {
+            key,
+            timestamp: SystemTime::now(),
+            tokens,
+            reason: reason.clone(),
+        };
+        // the queue is either full, or the sink has panicked
+        if self.sender.try_send(event).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Return the number of events dropped so far.
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<K> Clone for EventQueue<K> {
+    /// Returns another handle to the same queue and sink.
+    fn clone(&self) -> Self {
+        EventQueue {
+            sender: self.sender.clone(),
+            dropped: Arc::clone(&self.dropped),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn bounded() {
+        let (gate, closed) = mpsc::channel::<()>();
+        let (sender, received) = mpsc::channel();
+        let queue = EventQueue::spawn(
+            move |event: RejectionEvent<&'static str>| {
+                closed.recv().unwrap();
+                sender.send(event).unwrap();
+            },
+            1,
+        );
+
+        // the sink is stuck, yet pushing doesn't block, and at most one event
+        // is being received while another one is queued
+        for _ in 0..5 {
+            queue.push("A", 1, &Error::Blocked);
+        }
+        assert!(queue.dropped() >= 3);
+
+        for _ in 0..5 {
+            let _ = gate.send(());
+        }
+        let delivered = received
+            .iter()
+            .take(5 - queue.dropped() as usize)
+            .inspect(|event: &RejectionEvent<&str>| {
+                assert_eq!((event.key(), event.tokens()), (&"A", 1));
+                assert_eq!(event.reason(), &Error::Blocked);
+            })
+            .count();
+        assert_eq!(delivered as u64 + queue.dropped(), 5);
+        assert!(received.recv_timeout(Duration::from_millis(10)).is_err());
+    }
+}
@@ -0,0 +1,204 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+
+use crate::clock::{Clock, MonotonicClock};
+use crate::costs::Costs;
+use crate::error::Error;
+use crate::rate_limiter::RateLimiter;
+
+/// A [`RateLimiter`] whose budgets are shared between classes of operations
+/// (`O`), each of which costs its own number of tokens per unit.
+///
+/// Rather than coordinating a limiter per class of operations, e.g. one for
+/// reads and another for writes, a single budget is set per key, and every
+/// class consumes from it at its exchange rate: with reads costing 1 token and
+/// writes costing 5, a budget of 100 tokens admits 100 reads, 20 writes, or
+/// anything in between.
+///
+/// The rates are set for all the policies, and may be overridden for the
+/// policy of a specific key. Both may be adjusted at runtime. An operation
+/// without a rate costs a token per unit.
+///
+/// ```
+/// use std::time::Duration;
+/// use youshallnotpass::{Exchange, RateLimiter};
+///
+/// #[derive(PartialEq, Eq, Hash)]
+/// enum Op {
+///     Read,
+///     Write,
+/// }
+///
+/// let limiter = RateLimiter::configure()
+///     .limit("table", 10, Duration::from_secs(1))
+///     .done();
+/// let limiter = Exchange::new(limiter);
+/// limiter.set_rate(Op::Write, 5);
+///
+/// assert!(limiter.consume(&"table", Op::Write, 1).is_ok());
+/// assert!(limiter.consume(&"table", Op::Read, 5).is_ok());
+/// assert!(limiter.consume(&"table", Op::Read, 1).is_err());
+/// ```
+pub struct Exchange<K, O, C = MonotonicClock, S = RandomState> {
+    limiter: RateLimiter<K, C, S>,
+    rates: Costs<K, HashMap<O, usize>, S>,
+}
+
+impl<K: Eq + Hash, O, C: Clock, S: BuildHasher + Clone> Exchange<K, O, C, S> {
+    /// Share the budgets of the `limiter` between classes of operations, each
+    /// costing a token per unit until its rate is set.
+    pub fn new(limiter: RateLimiter<K, C, S>) -> Self {
+        Exchange {
+            rates: Costs::with_hasher(limiter.hasher().clone()),
+            limiter,
+        }
+    }
+}
+
+impl<K, O, C, S> Exchange<K, O, C, S> {
+    /// Return the rate limiter holding the budgets.
+    pub fn limiter(&self) -> &RateLimiter<K, C, S> {
+        &self.limiter
+    }
+}
+
+impl<K: Eq + Hash, O: Eq + Hash, C: Clock, S: BuildHasher> Exchange<K, O, C, S> {
+    /// Set the number of `tokens` a unit of the `op` costs, unless overridden
+    /// for the policy of a key.
+    pub fn set_rate(&self, op: O, tokens: usize) {
+        self.rates.update(|rates| {
+            rates.insert(op, tokens);
+        });
+    }
+
+    /// Set the number of `tokens` a unit of the `op` costs for the policy of
+    /// the `key`, overriding the rate set for all the policies.
+    pub fn set_key_rate(&self, key: K, op: O, tokens: usize) {
+        self.rates.update_key(key, |rates| {
+            rates.insert(op, tokens);
+        });
+    }
+
+    /// Remove the rates set for the policy of the `key`, so that the rates
+    /// set for all the policies apply again.
+    pub fn remove_key_rates<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.rates.remove_key(key);
+    }
+
+    /// Return the number of tokens a unit of the `op` costs for the `key`.
+    ///
+    /// Rates not overridden for the policy of the `key` are the ones set for
+    /// all the policies, one by one.
+    pub fn rate<Q>(&self, key: &Q, op: &O) -> usize
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.rates
+            .find(key, |rates| rates.get(op).copied())
+            .unwrap_or(1)
+    }
+
+    /// Try to consume the specified number of `units` of the `op` from the
+    /// budget for a given event (`key`).
+    ///
+    /// Same as [`RateLimiter::consume`], for the number of tokens the `units`
+    /// cost at the exchange rate of the `op`.
+    pub fn consume<Q>(&self, key: &Q, op: O, units: usize) -> Result<(), Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        let tokens = self.tokens(key, &op, units);
+        self.limiter.consume(key, tokens)
+    }
+
+    /// Same as [`RateLimiter::check`], for the number of tokens the `units`
+    /// cost at the exchange rate of the `op`.
+    pub fn check<Q>(&self, key: &Q, op: O, units: usize) -> Result<(), Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        let tokens = self.tokens(key, &op, units);
+        self.limiter.check(key, tokens)
+    }
+
+    /// Same as [`RateLimiter::refund`], for the number of tokens the `units`
+    /// cost at the exchange rate of the `op`.
+    ///
+    /// The tokens are computed at the current rate, so a refund following a
+    /// change of the rate returns a different number of tokens than consumed.
+    pub fn refund<Q>(&self, key: &Q, op: O, units: usize)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        let tokens = self.tokens(key, &op, units);
+        self.limiter.refund(key, tokens)
+    }
+
+    fn tokens<Q>(&self, key: &Q, op: &O, units: usize) -> usize
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        // an unaffordable number of tokens is rejected as exceeding capacity
+        units.saturating_mul(self.rate(key, op))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn consume() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 10, Duration::from_secs(1))
+            .limit("B", 10, Duration::from_secs(1))
+            .done();
+        let limiter = Exchange::new(limiter);
+        limiter.set_rate("write", 5);
+        limiter.set_key_rate("B", "write", 2);
+
+        assert_eq!(limiter.rate(&"A", &"read"), 1);
+        assert_eq!(limiter.rate(&"A", &"write"), 5);
+        assert_eq!(limiter.rate(&"B", &"write"), 2);
+
+        // reads and writes share a single budget
+        assert_eq!(limiter.consume(&"A", "write", 1), Ok(()));
+        assert_eq!(limiter.consume(&"A", "read", 4), Ok(()));
+        assert!(limiter.check(&"A", "write", 1).is_err());
+        assert_eq!(limiter.consume(&"A", "read", 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&"A", "read", 1),
+            Err(Error::RetryAfter(Duration::from_millis(100)))
+        );
+        limiter.refund(&"A", "read", 1);
+        assert_eq!(limiter.consume(&"A", "read", 1), Ok(()));
+
+        assert_eq!(limiter.consume(&"B", "write", 5), Ok(()));
+        assert!(limiter.consume(&"B", "read", 1).is_err());
+
+        // rates are adjusted at runtime
+        *now.lock().unwrap() += Duration::from_secs(1);
+        limiter.remove_key_rates(&"B");
+        assert_eq!(limiter.consume(&"B", "write", 2), Ok(()));
+        assert!(limiter.consume(&"B", "write", 1).is_err());
+        assert_eq!(
+            limiter.consume(&"A", "write", usize::MAX),
+            Err(Error::ExceedsCapacity)
+        );
+    }
+}
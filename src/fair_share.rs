@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::clock::{Clock, MonotonicClock};
+use crate::error::Error;
+use crate::rate_limiter::RateLimiter;
+use crate::TokenBucket;
+
+/// A [`RateLimiter`] with per-key limits combined with a global cap, a share
+/// of which is guaranteed to every active key.
+///
+/// With a plain global bucket, a handful of aggressive keys can drain it and
+/// starve everyone else, even if every key stays within its own limit. Here,
+/// the `reserved` fraction of the global rate is partitioned equally between
+/// the keys active within the last `interval`, while the rest is shared on a
+/// first come, first served basis. A key consumes from its own share first,
+/// and from the shared part once its share is exhausted.
+///
+/// The shares are recomputed as keys come and go, so a key is guaranteed
+/// `reserved * limit / active_keys` tokens per `interval`, whatever the other
+/// keys do. A key seen first is handed the tokens the shares of the other
+/// keys lose as they shrink, rather than a full share, so that the tokens
+/// admitted at once never exceed the global cap. A request is admitted only
+/// if both the per-key limit of the `limiter`, and the global cap allow it. A
+/// request larger than what's left of the share takes the rest from the
+/// shared part.
+///
+/// ```
+/// use std::time::Duration;
+/// use youshallnotpass::{FairShare, RateLimiter};
+///
+/// let limiter = RateLimiter::configure()
+///     .default_limit(10, Duration::from_secs(60))
+///     .done();
+///
+/// // half of 10 requests per minute is split between active keys
+/// let limiter = FairShare::new(limiter, 10, Duration::from_secs(60), 0.5);
+/// assert!(limiter.consume("bob", 1).is_ok());
+///
+/// // "alice" takes its own share and everything shared...
+/// assert!(limiter.consume("alice", 7).is_ok());
+/// assert!(limiter.consume("alice", 1).is_err());
+///
+/// // ...but "bob" still has tokens of its own share
+/// assert!(limiter.consume("bob", 1).is_ok());
+/// ```
+pub struct FairShare<K, C = MonotonicClock> {
+    limiter: RateLimiter<K, C>,
+    interval: Duration,
+    reserved: usize,
+    shared: TokenBucket<C>,
+    shares: Mutex<Shares<K, C>>,
+    clock: C,
+}
+
+/// Shares of the global cap of the active keys.
+struct Shares<K, C> {
+    shares: HashMap<K, Share<C>>,
+    /// The number of active keys the rates of the shares are computed for.
+    active: usize,
+    pruned_at: Instant,
+}
+
+struct Share<C> {
+    bucket: TokenBucket<C>,
+    last_seen_at: Instant,
+}
+
+impl<K: Eq + Hash + Clone, C: Clock + Clone> FairShare<K, C> {
+    /// Combine per-key limits of the `limiter` with a global cap of `limit`
+    /// tokens per `interval`, of which the `reserved` fraction (between `0.0`
+    /// and `1.0`) is partitioned between the active keys.
+    ///
+    /// The clock of the `limiter` is used for the global cap too.
+    pub fn new(
+        limiter: RateLimiter<K, C>,
+        limit: usize,
+        interval: Duration,
+        reserved: f64,
+    ) -> Self {
+        let reserved = (limit as f64 * reserved.clamp(0.0, 1.0)).round() as usize;
+        let clock = limiter.clock().clone();
+        FairShare {
+            shared: TokenBucket::with_clock(limit - reserved, interval, clock.clone()),
+            shares: Mutex::new(Shares {
+                shares: HashMap::new(),
+                active: 0,
+                pruned_at: clock.now(),
+            }),
+            limiter,
+            interval,
+            reserved,
+            clock,
+        }
+    }
+
+    /// Try to consume the specified number of `tokens` for a given event
+    /// (`key`), both from the per-key limit and from the global cap.
+    ///
+    /// Same as [`RateLimiter::consume`], except that the request is rejected
+    /// if the global cap is reached, unless the `key` has enough tokens left
+    /// in its share. Nothing is consumed from either limit if the request is
+    /// rejected.
+    pub fn consume(&self, key: K, tokens: usize) -> Result<(), Error> {
+        let permit = self.limiter.consume_permit(&key, tokens)?;
+
+        let now = self.clock.now();
+        let mut shares = self.shares.lock().unwrap();
+        shares.prune(now, self.interval);
+        let share = shares.share(key, self.reserved, self.interval, &self.clock);
+        share.last_seen_at = now;
+
+        let own = share.bucket.consume_up_to(tokens);
+        let result = match tokens - own {
+            0 => Ok(()),
+            rest => self.shared.consume(rest),
+        };
+        match result {
+            Ok(()) => {
+                permit.commit();
+                Ok(())
+            }
+            Err(shared) => {
+                share.bucket.refund(own);
+                Err(match (share.bucket.check(tokens), shared) {
+                    (Err(Error::RetryAfter(own)), Error::RetryAfter(shared)) => {
+                        Error::RetryAfter(own.min(shared))
+                    }
+                    (Err(Error::RetryAfter(own)), _) => Error::RetryAfter(own),
+                    (_, shared) => shared,
+                })
+            }
+        }
+    }
+
+    /// Return the number of keys the reserved part of the global cap is
+    /// currently partitioned between.
+    pub fn active_keys(&self) -> usize {
+        let mut shares = self.shares.lock().unwrap();
+        shares.prune(self.clock.now(), self.interval);
+        shares.shares.len()
+    }
+
+    /// Return the rate limiter enforcing the per-key limits.
+    pub fn limiter(&self) -> &RateLimiter<K, C> {
+        &self.limiter
+    }
+}
+
+impl<K: Eq + Hash, C> Shares<K, C> {
+    /// Forget the keys that haven't been seen within the last `interval`, at
+    /// most once per `interval`.
+    fn prune(&mut self, now: Instant, interval: Duration) {
+        if now.saturating_duration_since(self.pruned_at) < interval {
+            return;
+        }
+        self.shares
+            .retain(|_, share| now.saturating_duration_since(share.last_seen_at) < interval);
+        self.pruned_at = now;
+    }
+}
+
+impl<K: Eq + Hash, C: Clock + Clone> Shares<K, C> {
+    /// Return the share of the `key`, rescaling the shares of every key if
+    /// the number of active keys changes.
+    ///
+    /// The share of a key seen first starts with the tokens the shares of the
+    /// other keys lose as they shrink, so that the reserved tokens are never
+    /// handed out twice. A lone key gets a full share, since every key it
+    /// replaces has been idle for the interval.
+    fn share(&mut self, key: K, reserved: usize, interval: Duration, clock: &C) -> &mut Share<C> {
+        if self.shares.contains_key(&key) {
+            self.rescale(self.shares.len(), reserved, interval);
+            return self.shares.get_mut(&key).unwrap();
+        }
+
+        let held = self.tokens();
+        self.rescale(self.shares.len() + 1, reserved, interval);
+        let freed = match self.shares.is_empty() {
+            true => reserved,
+            false => held.saturating_sub(self.tokens()),
+        };
+        let mut bucket = TokenBucket::with_clock(0, interval, clock.clone());
+        set_rate(&mut bucket, reserved, interval, self.active);
+        bucket.drain();
+        bucket.refund(freed);
+        self.shares.entry(key).or_insert(Share {
+            bucket,
+            last_seen_at: clock.now(),
+        })
+    }
+
+    /// Set the rates of the shares to fair shares of `reserved` tokens per
+    /// `interval` partitioned between `active` keys, keeping the tokens they
+    /// hold, up to their new capacity.
+    fn rescale(&mut self, active: usize, reserved: usize, interval: Duration) {
+        if self.active == active {
+            return;
+        }
+        for share in self.shares.values_mut() {
+            set_rate(&mut share.bucket, reserved, interval, active);
+        }
+        self.active = active;
+    }
+
+    /// Return the number of tokens held by the shares.
+    fn tokens(&self) -> usize {
+        self.shares
+            .values()
+            .map(|share| {
+                share
+                    .bucket
+                    .available()
+                    .map_or(0, |available| available.tokens())
+            })
+            .sum()
+    }
+}
+
+/// Set the rate of the `bucket` to a fair share of `reserved` tokens per
+/// `interval` partitioned between `active` keys.
+fn set_rate<C: Clock>(
+    bucket: &mut TokenBucket<C>,
+    reserved: usize,
+    interval: Duration,
+    active: usize,
+) {
+    let tokens = reserved / active;
+    if tokens > 0 || reserved == 0 {
+        bucket.set_rate(tokens, interval);
+    } else {
+        // less than a token per interval, so a single token is generated over
+        // a longer period instead
+        let period = interval.mul_f64(active as f64 / reserved as f64);
+        bucket.set_rate(1, period);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn consume() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 16, Duration::from_secs(1))
+            .limit("B", 16, Duration::from_secs(1))
+            .limit("C", 1, Duration::from_secs(1))
+            .done();
+        let limiter = FairShare::new(limiter, 8, Duration::from_secs(1), 0.5);
+
+        // a lone key gets everything
+        assert_eq!(limiter.consume("A", 8), Ok(()));
+        assert_eq!(
+            limiter.consume("A", 1),
+            Err(Error::RetryAfter(Duration::from_millis(250)))
+        );
+
+        // once another key shows up, it's guaranteed a half of the reserve,
+        // which is held by the first key until it refills
+        assert_eq!(limiter.active_keys(), 1);
+        assert_eq!(
+            limiter.consume("B", 1),
+            Err(Error::RetryAfter(Duration::from_millis(250)))
+        );
+        assert_eq!(limiter.active_keys(), 2);
+        *now.lock().unwrap() += Duration::from_millis(500);
+        assert_eq!(limiter.consume("A", 3), Ok(()));
+        assert!(limiter.consume("A", 1).is_err());
+        assert_eq!(limiter.consume("B", 1), Ok(()));
+        assert!(limiter.consume("B", 1).is_err());
+
+        // per-key limits apply, and aren't charged by rejected requests
+        assert_eq!(limiter.consume("C", 2), Err(Error::ExceedsCapacity));
+        assert_eq!(limiter.limiter().check(&"C", 1), Ok(()));
+
+        // keys idle for the interval stop taking a share
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(limiter.consume("B", 4), Ok(()));
+        assert_eq!(limiter.active_keys(), 1);
+        assert_eq!(limiter.consume("B", 4), Ok(()));
+        assert!(limiter.consume("B", 1).is_err());
+    }
+
+    #[test]
+    fn small_share() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = RateLimiter::with_clock(&clock)
+            .default_limit(10, Duration::from_secs(1))
+            .done();
+        let limiter = FairShare::new(limiter, 2, Duration::from_secs(1), 0.5);
+
+        // a single reserved token per second is shared between 4 keys, and
+        // the one held by the first key isn't handed out again
+        assert_eq!(limiter.consume("A", 1), Ok(()));
+        assert_eq!(limiter.consume("B", 1), Ok(()));
+        assert!(limiter.consume("C", 1).is_err());
+        assert!(limiter.consume("D", 1).is_err());
+        *now.lock().unwrap() += Duration::from_millis(500);
+        for key in ["A", "B", "C", "D"] {
+            assert!(limiter.consume(key, 1).is_err(), "{}", key);
+        }
+        *now.lock().unwrap() += Duration::from_millis(500);
+        assert_eq!(limiter.consume("A", 1), Ok(()));
+        assert_eq!(
+            limiter.consume("A", 1),
+            Err(Error::RetryAfter(Duration::from_millis(1_000)))
+        );
+    }
+
+    #[test]
+    fn capped() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = RateLimiter::with_clock(&clock)
+            .default_limit(100, Duration::from_secs(60))
+            .done();
+        let limiter = FairShare::new(limiter, 100, Duration::from_secs(60), 1.0);
+
+        // however many keys show up at once, no more than the cap is admitted
+        let mut admitted = 0;
+        for key in 0..100 {
+            while limiter.consume(key, 1).is_ok() {
+                admitted += 1;
+            }
+        }
+        assert_eq!(admitted, 100);
+
+        // and as much is admitted, and no more, once the shares refill
+        *now.lock().unwrap() += Duration::from_secs(60);
+        let mut admitted = 0;
+        for key in 0..100 {
+            while limiter.consume(key, 1).is_ok() {
+                admitted += 1;
+            }
+        }
+        assert_eq!(admitted, 100);
+    }
+}
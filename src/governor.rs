@@ -0,0 +1,318 @@
+//! Compatibility layer mirroring the most common APIs of the
+//! [governor](https://docs.rs/governor) crate.
+//!
+//! The module is available behind the `governor-compat` feature, and is meant
+//! to ease migration: code using `governor::{Quota, RateLimiter}` keeps
+//! compiling after switching the imports over, while the rate limiting is
+//! done by [`TokenBucket`]s of this crate.
+//!
+//! ```
+//! use std::num::NonZeroU32;
+//! use youshallnotpass::governor::{Quota, RateLimiter};
+//!
+//! let limiter = RateLimiter::direct(Quota::per_second(NonZeroU32::new(2).unwrap()));
+//! assert!(limiter.check().is_ok());
+//! assert!(limiter.check().is_ok());
+//! assert!(limiter.check().is_err());
+//!
+//! let limiter = RateLimiter::keyed(Quota::per_minute(NonZeroU32::new(1).unwrap()));
+//! assert!(limiter.check_key(&"alice").is_ok());
+//! assert!(limiter.check_key(&"alice").is_err());
+//! assert!(limiter.check_key(&"bob").is_ok());
+//! ```
+//!
+//! Behavioral differences from governor:
+//!
+//! * [`NotUntil`] carries the time to wait rather than the earliest moment
+//!   of the next attempt, so there is no `wait_time_from()`, but
+//!   [`NotUntil::wait_time()`] instead.
+//!
+//! * Keyed state is kept in a map under a single lock rather than in a
+//!   lock-free concurrent map, so heavily contended keyed limiters are slower.
+//!
+//! * There are no custom clocks, state stores or middlewares; the limiters
+//!   always use the system monotonic clock.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::num::NonZeroU32;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::{Error, TokenBucket};
+
+/// A rate limiting quota, same as `governor::Quota`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Quota {
+    max_burst: NonZeroU32,
+    replenish_1_per: Duration,
+}
+
+impl Quota {
+    /// Construct a quota allowing `max_burst` cells per second.
+    pub const fn per_second(max_burst: NonZeroU32) -> Quota {
+        Quota::per_period(max_burst, Duration::from_secs(1))
+    }
+
+    /// Construct a quota allowing `max_burst` cells per minute.
+    pub const fn per_minute(max_burst: NonZeroU32) -> Quota {
+        Quota::per_period(max_burst, Duration::from_secs(60))
+    }
+
+    /// Construct a quota allowing `max_burst` cells per hour.
+    pub const fn per_hour(max_burst: NonZeroU32) -> Quota {
+        Quota::per_period(max_burst, Duration::from_secs(60 * 60))
+    }
+
+    /// Construct a quota replenishing one cell every `replenish_1_per`, with
+    /// a burst of one cell. Returns `None` for a zero period.
+    pub fn with_period(replenish_1_per: Duration) -> Option<Quota> {
+        if replenish_1_per.is_zero() {
+            return None;
+        }
+        Some(Quota {
+            max_burst: NonZeroU32::MIN,
+            replenish_1_per,
+        })
+    }
+
+    /// Set the number of cells that can be consumed at once, while keeping the
+    /// replenishment rate the same.
+    pub const fn allow_burst(mut self, max_burst: NonZeroU32) -> Quota {
+        self.max_burst = max_burst;
+        self
+    }
+
+    /// Return the number of cells that can be consumed at once.
+    pub const fn burst_size(&self) -> NonZeroU32 {
+        self.max_burst
+    }
+
+    /// Return the time it takes to replenish a single cell.
+    pub const fn replenish_interval(&self) -> Duration {
+        self.replenish_1_per
+    }
+
+    const fn per_period(max_burst: NonZeroU32, period: Duration) -> Quota {
+        // same as governor, cells are replenished no faster than one per
+        // nanosecond, rather than never
+        let nanos = period.as_nanos() / max_burst.get() as u128;
+        let nanos = if nanos == 0 { 1 } else { nanos };
+        Quota {
+            max_burst,
+            replenish_1_per: Duration::from_nanos(nanos as u64),
+        }
+    }
+
+    /// Convert the quota into a bucket of this crate.
+    fn bucket(&self) -> TokenBucket {
+        let burst = self.max_burst.get() as usize;
+        TokenBucket::builder()
+            .capacity(burst)
+            .refill(1, self.replenish_1_per)
+            .done()
+    }
+}
+
+/// A negative rate limiting outcome, same as `governor::NotUntil`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NotUntil {
+    wait_time: Duration,
+}
+
+impl NotUntil {
+    /// The outcome of a bucket that never admits any cells.
+    const NEVER: NotUntil = NotUntil {
+        wait_time: Duration::MAX,
+    };
+
+    /// Return how long to wait before the next attempt can succeed.
+    pub fn wait_time(&self) -> Duration {
+        self.wait_time
+    }
+}
+
+impl std::fmt::Display for NotUntil {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "rate-limited for {:?}", self.wait_time)
+    }
+}
+
+impl std::error::Error for NotUntil {}
+
+/// A batch of cells that can never be admitted, because it's larger than the
+/// burst size; same as `governor::InsufficientCapacity`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InsufficientCapacity(pub u32);
+
+impl std::fmt::Display for InsufficientCapacity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "required number of cells {} exceeds burst size", self.0)
+    }
+}
+
+impl std::error::Error for InsufficientCapacity {}
+
+/// The key of direct rate limiters, which have a single state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NotKeyed;
+
+/// A rate limiter, either direct or keyed, same as `governor::RateLimiter`.
+pub struct RateLimiter<K> {
+    quota: Quota,
+    buckets: Mutex<HashMap<K, TokenBucket>>,
+}
+
+/// A direct rate limiter, same as `governor::DefaultDirectRateLimiter`.
+pub type DefaultDirectRateLimiter = RateLimiter<NotKeyed>;
+
+/// A keyed rate limiter, same as `governor::DefaultKeyedRateLimiter`.
+pub type DefaultKeyedRateLimiter<K> = RateLimiter<K>;
+
+impl RateLimiter<NotKeyed> {
+    /// Construct a rate limiter with a single state for the `quota`.
+    pub fn direct(quota: Quota) -> Self {
+        RateLimiter::keyed(quota)
+    }
+
+    /// Allow a single cell through the rate limiter.
+    pub fn check(&self) -> Result<(), NotUntil> {
+        self.check_key(&NotKeyed)
+    }
+
+    /// Allow `n` cells through the rate limiter at once.
+    pub fn check_n(&self, n: NonZeroU32) -> Result<Result<(), NotUntil>, InsufficientCapacity> {
+        self.check_key_n(&NotKeyed, n)
+    }
+}
+
+impl<K: Eq + Hash + Clone> RateLimiter<K> {
+    /// Construct a rate limiter with a state per key, each of them limited by
+    /// the `quota`.
+    pub fn keyed(quota: Quota) -> Self {
+        RateLimiter {
+            quota,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Allow a single cell for the `key` through the rate limiter.
+    pub fn check_key(&self, key: &K) -> Result<(), NotUntil> {
+        match self.consume(key, 1) {
+            Ok(()) => Ok(()),
+            Err(Error::RetryAfter(wait_time)) => Err(NotUntil { wait_time }),
+            // the burst size is never zero, so a single cell fits into the
+            // bucket, unless the bucket is blocked and never admits any
+            Err(Error::Blocked | Error::ExceedsCapacity) => Err(NotUntil::NEVER),
+        }
+    }
+
+    /// Allow `n` cells for the `key` through the rate limiter at once.
+    pub fn check_key_n(
+        &self,
+        key: &K,
+        n: NonZeroU32,
+    ) -> Result<Result<(), NotUntil>, InsufficientCapacity> {
+        match self.consume(key, n.get() as usize) {
+            Ok(()) => Ok(Ok(())),
+            Err(Error::RetryAfter(wait_time)) => Ok(Err(NotUntil { wait_time })),
+            Err(Error::Blocked) => Ok(Err(NotUntil::NEVER)),
+            Err(Error::ExceedsCapacity) => Err(InsufficientCapacity(n.get())),
+        }
+    }
+
+    /// Consume `cells` from the bucket of the `key`, creating it if needed.
+    fn consume(&self, key: &K, cells: usize) -> Result<(), Error> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = match buckets.get(key) {
+            Some(bucket) => bucket,
+            None => buckets.entry(key.clone()).or_insert(self.quota.bucket()),
+        };
+        bucket.consume(cells)
+    }
+
+    /// Forget the keys whose state is indistinguishable from a fresh one,
+    /// i.e. whose buckets are full.
+    pub fn retain_recent(&self) {
+        self.buckets
+            .lock()
+            .unwrap()
+            .retain(|_, bucket| bucket.time_to_full() != Ok(Duration::ZERO));
+    }
+
+    /// Return the number of keys with a state.
+    pub fn len(&self) -> usize {
+        self.buckets.lock().unwrap().len()
+    }
+
+    /// Return `true` if no key has a state.
+    pub fn is_empty(&self) -> bool {
+        self.buckets.lock().unwrap().is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nonzero(n: u32) -> NonZeroU32 {
+        NonZeroU32::new(n).unwrap()
+    }
+
+    #[test]
+    fn quota() {
+        let quota = Quota::per_second(nonzero(4));
+        assert_eq!(quota.burst_size(), nonzero(4));
+        assert_eq!(quota.replenish_interval(), Duration::from_millis(250));
+
+        let quota = quota.allow_burst(nonzero(10));
+        assert_eq!(quota.burst_size(), nonzero(10));
+        assert_eq!(quota.replenish_interval(), Duration::from_millis(250));
+
+        let quota = Quota::with_period(Duration::from_secs(3)).unwrap();
+        assert_eq!(quota.burst_size(), nonzero(1));
+        assert_eq!(quota.replenish_interval(), Duration::from_secs(3));
+        assert_eq!(Quota::with_period(Duration::ZERO), None);
+
+        // more cells than nanoseconds per period are replenished one per
+        // nanosecond
+        let quota = Quota::per_second(nonzero(2_000_000_000));
+        assert_eq!(quota.replenish_interval(), Duration::from_nanos(1));
+        let limiter = RateLimiter::direct(quota);
+        assert_eq!(limiter.check(), Ok(()));
+        assert_eq!(limiter.check_n(nonzero(1_000)), Ok(Ok(())));
+    }
+
+    #[test]
+    fn direct() {
+        let limiter = RateLimiter::direct(Quota::per_minute(nonzero(2)));
+        assert_eq!(limiter.check_n(nonzero(2)), Ok(Ok(())));
+
+        // unlike governor, the rejection reports how long to wait
+        let not_until = limiter.check().unwrap_err();
+        assert!(not_until.wait_time() > Duration::from_secs(29));
+        assert!(not_until.wait_time() <= Duration::from_secs(30));
+
+        assert_eq!(limiter.check_n(nonzero(3)), Err(InsufficientCapacity(3)));
+    }
+
+    #[test]
+    fn keyed() {
+        let limiter = RateLimiter::keyed(Quota::per_minute(nonzero(1)).allow_burst(nonzero(2)));
+        assert!(limiter.is_empty());
+
+        assert_eq!(limiter.check_key(&"a"), Ok(()));
+        assert_eq!(limiter.check_key(&"a"), Ok(()));
+        assert!(limiter.check_key(&"a").is_err());
+        assert_eq!(limiter.check_key_n(&"b", nonzero(2)), Ok(Ok(())));
+        assert_eq!(
+            limiter.check_key_n(&"c", nonzero(3)),
+            Err(InsufficientCapacity(3))
+        );
+        assert_eq!(limiter.len(), 3);
+
+        // the key with an untouched bucket is forgotten
+        limiter.retain_recent();
+        assert_eq!(limiter.len(), 2);
+    }
+}
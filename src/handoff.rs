@@ -0,0 +1,180 @@
+use crate::token_bucket::Snapshot;
+
+/// State of every bucket of a [`RateLimiter`], handed over from one instance
+/// to another.
+///
+/// Handoffs are meant for rolling deploys: a new instance requests the state
+/// from the old one via [`RateLimiter::handoff()`], and starts warm via
+/// [`RateLimiter::warm()`] rather than with every bucket full. Every bucket
+/// is captured as a [`Snapshot`], so the time spent in transit is accounted
+/// for, and the limiting policies of the new instance are kept.
+///
+/// The transport is up to the caller: with the `serde` feature enabled, a
+/// handoff can be serialized with any serde format, e.g. to be served by an
+/// admin API. The `uds` feature provides a transport out of the box, see
+/// `uds::Client::handoff()`.
+///
+/// ```
+/// use std::time::Duration;
+/// use youshallnotpass::RateLimiter;
+///
+/// let old = RateLimiter::configure()
+///     .limit("A", 2, Duration::from_secs(60))
+///     .done();
+/// assert!(old.consume(&"A", 2).is_ok());
+///
+/// let new = RateLimiter::configure()
+///     .limit("A", 2, Duration::from_secs(60))
+///     .done();
+/// new.warm(old.handoff());
+/// assert!(new.consume(&"A", 1).is_err());
+/// ```
+///
+/// [`RateLimiter`]: crate::RateLimiter
+/// [`RateLimiter::handoff()`]: crate::RateLimiter::handoff
+/// [`RateLimiter::warm()`]: crate::RateLimiter::warm
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Handoff<K> {
+    buckets: Vec<(K, Snapshot)>,
+}
+
+impl<K> Handoff<K> {
+    /// Return the number of buckets in the handoff.
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    /// Return `true` if the handoff contains no buckets.
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /// Iterate over the keys and the snapshots of their buckets, in arbitrary
+    /// order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &Snapshot)> {
+        self.buckets.iter().map(|(key, snapshot)| (key, snapshot))
+    }
+}
+
+/// State of every bucket of a [`RateLimiter`], including the ones without a
+/// key, captured via [`RateLimiter::snapshot()`].
+///
+/// Unlike a [`Handoff`], which carries the buckets of keys alone, a snapshot
+/// carries the global bucket, and the buckets of regular expressions as well,
+/// so that the whole accounting stays continuous across graceful restarts,
+/// and blue/green deploys. Every bucket is anchored to the wall clock, see
+/// [`Snapshot`]. With the `serde` feature enabled, a snapshot can be
+/// serialized with any serde format, e.g. to be stored in a file on shutdown,
+/// and restored via [`RateLimiter::restore()`] on startup.
+///
+/// ```
+/// use std::time::Duration;
+/// use youshallnotpass::RateLimiter;
+///
+/// let configure = || {
+///     RateLimiter::configure()
+///         .limit("A", 2, Duration::from_secs(60))
+///         .global_limit(3, Duration::from_secs(60))
+///         .done()
+/// };
+///
+/// let old = configure();
+/// assert!(old.consume(&"A", 2).is_ok());
+/// assert!(old.consume(&"B", 1).is_ok());
+///
+/// let new = configure();
+/// new.restore(old.snapshot());
+/// assert!(new.consume(&"A", 1).is_err());
+/// assert!(new.consume(&"B", 1).is_err());
+/// ```
+///
+/// [`RateLimiter`]: crate::RateLimiter
+/// [`RateLimiter::snapshot()`]: crate::RateLimiter::snapshot
+/// [`RateLimiter::restore()`]: crate::RateLimiter::restore
+///
+/// Same as a [`Snapshot`], a serialized snapshot carries the version of its
+/// layout, so that the ones taken by an older version of this crate are
+/// migrated on deserialization, and the ones taken by a newer, unknown
+/// version are rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "VersionedRateLimiterSnapshot<K>"))]
+pub struct RateLimiterSnapshot<K> {
+    /// The version of the layout of the snapshot.
+    pub(crate) version: u32,
+    pub(crate) handoff: Handoff<K>,
+    pub(crate) global: Option<Snapshot>,
+    /// Buckets of regular expressions, keyed by the expressions, which are
+    /// empty unless the `regex` feature is enabled.
+    pub(crate) regexes: Vec<(String, Snapshot)>,
+}
+
+impl<K> RateLimiterSnapshot<K> {
+    /// Return the state of the buckets of keys.
+    pub fn handoff(&self) -> &Handoff<K> {
+        &self.handoff
+    }
+}
+
+/// Version of the layout of a serialized [`RateLimiterSnapshot`].
+pub(crate) const RATE_LIMITER_SNAPSHOT_VERSION: u32 = 1;
+
+/// A [`RateLimiterSnapshot`] of any version, as deserialized, before it's
+/// migrated to what the current version expects.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct VersionedRateLimiterSnapshot<K> {
+    /// Snapshots serialized before the version has been introduced are of
+    /// the first one.
+    #[serde(default = "snapshot_v1")]
+    version: u32,
+    handoff: Handoff<K>,
+    global: Option<Snapshot>,
+    regexes: Vec<(String, Snapshot)>,
+}
+
+#[cfg(feature = "serde")]
+fn snapshot_v1() -> u32 {
+    1
+}
+
+#[cfg(feature = "serde")]
+impl<K> TryFrom<VersionedRateLimiterSnapshot<K>> for RateLimiterSnapshot<K> {
+    type Error = String;
+
+    fn try_from(snapshot: VersionedRateLimiterSnapshot<K>) -> Result<Self, Self::Error> {
+        match snapshot.version {
+            1 => Ok(migrate_v1(snapshot)),
+            version => Err(format!("unsupported snapshot version {}", version)),
+        }
+    }
+}
+
+/// The first version is the current one, so there is nothing to migrate.
+#[cfg(feature = "serde")]
+fn migrate_v1<K>(snapshot: VersionedRateLimiterSnapshot<K>) -> RateLimiterSnapshot<K> {
+    RateLimiterSnapshot {
+        version: RATE_LIMITER_SNAPSHOT_VERSION,
+        handoff: snapshot.handoff,
+        global: snapshot.global,
+        regexes: snapshot.regexes,
+    }
+}
+
+impl<K> FromIterator<(K, Snapshot)> for Handoff<K> {
+    fn from_iter<I: IntoIterator<Item = (K, Snapshot)>>(iter: I) -> Self {
+        Handoff {
+            buckets: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl<K> IntoIterator for Handoff<K> {
+    type Item = (K, Snapshot);
+    type IntoIter = std::vec::IntoIter<(K, Snapshot)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.buckets.into_iter()
+    }
+}
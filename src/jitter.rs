@@ -0,0 +1,81 @@
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::Error;
+
+/// Source of uniformly distributed random numbers.
+pub(crate) type RandomSource = Arc<dyn Fn() -> u64 + Send + Sync>;
+
+/// Random delay added to [`Error::RetryAfter`] durations, so that clients
+/// rejected at the same time don't come back all at once.
+#[derive(Clone)]
+pub(crate) struct Jitter {
+    range: Range<Duration>,
+    random: RandomSource,
+}
+
+impl Jitter {
+    pub(crate) fn new(range: Range<Duration>, random: RandomSource) -> Self {
+        Jitter { range, random }
+    }
+
+    /// Add a random delay from the range to the `error`, if it asks to retry.
+    pub(crate) fn apply(&self, error: Error) -> Error {
+        match error {
+            Error::RetryAfter(duration) => {
+                Error::RetryAfter(duration.saturating_add(self.sample()))
+            }
+            error => error,
+        }
+    }
+
+    /// Pick a random delay from the range.
+    fn sample(&self) -> Duration {
+        let span = self.range.end.saturating_sub(self.range.start).as_nanos();
+        let span = std::cmp::min(span, u64::MAX as u128);
+        // scale a random 64-bit number down to [0, span)
+        let offset = ((self.random)() as u128 * span) >> 64;
+        self.range.start.saturating_add(Duration::from_nanos(
+            u64::try_from(offset).unwrap_or(u64::MAX),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply() {
+        let range = Duration::from_secs(1)..Duration::from_secs(3);
+        let jitter = Jitter::new(range.clone(), Arc::new(|| 0));
+        assert_eq!(
+            jitter.apply(Error::RetryAfter(Duration::from_secs(5))),
+            Error::RetryAfter(Duration::from_secs(6))
+        );
+
+        let jitter = Jitter::new(range.clone(), Arc::new(|| u64::MAX / 2 + 1));
+        assert_eq!(
+            jitter.apply(Error::RetryAfter(Duration::from_secs(5))),
+            Error::RetryAfter(Duration::from_secs(7))
+        );
+
+        let jitter = Jitter::new(range, Arc::new(|| u64::MAX));
+        assert!(matches!(
+            jitter.apply(Error::RetryAfter(Duration::from_secs(5))),
+            Error::RetryAfter(duration) if duration < Duration::from_secs(8)
+        ));
+
+        // errors other than RetryAfter are left intact
+        assert_eq!(jitter.apply(Error::Blocked), Error::Blocked);
+        assert_eq!(jitter.apply(Error::ExceedsCapacity), Error::ExceedsCapacity);
+
+        // an empty range adds nothing
+        let jitter = Jitter::new(Duration::ZERO..Duration::ZERO, Arc::new(|| u64::MAX));
+        assert_eq!(
+            jitter.apply(Error::RetryAfter(Duration::from_secs(5))),
+            Error::RetryAfter(Duration::from_secs(5))
+        );
+    }
+}
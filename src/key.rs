@@ -0,0 +1,263 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// A type that can be used as a rate limiting key via its canonical binary
+/// representation.
+///
+/// Keys made of several parts are often glued together with `format!()`,
+/// which allocates on every request, and drifts between call sites that
+/// format the same parts differently. A [`RateLimitKey`] is encoded into a
+/// [`Key`] instead: the encoding is unambiguous, i.e. different values are
+/// never encoded the same way, and it's stable, so that keys can be shared
+/// between processes or persisted.
+///
+/// With the `derive` feature enabled, the trait can be derived for structs and
+/// enums whose fields are keys themselves. Fields are encoded in the order of
+/// declaration, preceded by the index of the variant for enums, so reordering
+/// fields or variants changes the encoding. A field can be excluded via
+/// `#[rate_limit_key(skip)]`, or normalized first via
+/// `#[rate_limit_key(normalize = path::to::function)]`, which is called with a
+/// reference to the field, and returns a key to be encoded instead.
+///
+/// ```
+/// # #[cfg(feature = "derive")] {
+/// use std::time::Duration;
+/// use youshallnotpass::{Key, KeyedRateLimiter, RateLimitKey};
+///
+/// #[derive(RateLimitKey)]
+/// struct Login {
+///     #[rate_limit_key(normalize = str::to_ascii_lowercase)]
+///     user: String,
+///     #[rate_limit_key(skip)]
+///     attempt: u32,
+/// }
+///
+/// let limiter: KeyedRateLimiter<Key> = KeyedRateLimiter::new(1, Duration::from_secs(60));
+///
+/// let login = Login { user: "Alice".to_string(), attempt: 1 };
+/// assert!(limiter.consume(&login.to_key(), 1).is_ok());
+///
+/// let login = Login { user: "alice".to_string(), attempt: 2 };
+/// assert!(limiter.consume(&login.to_key(), 1).is_err());
+/// # }
+/// ```
+pub trait RateLimitKey {
+    /// Append the canonical representation of the key to `buf`.
+    fn encode_key(&self, buf: &mut Vec<u8>);
+
+    /// Return the canonical representation of the key.
+    fn to_key(&self) -> Key {
+        let mut buf = Vec::new();
+        self.encode_key(&mut buf);
+        Key(buf)
+    }
+}
+
+/// The canonical representation of a [`RateLimitKey`], which can be used as a
+/// key of any rate limiter.
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Key(Vec<u8>);
+
+impl Key {
+    /// Return the bytes of the representation.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Key(")?;
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl<T: RateLimitKey + ?Sized> RateLimitKey for &T {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        (**self).encode_key(buf)
+    }
+}
+
+macro_rules! impl_int {
+    ($($int:ty),*) => {
+        $(
+            impl RateLimitKey for $int {
+                fn encode_key(&self, buf: &mut Vec<u8>) {
+                    buf.extend_from_slice(&self.to_be_bytes());
+                }
+            }
+        )*
+    };
+}
+
+impl_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl RateLimitKey for usize {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        (*self as u64).encode_key(buf)
+    }
+}
+
+impl RateLimitKey for isize {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        (*self as i64).encode_key(buf)
+    }
+}
+
+impl RateLimitKey for bool {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        buf.push(u8::from(*self));
+    }
+}
+
+impl RateLimitKey for char {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        u32::from(*self).encode_key(buf)
+    }
+}
+
+impl RateLimitKey for str {
+    /// Strings are prefixed with their length, so that a key made of several
+    /// strings is unambiguous.
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        (self.len() as u64).encode_key(buf);
+        buf.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl RateLimitKey for String {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        self.as_str().encode_key(buf)
+    }
+}
+
+impl<T: RateLimitKey> RateLimitKey for Option<T> {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        match self {
+            None => buf.push(0),
+            Some(value) => {
+                buf.push(1);
+                value.encode_key(buf);
+            }
+        }
+    }
+}
+
+impl RateLimitKey for Ipv4Addr {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.octets());
+    }
+}
+
+impl RateLimitKey for Ipv6Addr {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.octets());
+    }
+}
+
+impl RateLimitKey for IpAddr {
+    fn encode_key(&self, buf: &mut Vec<u8>) {
+        match self {
+            IpAddr::V4(addr) => {
+                buf.push(4);
+                addr.encode_key(buf);
+            }
+            IpAddr::V6(addr) => {
+                buf.push(6);
+                addr.encode_key(buf);
+            }
+        }
+    }
+}
+
+macro_rules! impl_tuple {
+    ($($name:ident),*) => {
+        impl<$($name: RateLimitKey),*> RateLimitKey for ($($name,)*) {
+            #[allow(non_snake_case)]
+            fn encode_key(&self, buf: &mut Vec<u8>) {
+                let ($($name,)*) = self;
+                $($name.encode_key(buf);)*
+            }
+        }
+    };
+}
+
+impl RateLimitKey for () {
+    fn encode_key(&self, _: &mut Vec<u8>) {}
+}
+
+impl_tuple!(A);
+impl_tuple!(A, B);
+impl_tuple!(A, B, C);
+impl_tuple!(A, B, C, D);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unambiguous() {
+        assert_ne!(("ab", "c").to_key(), ("a", "bc").to_key());
+        assert_ne!(Some(0u8).to_key(), None::<u8>.to_key());
+        assert_ne!(1u32.to_key(), 1u64.to_key());
+        assert_eq!("a".to_key(), "a".to_string().to_key());
+        assert_eq!(
+            IpAddr::V4(Ipv4Addr::LOCALHOST).to_key().as_bytes(),
+            [4, 127, 0, 0, 1]
+        );
+        assert_eq!(format!("{:?}", (1u8, true).to_key()), "Key(0101)");
+    }
+
+    #[cfg(feature = "derive")]
+    #[test]
+    fn derive() {
+        use crate::RateLimitKey;
+
+        #[derive(RateLimitKey)]
+        struct Named {
+            #[rate_limit_key(normalize = str::to_ascii_lowercase)]
+            user: String,
+            #[rate_limit_key(skip)]
+            #[allow(dead_code)]
+            attempt: u32,
+            endpoint: &'static str,
+        }
+
+        #[derive(RateLimitKey)]
+        struct Unnamed<T>(T, u8);
+
+        #[derive(RateLimitKey)]
+        struct Unit;
+
+        #[derive(RateLimitKey)]
+        enum Endpoint {
+            Login,
+            Search { query: String },
+            Item(u64),
+        }
+
+        let named = |user: &str, attempt| Named {
+            user: user.to_string(),
+            attempt,
+            endpoint: "/login",
+        };
+        assert_eq!(named("Alice", 1).to_key(), named("alice", 2).to_key());
+        assert_eq!(named("Alice", 1).to_key(), ("alice", "/login").to_key());
+
+        assert_eq!(Unnamed("a", 1).to_key(), ("a", 1u8).to_key());
+        assert_eq!(Unit.to_key(), ().to_key());
+
+        assert_eq!(Endpoint::Login.to_key(), 0u32.to_key());
+        assert_eq!(
+            Endpoint::Search {
+                query: "q".to_string()
+            }
+            .to_key(),
+            (1u32, "q").to_key()
+        );
+        assert_eq!(Endpoint::Item(7).to_key(), (2u32, 7u64).to_key());
+    }
+}
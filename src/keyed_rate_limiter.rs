@@ -0,0 +1,458 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::Hash;
+use std::time::Duration;
+
+use crate::clock::{Clock, MonotonicClock};
+use crate::decaying_counter::DecayingCounter;
+use crate::default_policy::DefaultPolicy;
+use crate::error::Error;
+use crate::limiter::Limiter;
+use crate::quota::Quota;
+use crate::token_bucket::{Permit, TokenBucket};
+
+/// Rate limiter with the same policy for every key, and a bucket per key
+/// created on first use.
+///
+/// Unlike [`RateLimiter`](crate::RateLimiter), which is configured with a
+/// policy per key upfront, the keyed rate limiter is meant for keys that are
+/// unknown in advance, e.g. client IP addresses. Every key is limited on its
+/// own, as if it had a [`TokenBucket`] created from the same [`Quota`].
+///
+/// By default, the buckets are never forgotten, so the number of distinct keys
+/// must be bounded. Otherwise, e.g. when limiting by client IP addresses,
+/// idle buckets must be evicted via [`KeyedRateLimiter::idle_ttl()`] and
+/// [`KeyedRateLimiter::max_keys()`], or else the memory usage is up to the
+/// clients.
+///
+/// The buckets are spread across shards locked independently, so that looking
+/// buckets up and creating them for new keys scales across cores.
+///
+/// Keys may be limited by another [`Limiter`] than a [`TokenBucket`], e.g. by
+/// a [`DecayingCounter`] via [`KeyedRateLimiter::decaying()`], in which case
+/// only the functions common to every limiter are available.
+///
+/// ```
+/// use std::net::{IpAddr, Ipv4Addr};
+/// use std::time::Duration;
+/// use youshallnotpass::KeyedRateLimiter;
+///
+/// let limiter = KeyedRateLimiter::new(1, Duration::from_secs(60));
+///
+/// let alice = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+/// let bob = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+/// assert!(limiter.consume(&alice, 1).is_ok());
+/// assert!(limiter.consume(&alice, 1).is_err());
+/// assert!(limiter.consume(&bob, 1).is_ok());
+/// ```
+pub struct KeyedRateLimiter<K, C = MonotonicClock, B = TokenBucket<C>> {
+    policy: DefaultPolicy<K, C, RandomState, B>,
+}
+
+impl<K: Eq + Hash + Clone> KeyedRateLimiter<K> {
+    /// Create a new [`KeyedRateLimiter`] allowing every key to consume `limit`
+    /// tokens within the `interval`, same as [`TokenBucket::new()`].
+    pub fn new(limit: usize, interval: Duration) -> Self {
+        KeyedRateLimiter::with_clock(limit, interval, MonotonicClock)
+    }
+
+    /// Create a new [`KeyedRateLimiter`] limiting every key to the given
+    /// `quota`.
+    ///
+    /// ```
+    /// use youshallnotpass::{KeyedRateLimiter, Quota};
+    ///
+    /// let limiter = KeyedRateLimiter::from_quota(Quota::per_minute(60).allow_burst(2));
+    /// assert!(limiter.consume(&"A", 2).is_ok());
+    /// assert!(limiter.consume(&"A", 1).is_err());
+    /// ```
+    pub fn from_quota(quota: Quota) -> Self {
+        KeyedRateLimiter::from_quota_with_clock(quota, MonotonicClock)
+    }
+}
+
+impl<K: Eq + Hash + Clone, C: Clock + Clone> KeyedRateLimiter<K, C> {
+    /// Same as [`KeyedRateLimiter::new()`], but allows to override the internal
+    /// clock, which is mainly useful in tests.
+    pub fn with_clock(limit: usize, interval: Duration, clock: C) -> Self {
+        KeyedRateLimiter::from_quota_with_clock(Quota::new(limit, interval), clock)
+    }
+
+    /// Same as [`KeyedRateLimiter::from_quota()`], but allows to override the
+    /// internal clock, which is mainly useful in tests.
+    pub fn from_quota_with_clock(quota: Quota, clock: C) -> Self {
+        KeyedRateLimiter {
+            policy: DefaultPolicy::new(
+                quota,
+                K::clone,
+                |quota, clock: &C| TokenBucket::from_quota_with_clock(quota, clock.clone()),
+                clock,
+            ),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone> KeyedRateLimiter<K, MonotonicClock, DecayingCounter> {
+    /// Create a new [`KeyedRateLimiter`] keeping a [`DecayingCounter`] for
+    /// every key, which admits a score of up to `threshold` halved every
+    /// `half_life` period, same as [`DecayingCounter::new()`].
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::KeyedRateLimiter;
+    ///
+    /// let limiter = KeyedRateLimiter::decaying(3, Duration::from_secs(10));
+    /// assert!(limiter.consume(&"A", 3).is_ok());
+    /// assert!(limiter.consume(&"A", 1).is_err());
+    /// assert!(limiter.consume(&"B", 1).is_ok());
+    /// ```
+    pub fn decaying(threshold: usize, half_life: Duration) -> Self {
+        KeyedRateLimiter::decaying_with_clock(threshold, half_life, MonotonicClock)
+    }
+}
+
+impl<K: Eq + Hash + Clone, C: Clock + Clone> KeyedRateLimiter<K, C, DecayingCounter<C>> {
+    /// Same as [`KeyedRateLimiter::decaying()`], but allows to override the
+    /// internal clock, which is mainly useful in tests.
+    pub fn decaying_with_clock(threshold: usize, half_life: Duration, clock: C) -> Self {
+        KeyedRateLimiter {
+            policy: DefaultPolicy::new(
+                Quota::new(threshold, half_life),
+                K::clone,
+                |quota, clock: &C| {
+                    DecayingCounter::with_clock(quota.tokens(), quota.interval(), clock.clone())
+                },
+                clock,
+            ),
+        }
+    }
+}
+
+impl<K: Eq + Hash, C: Clock, B: Limiter> KeyedRateLimiter<K, C, B> {
+    /// Evict buckets that have been idle for longer than the `ttl`.
+    ///
+    /// Idle buckets are evicted as new keys come, so a bucket may outlive the
+    /// `ttl` up to twice. An evicted key starts over with a full bucket, so
+    /// the `ttl` is expected to be no shorter than the time it takes to refill
+    /// an empty bucket, which makes the eviction unnoticeable.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::KeyedRateLimiter;
+    ///
+    /// let limiter = KeyedRateLimiter::new(10, Duration::from_secs(60))
+    ///     .idle_ttl(Duration::from_secs(60));
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    /// ```
+    pub fn idle_ttl(mut self, ttl: Duration) -> Self {
+        self.policy.eviction_mut().ttl = Some(ttl);
+        self
+    }
+
+    /// Keep at most `max_keys` buckets, evicting the least recently used ones
+    /// once the limit is reached.
+    ///
+    /// The buckets are evicted in batches of an eighth of `max_keys`, so that
+    /// the cost of finding them is amortized. An evicted key starts over with
+    /// a full bucket, so the limit is a last line of defense against floods of
+    /// new keys, and is expected to be well above the number of keys seen
+    /// normally.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::KeyedRateLimiter;
+    ///
+    /// let limiter = KeyedRateLimiter::new(1, Duration::from_secs(60)).max_keys(2);
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    /// assert!(limiter.consume(&"B", 1).is_ok());
+    /// assert!(limiter.consume(&"C", 1).is_ok());
+    /// assert_eq!(limiter.len(), 2);
+    /// ```
+    pub fn max_keys(mut self, max_keys: usize) -> Self {
+        self.policy.eviction_mut().max_keys = Some(max_keys);
+        self
+    }
+
+    /// Try to consume the specified number of `tokens` from the bucket for a
+    /// given `key`.
+    ///
+    /// See [`TokenBucket::consume()`] for details.
+    #[inline]
+    pub fn consume<Q>(&self, key: &Q, tokens: usize) -> Result<(), Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        self.policy
+            .bucket_borrowed(key, Q::to_owned)
+            .consume(tokens)
+    }
+
+    /// Check whether the specified number of `tokens` can be consumed from the
+    /// bucket for a given `key`, without consuming them.
+    pub fn check<Q>(&self, key: &Q, tokens: usize) -> Result<(), Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        match self.policy.get(key) {
+            Some(bucket) => bucket.check(tokens),
+            None => self.policy.fresh().check(tokens),
+        }
+    }
+
+    /// Return the number of keys with a bucket.
+    pub fn len(&self) -> usize {
+        self.policy.len()
+    }
+
+    /// Return `true` if no key has a bucket.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K: Eq + Hash, C: Clock> KeyedRateLimiter<K, C> {
+    /// Same as [`KeyedRateLimiter::consume()`], but returns the number of
+    /// tokens remaining in the bucket for a given `key` after the consumption.
+    pub fn consume_remaining<Q>(&self, key: &Q, tokens: usize) -> Result<u64, Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        self.policy
+            .bucket_borrowed(key, Q::to_owned)
+            .consume_remaining(tokens)
+    }
+
+    /// Try to consume the specified number of `tokens` from the bucket for a
+    /// given `key`, and return a [`Permit`] that refunds them unless committed.
+    ///
+    /// See [`TokenBucket::consume_permit()`] for details.
+    pub fn consume_permit<Q>(&self, key: &Q, tokens: usize) -> Result<Permit<'_, C>, Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        let bucket = self.policy.bucket_borrowed(key, Q::to_owned);
+        let permit = bucket.consume_permit(tokens)?;
+        Ok(permit.shared(bucket.clone()))
+    }
+
+    /// Return the specified number of `tokens` back to the bucket for a given
+    /// `key`.
+    ///
+    /// See [`TokenBucket::refund()`] for details. The function does nothing
+    /// for a key seen first, since its bucket is full.
+    pub fn refund<Q>(&self, key: &Q, tokens: usize)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        if let Some(bucket) = self.policy.get(key) {
+            bucket.refund(tokens);
+        }
+    }
+
+    /// Refill the bucket for a given `key` up to its capacity.
+    pub fn reset<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        if let Some(bucket) = self.policy.get(key) {
+            bucket.reset();
+        }
+    }
+
+    /// Return how long it takes to refill the bucket for a given `key` up to
+    /// its capacity.
+    ///
+    /// See [`TokenBucket::time_to_full()`] for details. For a key seen first,
+    /// the function returns zero, unless the quota blocks every key.
+    pub fn time_to_full<Q>(&self, key: &Q) -> Result<Duration, Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        match self.policy.get(key) {
+            Some(bucket) => bucket.time_to_full(),
+            None => self.policy.fresh().time_to_full(),
+        }
+    }
+}
+
+impl<K, C, B> fmt::Debug for KeyedRateLimiter<K, C, B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyedRateLimiter")
+            .field("quota", self.policy.quota())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<K: Clone, C: Clone, B: Clone> Clone for KeyedRateLimiter<K, C, B> {
+    /// Clone the policy along with the state of every bucket, see
+    /// [`TokenBucket::clone`] for details.
+    fn clone(&self) -> Self {
+        KeyedRateLimiter {
+            policy: self.policy.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    #[test]
+    fn consume() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = KeyedRateLimiter::with_clock(2, Duration::from_secs(1), &clock);
+        assert!(limiter.is_empty());
+
+        assert_eq!(limiter.consume_remaining(&"A", 1), Ok(1));
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_millis(500)))
+        );
+        assert_eq!(limiter.consume(&"B", 3), Err(Error::ExceedsCapacity));
+        assert_eq!(limiter.len(), 2);
+
+        assert_eq!(limiter.time_to_full(&"A"), Ok(Duration::from_secs(1)));
+        limiter.refund(&"A", 1);
+        assert_eq!(limiter.check(&"A", 1), Ok(()));
+        let permit = limiter.consume_permit(&"A", 1).unwrap();
+        assert!(limiter.check(&"A", 1).is_err());
+        drop(permit);
+        limiter.reset(&"A");
+        assert_eq!(limiter.consume(&"A", 2), Ok(()));
+
+        // only consuming creates buckets
+        limiter.refund(&"C", 1);
+        limiter.reset(&"C");
+        assert_eq!(limiter.check(&"C", 2), Ok(()));
+        assert_eq!(limiter.time_to_full(&"C"), Ok(Duration::ZERO));
+        assert_eq!(limiter.len(), 2);
+
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(limiter.consume(&"A", 2), Ok(()));
+
+        // owned keys are looked up by their borrowed forms
+        let limiter = KeyedRateLimiter::with_clock(1, Duration::from_secs(1), &clock);
+        assert_eq!(limiter.consume::<str>("A", 1), Ok(()));
+        assert!(limiter.check("A", 1).is_err());
+        assert!(limiter.consume(&"A".to_string(), 1).is_err());
+    }
+
+    #[test]
+    fn blocked() {
+        let limiter = KeyedRateLimiter::new(0, Duration::from_secs(1));
+        assert_eq!(limiter.consume(&"A", 1), Err(Error::Blocked));
+        assert_eq!(limiter.time_to_full(&"B"), Err(Error::Blocked));
+    }
+
+    #[test]
+    fn debug_clone() {
+        let limiter = KeyedRateLimiter::new(1, Duration::from_secs(60));
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+
+        let cloned = limiter.clone();
+        assert!(cloned.consume(&"A", 1).is_err());
+        assert_eq!(cloned.consume(&"B", 1), Ok(()));
+        assert_eq!(limiter.len(), 1);
+        assert!(format!("{:?}", limiter).starts_with("KeyedRateLimiter { quota: Quota {"));
+    }
+
+    #[test]
+    fn concurrent() {
+        let limiter = KeyedRateLimiter::new(2, Duration::from_secs(60));
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let limiter = &limiter;
+                scope.spawn(move || {
+                    for key in 0..1_000 {
+                        // every key is shared by two threads
+                        assert_eq!(limiter.consume(&(thread % 4, key), 1), Ok(()));
+                    }
+                });
+            }
+        });
+
+        assert_eq!(limiter.len(), 4_000);
+        assert!(limiter.consume(&(0, 0), 1).is_err());
+        assert_eq!(limiter.consume(&(4, 0), 1), Ok(()));
+    }
+
+    #[test]
+    fn idle_ttl() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = KeyedRateLimiter::with_clock(1, Duration::from_secs(1), &clock)
+            .idle_ttl(Duration::from_secs(2));
+
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(limiter.consume(&"B", 1), Ok(()));
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert!(limiter.consume(&"B", 1).is_ok());
+
+        // idle buckets are evicted once a new key comes
+        assert_eq!(limiter.len(), 2);
+        assert_eq!(limiter.consume(&"C", 1), Ok(()));
+        assert_eq!(limiter.len(), 2);
+        assert_eq!(limiter.time_to_full(&"B"), Ok(Duration::from_secs(1)));
+        assert_eq!(limiter.time_to_full(&"A"), Ok(Duration::ZERO));
+    }
+
+    #[test]
+    fn max_keys() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = KeyedRateLimiter::with_clock(1, Duration::from_secs(60), &clock).max_keys(16);
+
+        for key in 0..16 {
+            assert_eq!(limiter.consume(&key, 1), Ok(()));
+            *now.lock().unwrap() += Duration::from_millis(1);
+        }
+        // keep the oldest key in use
+        assert!(limiter.consume(&0, 1).is_err());
+        assert_eq!(limiter.len(), 16);
+
+        // an eighth of the keys, the least recently used ones, are evicted
+        assert_eq!(limiter.consume(&16, 1), Ok(()));
+        assert_eq!(limiter.len(), 14);
+        assert!(limiter.consume(&0, 1).is_err());
+        assert!(limiter.consume(&4, 1).is_err());
+        assert_eq!(limiter.consume(&1, 1), Ok(()));
+        assert_eq!(limiter.len(), 15);
+    }
+
+    #[test]
+    fn decaying() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = KeyedRateLimiter::decaying_with_clock(2, Duration::from_secs(10), &clock)
+            .idle_ttl(Duration::from_secs(60));
+
+        assert_eq!(limiter.consume(&"A", 2), Ok(()));
+        assert_eq!(
+            limiter.check(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_secs(10)))
+        );
+        assert_eq!(limiter.check(&"B", 2), Ok(()));
+        assert_eq!(limiter.consume(&"B", 3), Err(Error::ExceedsCapacity));
+        assert_eq!(limiter.len(), 2);
+
+        // rejections don't keep a counter from being evicted
+        *now.lock().unwrap() += Duration::from_secs(60);
+        assert!(limiter.consume(&"B", 1).is_ok());
+        assert_eq!(limiter.consume(&"C", 1), Ok(()));
+        assert_eq!(limiter.len(), 2);
+        assert_eq!(limiter.check(&"A", 2), Ok(()));
+    }
+}
@@ -1,7 +1,165 @@
+// Allows the derive macros, which refer to the crate by name, to be used
+// within the crate itself.
+extern crate self as youshallnotpass;
+
+mod allowance;
+mod anomaly;
+#[cfg(feature = "bench")]
+pub mod bench;
+mod bypass;
+mod clock;
+#[cfg(any(feature = "toml", feature = "yaml", feature = "json"))]
+mod config;
+mod costed_rate_limiter;
+mod costs;
+mod decaying_counter;
+mod default_policy;
+mod dense_rate_limiter;
 mod error;
+mod escalation;
+mod events;
+mod exchange;
+mod fair_share;
+#[cfg(feature = "governor-compat")]
+pub mod governor;
+mod handoff;
+mod jitter;
+mod key;
+mod keyed_rate_limiter;
+mod limiter;
+mod lint;
+mod pattern;
+#[cfg(feature = "poem")]
+pub mod poem;
+mod policy;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+mod quota;
 mod rate_limiter;
+#[cfg(feature = "regex")]
+mod regex_policy;
+mod runtime_policy;
+mod scoped;
+mod shared;
+mod stats;
+#[cfg(feature = "tide")]
+pub mod tide;
 mod token_bucket;
+#[cfg(all(unix, feature = "uds"))]
+pub mod uds;
 
-pub use error::Error;
+pub use allowance::{Allowance, Denial};
+pub use clock::{Clock, ManualClock, MonotonicClock};
+#[cfg(any(feature = "toml", feature = "yaml", feature = "json"))]
+pub use config::ConfigFormat;
+pub use costed_rate_limiter::CostedRateLimiter;
+pub use decaying_counter::DecayingCounter;
+pub use dense_rate_limiter::DenseRateLimiter;
+#[cfg(any(feature = "toml", feature = "yaml", feature = "json"))]
+pub use error::ConfigError;
+pub use error::{BuildError, DecodeError, Error, ParseRateError};
+pub use events::{EventSink, RejectionEvent};
+pub use exchange::Exchange;
+pub use fair_share::FairShare;
+pub use handoff::{Handoff, RateLimiterSnapshot};
+pub use key::{Key, RateLimitKey};
+pub use keyed_rate_limiter::KeyedRateLimiter;
+pub use limiter::Limiter;
+pub use lint::Lint;
+pub use pattern::KeyPattern;
+pub use policy::{PolicyDiff, PolicySet};
+pub use quota::Quota;
 pub use rate_limiter::{RateLimiter, RateLimiterBuilder};
-pub use token_bucket::TokenBucket;
+pub use scoped::Scoped;
+pub use shared::SharedRateLimiter;
+pub use stats::Stats;
+pub use token_bucket::{
+    Availability, Intersection, Permit, QosClass, Reservation, Snapshot, Speculation, TokenBucket,
+    TokenBucketBuilder,
+};
+#[cfg(feature = "derive")]
+pub use youshallnotpass_derive::RateLimitKey;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    /// Allocator counting allocations made by the current thread, so that
+    /// tests running in parallel don't interfere with each other.
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.with(|allocations| allocations.set(allocations.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    fn allocations<R>(f: impl FnOnce() -> R) -> usize {
+        let before = ALLOCATIONS.with(Cell::get);
+        std::hint::black_box(f());
+        ALLOCATIONS.with(Cell::get) - before
+    }
+
+    #[test]
+    fn consume_does_not_allocate() {
+        let bucket = TokenBucket::new(2, Duration::from_secs(60));
+        assert_eq!(allocations(|| bucket.consume(1)), 0);
+        assert_eq!(allocations(|| bucket.consume(1)), 0);
+        // both a rejection, and a rejection served from the cached denial
+        assert_eq!(allocations(|| bucket.consume(1)), 0);
+        assert_eq!(allocations(|| bucket.consume(1)), 0);
+        assert_eq!(allocations(|| bucket.consume(3)), 0);
+
+        let bucket = TokenBucket::new(0, Duration::from_secs(60));
+        assert_eq!(allocations(|| bucket.consume(1)), 0);
+
+        let limiter = RateLimiter::configure()
+            .limit("A", 1, Duration::from_secs(60))
+            .done();
+        assert_eq!(allocations(|| limiter.consume(&"A", 1)), 0);
+        assert_eq!(allocations(|| limiter.consume(&"A", 1)), 0);
+        assert_eq!(allocations(|| limiter.consume(&"B", 1)), 0);
+
+        let limiter = RateLimiter::configure()
+            .limit("A".to_string(), 1, Duration::from_secs(60))
+            .default_limit(1, Duration::from_secs(60))
+            .done();
+        assert_eq!(allocations(|| limiter.consume("A", 1)), 0);
+        assert_eq!(allocations(|| limiter.consume("A", 1)), 0);
+        limiter.consume("B", 1).unwrap();
+        assert_eq!(allocations(|| limiter.consume("B", 1)), 0);
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn observe_does_not_allocate() {
+        let metrics = prometheus::Metrics::new().max_keys(1);
+        let error = Error::RetryAfter(Duration::from_secs(1));
+        // labels are created when their keys are seen first
+        metrics.observe("A", Ok(()));
+        metrics.observe("A", Err(&error));
+        metrics.observe("B", Ok(()));
+        metrics.observe("B", Err(&error));
+
+        assert_eq!(allocations(|| metrics.observe("A", Ok(()))), 0);
+        assert_eq!(allocations(|| metrics.observe("A", Err(&error))), 0);
+        assert_eq!(allocations(|| metrics.observe("B", Ok(()))), 0);
+        assert_eq!(allocations(|| metrics.observe("C", Err(&error))), 0);
+    }
+}
@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+use crate::clock::Clock;
+use crate::decaying_counter::DecayingCounter;
+use crate::error::Error;
+use crate::token_bucket::TokenBucket;
+
+/// An algorithm limiting requests of a single entity, e.g. a [`TokenBucket`]
+/// or a [`DecayingCounter`].
+///
+/// This is what a [`KeyedRateLimiter`](crate::KeyedRateLimiter) keeps for
+/// every key, so that the same keyed infrastructure, including the eviction
+/// of idle keys, works with any of the algorithms.
+///
+/// ```
+/// use std::time::Duration;
+/// use youshallnotpass::{DecayingCounter, Limiter, TokenBucket};
+///
+/// fn admit(limiter: &impl Limiter) -> bool {
+///     limiter.consume(1).is_ok()
+/// }
+///
+/// assert!(admit(&TokenBucket::new(1, Duration::from_secs(60))));
+/// assert!(admit(&DecayingCounter::new(1, Duration::from_secs(60))));
+/// ```
+pub trait Limiter {
+    /// Try to consume the specified number of `tokens`, and return why the
+    /// request is rejected, if it is.
+    fn consume(&self, tokens: usize) -> Result<(), Error>;
+
+    /// Check whether the specified number of `tokens` can be consumed,
+    /// without consuming them.
+    fn check(&self, tokens: usize) -> Result<(), Error>;
+
+    /// Return how long the limiter has been unused, so that it can be
+    /// forgotten without being noticed.
+    fn idle_for(&self) -> Duration;
+}
+
+impl<C: Clock> Limiter for TokenBucket<C> {
+    #[inline]
+    fn consume(&self, tokens: usize) -> Result<(), Error> {
+        TokenBucket::consume(self, tokens)
+    }
+
+    fn check(&self, tokens: usize) -> Result<(), Error> {
+        TokenBucket::check(self, tokens)
+    }
+
+    fn idle_for(&self) -> Duration {
+        TokenBucket::idle_for(self)
+    }
+}
+
+impl<C: Clock> Limiter for DecayingCounter<C> {
+    #[inline]
+    fn consume(&self, tokens: usize) -> Result<(), Error> {
+        DecayingCounter::consume(self, tokens)
+    }
+
+    fn check(&self, tokens: usize) -> Result<(), Error> {
+        DecayingCounter::check(self, tokens)
+    }
+
+    fn idle_for(&self) -> Duration {
+        DecayingCounter::idle_for(self)
+    }
+}
@@ -0,0 +1,90 @@
+use std::fmt::Debug;
+use std::time::Duration;
+
+/// A likely mistake in the configuration of a [`RateLimiter`](crate::RateLimiter).
+///
+/// Lints are reported by [`RateLimiter::lint()`](crate::RateLimiter::lint),
+/// and are meant to be checked at startup, so that suspicious configurations
+/// are caught before they affect production traffic.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Lint<'l, K> {
+    /// Several limiting policies were set for the `key`, and one of them has
+    /// no effect, since another one is at least as strict in both the burst
+    /// size and the rate.
+    Shadowed {
+        /// The key with several policies.
+        key: &'l K,
+    },
+
+    /// The policy for the `key` generates a token faster than every
+    /// millisecond, which usually means the limit and the interval have been
+    /// mixed up, or the interval is in the wrong units.
+    SubMillisecondTokenTime {
+        /// The key with the policy.
+        key: &'l K,
+
+        /// The time it takes to generate a single token.
+        time_per_token: Duration,
+    },
+
+    /// The policy for the `key` allows bursts larger than the global policy
+    /// does, so the requests for more tokens than the global burst are
+    /// rejected regardless, see [`Error::ExceedsCapacity`](crate::Error::ExceedsCapacity).
+    BurstAboveGlobal {
+        /// The key with the policy.
+        key: &'l K,
+
+        /// The burst size of the policy.
+        burst: usize,
+
+        /// The burst size of the global policy.
+        global_burst: usize,
+    },
+
+    /// The `pattern` never limits a key, since every key it matches is
+    /// matched by another pattern, which is either more specific or set
+    /// earlier, see [`RateLimiterBuilder::pattern()`](crate::RateLimiterBuilder::pattern).
+    ///
+    /// Patterns are judged by matching them against each other, i.e. the
+    /// other pattern matches the `pattern` itself, and does so with at least
+    /// the same specificity.
+    UnreachablePattern {
+        /// The pattern that never wins.
+        pattern: &'l K,
+
+        /// The pattern that wins instead.
+        by: &'l K,
+    },
+}
+
+impl<K: Debug> std::fmt::Display for Lint<'_, K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Lint::Shadowed { key } => {
+                write!(f, "Policy for {:?} is shadowed by a stricter one", key)
+            }
+            Lint::SubMillisecondTokenTime {
+                key,
+                time_per_token,
+            } => write!(
+                f,
+                "Policy for {:?} generates a token every {:?}",
+                key, time_per_token
+            ),
+            Lint::BurstAboveGlobal {
+                key,
+                burst,
+                global_burst,
+            } => write!(
+                f,
+                "Policy for {:?} allows bursts of {} tokens, while the global one allows {}",
+                key, burst, global_burst
+            ),
+            Lint::UnreachablePattern { pattern, by } => write!(
+                f,
+                "Pattern {:?} never wins, since {:?} matches the same keys first",
+                pattern, by
+            ),
+        }
+    }
+}
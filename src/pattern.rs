@@ -0,0 +1,142 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// A type whose values may be patterns matching other values of the type,
+/// e.g. keys of routes matched by the prefix of their paths.
+///
+/// Patterns are registered via [`RateLimiterBuilder::pattern()`], and the
+/// keys without a policy of their own are limited by the most specific
+/// pattern matching them.
+///
+/// Strings ending with `*` match every string starting with the rest of the
+/// pattern, and `*` alone matches any string. The other strings, as well as
+/// values of the other types, match only themselves. A tuple matches if every
+/// of its elements does.
+///
+/// ```
+/// use youshallnotpass::KeyPattern;
+///
+/// assert!(("GET", "/api/*").match_key(&("GET", "/api/users")).is_some());
+/// assert!(("GET", "/api/*").match_key(&("PUT", "/api/users")).is_none());
+///
+/// // the longer the literal part, the more specific the pattern is
+/// let users = ("*", "/api/users/*").match_key(&("GET", "/api/users/1"));
+/// let api = ("GET", "/api/*").match_key(&("GET", "/api/users/1"));
+/// assert!(users > api);
+/// ```
+///
+/// [`RateLimiterBuilder::pattern()`]: crate::RateLimiterBuilder::pattern
+pub trait KeyPattern {
+    /// Return how specific the pattern is if it matches the `key`, and `None`
+    /// otherwise.
+    ///
+    /// Of several patterns matching the same key, the one with the greatest
+    /// specificity wins. For strings, it's the length of the literal part,
+    /// plus one for a pattern without a wildcard, so that an exact match wins
+    /// over a prefix of the same length. For tuples, it's the sum of the
+    /// specificities of the elements.
+    fn match_key(&self, key: &Self) -> Option<usize>;
+}
+
+/// Patterns set for a rate limiter, along with the function matching keys
+/// against them, which is only known while the patterns are set.
+pub(crate) struct Patterns<K> {
+    pub(crate) keys: Vec<K>,
+    pub(crate) match_key: fn(&K, &K) -> Option<usize>,
+}
+
+impl<K> Patterns<K> {
+    pub(crate) fn new(match_key: fn(&K, &K) -> Option<usize>) -> Self {
+        Patterns {
+            keys: Vec::new(),
+            match_key,
+        }
+    }
+}
+
+impl<K: Clone> Clone for Patterns<K> {
+    fn clone(&self) -> Self {
+        Patterns {
+            keys: self.keys.clone(),
+            match_key: self.match_key,
+        }
+    }
+}
+
+impl<T: KeyPattern + ?Sized> KeyPattern for &T {
+    fn match_key(&self, key: &Self) -> Option<usize> {
+        (**self).match_key(key)
+    }
+}
+
+impl KeyPattern for str {
+    fn match_key(&self, key: &Self) -> Option<usize> {
+        match self.strip_suffix('*') {
+            Some(prefix) => key.starts_with(prefix).then_some(prefix.len()),
+            None => (self == key).then_some(self.len() + 1),
+        }
+    }
+}
+
+impl KeyPattern for String {
+    fn match_key(&self, key: &Self) -> Option<usize> {
+        self.as_str().match_key(key.as_str())
+    }
+}
+
+macro_rules! impl_exact {
+    ($($ty:ty),*) => {
+        $(
+            impl KeyPattern for $ty {
+                fn match_key(&self, key: &Self) -> Option<usize> {
+                    (self == key).then_some(1)
+                }
+            }
+        )*
+    };
+}
+
+impl_exact!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+impl_exact!(bool, char, IpAddr, Ipv4Addr, Ipv6Addr);
+
+macro_rules! impl_tuple {
+    ($($name:ident $index:tt),*) => {
+        impl<$($name: KeyPattern),*> KeyPattern for ($($name,)*) {
+            fn match_key(&self, key: &Self) -> Option<usize> {
+                let specificity = 0;
+                $(let specificity = specificity + self.$index.match_key(&key.$index)?;)*
+                Some(specificity)
+            }
+        }
+    };
+}
+
+impl_tuple!(A 0);
+impl_tuple!(A 0, B 1);
+impl_tuple!(A 0, B 1, C 2);
+impl_tuple!(A 0, B 1, C 2, D 3);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn match_key() {
+        assert_eq!("/api/*".match_key("/api/users"), Some(5));
+        assert_eq!("/api/*".match_key("/api/"), Some(5));
+        assert_eq!("/api/*".match_key("/ap"), None);
+        assert_eq!("*".match_key("/api"), Some(0));
+        assert_eq!("/api".match_key("/api"), Some(5));
+        assert_eq!("/api".match_key("/api/users"), None);
+        assert_eq!(
+            "/api/*".to_string().match_key(&"/api/users".to_string()),
+            Some(5)
+        );
+        assert_eq!(1u8.match_key(&1), Some(1));
+        assert_eq!(1u8.match_key(&2), None);
+
+        assert_eq!(("GET", "/api/*").match_key(&("GET", "/api/users")), Some(9));
+        assert_eq!(("*", "/api/*").match_key(&("PUT", "/api/users")), Some(5));
+        assert_eq!(("GET", "/api/*").match_key(&("PUT", "/api/users")), None);
+        assert_eq!((1u8, "*").match_key(&(1, "/")), Some(1));
+    }
+}
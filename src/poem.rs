@@ -0,0 +1,238 @@
+//! Integration with the [poem](https://docs.rs/poem) web framework.
+//!
+//! The module is available behind the `poem` feature, and provides a
+//! middleware that rate limits incoming requests before they reach the
+//! endpoint. Since `poem-openapi` services are regular poem endpoints, the
+//! middleware can be used with them as well.
+//!
+//! ```
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//! use poem::{handler, EndpointExt, Route};
+//! use youshallnotpass::poem::RateLimitMiddleware;
+//! use youshallnotpass::RateLimiter;
+//!
+//! #[handler]
+//! fn login() -> &'static str {
+//!     "ok"
+//! }
+//!
+//! let limiter = RateLimiter::configure()
+//!     .limit("/login".to_string(), 5, Duration::from_secs(60))
+//!     .done();
+//!
+//! let app = Route::new()
+//!     .at("/login", login)
+//!     .with(RateLimitMiddleware::new(Arc::new(limiter), |req: &poem::Request| {
+//!         req.uri().path().to_string()
+//!     }));
+//! ```
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+
+use poem::http::header::RETRY_AFTER;
+use poem::http::{HeaderName, HeaderValue, StatusCode};
+use poem::{Endpoint, IntoResponse, Middleware, Request, Response, Result};
+
+use crate::allowance::{seconds, Usage};
+use crate::clock::{Clock, MonotonicClock};
+use crate::{Error, RateLimiter};
+
+/// Poem middleware consuming a token for every incoming request.
+///
+/// The `key` function maps a request to a key of the [`RateLimiter`], e.g. a
+/// path or a client address. Requests that are allowed by the limiter are
+/// passed to the wrapped endpoint, while the rest are answered with
+/// `429 Too Many Requests`. If retrying makes sense, the response carries the
+/// `Retry-After` header with the number of seconds to wait.
+///
+/// Responses for keys with a limit carry the `RateLimit-Limit`,
+/// `RateLimit-Remaining` and `RateLimit-Reset` headers as well, both allowed
+/// and rejected ones, see [`RateLimiter::consume_detailed`].
+pub struct RateLimitMiddleware<K: 'static, F, C: 'static = MonotonicClock, S: 'static = RandomState>
+{
+    limiter: Arc<RateLimiter<K, C, S>>,
+    key: Arc<F>,
+}
+
+impl<K, F, C, S> RateLimitMiddleware<K, F, C, S> {
+    /// Create a middleware consuming tokens from `limiter` for keys extracted
+    /// from requests by `key`.
+    ///
+    /// The `limiter` is either an [`Arc`], or a [`SharedRateLimiter`].
+    ///
+    /// [`SharedRateLimiter`]: crate::SharedRateLimiter
+    pub fn new(limiter: impl Into<Arc<RateLimiter<K, C, S>>>, key: F) -> Self {
+        RateLimitMiddleware {
+            limiter: limiter.into(),
+            key: Arc::new(key),
+        }
+    }
+}
+
+impl<E, K, F, C, S> Middleware<E> for RateLimitMiddleware<K, F, C, S>
+where
+    E: Endpoint,
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    F: Fn(&Request) -> K + Send + Sync,
+    C: Clock + Send + Sync + 'static,
+    S: BuildHasher + Send + Sync + 'static,
+{
+    type Output = RateLimitEndpoint<E, K, F, C, S>;
+
+    fn transform(&self, endpoint: E) -> Self::Output {
+        RateLimitEndpoint {
+            inner: endpoint,
+            limiter: self.limiter.clone(),
+            key: self.key.clone(),
+        }
+    }
+}
+
+/// Endpoint produced by [`RateLimitMiddleware`].
+pub struct RateLimitEndpoint<
+    E,
+    K: 'static,
+    F,
+    C: 'static = MonotonicClock,
+    S: 'static = RandomState,
+> {
+    inner: E,
+    limiter: Arc<RateLimiter<K, C, S>>,
+    key: Arc<F>,
+}
+
+impl<E, K, F, C, S> Endpoint for RateLimitEndpoint<E, K, F, C, S>
+where
+    E: Endpoint,
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    F: Fn(&Request) -> K + Send + Sync,
+    C: Clock + Send + Sync + 'static,
+    S: BuildHasher + Send + Sync + 'static,
+{
+    type Output = Response;
+
+    async fn call(&self, request: Request) -> Result<Self::Output> {
+        let (mut response, usage) = match self.limiter.consume_detailed(&(self.key)(&request), 1) {
+            Ok(allowance) => {
+                let response = self.inner.call(request).await?.into_response();
+                (response, *allowance.usage())
+            }
+            Err(denial) => {
+                let mut response = StatusCode::TOO_MANY_REQUESTS.into_response();
+                if let Error::RetryAfter(duration) = denial.error() {
+                    let retry_after = HeaderValue::from(seconds(*duration));
+                    response.headers_mut().insert(RETRY_AFTER, retry_after);
+                }
+                (response, *denial.usage())
+            }
+        };
+        insert_headers(&mut response, &usage);
+        Ok(response)
+    }
+}
+
+/// Insert the `RateLimit-*` headers describing the `usage` into a `response`.
+fn insert_headers(response: &mut Response, usage: &Usage) {
+    for (name, value) in usage.headers() {
+        let name = HeaderName::from_static(name);
+        response
+            .headers_mut()
+            .insert(name, HeaderValue::from(value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use poem::{handler, EndpointExt, Route};
+
+    use crate::ManualClock;
+
+    #[handler]
+    fn index() -> &'static str {
+        "ok"
+    }
+
+    fn app(limiter: RateLimiter<String>) -> impl Endpoint<Output = Response> {
+        Route::new().at("/*", index).with(RateLimitMiddleware::new(
+            Arc::new(limiter),
+            |req: &Request| req.uri().path().to_string(),
+        ))
+    }
+
+    fn get(app: &impl Endpoint<Output = Response>, path: &str) -> Response {
+        let request = Request::builder().uri_str(path).finish();
+        async_std::task::block_on(app.call(request)).unwrap()
+    }
+
+    #[test]
+    fn retry_after() {
+        let app = app(RateLimiter::configure()
+            .limit("/a".to_string(), 1, Duration::from_secs(60))
+            .done());
+
+        assert_eq!(get(&app, "/a").status(), StatusCode::OK);
+
+        let response = get(&app, "/a");
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = response.headers()[RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(retry_after > 0 && retry_after <= 60);
+
+        // keys without a policy are never limited
+        assert_eq!(get(&app, "/b").status(), StatusCode::OK);
+        assert_eq!(get(&app, "/b").status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn blocked() {
+        let app = app(RateLimiter::configure()
+            .limit("/a".to_string(), 0, Duration::from_secs(60))
+            .done());
+
+        let response = get(&app, "/a");
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().get(RETRY_AFTER).is_none());
+        assert_eq!(response.headers()["ratelimit-limit"], "0");
+        assert!(response.headers().get("ratelimit-reset").is_none());
+    }
+
+    #[test]
+    fn headers() {
+        let clock = ManualClock::new();
+        let limiter = RateLimiter::with_clock(clock.clone())
+            .limit("/a".to_string(), 2, Duration::from_secs(60))
+            .done();
+        let app = Route::new().at("/*", index).with(RateLimitMiddleware::new(
+            Arc::new(limiter),
+            |req: &Request| req.uri().path().to_string(),
+        ));
+        let headers = |response: &Response| {
+            ["ratelimit-limit", "ratelimit-remaining", "ratelimit-reset"]
+                .map(|name| response.headers()[name].to_str().unwrap().to_string())
+        };
+
+        let response = get(&app, "/a");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(headers(&response), ["2", "1", "30"]);
+
+        assert_eq!(get(&app, "/a").status(), StatusCode::OK);
+        let response = get(&app, "/a");
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(headers(&response), ["2", "0", "60"]);
+        assert_eq!(response.headers()[RETRY_AFTER], "30");
+
+        // keys without a policy have no limit to report
+        let response = get(&app, "/b");
+        assert!(response.headers().get("ratelimit-limit").is_none());
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::quota::Quota;
+
+/// A set of limiting policies, i.e. a [`Quota`] for every key.
+///
+/// Policy sets are meant for configurations that are reloaded at runtime:
+/// [`PolicySet::diff()`] tells what has changed between two versions of the
+/// configuration, and [`RateLimiter::apply()`](crate::RateLimiter::apply)
+/// updates a live rate limiter without touching the keys that haven't.
+///
+/// ```
+/// use youshallnotpass::{PolicySet, Quota, RateLimiter};
+///
+/// let policies: PolicySet<&str> = [("A", Quota::per_second(1))].into_iter().collect();
+/// let limiter = RateLimiter::configure().policies(policies).done();
+/// assert!(limiter.consume(&"A", 1).is_ok());
+/// assert!(limiter.consume(&"A", 1).is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicySet<K: Eq + Hash> {
+    policies: HashMap<K, Quota>,
+}
+
+impl<K: Eq + Hash> PolicySet<K> {
+    /// Create an empty [`PolicySet`].
+    pub fn new() -> Self {
+        PolicySet {
+            policies: HashMap::new(),
+        }
+    }
+
+    /// Set the `quota` for a `key`, and return the previous one, if any.
+    pub fn insert(&mut self, key: K, quota: Quota) -> Option<Quota> {
+        self.policies.insert(key, quota)
+    }
+
+    /// Remove the policy for a `key`, and return its quota, if any.
+    pub fn remove(&mut self, key: &K) -> Option<Quota> {
+        self.policies.remove(key)
+    }
+
+    /// Return the quota for a `key`, if any.
+    pub fn get(&self, key: &K) -> Option<&Quota> {
+        self.policies.get(key)
+    }
+
+    /// Return the number of policies in the set.
+    pub fn len(&self) -> usize {
+        self.policies.len()
+    }
+
+    /// Return `true` if the set contains no policies.
+    pub fn is_empty(&self) -> bool {
+        self.policies.is_empty()
+    }
+
+    /// Iterate over the keys and their quotas, in arbitrary order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &Quota)> {
+        self.policies.iter()
+    }
+}
+
+impl<K: Eq + Hash + Clone> PolicySet<K> {
+    /// Describe the changes that turn the `old` set of policies into the `new`
+    /// one.
+    ///
+    /// ```
+    /// use youshallnotpass::{PolicySet, Quota};
+    ///
+    /// let old: PolicySet<&str> = [("A", Quota::per_second(1)), ("B", Quota::per_second(1))]
+    ///     .into_iter()
+    ///     .collect();
+    /// let new: PolicySet<&str> = [("A", Quota::per_second(2)), ("C", Quota::per_second(1))]
+    ///     .into_iter()
+    ///     .collect();
+    ///
+    /// let diff = PolicySet::diff(&old, &new);
+    /// assert_eq!(diff.added(), &[("C", Quota::per_second(1))]);
+    /// assert_eq!(diff.removed(), &["B"]);
+    /// assert_eq!(diff.changed(), &[("A", Quota::per_second(1), Quota::per_second(2))]);
+    /// ```
+    pub fn diff(old: &PolicySet<K>, new: &PolicySet<K>) -> PolicyDiff<K> {
+        let mut diff = PolicyDiff {
+            added: Vec::new(),
+            removed: Vec::new(),
+            changed: Vec::new(),
+        };
+        for (key, quota) in &new.policies {
+            match old.policies.get(key) {
+                None => diff.added.push((key.clone(), *quota)),
+                Some(old_quota) if old_quota != quota => {
+                    diff.changed.push((key.clone(), *old_quota, *quota))
+                }
+                Some(_) => {}
+            }
+        }
+        for key in old.policies.keys() {
+            if !new.policies.contains_key(key) {
+                diff.removed.push(key.clone());
+            }
+        }
+        diff
+    }
+}
+
+impl<K: Eq + Hash> Default for PolicySet<K> {
+    fn default() -> Self {
+        PolicySet::new()
+    }
+}
+
+impl<K: Eq + Hash> FromIterator<(K, Quota)> for PolicySet<K> {
+    fn from_iter<I: IntoIterator<Item = (K, Quota)>>(iter: I) -> Self {
+        PolicySet {
+            policies: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl<K: Eq + Hash> IntoIterator for PolicySet<K> {
+    type Item = (K, Quota);
+    type IntoIter = std::collections::hash_map::IntoIter<K, Quota>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.policies.into_iter()
+    }
+}
+
+/// Changes between two sets of policies, computed via [`PolicySet::diff()`].
+///
+/// Entries of every kind are listed in arbitrary order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyDiff<K> {
+    pub(crate) added: Vec<(K, Quota)>,
+    pub(crate) removed: Vec<K>,
+    pub(crate) changed: Vec<(K, Quota, Quota)>,
+}
+
+impl<K> PolicyDiff<K> {
+    /// Return the keys that have got a policy, along with their quotas.
+    pub fn added(&self) -> &[(K, Quota)] {
+        &self.added
+    }
+
+    /// Return the keys whose policies have been removed.
+    pub fn removed(&self) -> &[K] {
+        &self.removed
+    }
+
+    /// Return the keys whose policies have changed, along with their old and
+    /// new quotas.
+    pub fn changed(&self) -> &[(K, Quota, Quota)] {
+        &self.changed
+    }
+
+    /// Return `true` if nothing has changed.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff() {
+        let old: PolicySet<&str> = [
+            ("A", Quota::per_second(1)),
+            ("B", Quota::per_second(1)),
+            ("C", Quota::per_second(1)),
+        ]
+        .into_iter()
+        .collect();
+        let mut new = old.clone();
+        new.insert("B", Quota::per_second(1).allow_burst(5));
+        new.insert("D", Quota::per_minute(1));
+        new.remove(&"C");
+
+        let diff = PolicySet::diff(&old, &new);
+        assert_eq!(diff.added(), &[("D", Quota::per_minute(1))]);
+        assert_eq!(diff.removed(), &["C"]);
+        assert_eq!(
+            diff.changed(),
+            &[(
+                "B",
+                Quota::per_second(1),
+                Quota::per_second(1).allow_burst(5)
+            )]
+        );
+        assert!(!diff.is_empty());
+
+        assert!(PolicySet::diff(&old, &old).is_empty());
+        assert_eq!(PolicySet::diff(&new, &old).removed(), &["D"]);
+    }
+}
@@ -0,0 +1,520 @@
+//! Integration with the [prometheus](https://docs.rs/prometheus) metrics
+//! library.
+//!
+//! The module is available behind the `prometheus` feature, and provides a
+//! collector reporting, per key, the number of allowed and denied requests,
+//! the tokens currently available, and the distribution of delays denied
+//! requests are told to retry after. The collector can be registered with any
+//! [`prometheus::Registry`].
+//!
+//! Decisions are fed to the collector via [`Metrics::observer`], passed to
+//! [`RateLimiterBuilder::on_decision`], while the tokens available are read
+//! from the rate limiters [watched](Metrics::watch) on every scrape.
+//!
+//! ```
+//! use std::time::Duration;
+//! use prometheus::{Registry, TextEncoder};
+//! use youshallnotpass::prometheus::Metrics;
+//! use youshallnotpass::RateLimiter;
+//!
+//! let metrics = Metrics::new();
+//! let limiter = RateLimiter::configure()
+//!     .limit("/login", 5, Duration::from_secs(60))
+//!     .on_decision(metrics.observer())
+//!     .done()
+//!     .into_shared();
+//! metrics.watch(limiter.clone());
+//!
+//! let registry = Registry::new();
+//! registry.register(Box::new(metrics.clone())).unwrap();
+//!
+//! assert!(limiter.consume(&"/login", 1).is_ok());
+//! let exposition = TextEncoder::new().encode_to_string(&registry.gather()).unwrap();
+//! assert!(exposition.contains(r#"youshallnotpass_tokens_available{key="/login"} 4"#));
+//! ```
+//!
+//! [`RateLimiterBuilder::on_decision`]: crate::RateLimiterBuilder::on_decision
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts,
+};
+
+use crate::clock::Clock;
+use crate::shared::SharedRateLimiter;
+use crate::Error;
+
+/// Upper bounds, in seconds, of the buckets of the retry-after histogram,
+/// spanning from throttling bursts to hourly quotas.
+const RETRY_AFTER_BUCKETS: &[f64] = &[
+    0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0,
+];
+
+/// The label keys are folded into once they don't get labels of their own.
+const OTHER: &str = "other";
+
+/// The number of keys given labels of their own by default, see
+/// [`Metrics::max_keys`].
+const DEFAULT_MAX_KEYS: usize = 1000;
+
+/// Reads the tokens available to every limited key of a watched rate limiter,
+/// along with the labels of the keys.
+type FillLevels = Box<dyn Fn(&Inner) -> Vec<(Arc<Label>, i64)> + Send + Sync>;
+
+struct Inner {
+    decisions: IntCounterVec,
+    retry_after: HistogramVec,
+    tokens: IntGaugeVec,
+    labels: RwLock<Labels>,
+    other: Arc<Label>,
+    watched: RwLock<Vec<FillLevels>>,
+    /// Serializes scrapes, which reset the gauges of the tokens available
+    /// before setting them.
+    collecting: Mutex<()>,
+}
+
+/// Keys with labels of their own, see [`Metrics::max_keys`].
+///
+/// Keys are looked up by their hashes, so that keys of any type are labelled
+/// without being formatted. The hashes are seeded randomly, so that clients
+/// can't pick keys that collide with the labelled ones.
+struct Labels {
+    hasher: RandomState,
+    keys: HashMap<u64, Arc<Label>>,
+    max_keys: usize,
+}
+
+/// The metrics of a label, created on first use, so that the ones never
+/// observed aren't reported.
+struct Label {
+    name: String,
+    allowed: OnceLock<IntCounter>,
+    denied: OnceLock<IntCounter>,
+    retry_after: OnceLock<Histogram>,
+}
+
+impl Label {
+    fn new(name: String) -> Self {
+        Label {
+            name,
+            allowed: OnceLock::new(),
+            denied: OnceLock::new(),
+            retry_after: OnceLock::new(),
+        }
+    }
+}
+
+impl Inner {
+    /// Return the label of a `key`, which is given one of its own if there is
+    /// room for it, and is folded into the `other` label otherwise.
+    fn label<K: Display + Hash + ?Sized>(&self, key: &K) -> Arc<Label> {
+        let labels = self.labels.read().unwrap();
+        let hash = labels.hasher.hash_one(key);
+        if let Some(label) = labels.keys.get(&hash) {
+            return Arc::clone(label);
+        }
+        if labels.keys.len() >= labels.max_keys {
+            return Arc::clone(&self.other);
+        }
+        drop(labels);
+
+        let mut labels = self.labels.write().unwrap();
+        if let Some(label) = labels.keys.get(&hash) {
+            return Arc::clone(label);
+        }
+        if labels.keys.len() >= labels.max_keys {
+            return Arc::clone(&self.other);
+        }
+        let label = Arc::new(Label::new(key.to_string()));
+        labels.keys.insert(hash, Arc::clone(&label));
+        label
+    }
+}
+
+/// Prometheus collector of the decisions made by rate limiters.
+///
+/// The following metrics are reported, with keys formatted via [`Display`]
+/// into the `key` label, or folded into the `other` one, see
+/// [`Metrics::max_keys`] and [`Metrics::allow_keys`]:
+///
+/// * `<namespace>_decisions_total{key, outcome}`, the number of requests
+///   allowed or denied, with `outcome` being either `allowed` or `denied`;
+/// * `<namespace>_retry_after_seconds{key}`, a histogram of the delays denied
+///   requests are told to retry after, so blocked keys and requests exceeding
+///   the capacity aren't observed;
+/// * `<namespace>_tokens_available{key}`, the tokens available to every key
+///   with a limiting policy of the watched rate limiters, so keys limited by
+///   the default policy aren't reported, as they can't be enumerated.
+///
+/// Cloning the collector returns another handle to the same metrics, e.g. to
+/// be registered while the original one observes decisions.
+#[derive(Clone)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+impl Metrics {
+    /// Create a collector with metrics in the `youshallnotpass` namespace.
+    pub fn new() -> Self {
+        Metrics::with_namespace("youshallnotpass").expect("metrics must be valid")
+    }
+
+    /// Create a collector with metrics in a given `namespace`, e.g. to tell
+    /// several rate limiters apart in the same registry.
+    ///
+    /// Fails if the `namespace` isn't a valid prefix of a metric name.
+    pub fn with_namespace(namespace: &str) -> prometheus::Result<Self> {
+        let decisions = IntCounterVec::new(
+            Opts::new("decisions_total", "Number of requests allowed or denied.")
+                .namespace(namespace),
+            &["key", "outcome"],
+        )?;
+        let retry_after = HistogramVec::new(
+            HistogramOpts::new(
+                "retry_after_seconds",
+                "Delays denied requests are told to retry after.",
+            )
+            .namespace(namespace)
+            .buckets(RETRY_AFTER_BUCKETS.to_vec()),
+            &["key"],
+        )?;
+        let tokens = IntGaugeVec::new(
+            Opts::new("tokens_available", "Tokens currently available to a key.")
+                .namespace(namespace),
+            &["key"],
+        )?;
+        Ok(Metrics {
+            inner: Arc::new(Inner {
+                decisions,
+                retry_after,
+                tokens,
+                labels: RwLock::new(Labels {
+                    hasher: RandomState::new(),
+                    keys: HashMap::new(),
+                    max_keys: DEFAULT_MAX_KEYS,
+                }),
+                other: Arc::new(Label::new(OTHER.to_string())),
+                watched: RwLock::new(Vec::new()),
+                collecting: Mutex::new(()),
+            }),
+        })
+    }
+
+    /// Give labels of their own to at most `max_keys` keys, the first ones
+    /// observed or watched, and fold the others into the `other` label.
+    ///
+    /// Every label is a time series kept by Prometheus, so the number of them
+    /// must be bounded when the keys come from clients, e.g. IP addresses.
+    /// Defaults to 1000 keys.
+    ///
+    /// ```
+    /// use youshallnotpass::prometheus::Metrics;
+    ///
+    /// let metrics = Metrics::new().max_keys(100);
+    /// ```
+    pub fn max_keys(self, max_keys: usize) -> Self {
+        self.inner.labels.write().unwrap().max_keys = max_keys;
+        self
+    }
+
+    /// Give labels of their own only to the given `keys`, and fold the others
+    /// into the `other` label.
+    ///
+    /// Keys are matched by their hashes, so the `keys` must hash the same as
+    /// the keys of the rate limiters, which is the case for e.g. `&str` and
+    /// `String`. A subsequent [`Metrics::max_keys`] lets other keys in, up to
+    /// `max_keys` labels in total.
+    ///
+    /// ```
+    /// use youshallnotpass::prometheus::Metrics;
+    ///
+    /// let metrics = Metrics::new().allow_keys(["/login", "/signup"]);
+    /// ```
+    pub fn allow_keys<I>(self, keys: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Display + Hash,
+    {
+        let mut labels = self.inner.labels.write().unwrap();
+        for key in keys {
+            let hash = labels.hasher.hash_one(&key);
+            labels
+                .keys
+                .entry(hash)
+                .or_insert_with(|| Arc::new(Label::new(key.to_string())));
+        }
+        labels.max_keys = labels.keys.len();
+        drop(labels);
+        self
+    }
+
+    /// Record a decision made for a `key`, as reported to the callback of
+    /// [`RateLimiterBuilder::on_decision`].
+    ///
+    /// Use this function to observe decisions from a callback doing more than
+    /// that, and [`Metrics::observer`] otherwise.
+    ///
+    /// [`RateLimiterBuilder::on_decision`]: crate::RateLimiterBuilder::on_decision
+    pub fn observe<K: Display + Hash + ?Sized>(&self, key: &K, outcome: Result<(), &Error>) {
+        let label = self.inner.label(key);
+        let decisions = &self.inner.decisions;
+        match outcome {
+            Ok(()) => label
+                .allowed
+                .get_or_init(|| decisions.with_label_values(&[&label.name, "allowed"]))
+                .inc(),
+            Err(error) => {
+                label
+                    .denied
+                    .get_or_init(|| decisions.with_label_values(&[&label.name, "denied"]))
+                    .inc();
+                if let Error::RetryAfter(delay) = error {
+                    label
+                        .retry_after
+                        .get_or_init(|| self.inner.retry_after.with_label_values(&[&label.name]))
+                        .observe(delay.as_secs_f64());
+                }
+            }
+        }
+    }
+
+    /// Return a callback recording decisions, to be passed to
+    /// [`RateLimiterBuilder::on_decision`].
+    ///
+    /// [`RateLimiterBuilder::on_decision`]: crate::RateLimiterBuilder::on_decision
+    pub fn observer<K: Display + Hash>(
+        &self,
+    ) -> impl Fn(&K, usize, Result<(), &Error>) + Send + Sync {
+        let metrics = self.clone();
+        move |key, _, outcome| metrics.observe(key, outcome)
+    }
+
+    /// Report the tokens available to every key with a limiting policy of
+    /// the `limiter` on every scrape.
+    ///
+    /// A key blocked by its policy reports no tokens available, and keys
+    /// folded into the `other` label report the sum of their tokens. Same as
+    /// for
+    /// [`RateLimiter::state`], keys blocked via [`RateLimiter::always_block`]
+    /// aren't told apart from the others.
+    ///
+    /// [`RateLimiter::state`]: crate::RateLimiter::state
+    /// [`RateLimiter::always_block`]: crate::RateLimiter::always_block
+    pub fn watch<K, C, S>(&self, limiter: impl Into<SharedRateLimiter<K, C, S>>)
+    where
+        K: Display + Clone + Eq + Hash + Send + Sync + 'static,
+        C: Clock + Send + Sync + 'static,
+        S: BuildHasher + Send + Sync + 'static,
+    {
+        let limiter = limiter.into();
+        let fill_levels = move |inner: &Inner| {
+            limiter
+                .policies()
+                .into_iter()
+                .filter_map(|(key, _)| {
+                    let label = inner.label(&key);
+                    match limiter.state(&key) {
+                        Ok(Some(availability)) => Some((label, availability.tokens() as i64)),
+                        Ok(None) => None,
+                        Err(_) => Some((label, 0)),
+                    }
+                })
+                .collect()
+        };
+        self.inner
+            .watched
+            .write()
+            .unwrap()
+            .push(Box::new(fill_levels));
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+impl Collector for Metrics {
+    fn desc(&self) -> Vec<&Desc> {
+        let mut descs = self.inner.decisions.desc();
+        descs.extend(self.inner.retry_after.desc());
+        descs.extend(self.inner.tokens.desc());
+        descs
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        // keys folded into the same label report the sum of their tokens
+        let mut levels: HashMap<_, i64> = HashMap::new();
+        for fill_levels in self.inner.watched.read().unwrap().iter() {
+            for (label, tokens) in fill_levels(&self.inner) {
+                *levels.entry(label.name.clone()).or_default() += tokens;
+            }
+        }
+
+        let mut families = self.inner.decisions.collect();
+        families.extend(self.inner.retry_after.collect());
+
+        // keys may be removed since the last scrape, so the gauges are set
+        // afresh rather than updated, one scrape at a time
+        let _collecting = self.inner.collecting.lock().unwrap();
+        self.inner.tokens.reset();
+        for (name, tokens) in levels {
+            self.inner.tokens.with_label_values(&[&name]).set(tokens);
+        }
+        families.extend(self.inner.tokens.collect());
+        families
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use prometheus::{Registry, TextEncoder};
+
+    use crate::clock::ManualClock;
+    use crate::RateLimiter;
+
+    #[test]
+    fn metrics() {
+        let metrics = Metrics::with_namespace("test").unwrap();
+        let clock = ManualClock::new();
+        let limiter = RateLimiter::with_clock(clock.clone())
+            .limit("A", 2, Duration::from_secs(2))
+            .block("B")
+            .on_decision(metrics.observer())
+            .done()
+            .into_shared();
+        metrics.watch(limiter.clone());
+
+        let registry = Registry::new();
+        registry.register(Box::new(metrics.clone())).unwrap();
+        let scrape = || {
+            TextEncoder::new()
+                .encode_to_string(&registry.gather())
+                .unwrap()
+        };
+
+        assert_eq!(limiter.consume(&"A", 2), Ok(()));
+        assert!(limiter.consume(&"A", 1).is_err());
+        assert_eq!(limiter.consume(&"B", 1), Err(Error::Blocked));
+
+        let exposition = scrape();
+        for line in [
+            r#"test_decisions_total{key="A",outcome="allowed"} 1"#,
+            r#"test_decisions_total{key="A",outcome="denied"} 1"#,
+            r#"test_decisions_total{key="B",outcome="denied"} 1"#,
+            r#"test_retry_after_seconds_bucket{key="A",le="0.5"} 0"#,
+            r#"test_retry_after_seconds_bucket{key="A",le="1"} 1"#,
+            r#"test_retry_after_seconds_count{key="A"} 1"#,
+            r#"test_tokens_available{key="A"} 0"#,
+            r#"test_tokens_available{key="B"} 0"#,
+        ] {
+            assert!(exposition.contains(line), "{line} not in {exposition}");
+        }
+        // blocked keys have no delay to observe
+        assert!(!exposition.contains(r#"test_retry_after_seconds_count{key="B"}"#));
+
+        // fill levels are read on every scrape
+        clock.advance(Duration::from_secs(1));
+        assert!(scrape().contains(r#"test_tokens_available{key="A"} 1"#));
+    }
+
+    #[test]
+    fn other() {
+        let scrape = |metrics: &Metrics| {
+            let registry = Registry::new();
+            registry.register(Box::new(metrics.clone())).unwrap();
+            TextEncoder::new()
+                .encode_to_string(&registry.gather())
+                .unwrap()
+        };
+
+        let metrics = Metrics::with_namespace("test")
+            .unwrap()
+            .allow_keys(["A".to_string()]);
+        let limiter = RateLimiter::configure()
+            .limit("A", 1, Duration::from_secs(60))
+            .limit("B", 2, Duration::from_secs(60))
+            .limit("C", 3, Duration::from_secs(60))
+            .on_decision(metrics.observer())
+            .done()
+            .into_shared();
+        metrics.watch(limiter.clone());
+        for key in ["A", "B", "C"] {
+            assert_eq!(limiter.consume(&key, 1), Ok(()));
+        }
+        assert!(limiter.consume(&"A", 1).is_err());
+
+        let exposition = scrape(&metrics);
+        for line in [
+            r#"test_decisions_total{key="A",outcome="allowed"} 1"#,
+            r#"test_decisions_total{key="A",outcome="denied"} 1"#,
+            r#"test_decisions_total{key="other",outcome="allowed"} 2"#,
+            r#"test_tokens_available{key="A"} 0"#,
+            r#"test_tokens_available{key="other"} 3"#,
+        ] {
+            assert!(exposition.contains(line), "{line} not in {exposition}");
+        }
+        assert!(!exposition.contains(r#"key="B""#));
+
+        // the first keys seen get labels of their own
+        let metrics = Metrics::with_namespace("test").unwrap().max_keys(2);
+        for key in ["A", "B", "C", "D", "A"] {
+            metrics.observe(key, Ok(()));
+        }
+        let exposition = scrape(&metrics);
+        for line in [
+            r#"test_decisions_total{key="A",outcome="allowed"} 2"#,
+            r#"test_decisions_total{key="B",outcome="allowed"} 1"#,
+            r#"test_decisions_total{key="other",outcome="allowed"} 2"#,
+        ] {
+            assert!(exposition.contains(line), "{line} not in {exposition}");
+        }
+
+        // and the number of labels is bounded by default
+        let metrics = Metrics::with_namespace("test").unwrap();
+        for key in 0..=DEFAULT_MAX_KEYS {
+            metrics.observe(&key, Ok(()));
+        }
+        let exposition = scrape(&metrics);
+        assert!(exposition.contains(r#"test_decisions_total{key="999",outcome="allowed"} 1"#));
+        assert!(exposition.contains(r#"test_decisions_total{key="other",outcome="allowed"} 1"#));
+    }
+
+    #[test]
+    fn concurrent_scrapes() {
+        let metrics = Metrics::with_namespace("test").unwrap();
+        let limiter = RateLimiter::configure()
+            .limit("A", 5, Duration::from_secs(60))
+            .done()
+            .into_shared();
+        metrics.watch(limiter);
+
+        // scrapes racing each other don't sum up the tokens they report
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..100 {
+                        let families = metrics.collect();
+                        let tokens = families
+                            .iter()
+                            .find(|family| family.name() == "test_tokens_available")
+                            .map(|family| family.get_metric()[0].get_gauge().get_value());
+                        assert_eq!(tokens, Some(5.0));
+                    }
+                });
+            }
+        });
+    }
+}
@@ -0,0 +1,262 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::error::ParseRateError;
+
+/// A rate limit: how many tokens are generated over a period of time, and how
+/// many of them can be accumulated for a burst.
+///
+/// Unlike raw `(limit, interval)` pairs, a quota spells out the units of the
+/// rate, and allows to set the burst size independently of it.
+///
+/// ```
+/// use youshallnotpass::{Quota, TokenBucket};
+///
+/// // sustain 100 tokens per minute, but never allow bursts above 20 tokens
+/// let bucket = TokenBucket::from_quota(Quota::per_minute(100).allow_burst(20));
+/// assert!(bucket.consume(20).is_ok());
+/// assert!(bucket.consume(1).is_err());
+/// ```
+///
+/// Quotas can also be parsed from human readable specs of the
+/// `<tokens>/<interval>` form, e.g. to be read from a configuration. See
+/// [`Quota::from_str()`] for details.
+///
+/// ```
+/// use youshallnotpass::{Quota, RateLimiter};
+///
+/// let limiter = RateLimiter::configure()
+///     .quota("login", "5/min".parse().unwrap())
+///     .done();
+/// assert!(limiter.consume(&"login", 5).is_ok());
+/// assert!(limiter.consume(&"login", 1).is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Quota {
+    burst: usize,
+    tokens: usize,
+    interval: Duration,
+    penalty: Option<Penalty>,
+}
+
+/// A cooldown imposed after a number of consecutive rejections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Penalty {
+    /// The number of consecutive rejections that trigger the penalty.
+    pub(crate) rejections: usize,
+    /// The period of time the rejections must happen within.
+    pub(crate) window: Duration,
+    /// For how long every request is rejected once the penalty is triggered.
+    pub(crate) cooldown: Duration,
+}
+
+impl Quota {
+    /// Create a new [`Quota`] of `tokens` generated with a constant rate over
+    /// the specified `interval` of time. The burst size is equal to `tokens`.
+    pub const fn new(tokens: usize, interval: Duration) -> Self {
+        Quota {
+            burst: tokens,
+            tokens,
+            interval,
+            penalty: None,
+        }
+    }
+
+    /// Create a new [`Quota`] admitting nothing, which blocks every request.
+    ///
+    /// Same as a quota of zero tokens, but spells out that blocking is
+    /// intended, see [`RateLimiterBuilder::try_done()`].
+    ///
+    /// [`RateLimiterBuilder::try_done()`]: crate::RateLimiterBuilder::try_done
+    pub const fn blocked() -> Self {
+        Quota::new(0, Duration::ZERO)
+    }
+
+    /// Create a new [`Quota`] of `tokens` per second.
+    pub const fn per_second(tokens: usize) -> Self {
+        Quota::new(tokens, Duration::from_secs(1))
+    }
+
+    /// Create a new [`Quota`] of `tokens` per minute.
+    pub const fn per_minute(tokens: usize) -> Self {
+        Quota::new(tokens, Duration::from_secs(60))
+    }
+
+    /// Create a new [`Quota`] of `tokens` per hour.
+    pub const fn per_hour(tokens: usize) -> Self {
+        Quota::new(tokens, Duration::from_secs(60 * 60))
+    }
+
+    /// Set the maximum number of tokens that can be consumed at once, while
+    /// keeping the rate the same.
+    pub const fn allow_burst(mut self, burst: usize) -> Self {
+        self.burst = burst;
+        self
+    }
+
+    /// Penalize clients that keep hammering an exhausted limit: after
+    /// `rejections` consecutive rejections within the `window`, every request
+    /// is rejected for the `cooldown` period, even if tokens are available.
+    ///
+    /// Every function consuming tokens of a bucket counts its rejections, and
+    /// is subject to the cooldown, including [`TokenBucket::consume_up_to()`],
+    /// [`TokenBucket::admit()`], and [`Intersection::consume()`].
+    ///
+    /// [`TokenBucket::consume_up_to()`]: crate::TokenBucket::consume_up_to
+    /// [`TokenBucket::admit()`]: crate::TokenBucket::admit
+    /// [`Intersection::consume()`]: crate::Intersection::consume
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::{Error, Quota, TokenBucket};
+    ///
+    /// let quota = Quota::per_minute(1).penalize(2, Duration::from_secs(10), Duration::from_secs(600));
+    /// let bucket = TokenBucket::from_quota(quota);
+    /// assert!(bucket.consume(1).is_ok());
+    /// assert!(bucket.consume(1).is_err());
+    ///
+    /// // the second rejection in a row triggers the penalty
+    /// assert_eq!(bucket.consume(1), Err(Error::RetryAfter(Duration::from_secs(600))));
+    /// ```
+    pub const fn penalize(
+        mut self,
+        rejections: usize,
+        window: Duration,
+        cooldown: Duration,
+    ) -> Self {
+        self.penalty = Some(Penalty {
+            rejections,
+            window,
+            cooldown,
+        });
+        self
+    }
+
+    /// Return the maximum number of tokens that can be consumed at once.
+    pub const fn burst(&self) -> usize {
+        self.burst
+    }
+
+    /// Return the number of tokens generated over [`Quota::interval()`].
+    pub const fn tokens(&self) -> usize {
+        self.tokens
+    }
+
+    /// Return the period of time over which [`Quota::tokens()`] are generated.
+    pub const fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Return the penalty for consecutive rejections, if any.
+    pub(crate) const fn penalty(&self) -> Option<Penalty> {
+        self.penalty
+    }
+
+    /// Return whether the quota admits nothing, since it holds no tokens, or
+    /// generates none.
+    pub(crate) const fn is_blocking(&self) -> bool {
+        self.burst == 0 || self.tokens == 0 || self.interval.is_zero()
+    }
+
+    /// Return whether either the interval, or the time it takes to accumulate
+    /// a burst, is too long to be represented, and is capped.
+    pub(crate) fn overflows(&self) -> bool {
+        let burst_interval = (self.interval.as_nanos() * self.burst as u128)
+            .checked_div(self.tokens as u128)
+            .unwrap_or(0);
+        self.interval.as_nanos().max(burst_interval) > u64::MAX as u128
+    }
+}
+
+impl FromStr for Quota {
+    type Err = ParseRateError;
+
+    /// Parse a rate spec of the `<tokens>/<interval>` form, where the interval
+    /// is a unit of time optionally preceded by their number, e.g. `10/s`,
+    /// `500/5m`, or `1000/hour`. The burst size is equal to `tokens`.
+    ///
+    /// The units are `ms`, `s`, `m`, `h`, and `d`, which may be spelled out
+    /// in either singular or plural, e.g. `min` or `minutes`.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::Quota;
+    ///
+    /// assert_eq!("100/min".parse(), Ok(Quota::per_minute(100)));
+    /// assert_eq!("500/5m".parse(), Ok(Quota::new(500, Duration::from_secs(300))));
+    /// assert!("100 per minute".parse::<Quota>().is_err());
+    /// ```
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (tokens, interval) = spec.split_once('/').ok_or(ParseRateError::Malformed)?;
+        let tokens = tokens
+            .trim()
+            .parse()
+            .map_err(|_| ParseRateError::InvalidTokens)?;
+        Ok(Quota::new(tokens, parse_interval(interval)?))
+    }
+}
+
+/// Parse an interval of the `[<count>]<unit>` form, e.g. `5m`, see
+/// [`Quota::from_str()`].
+pub(crate) fn parse_interval(interval: &str) -> Result<Duration, ParseRateError> {
+    let interval = interval.trim();
+    let digits = interval
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(interval.len());
+    let (count, unit) = interval.split_at(digits);
+    let count = match count {
+        "" => 1,
+        count => count.parse().map_err(|_| ParseRateError::InvalidInterval)?,
+    };
+    let unit = match unit.trim_start() {
+        "ms" | "msec" | "millisecond" | "milliseconds" => Duration::from_millis(1),
+        "s" | "sec" | "secs" | "second" | "seconds" => Duration::from_secs(1),
+        "m" | "min" | "mins" | "minute" | "minutes" => Duration::from_secs(60),
+        "h" | "hr" | "hrs" | "hour" | "hours" => Duration::from_secs(60 * 60),
+        "d" | "day" | "days" => Duration::from_secs(24 * 60 * 60),
+        _ => return Err(ParseRateError::InvalidInterval),
+    };
+    unit.checked_mul(count)
+        .ok_or(ParseRateError::InvalidInterval)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_str() {
+        assert_eq!("10/s".parse(), Ok(Quota::per_second(10)));
+        assert_eq!("100/min".parse(), Ok(Quota::per_minute(100)));
+        assert_eq!("1000/h".parse(), Ok(Quota::per_hour(1000)));
+        assert_eq!(
+            " 500 / 5 minutes ".parse(),
+            Ok(Quota::new(500, Duration::from_secs(300)))
+        );
+        assert_eq!(
+            "1/250ms".parse(),
+            Ok(Quota::new(1, Duration::from_millis(250)))
+        );
+        assert_eq!(
+            "7/2d".parse(),
+            Ok(Quota::new(7, Duration::from_secs(2 * 24 * 60 * 60)))
+        );
+
+        assert_eq!("10".parse::<Quota>(), Err(ParseRateError::Malformed));
+        assert_eq!("-1/s".parse::<Quota>(), Err(ParseRateError::InvalidTokens));
+        assert_eq!("1.5/s".parse::<Quota>(), Err(ParseRateError::InvalidTokens));
+        assert_eq!("10/".parse::<Quota>(), Err(ParseRateError::InvalidInterval));
+        assert_eq!(
+            "10/5".parse::<Quota>(),
+            Err(ParseRateError::InvalidInterval)
+        );
+        assert_eq!(
+            "10/fortnight".parse::<Quota>(),
+            Err(ParseRateError::InvalidInterval)
+        );
+        assert_eq!(
+            "10/99999999999d".parse::<Quota>(),
+            Err(ParseRateError::InvalidInterval)
+        );
+    }
+}
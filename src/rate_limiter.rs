@@ -1,8 +1,40 @@
-use std::collections::HashMap;
-use std::hash::Hash;
+use std::borrow::Borrow;
+use std::collections::hash_map::Entry;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::iter::{self, Chain};
+use std::ops::Range;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
+use std::{option, slice};
 
-use crate::error::Error;
+#[cfg(feature = "regex")]
+use regex::Regex;
+
+use crate::allowance::{Allowance, Denial, Usage};
+use crate::anomaly::{AnomalyCallback, AnomalyDetector};
+use crate::bypass::{Bypass, Bypasses};
+use crate::clock::{Clock, MonotonicClock};
+use crate::default_policy::{CloneKey, DefaultPolicy};
+use crate::dense_rate_limiter::DenseRateLimiter;
+use crate::error::{BuildError, Error};
+use crate::escalation::Escalation;
+use crate::events::{EventQueue, EventSink};
+use crate::handoff::{Handoff, RateLimiterSnapshot, RATE_LIMITER_SNAPSHOT_VERSION};
+use crate::jitter::Jitter;
+use crate::lint::Lint;
+use crate::pattern::{KeyPattern, Patterns};
+use crate::policy::{PolicyDiff, PolicySet};
+use crate::quota::Quota;
+#[cfg(feature = "regex")]
+use crate::regex_policy::{RegexPolicies, RegexQuotas};
+use crate::runtime_policy::RuntimePolicies;
+use crate::stats::{Stats, StatsTracker, DEFAULT_MAX_KEYS};
+use crate::token_bucket::{
+    admit_all, consume_all, Availability, BucketRef, Permit, QosClass, Speculation,
+};
 use crate::TokenBucket;
 
 /// An object providing rate limiting functionality.
@@ -14,7 +46,10 @@ use crate::TokenBucket;
 ///
 /// A [`RateLimiter`] instance can be used to set how many times an event is
 /// allowed to happen (`limit`) within a given period of time (`interval`). If
-/// no such policy is set for an event, the event is always allowed.
+/// no such policy is set for an event, the event is limited by the default
+/// policy, if any (see [`RateLimiterBuilder::default_limit`]), and is always
+/// allowed otherwise. On top of that, all the events together may be limited
+/// by a global policy (see [`RateLimiterBuilder::global_limit`]).
 ///
 /// Once constructed, a `RateLimiter` instance is safe to be used from multiple
 /// threads.
@@ -33,17 +68,49 @@ use crate::TokenBucket;
 ///     .limit("B", 3, Duration::from_secs(60))
 ///     .done();
 ///
-/// assert_eq!(limiter.consume("A", 1), Ok(()));
-/// assert_eq!(limiter.consume("A", 1), Ok(()));
+/// assert_eq!(limiter.consume(&"A", 1), Ok(()));
+/// assert_eq!(limiter.consume(&"A", 1), Ok(()));
 ///
-/// assert!(matches!(limiter.consume("A", 1), Err(Error::RetryAfter(_))));
-/// assert!(matches!(limiter.consume("B", 5), Err(Error::RetryAfter(_))));
+/// assert!(matches!(limiter.consume(&"A", 1), Err(Error::RetryAfter(_))));
+/// assert_eq!(limiter.consume(&"B", 5), Err(Error::ExceedsCapacity));
 /// ```
-pub struct RateLimiter<'a, K> {
-    buckets: HashMap<K, TokenBucket<'a>>,
+pub struct RateLimiter<K, C = MonotonicClock, S = RandomState> {
+    buckets: HashMap<K, TokenBucket<C>, S>,
+    runtime: RuntimePolicies<K, C, S>,
+    default: Option<DefaultPolicy<K, C, S>>,
+    anomalies: Option<AnomalyDetector<K, C, S>>,
+    escalation: Option<Escalation<K, C, S>>,
+    on_decision: Option<DecisionCallback<K>>,
+    stats: Option<StatsTracker<K, S>>,
+    events: Option<EventQueue<K>>,
+    jitter: Option<Jitter>,
+    stacked: HashMap<K, Vec<TokenBucket<C>>, S>,
+    /// Keys of groups mapped to the index of the bucket of their group in
+    /// `group_buckets`, see [`RateLimiterBuilder::group`].
+    groups: HashMap<K, usize, S>,
+    group_buckets: Box<[TokenBucket<C>]>,
+    bypasses: Bypasses<K, S>,
+    global: Option<TokenBucket<C>>,
+    patterns: Option<Patterns<K>>,
+    #[cfg(feature = "regex")]
+    regexes: Option<RegexPolicies<K, C>>,
+    normalize: Option<Normalize<K>>,
+    clock: C,
 }
 
-impl<'a, K> RateLimiter<'a, K> {
+/// The bucket of a key along with the limits stacked on top of it.
+type Limited<'a, C> = (BucketRef<'a, C>, &'a [TokenBucket<C>]);
+
+/// Buckets limiting a key on top of its own bucket, see [`RateLimiter::limits`].
+type Tiers<'a, C> = Chain<slice::Iter<'a, TokenBucket<C>>, option::IntoIter<&'a TokenBucket<C>>>;
+
+/// Normalizes a key before it's looked up, see [`RateLimiterBuilder::normalize`].
+type Normalize<K> = Arc<dyn Fn(&K) -> K + Send + Sync>;
+
+/// Observes the outcome of a request, see [`RateLimiterBuilder::on_decision`].
+type DecisionCallback<K> = Arc<dyn Fn(&K, usize, Result<(), &Error>) + Send + Sync>;
+
+impl<K> RateLimiter<K> {
     /// Constructs a new `RateLimiterBuilder` object.
     ///
     /// A returned instance of [`RateLimiterBuilder`] can be used to set
@@ -62,29 +129,63 @@ impl<'a, K> RateLimiter<'a, K> {
     /// let builder = RateLimiter::<&str>::configure();
     /// ```
     #[inline]
-    pub fn configure() -> RateLimiterBuilder<'a, K> {
-        Self::with_timer(&Instant::now)
+    pub fn configure() -> RateLimiterBuilder<K> {
+        RateLimiter::with_clock(MonotonicClock)
     }
+}
 
-    /// Constructs a new `RateLimiterBuilder` object with custom `clock`
-    /// function.
+impl<K, C: Clock> RateLimiter<K, C> {
+    /// Constructs a new `RateLimiterBuilder` object with custom `clock`.
     ///
-    /// Unlike [`configure`], this function receives custom `clock` function to
-    /// be used instead of [`Instant::now`]. It doesn't make sense to provide
-    /// custom `clock` unless you want to test the object. That's why this
-    /// function is private and not exposed to end users.
+    /// Unlike [`configure`], this function receives custom [`Clock`] to be
+    /// used instead of [`Instant::now`]. It mostly makes sense in tests, where
+    /// a [`ManualClock`] allows to control the passage of time.
     ///
     /// [`configure`]: RateLimiter::configure
+    /// [`ManualClock`]: crate::ManualClock
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::{ManualClock, RateLimiter};
+    ///
+    /// let clock = ManualClock::new();
+    /// let limiter = RateLimiter::with_clock(clock.clone())
+    ///     .limit("A", 1, Duration::from_secs(60))
+    ///     .done();
+    ///
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    /// assert!(limiter.consume(&"A", 1).is_err());
+    ///
+    /// clock.advance(Duration::from_secs(60));
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    /// ```
     #[inline]
-    fn with_timer(clock: &'a (dyn Fn() -> Instant + Sync)) -> RateLimiterBuilder<'a, K> {
+    pub fn with_clock(clock: C) -> RateLimiterBuilder<K, C> {
         RateLimiterBuilder {
             limits: Vec::new(),
+            groups: Vec::new(),
+            bypasses: Vec::new(),
+            default: None,
+            global: None,
+            patterns: None,
+            #[cfg(feature = "regex")]
+            regexes: None,
+            normalize: None,
+            anomalies: None,
+            escalation: None,
+            on_decision: None,
+            stats: None,
+            events: None,
+            jitter: None,
+            hasher: RandomState::new(),
             clock,
         }
     }
 }
 
-impl<'a, K: Eq + Hash> RateLimiter<'a, K> {
+impl<K: Eq + Hash, C: Clock, S: BuildHasher> RateLimiter<K, C, S> {
     /// Tries to consume the specified number of `tokens` from the bucket for a
     /// given event (`key`).
     ///
@@ -93,11 +194,19 @@ impl<'a, K: Eq + Hash> RateLimiter<'a, K> {
     /// see [`TokenBucket`] documentation for details on what's returned by this
     /// function.
     ///
-    /// If not `limit` is set, the `consume` function always succeed.
+    /// If not `limit` is set, the `consume` function always succeed, unless
+    /// there is a [`default_limit`] or a [`global_limit`].
+    ///
+    /// The `key` is looked up by any of its borrowed forms, e.g. by `&str` for
+    /// `String` keys, so that no key needs to be allocated per call. The key
+    /// is converted into an owned one only if a bucket is created for it, i.e.
+    /// the first time the key is limited by the default policy.
     ///
     /// See [`limit`] for how to setup a limiting policy for a `key`.
     ///
     /// [`limit`]: RateLimiterBuilder::limit
+    /// [`default_limit`]: RateLimiterBuilder::default_limit
+    /// [`global_limit`]: RateLimiterBuilder::global_limit
     ///
     /// # Examples
     ///
@@ -109,352 +218,4447 @@ impl<'a, K: Eq + Hash> RateLimiter<'a, K> {
     ///     .limit("A", 2, Duration::from_secs(60))
     ///     .done();
     ///
-    /// assert!(limiter.consume("A", 1).is_ok());
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    /// assert!(limiter.consume(&"A", 1).is_err());
+    ///
+    /// assert!(limiter.consume(&"B", 1).is_ok());
+    /// ```
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("A".to_string(), 1, Duration::from_secs(60))
+    ///     .done();
+    ///
     /// assert!(limiter.consume("A", 1).is_ok());
     /// assert!(limiter.consume("A", 1).is_err());
+    /// ```
+    #[inline]
+    pub fn consume<Q>(&self, key: &Q, tokens: usize) -> Result<(), Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        self.consume_remaining(key, tokens).map(|_| ())
+    }
+
+    /// Same as [`consume`], but returns the number of tokens remaining in the
+    /// bucket for a given event (`key`) after the consumption.
+    ///
+    /// If not `limit` is set, the function always succeeds with `None`, since
+    /// there is no limit on the number of tokens.
+    ///
+    /// [`consume`]: RateLimiter::consume
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("A", 2, Duration::from_secs(60))
+    ///     .done();
+    ///
+    /// assert_eq!(limiter.consume_remaining(&"A", 1), Ok(Some(1)));
+    /// assert_eq!(limiter.consume_remaining(&"A", 1), Ok(Some(0)));
+    /// assert!(limiter.consume_remaining(&"A", 1).is_err());
     ///
-    /// assert!(limiter.consume("B", 1).is_ok());
+    /// assert_eq!(limiter.consume_remaining(&"B", 1), Ok(None));
     /// ```
-    pub fn consume(&self, key: K, tokens: usize) -> Result<(), Error> {
-        self.buckets
-            .get(&key)
-            .map(|bucket| bucket.consume(tokens))
-            .unwrap_or(Ok(()))
+    #[inline]
+    pub fn consume_remaining<Q>(&self, key: &Q, tokens: usize) -> Result<Option<u64>, Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        match &self.normalize {
+            Some(normalize) => {
+                let key = normalize(&key.to_owned());
+                self.consume_remaining_normalized(key.borrow(), tokens)
+            }
+            None => self.consume_remaining_normalized(key, tokens),
+        }
     }
-}
 
-/// The builder exposes ability to configure a [`RateLimiter`] instance by
-/// setting limiting policies.
-pub struct RateLimiterBuilder<'a, K> {
-    limits: Vec<(K, usize, Duration)>,
-    clock: &'a (dyn Fn() -> Instant + Sync),
-}
+    /// Same as [`consume`], but reports the limit the event (`key`) is charged
+    /// against, the tokens remaining, and the time until the limit resets, on
+    /// both success and failure.
+    ///
+    /// This is useful for `RateLimit-*` response headers. See [`Allowance`]
+    /// for the details reported.
+    ///
+    /// [`consume`]: RateLimiter::consume
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::{Error, ManualClock, RateLimiter};
+    ///
+    /// let limiter = RateLimiter::with_clock(ManualClock::new())
+    ///     .limit("A", 2, Duration::from_secs(60))
+    ///     .done();
+    ///
+    /// let allowance = limiter.consume_detailed(&"A", 2).unwrap();
+    /// assert_eq!(allowance.limit(), 2);
+    /// assert_eq!(allowance.remaining(), 0);
+    /// assert_eq!(allowance.reset(), Duration::from_secs(60));
+    ///
+    /// let denial = limiter.consume_detailed(&"A", 1).unwrap_err();
+    /// assert_eq!(denial.error(), &Error::RetryAfter(Duration::from_secs(30)));
+    /// assert_eq!(denial.remaining(), 0);
+    /// ```
+    pub fn consume_detailed<Q>(&self, key: &Q, tokens: usize) -> Result<Allowance, Denial>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        match &self.normalize {
+            Some(normalize) => {
+                let key = normalize(&key.to_owned());
+                self.consume_detailed_normalized(key.borrow(), tokens)
+            }
+            None => self.consume_detailed_normalized(key, tokens),
+        }
+    }
 
-impl<'a, K> RateLimiterBuilder<'a, K> {
-    /// Sets a limiting policy for a `key`.
+    /// Tries to consume the specified number of `tokens` from the bucket for a
+    /// given event (`key`) at the given moment of time (`at`).
     ///
-    /// The limiting policy sets how many times an event is allowed to happen
-    /// (`limit`) within a given period of time (`interval`). Event is vague
-    /// term. Thus we use a `key` to uniquely identify an event we want to rate
-    /// limit.
-    pub fn limit(mut self, key: K, limit: usize, interval: Duration) -> Self {
-        self.limits.push((key, limit, interval));
-        self
+    /// See [`TokenBucket::consume_at`] for details. This is useful to charge
+    /// events recorded elsewhere, e.g. in another region with a known clock
+    /// offset, against the same logical window: the caller translates the
+    /// event's timestamp to the local clock, and passes it as `at`.
+    ///
+    /// If not `limit` is set, the function always succeeds.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::{Duration, Instant};
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("A", 1, Duration::from_secs(60))
+    ///     .done();
+    ///
+    /// // the event happened in a region whose clock is 2 seconds ahead
+    /// let remote_offset = Duration::from_secs(2);
+    /// let happened_at = Instant::now() + remote_offset;
+    ///
+    /// assert!(limiter.consume_at(&"A", 1, happened_at - remote_offset).is_ok());
+    /// assert!(limiter.consume(&"A", 1).is_err());
+    /// ```
+    pub fn consume_at<Q>(&self, key: &Q, tokens: usize, at: Instant) -> Result<(), Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        self.with_normalized(key, |key| {
+            if let Some(result) = self.bypass(key) {
+                self.report_borrowed(key, tokens, &result);
+                return result;
+            }
+            match self.limits(self.borrowed_bucket(key)) {
+                Some((bucket, tiers)) => {
+                    let result = consume_stacked(&bucket, tiers, tokens, Some(at))
+                        .map(|_| ())
+                        .map_err(|error| self.jitter(error));
+                    self.report_borrowed(key, tokens, &result);
+                    self.record_borrowed(key, &result);
+                    result
+                }
+                None => {
+                    self.report_borrowed(key, tokens, &Ok(()));
+                    Ok(())
+                }
+            }
+        })
     }
-}
 
-impl<'a, K: Eq + Hash> RateLimiterBuilder<'a, K> {
-    /// Constructs a [`RateLimiter`] instance with configured limiting policies.
+    /// Tries to consume the specified number of `tokens` from the bucket for a
+    /// given event (`key`), and returns a [`Permit`] that refunds them unless
+    /// committed.
     ///
-    /// Once constructed, the `RateLimiter` instance cannot be changed.
-    pub fn done(self) -> RateLimiter<'a, K> {
-        RateLimiter {
-            buckets: self
-                .limits
-                .into_iter()
-                .map(|(key, limit, interval)| {
-                    (key, TokenBucket::with_timer(limit, interval, self.clock))
-                })
-                .collect(),
+    /// See [`TokenBucket::consume_permit`] for details. If not `limit` is set,
+    /// the function always succeeds, and the returned permit has nothing to
+    /// refund.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("A", 1, Duration::from_secs(60))
+    ///     .done();
+    ///
+    /// drop(limiter.consume_permit(&"A", 1).unwrap());
+    /// limiter.consume_permit(&"A", 1).unwrap().commit();
+    /// assert!(limiter.consume(&"A", 1).is_err());
+    /// ```
+    pub fn consume_permit<Q>(&self, key: &Q, tokens: usize) -> Result<Permit<'_, C>, Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        self.with_normalized(key, |key| {
+            if let Some(result) = self.bypass(key) {
+                self.report_borrowed(key, tokens, &result);
+                return result.map(|()| Permit::unlimited(tokens));
+            }
+            let result = match self.limits(self.borrowed_bucket(key)) {
+                Some((bucket, tiers)) => Permit::consume(bucket, tiers, tokens),
+                None => {
+                    self.report_borrowed(key, tokens, &Ok(()));
+                    return Ok(Permit::unlimited(tokens));
+                }
+            };
+            let result = result.map_err(|error| self.jitter(error));
+            self.report_borrowed(key, tokens, &result);
+            self.record_borrowed(key, &result);
+            result
+        })
+    }
+
+    /// Tries to consume the specified number of `tokens` from the bucket for a
+    /// given event (`key`) speculatively.
+    ///
+    /// The tokens are returned to the bucket unless the returned [`Speculation`]
+    /// is confirmed within `timeout`. See [`TokenBucket::consume_speculative`]
+    /// for details.
+    ///
+    /// If not `limit` is set, the function always succeeds, and the returned
+    /// speculation has nothing to refund.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("A", 1, Duration::from_secs(60))
+    ///     .done();
+    ///
+    /// let speculation = limiter.consume_speculative(&"A", 1, Duration::from_secs(1));
+    /// assert!(speculation.unwrap().confirm());
+    /// assert!(limiter.consume(&"A", 1).is_err());
+    /// ```
+    pub fn consume_speculative<Q>(
+        &self,
+        key: &Q,
+        tokens: usize,
+        timeout: Duration,
+    ) -> Result<Speculation<'_, C>, Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        self.with_normalized(key, |key| {
+            if let Some(result) = self.bypass(key) {
+                self.report_borrowed(key, tokens, &result);
+                return result.map(|()| Speculation::unlimited());
+            }
+            let result = match self.limits(self.borrowed_bucket(key)) {
+                Some((bucket, tiers)) => Speculation::consume(bucket, tiers, tokens, timeout),
+                None => {
+                    self.report_borrowed(key, tokens, &Ok(()));
+                    return Ok(Speculation::unlimited());
+                }
+            };
+            let result = result.map_err(|error| self.jitter(error));
+            self.report_borrowed(key, tokens, &result);
+            self.record_borrowed(key, &result);
+            result
+        })
+    }
+
+    /// Checks whether the specified number of `tokens` can be consumed from the
+    /// bucket for a given event (`key`), without consuming them.
+    ///
+    /// See [`TokenBucket::check`] for details. If not `limit` is set, the
+    /// function always succeeds.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("A", 1, Duration::from_secs(60))
+    ///     .done();
+    ///
+    /// assert!(limiter.check(&"A", 1).is_ok());
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    /// assert!(limiter.check(&"A", 1).is_err());
+    /// ```
+    pub fn check<Q>(&self, key: &Q, tokens: usize) -> Result<(), Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        self.with_normalized(key, |key| {
+            if let Some(result) = self.bypass(key) {
+                return result;
+            }
+            match self.limits(self.borrowed_bucket(key)) {
+                Some((bucket, tiers)) => iter::once(&*bucket)
+                    .chain(tiers)
+                    .filter_map(|bucket| bucket.check(tokens).err())
+                    .reduce(Error::strictest)
+                    .map_or(Ok(()), Err),
+                None => Ok(()),
+            }
+        })
+    }
+
+    /// Returns the specified number of `tokens` back to the bucket for a given
+    /// event (`key`).
+    ///
+    /// See [`TokenBucket::refund`] for details. If not `limit` is set, the
+    /// function does nothing.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("A", 1, Duration::from_secs(60))
+    ///     .done();
+    ///
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    /// limiter.refund(&"A", 1);
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    /// ```
+    pub fn refund<Q>(&self, key: &Q, tokens: usize)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        self.with_normalized(key, |key| {
+            if let Some((bucket, tiers)) = self.limits(self.existing_bucket(key)) {
+                for bucket in iter::once(&*bucket).chain(tiers) {
+                    bucket.refund(tokens);
+                }
+            }
+        })
+    }
+
+    /// Refills the bucket for a given event (`key`) up to its capacity.
+    ///
+    /// See [`TokenBucket::reset`] for details. If not `limit` is set, the
+    /// function does nothing.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("A", 1, Duration::from_secs(60))
+    ///     .done();
+    ///
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    /// limiter.reset(&"A");
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    /// ```
+    pub fn reset<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        self.with_normalized(key, |key| {
+            if let Some((bucket, stacked)) = self.existing_bucket(key) {
+                for bucket in iter::once(&*bucket).chain(stacked) {
+                    bucket.reset();
+                }
+            }
+        })
+    }
+
+    /// Empties the bucket for a given event (`key`) immediately.
+    ///
+    /// See [`TokenBucket::drain`] for details. If not `limit` is set, the
+    /// function does nothing.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("A", 1, Duration::from_secs(60))
+    ///     .done();
+    ///
+    /// limiter.drain(&"A");
+    /// assert!(limiter.consume(&"A", 1).is_err());
+    /// ```
+    pub fn drain<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        self.with_normalized(key, |key| {
+            if let Some((bucket, stacked)) = self.borrowed_bucket(key) {
+                for bucket in iter::once(&*bucket).chain(stacked) {
+                    bucket.drain();
+                }
+            }
+        })
+    }
+
+    /// Returns how long it takes to refill the bucket for a given event (`key`)
+    /// up to its capacity.
+    ///
+    /// See [`TokenBucket::time_to_full`] for details. If not `limit` is set,
+    /// there is nothing to refill, and the function returns zero.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::{ManualClock, RateLimiter};
+    ///
+    /// let limiter = RateLimiter::with_clock(ManualClock::new())
+    ///     .limit("A", 2, Duration::from_secs(60))
+    ///     .done();
+    ///
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    /// assert_eq!(limiter.time_to_full(&"A"), Ok(Duration::from_secs(30)));
+    ///
+    /// assert_eq!(limiter.time_to_full(&"B"), Ok(Duration::ZERO));
+    /// ```
+    pub fn time_to_full<Q>(&self, key: &Q) -> Result<Duration, Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        self.with_normalized(key, |key| match self.existing_bucket(key) {
+            Some((bucket, stacked)) => iter::once(&*bucket)
+                .chain(stacked)
+                .try_fold(Duration::ZERO, |longest, bucket| {
+                    Ok(longest.max(bucket.time_to_full()?))
+                }),
+            None => Ok(Duration::ZERO),
+        })
+    }
+
+    /// Admits an event (`key`) costing `tokens` without ever rejecting it, and
+    /// returns its [`QosClass`].
+    ///
+    /// See [`TokenBucket::admit`] for details. The class of a key with
+    /// stacked limits, or with a global limit, is the worst of the classes of
+    /// all of them, and none of them is charged unless every one of them
+    /// admits the request. If not `limit` is set, the function always returns
+    /// [`QosClass::Green`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::{QosClass, RateLimiter};
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("A", 2, Duration::from_secs(60))
+    ///     .done();
+    ///
+    /// assert_eq!(limiter.admit(&"A", 1), QosClass::Green);
+    /// assert_eq!(limiter.admit(&"A", 1), QosClass::Yellow);
+    /// assert_eq!(limiter.admit(&"A", 1), QosClass::Red);
+    ///
+    /// assert_eq!(limiter.admit(&"B", 1), QosClass::Green);
+    /// ```
+    pub fn admit<Q>(&self, key: &Q, tokens: usize) -> QosClass
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        self.with_normalized(key, |key| {
+            match self.bypass(key) {
+                Some(Ok(())) => return QosClass::Green,
+                Some(Err(_)) => return QosClass::Red,
+                None => {}
+            }
+            match self.limits(self.borrowed_bucket(key)) {
+                Some((bucket, tiers)) => {
+                    let result = admit_all(iter::once(&*bucket).chain(tiers), tokens);
+                    self.record_borrowed(key, &result);
+                    result.unwrap_or(QosClass::Red)
+                }
+                None => QosClass::Green,
+            }
+        })
+    }
+
+    /// Renames keys of a live `RateLimiter` instance while preserving the
+    /// state of corresponding buckets.
+    ///
+    /// The function `f` returns a new key to be used instead of a given one.
+    /// This is useful when a key scheme changes, and throttling state must
+    /// survive the migration.
+    ///
+    /// The function is called for every key the rate limiter knows about:
+    /// configured keys, members of groups, keys with stacked limits, patterns,
+    /// keys that are always allowed or blocked, keys of the default policy,
+    /// keys counted in the statistics, and keys with rejections counted
+    /// towards a ban. It may therefore be called several times for the same
+    /// key, and must return the same new key every time.
+    ///
+    /// If several keys are mapped to the same new key, their buckets are merged
+    /// by summing up the consumed tokens. The policy of one of them, unspecified
+    /// which, is kept for the merged bucket, so colliding keys are expected to
+    /// share the same policy.
+    ///
+    /// Anomaly detection, if configured, starts over for all keys, while the
+    /// statistics follow their keys, and are summed up for merged ones. Bans
+    /// follow their keys too, and the longest one is kept for merged keys.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let mut limiter = RateLimiter::configure()
+    ///     .limit("user", 2, Duration::from_secs(60))
+    ///     .done();
+    ///
+    /// assert!(limiter.consume(&"user", 2).is_ok());
+    ///
+    /// limiter.rekey(|key| if key == "user" { "org/user" } else { key });
+    ///
+    /// assert!(limiter.consume(&"org/user", 1).is_err());
+    /// ```
+    pub fn rekey<F: FnMut(K) -> K>(&mut self, mut f: F)
+    where
+        S: Clone,
+    {
+        self.merge_runtime();
+        let mut buckets: HashMap<K, TokenBucket<C>, S> =
+            HashMap::with_capacity_and_hasher(self.buckets.len(), self.buckets.hasher().clone());
+        for (key, bucket) in self.buckets.drain() {
+            match buckets.entry(f(key)) {
+                Entry::Occupied(entry) => entry.get().absorb(&bucket),
+                Entry::Vacant(entry) => {
+                    entry.insert(bucket);
+                }
+            }
+        }
+        self.buckets = buckets;
+        let mut stacked: HashMap<K, Vec<TokenBucket<C>>, S> =
+            HashMap::with_hasher(self.stacked.hasher().clone());
+        for (key, tiers) in self.stacked.drain() {
+            match stacked.entry(f(key)) {
+                Entry::Occupied(mut entry) => {
+                    let existing = entry.get_mut();
+                    for (existing, bucket) in existing.iter().zip(&tiers) {
+                        existing.absorb(bucket);
+                    }
+                    let absorbed = existing.len();
+                    existing.extend(tiers.into_iter().skip(absorbed));
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(tiers);
+                }
+            }
+        }
+        self.stacked = stacked;
+        let mut groups = HashMap::with_hasher(self.groups.hasher().clone());
+        groups.extend(self.groups.drain().map(|(key, index)| (f(key), index)));
+        self.groups = groups;
+        self.bypasses.rekey(&mut f);
+        if let Some(patterns) = &mut self.patterns {
+            patterns.keys = patterns.keys.drain(..).map(&mut f).collect();
+        }
+        if let Some(stats) = &mut self.stats {
+            stats.rekey(&mut f);
+        }
+        if let Some(escalation) = &mut self.escalation {
+            escalation.rekey(&mut f);
+        }
+        if let Some(default) = &mut self.default {
+            default.rekey(f);
+        }
+        if let Some(anomalies) = &mut self.anomalies {
+            anomalies.reset();
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::sync::Mutex;
+    /// Captures the state of every bucket, so that another `RateLimiter`
+    /// instance can start warm via [`RateLimiter::warm`].
+    ///
+    /// Buckets of keys limited by the default policy are included, and so are
+    /// the buckets of stacked limits, which follow the first bucket of their
+    /// key, and the buckets of groups, which are listed under every key of
+    /// their group. See [`Handoff`] for details.
+    pub fn handoff(&self) -> Handoff<K>
+    where
+        K: Clone,
+    {
+        let runtime = self.runtime.read();
+        let explicit = self
+            .buckets
+            .iter()
+            .filter(|(key, _)| !runtime.contains_key(key))
+            .flat_map(|(key, bucket)| {
+                let stacked = self.stacked.get(key).into_iter().flatten();
+                iter::once(bucket)
+                    .chain(stacked)
+                    .map(move |bucket| (key.clone(), bucket.snapshot()))
+            });
+        let grouped = self
+            .groups
+            .iter()
+            .filter(|(key, _)| !runtime.contains_key(key) && !self.buckets.contains_key(key))
+            .map(|(key, &index)| (key.clone(), self.group_buckets[index].snapshot()));
+        let changed = runtime.iter().filter_map(|(key, bucket)| {
+            let bucket = bucket.as_ref()?;
+            Some((key.clone(), bucket.snapshot()))
+        });
+        let default = self
+            .default
+            .iter()
+            .flat_map(|default| default.buckets())
+            .map(|(key, bucket)| (key, bucket.snapshot()));
+        explicit
+            .chain(grouped)
+            .chain(changed)
+            .chain(default)
+            .collect()
+    }
+
+    /// Restores the state of buckets from a `handoff` captured by another
+    /// `RateLimiter` instance via [`RateLimiter::handoff`].
+    ///
+    /// The limiting policies of this instance are kept, and only the state is
+    /// restored, see [`TokenBucket::restore`] for details. Keys without a
+    /// policy are ignored, unless there is a default policy. Stacked limits are
+    /// restored in the order they're set, and the ones missing in either
+    /// instance are ignored.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let old = RateLimiter::configure()
+    ///     .limit("A", 2, Duration::from_secs(60))
+    ///     .done();
+    /// assert!(old.consume(&"A", 1).is_ok());
+    ///
+    /// let new = RateLimiter::configure()
+    ///     .limit("A", 2, Duration::from_secs(60))
+    ///     .done();
+    /// new.warm(old.handoff());
+    /// assert!(new.consume(&"A", 1).is_ok());
+    /// assert!(new.consume(&"A", 1).is_err());
+    /// ```
+    pub fn warm(&self, handoff: Handoff<K>) {
+        let mut tiers = HashMap::new();
+        for (key, snapshot) in handoff.iter() {
+            let tier: usize = *tiers.entry(key).and_modify(|tier| *tier += 1).or_insert(0);
+            // buckets of patterns are handed off under the patterns, and are
+            // never restored from the keys they match
+            let limited = match self.explicit_bucket(key) {
+                Some(limited) => Some(limited),
+                None if self.matching_bucket(key).is_some() => None,
+                None => self.bucket(key),
+            };
+            let Some((bucket, stacked)) = limited else {
+                continue;
+            };
+            match tier.checked_sub(1) {
+                Some(tier) => {
+                    if let Some(stacked) = stacked.get(tier) {
+                        stacked.restore(snapshot.clone());
+                    }
+                }
+                None => bucket.restore(snapshot.clone()),
+            }
+        }
+    }
+
+    /// Captures the state of every bucket, including the global one and the
+    /// ones of regular expressions, so that another `RateLimiter` instance can
+    /// continue the accounting via [`RateLimiter::restore`].
+    ///
+    /// Same as [`RateLimiter::handoff`], but for the whole rate limiter, e.g.
+    /// to survive a restart. See [`RateLimiterSnapshot`] for details.
+    pub fn snapshot(&self) -> RateLimiterSnapshot<K>
+    where
+        K: Clone,
+    {
+        #[cfg(feature = "regex")]
+        let regexes = self
+            .regexes
+            .as_ref()
+            .map_or_else(Vec::new, RegexPolicies::snapshots);
+        #[cfg(not(feature = "regex"))]
+        let regexes = Vec::new();
+        RateLimiterSnapshot {
+            version: RATE_LIMITER_SNAPSHOT_VERSION,
+            handoff: self.handoff(),
+            global: self.global.as_ref().map(TokenBucket::snapshot),
+            regexes,
+        }
+    }
+
+    /// Restores the state of every bucket from a `snapshot` captured by
+    /// another `RateLimiter` instance via [`RateLimiter::snapshot`].
+    ///
+    /// Same as [`RateLimiter::warm`], but the global bucket, and the buckets
+    /// of regular expressions are restored as well. The latter are matched by
+    /// their expressions, and the ones missing in either instance are ignored.
+    pub fn restore(&self, snapshot: RateLimiterSnapshot<K>) {
+        self.warm(snapshot.handoff);
+        if let (Some(global), Some(snapshot)) = (&self.global, snapshot.global) {
+            global.restore(snapshot);
+        }
+        #[cfg(feature = "regex")]
+        if let Some(regexes) = &self.regexes {
+            for (expression, snapshot) in snapshot.regexes {
+                regexes.restore(&expression, snapshot);
+            }
+        }
+    }
+
+    /// Analyzes the configured limiting policies, and reports likely mistakes.
+    ///
+    /// An empty result means no suspicious policies have been found. See
+    /// [`Lint`] for the mistakes detected.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::{Lint, RateLimiter};
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("A", 5, Duration::from_secs(60))
+    ///     .limit("B", 5_000, Duration::from_secs(1))
+    ///     .limit("A", 10, Duration::from_secs(60))
+    ///     .done();
+    ///
+    /// let mut lints = limiter.lint();
+    /// lints.sort_by_key(|lint| lint.to_string());
+    /// assert_eq!(
+    ///     lints,
+    ///     vec![
+    ///         Lint::Shadowed { key: &"A" },
+    ///         Lint::SubMillisecondTokenTime {
+    ///             key: &"B",
+    ///             time_per_token: Duration::from_micros(200),
+    ///         },
+    ///     ]
+    /// );
+    /// ```
+    pub fn lint(&self) -> Vec<Lint<'_, K>> {
+        let shadowed = self.stacked.iter().filter_map(|(key, stacked)| {
+            let bucket = self.buckets.get(key)?;
+            let tiers: Vec<_> = iter::once(bucket).chain(stacked).collect();
+            let shadowed = tiers.iter().enumerate().any(|(i, bucket)| {
+                tiers
+                    .iter()
+                    .enumerate()
+                    .any(|(j, other)| i != j && other.is_stricter_than(bucket))
+            });
+            shadowed.then_some(Lint::Shadowed { key })
+        });
+        let tiers = self.buckets.iter().flat_map(|(key, bucket)| {
+            let stacked = self.stacked.get(key).into_iter().flatten();
+            iter::once(bucket)
+                .chain(stacked)
+                .map(move |bucket| (key, bucket))
+        });
+        let grouped = self
+            .groups
+            .iter()
+            .filter(|(key, _)| !self.buckets.contains_key(key))
+            .map(|(key, &index)| (key, &self.group_buckets[index]));
+        let tiers = tiers.chain(grouped);
+        let sub_millisecond = tiers.clone().filter_map(|(key, bucket)| {
+            let time_per_token = bucket.time_per_token()?;
+            (time_per_token < Duration::from_millis(1)).then_some(Lint::SubMillisecondTokenTime {
+                key,
+                time_per_token,
+            })
+        });
+        let global_burst = self
+            .global
+            .as_ref()
+            .filter(|global| global.time_per_token().is_some())
+            .map(|global| global.quota().burst());
+        let above_global = tiers.filter_map(|(key, bucket)| {
+            let global_burst = global_burst?;
+            bucket.time_per_token()?;
+            let burst = bucket.quota().burst();
+            (burst > global_burst).then_some(Lint::BurstAboveGlobal {
+                key,
+                burst,
+                global_burst,
+            })
+        });
+        let unreachable = self.patterns.iter().flat_map(|patterns| {
+            let keys = &patterns.keys;
+            keys.iter().enumerate().filter_map(|(i, pattern)| {
+                // stacked policies push the same pattern several times
+                if keys[..i].contains(pattern) {
+                    return None;
+                }
+                let specificity = (patterns.match_key)(pattern, pattern)?;
+                let by = keys.iter().enumerate().find_map(|(j, other)| {
+                    let other_specificity = (patterns.match_key)(other, pattern)?;
+                    let wins = other_specificity > specificity
+                        || other_specificity == specificity && j < i;
+                    (other != pattern && wins).then_some(other)
+                })?;
+                Some(Lint::UnreachablePattern { pattern, by })
+            })
+        });
+        shadowed
+            .chain(sub_millisecond)
+            .chain(above_global)
+            .chain(unreachable)
+            .collect()
+    }
+
+    /// Returns the limiting policies set for keys, including patterns and the
+    /// policies changed at runtime, in arbitrary order.
+    ///
+    /// Stacked limits are listed one by one under the same key. The default,
+    /// the global, and the regular expression policies have no key, and aren't
+    /// listed. A quota limits as the configured one does, but isn't
+    /// necessarily equal to it, e.g. `Quota::per_minute(100).allow_burst(20)`
+    /// is listed as 20 tokens every 12 seconds.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::{Quota, RateLimiter};
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("A", 2, Duration::from_secs(60))
+    ///     .done();
+    /// limiter.add_limit("B", 5, Duration::from_secs(1));
+    ///
+    /// let mut policies = limiter.policies();
+    /// policies.sort_by_key(|(key, _)| *key);
+    /// assert_eq!(
+    ///     policies,
+    ///     vec![("A", Quota::per_minute(2)), ("B", Quota::per_second(5))]
+    /// );
+    /// ```
+    pub fn policies(&self) -> Vec<(K, Quota)>
+    where
+        K: Clone,
+    {
+        let runtime = self.runtime.read();
+        let explicit = self
+            .buckets
+            .iter()
+            .filter(|(key, _)| !runtime.contains_key(key))
+            .flat_map(|(key, bucket)| {
+                let stacked = self.stacked.get(key).into_iter().flatten();
+                iter::once(bucket)
+                    .chain(stacked)
+                    .map(move |bucket| (key.clone(), bucket.quota()))
+            });
+        let changed = runtime
+            .iter()
+            .filter_map(|(key, bucket)| Some((key.clone(), bucket.as_ref()?.quota())));
+        explicit.chain(changed).collect()
+    }
+
+    /// Returns the number of tokens currently available for a given event
+    /// (`key`), and how long it takes to generate the next one, without
+    /// consuming anything.
+    ///
+    /// See [`TokenBucket::available`] for details. With stacked limits, the
+    /// strictest of them is reported. The global limit isn't taken into
+    /// account. A key limited by the default policy, but not seen yet, has a
+    /// full bucket. If not `limit` is set, the function returns `None`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::{ManualClock, RateLimiter};
+    ///
+    /// let limiter = RateLimiter::with_clock(ManualClock::new())
+    ///     .limit("A", 2, Duration::from_secs(60))
+    ///     .done();
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    ///
+    /// let state = limiter.state(&"A").unwrap().unwrap();
+    /// assert_eq!(state.tokens(), 1);
+    /// assert_eq!(state.next_token_in(), Duration::from_secs(30));
+    ///
+    /// assert_eq!(limiter.state(&"B"), Ok(None));
+    /// ```
+    pub fn state<Q>(&self, key: &Q) -> Result<Option<Availability>, Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        self.with_normalized(key, |key| {
+            let fresh;
+            let (bucket, stacked) = match self.existing_bucket(key) {
+                Some(limited) => limited,
+                None => match &self.default {
+                    Some(default) => {
+                        fresh = default.fresh();
+                        (BucketRef::Borrowed(&fresh), &[][..])
+                    }
+                    None => return Ok(None),
+                },
+            };
+            iter::once(&*bucket)
+                .chain(stacked)
+                .map(TokenBucket::available)
+                .try_fold(None, |scarcest: Option<Availability>, availability| {
+                    let availability = availability?;
+                    Ok(Some(match scarcest {
+                        Some(scarcest) => scarcest.scarcer(availability),
+                        None => availability,
+                    }))
+                })
+        })
+    }
+
+    /// Returns the counters of the decisions made for a given event (`key`),
+    /// or `None` if none has been made, or the statistics aren't tracked, see
+    /// [`RateLimiterBuilder::track_stats`].
+    pub fn stats<Q>(&self, key: &Q) -> Option<Stats>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        let stats = self.stats.as_ref()?;
+        match &self.normalize {
+            Some(normalize) => stats.get(normalize(&key.to_owned()).borrow()),
+            None => stats.get(key),
+        }
+    }
+
+    /// Iterates over every key a decision has been made for, along with its
+    /// counters, in arbitrary order.
+    ///
+    /// The counters are captured at once, and the iterator doesn't lock the
+    /// rate limiter. Nothing is iterated over if the statistics aren't
+    /// tracked, see [`RateLimiterBuilder::track_stats`].
+    pub fn stats_iter(&self) -> impl Iterator<Item = (K, Stats)> {
+        self.stats
+            .as_ref()
+            .map_or_else(Vec::new, StatsTracker::all)
+            .into_iter()
+    }
+
+    /// Returns the number of rejections dropped so far, as they didn't fit
+    /// into the queue of the event sink, see
+    /// [`RateLimiterBuilder::event_sink`].
+    pub fn dropped_events(&self) -> u64 {
+        self.events.as_ref().map_or(0, EventQueue::dropped)
+    }
+
+    /// Same as [`RateLimiter::consume_remaining`], for a `key` normalized
+    /// already.
+    #[inline]
+    fn consume_remaining_normalized<Q>(&self, key: &Q, tokens: usize) -> Result<Option<u64>, Error>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        let result = match self.bypass(key) {
+            Some(result) => result.map(|()| None),
+            None => match self.limits(self.borrowed_bucket(key)) {
+                Some((bucket, tiers)) => {
+                    let result = consume_stacked(&bucket, tiers, tokens, None);
+                    self.record_borrowed(key, &result);
+                    result.map(Some).map_err(|error| self.jitter(error))
+                }
+                None => Ok(None),
+            },
+        };
+        self.report_borrowed(key, tokens, &result);
+        result
+    }
+
+    /// Same as [`RateLimiter::consume_detailed`], for a `key` normalized
+    /// already.
+    fn consume_detailed_normalized<Q>(&self, key: &Q, tokens: usize) -> Result<Allowance, Denial>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        if let Some(result) = self.bypass(key) {
+            self.report_borrowed(key, tokens, &result);
+            return match result {
+                Ok(()) => Ok(Allowance::new(Usage::unlimited())),
+                Err(error) => Err(Denial::new(error, Usage::blocked())),
+            };
+        }
+        let Some((bucket, tiers)) = self.limits(self.borrowed_bucket(key)) else {
+            self.report_borrowed(key, tokens, &Ok(()));
+            return Ok(Allowance::new(Usage::unlimited()));
+        };
+        let result = consume_stacked(&bucket, tiers.clone(), tokens, None)
+            .map_err(|error| self.jitter(error));
+        self.record_borrowed(key, &result);
+        self.report_borrowed(key, tokens, &result);
+        let usage = Usage::strictest(iter::once(&*bucket).chain(tiers));
+        match result {
+            Ok(_) => Ok(Allowance::new(usage)),
+            Err(error) => Err(Denial::new(error, usage)),
+        }
+    }
+
+    /// Returns the outcome for a `key` that bypasses its buckets by being
+    /// always allowed or blocked, or by being banned, if it does.
+    #[inline]
+    fn bypass<Q>(&self, key: &Q) -> Option<Result<(), Error>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        match self.bypasses.get(key) {
+            Some(Bypass::Allow) => Some(Ok(())),
+            Some(Bypass::Block) => Some(Err(Error::Blocked)),
+            None => self
+                .escalation
+                .as_ref()
+                .filter(|escalation| escalation.banned(key))
+                .map(|_| Err(Error::Blocked)),
+        }
+    }
+
+    /// Normalizes the `key` with the configured function, if any.
+    #[inline]
+    fn normalize(&self, key: K) -> K {
+        match &self.normalize {
+            Some(normalize) => normalize(&key),
+            None => key,
+        }
+    }
+
+    /// Calls `f` with the `key` normalized with the configured function, if
+    /// any, which converts the key into an owned one only if there is such a
+    /// function.
+    #[inline]
+    fn with_normalized<Q, R>(&self, key: &Q, f: impl FnOnce(&Q) -> R) -> R
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + ToOwned<Owned = K>,
+    {
+        match &self.normalize {
+            Some(normalize) => f(normalize(&key.to_owned()).borrow()),
+            None => f(key),
+        }
+    }
+
+    /// Returns the clock the buckets are created with.
+    pub(crate) fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Returns the hasher the keys are looked up with.
+    pub(crate) fn hasher(&self) -> &S {
+        self.buckets.hasher()
+    }
+
+    /// Returns the bucket for `key` with a policy of its own, if any, along
+    /// with the limits stacked on top of it.
+    ///
+    /// Only policies set at construction time are stacked, so a bucket changed
+    /// at runtime comes alone.
+    #[inline]
+    fn explicit_bucket<Q>(&self, key: &Q) -> Option<Limited<'_, C>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        match self.runtime.get(key) {
+            Some(bucket) => bucket.map(|bucket| (BucketRef::Shared(bucket), &[][..])),
+            None => {
+                let Some(bucket) = self.buckets.get(key) else {
+                    if self.groups.is_empty() {
+                        return None;
+                    }
+                    let bucket = &self.group_buckets[*self.groups.get(key)?];
+                    return Some((BucketRef::Borrowed(bucket), &[]));
+                };
+                let stacked = match self.stacked.is_empty() {
+                    true => &[],
+                    false => self.stacked.get(key).map_or(&[][..], Vec::as_slice),
+                };
+                Some((BucketRef::Borrowed(bucket), stacked))
+            }
+        }
+    }
+
+    /// Returns the bucket of the pattern matching `key` most specifically, if
+    /// any, see [`RateLimiterBuilder::pattern`].
+    fn pattern_bucket(&self, key: &K) -> Option<Limited<'_, C>> {
+        let patterns = self.patterns.as_ref()?;
+        let mut matching = None;
+        for pattern in &patterns.keys {
+            if let Some(specificity) = (patterns.match_key)(pattern, key) {
+                // of equally specific patterns, the first one set wins
+                if matching.is_none_or(|(most, _)| specificity > most) {
+                    matching = Some((specificity, pattern));
+                }
+            }
+        }
+        matching.and_then(|(_, pattern)| self.explicit_bucket(pattern))
+    }
+
+    /// Returns the bucket of the pattern or the regular expression matching
+    /// `key`, if any, in the order of precedence documented for
+    /// [`RateLimiterBuilder::regex_limit`].
+    fn matching_bucket(&self, key: &K) -> Option<Limited<'_, C>> {
+        if let Some(limited) = self.pattern_bucket(key) {
+            return Some(limited);
+        }
+        #[cfg(feature = "regex")]
+        if let Some(bucket) = self
+            .regexes
+            .as_ref()
+            .and_then(|regexes| regexes.bucket(key))
+        {
+            return Some((BucketRef::Borrowed(bucket), &[]));
+        }
+        None
+    }
+
+    /// Returns the bucket for `key`, falling back to the patterns, and to the
+    /// default policy, which creates a bucket for a key seen first.
+    fn bucket(&self, key: &K) -> Option<Limited<'_, C>> {
+        self.explicit_bucket(key)
+            .or_else(|| self.matching_bucket(key))
+            .or_else(|| {
+                let default = self.default.as_ref()?;
+                Some((BucketRef::Shared(default.bucket(key)), &[][..]))
+            })
+    }
+
+    /// Same as [`RateLimiter::bucket`], but looks the `key` up by its borrowed
+    /// form, which is converted into an owned one only for a key seen first,
+    /// or to be matched against the patterns.
+    #[inline]
+    fn borrowed_bucket<Q>(&self, key: &Q) -> Option<Limited<'_, C>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        self.explicit_bucket(key)
+            .or_else(|| self.borrowed_matching_bucket(key))
+            .or_else(|| {
+                let default = self.default.as_ref()?;
+                let bucket = default.bucket_borrowed(key, Q::to_owned);
+                Some((BucketRef::Shared(bucket), &[][..]))
+            })
+    }
+
+    /// Same as [`RateLimiter::matching_bucket`], but looks the `key` up by its
+    /// borrowed form, which is converted into an owned one only if there are
+    /// patterns or regular expressions to match it against.
+    #[inline]
+    fn borrowed_matching_bucket<Q>(&self, key: &Q) -> Option<Limited<'_, C>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + ToOwned<Owned = K>,
+    {
+        #[cfg(feature = "regex")]
+        let matching = self.patterns.is_some() || self.regexes.is_some();
+        #[cfg(not(feature = "regex"))]
+        let matching = self.patterns.is_some();
+        matching.then(|| self.matching_bucket(&key.to_owned()))?
+    }
+
+    /// Same as [`RateLimiter::borrowed_bucket`], but never creates a bucket,
+    /// since a bucket not created yet is full.
+    fn existing_bucket<Q>(&self, key: &Q) -> Option<Limited<'_, C>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        self.explicit_bucket(key)
+            .or_else(|| self.borrowed_matching_bucket(key))
+            .or_else(|| {
+                let bucket = self.default.as_ref()?.get(key)?;
+                Some((BucketRef::Shared(bucket), &[][..]))
+            })
+    }
+
+    /// Returns the bucket of a key along with the buckets limiting the key on
+    /// top of it, i.e. the `stacked` limits, and the global limit, if any. A
+    /// key without a bucket is limited by the global limit alone.
+    #[inline]
+    fn limits<'a>(
+        &'a self,
+        bucket: Option<Limited<'a, C>>,
+    ) -> Option<(BucketRef<'a, C>, Tiers<'a, C>)> {
+        match bucket {
+            Some((bucket, stacked)) => Some((bucket, stacked.iter().chain(self.global.as_ref()))),
+            None => {
+                let global = self.global.as_ref()?;
+                Some((BucketRef::Borrowed(global), [].iter().chain(None)))
+            }
+        }
+    }
+
+    /// Merges the policies changed at runtime into the ones set at
+    /// construction time.
+    fn merge_runtime(&mut self) {
+        for (key, bucket) in self.runtime.take() {
+            self.stacked.remove(&key);
+            self.groups.remove(&key);
+            match bucket {
+                Some(bucket) => {
+                    // permits and speculations holding the bucket borrow the
+                    // rate limiter, so none of them can be alive here
+                    let bucket = Arc::into_inner(bucket).expect("bucket is not shared");
+                    self.buckets.insert(key, bucket);
+                }
+                None => {
+                    self.buckets.remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Records the `result` of a request for `key`, in its borrowed form, for
+    /// anomaly detection, and counts a rejection towards a ban, unless the
+    /// request exceeds the capacity, which is a mistake of the caller rather
+    /// than a violation.
+    #[inline]
+    fn record_borrowed<Q, T>(&self, key: &Q, result: &Result<T, Error>)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        if let (Some(escalation), Err(error)) = (&self.escalation, result) {
+            if *error != Error::ExceedsCapacity {
+                escalation.record(key, Q::to_owned);
+            }
+        }
+        if let Some(anomalies) = &self.anomalies {
+            anomalies.record(key, Q::to_owned, result.is_err());
+        }
+    }
+
+    /// Reports the `result` of a request for `tokens` of `key` to the decision
+    /// callback, counts it in the statistics, and queues it to the event sink
+    /// if rejected, if either is set. The `key` is given in its borrowed form,
+    /// which is converted into an owned one only if there is a decision
+    /// callback, a rejection to be queued, or the key is counted first.
+    #[inline]
+    fn report_borrowed<Q, T>(&self, key: &Q, tokens: usize, result: &Result<T, Error>)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        if let Some(stats) = &self.stats {
+            stats.record(key, Q::to_owned, tokens, result.is_ok());
+        }
+        if let Some(on_decision) = &self.on_decision {
+            on_decision(&key.to_owned(), tokens, result.as_ref().map(|_| ()));
+        }
+        if let (Some(events), Err(error)) = (&self.events, result) {
+            events.push(key.to_owned(), tokens, error);
+        }
+    }
+
+    /// Adds the configured jitter, if any, to the delay of a rejection.
+    fn jitter(&self, error: Error) -> Error {
+        match &self.jitter {
+            Some(jitter) => jitter.apply(error),
+            None => error,
+        }
+    }
+}
+
+impl<K: Eq + Hash, C: Clock + Clone, S: BuildHasher> RateLimiter<K, C, S> {
+    /// Sets a limiting policy for a `key` of a live `RateLimiter` instance.
+    ///
+    /// Same as [`RateLimiterBuilder::limit`], but doesn't require to rebuild
+    /// the rate limiter, so that buckets of other keys keep their state. If
+    /// the `key` already has a policy, it's replaced along with the limits
+    /// stacked on top of it, if any, and the key starts over with a full
+    /// bucket.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure().done();
+    /// assert!(limiter.consume(&"A", 2).is_ok());
+    ///
+    /// limiter.add_limit("A", 1, Duration::from_secs(60));
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    /// assert!(limiter.consume(&"A", 1).is_err());
+    /// ```
+    pub fn add_limit(&self, key: K, limit: usize, interval: Duration) {
+        let key = self.normalize(key);
+        if let Some(default) = &self.default {
+            default.remove(&key);
+        }
+        let bucket = TokenBucket::with_clock(limit, interval, self.clock.clone());
+        self.runtime.insert(key, Some(bucket));
+    }
+
+    /// Changes the limiting policy for a `key` of a live `RateLimiter`
+    /// instance, keeping the state of its bucket.
+    ///
+    /// Same as [`RateLimiter::add_limit`], but rather than starting over with a
+    /// full bucket, the tokens available are rescaled to the new rate, see
+    /// [`TokenBucket::set_rate`] for details. This way, tuning a limit doesn't
+    /// hand every client a burst. The limits stacked on top of the policy, if
+    /// any, are removed. A key limited by the default policy keeps the state
+    /// of its bucket as well, while a key without a bucket of its own starts
+    /// with a full one.
+    ///
+    /// Tokens consumed for the `key` while its policy is being changed may be
+    /// left unaccounted for.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("A", 10, Duration::from_secs(60))
+    ///     .done();
+    /// assert!(limiter.consume(&"A", 10).is_ok());
+    ///
+    /// // the bucket stays empty, rather than holding 20 tokens
+    /// limiter.update_limit("A", 20, Duration::from_secs(60));
+    /// assert!(limiter.consume(&"A", 1).is_err());
+    /// ```
+    pub fn update_limit(&self, key: K, limit: usize, interval: Duration) {
+        let key = self.normalize(key);
+        let existing = match self.explicit_bucket(&key) {
+            Some((bucket, _)) => Some(TokenBucket::clone(&bucket)),
+            None => self
+                .default
+                .as_ref()
+                .and_then(|default| default.get(&key))
+                .map(|bucket| TokenBucket::clone(&bucket)),
+        };
+        if let Some(default) = &self.default {
+            default.remove(&key);
+        }
+        let bucket = match existing {
+            Some(mut bucket) => {
+                bucket.set_rate(limit, interval);
+                bucket
+            }
+            None => TokenBucket::with_clock(limit, interval, self.clock.clone()),
+        };
+        self.runtime.insert(key, Some(bucket));
+    }
+
+    /// Allows every event of a `key` of a live `RateLimiter` instance, without
+    /// consuming any tokens.
+    ///
+    /// Same as [`RateLimiterBuilder::always_allow`], but doesn't require to
+    /// rebuild the rate limiter. The policy of the `key` is kept, and applies
+    /// again once the key is removed via [`RateLimiter::remove_bypass`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("A", 1, Duration::from_secs(60))
+    ///     .done();
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    ///
+    /// limiter.always_allow("A");
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    ///
+    /// limiter.remove_bypass("A");
+    /// assert!(limiter.consume(&"A", 1).is_err());
+    /// ```
+    pub fn always_allow(&self, key: K) {
+        let key = self.normalize(key);
+        self.bypasses.insert(key, Bypass::Allow);
+    }
+
+    /// Blocks every event of a `key` of a live `RateLimiter` instance.
+    ///
+    /// Same as [`RateLimiterBuilder::always_block`], but doesn't require to
+    /// rebuild the rate limiter. The policy of the `key` is kept, and applies
+    /// again once the key is removed via [`RateLimiter::remove_bypass`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use youshallnotpass::{Error, RateLimiter};
+    ///
+    /// let limiter = RateLimiter::configure().done();
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    ///
+    /// limiter.always_block("A");
+    /// assert_eq!(limiter.consume(&"A", 1), Err(Error::Blocked));
+    /// ```
+    pub fn always_block(&self, key: K) {
+        let key = self.normalize(key);
+        self.bypasses.insert(key, Bypass::Block);
+    }
+
+    /// Lets a `key` that is always allowed or blocked be limited by its policy
+    /// again, see [`RateLimiter::always_allow`] and
+    /// [`RateLimiter::always_block`].
+    pub fn remove_bypass(&self, key: K) {
+        let key = self.normalize(key);
+        self.bypasses.remove(&key);
+    }
+
+    /// Lifts the ban of a `key` before it expires, and forgets the rejections
+    /// counted towards the next one, see [`RateLimiterBuilder::escalate_after`].
+    pub fn pardon<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash + ToOwned<Owned = K>,
+    {
+        if let Some(escalation) = &self.escalation {
+            self.with_normalized(key, |key| escalation.pardon(key));
+        }
+    }
+
+    /// Removes the limiting policy for a `key` of a live `RateLimiter`
+    /// instance.
+    ///
+    /// The `key` is limited by the default policy afterwards, if any, and is
+    /// never limited otherwise.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("A", 1, Duration::from_secs(60))
+    ///     .done();
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    /// assert!(limiter.consume(&"A", 1).is_err());
+    ///
+    /// limiter.remove_limit("A");
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    /// ```
+    pub fn remove_limit(&self, key: K) {
+        let key = self.normalize(key);
+        if self.buckets.contains_key(&key) || self.groups.contains_key(&key) {
+            self.runtime.insert(key, None);
+        } else {
+            self.runtime.remove(&key);
+        }
+    }
+
+    /// Applies the changes of limiting policies described by `diff` to a live
+    /// `RateLimiter` instance.
+    ///
+    /// Buckets of keys whose policies haven't changed keep their state, which
+    /// isn't the case when the limiter is rebuilt from scratch. Buckets of keys
+    /// whose policies have changed keep their tokens, up to the new capacity,
+    /// same as with [`RateLimiter::update_limit`], while added keys get full
+    /// buckets.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use youshallnotpass::{PolicySet, Quota, RateLimiter};
+    ///
+    /// let old: PolicySet<&str> = [("A", Quota::per_minute(1)), ("B", Quota::per_minute(1))]
+    ///     .into_iter()
+    ///     .collect();
+    /// let mut limiter = RateLimiter::configure().policies(old.clone()).done();
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    /// assert!(limiter.consume(&"B", 1).is_ok());
+    ///
+    /// let mut new = old.clone();
+    /// new.insert("B", Quota::per_minute(2));
+    /// limiter.apply(PolicySet::diff(&old, &new));
+    ///
+    /// // neither key has tokens left, but B refills twice as fast now
+    /// assert!(limiter.consume(&"A", 1).is_err());
+    /// assert!(limiter.consume(&"B", 1).is_err());
+    /// ```
+    pub fn apply(&mut self, diff: PolicyDiff<K>) {
+        self.merge_runtime();
+        for key in diff.removed {
+            let key = self.normalize(key);
+            self.stacked.remove(&key);
+            self.groups.remove(&key);
+            self.buckets.remove(&key);
+        }
+        let changed = diff.changed.into_iter().map(|(key, _, quota)| (key, quota));
+        for (key, quota) in diff.added.into_iter().chain(changed) {
+            let key = self.normalize(key);
+            self.stacked.remove(&key);
+            self.groups.remove(&key);
+            let bucket = match self.buckets.remove(&key) {
+                Some(mut bucket) => {
+                    bucket.set_quota(quota);
+                    bucket
+                }
+                None => TokenBucket::from_quota_with_clock(quota, self.clock.clone()),
+            };
+            self.buckets.insert(key, bucket);
+        }
+    }
+}
+
+impl<K: fmt::Debug, C: Clock, S> fmt::Debug for RateLimiter<K, C, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("buckets", &self.buckets)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<K: Clone, C: Clone, S: Clone> Clone for RateLimiter<K, C, S> {
+    /// Clones the limiting policies along with the state of every bucket, see
+    /// [`TokenBucket::clone`] for details. The bans of keys are copied too,
+    /// along with the rejections counted towards the next ones, see
+    /// [`RateLimiterBuilder::escalate_after`]. Neither the state of anomaly
+    /// detection nor the statistics are cloned, and both start afresh, while
+    /// rejections keep being queued to the same event sink.
+    fn clone(&self) -> Self {
+        RateLimiter {
+            buckets: self.buckets.clone(),
+            runtime: self.runtime.clone(),
+            default: self.default.clone(),
+            anomalies: self.anomalies.clone(),
+            escalation: self.escalation.clone(),
+            on_decision: self.on_decision.clone(),
+            stats: self.stats.clone(),
+            events: self.events.clone(),
+            jitter: self.jitter.clone(),
+            stacked: self.stacked.clone(),
+            groups: self.groups.clone(),
+            group_buckets: self.group_buckets.clone(),
+            bypasses: self.bypasses.clone(),
+            global: self.global.clone(),
+            patterns: self.patterns.clone(),
+            #[cfg(feature = "regex")]
+            regexes: self.regexes.clone(),
+            normalize: self.normalize.clone(),
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+/// The builder exposes ability to configure a [`RateLimiter`] instance by
+/// setting limiting policies.
+pub struct RateLimiterBuilder<K, C = MonotonicClock, S = RandomState> {
+    limits: Vec<(K, Quota)>,
+    groups: Vec<(Vec<K>, Quota)>,
+    bypasses: Vec<(K, Bypass)>,
+    default: Option<(Quota, CloneKey<K>)>,
+    global: Option<Quota>,
+    patterns: Option<Patterns<K>>,
+    #[cfg(feature = "regex")]
+    regexes: Option<RegexQuotas<K>>,
+    normalize: Option<Normalize<K>>,
+    anomalies: Option<(Duration, usize, f64, AnomalyCallback<K>)>,
+    escalation: Option<(usize, Duration, Duration)>,
+    on_decision: Option<DecisionCallback<K>>,
+    stats: Option<(CloneKey<K>, usize)>,
+    events: Option<EventQueue<K>>,
+    jitter: Option<Jitter>,
+    hasher: S,
+    clock: C,
+}
+
+impl<K, C, S> RateLimiterBuilder<K, C, S> {
+    /// Sets a limiting policy for a `key`.
+    ///
+    /// The limiting policy sets how many times an event is allowed to happen
+    /// (`limit`) within a given period of time (`interval`). Event is vague
+    /// term. Thus we use a `key` to uniquely identify an event we want to rate
+    /// limit.
+    ///
+    /// Several policies set for the same `key` are stacked, so that an event
+    /// is allowed only if every one of them allows it, e.g. to limit both
+    /// bursts within a second and the total within an hour. A rejection comes
+    /// with the longest delay of the policies that reject the event, and
+    /// consumes tokens from none of them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::{Error, ManualClock, RateLimiter};
+    ///
+    /// let clock = ManualClock::new();
+    /// let limiter = RateLimiter::with_clock(clock.clone())
+    ///     .limit("A", 2, Duration::from_secs(1))
+    ///     .limit("A", 3, Duration::from_secs(60))
+    ///     .done();
+    ///
+    /// assert!(limiter.consume(&"A", 2).is_ok());
+    /// assert!(limiter.consume(&"A", 1).is_err());
+    ///
+    /// clock.advance(Duration::from_secs(1));
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    /// assert_eq!(
+    ///     limiter.consume(&"A", 1),
+    ///     Err(Error::RetryAfter(Duration::from_secs(19)))
+    /// );
+    /// ```
+    pub fn limit(self, key: K, limit: usize, interval: Duration) -> Self {
+        self.quota(key, Quota::new(limit, interval))
+    }
+
+    /// Sets a limiting policy for a `key` in terms of a [`Quota`].
+    ///
+    /// Same as [`limit`], but allows to set the burst size independently of
+    /// the rate. Quotas set for the same `key` are stacked, same as limits.
+    ///
+    /// [`limit`]: RateLimiterBuilder::limit
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use youshallnotpass::{Quota, RateLimiter};
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .quota("A", Quota::per_minute(60).allow_burst(5))
+    ///     .done();
+    ///
+    /// assert!(limiter.consume(&"A", 5).is_ok());
+    /// assert!(limiter.consume(&"A", 1).is_err());
+    /// ```
+    pub fn quota(mut self, key: K, quota: Quota) -> Self {
+        self.limits.push((key, quota));
+        self
+    }
+
+    /// Blocks every event of a `key`.
+    ///
+    /// Same as a limit of zero, but spells out that blocking is intended, so
+    /// that [`try_done`] accepts it.
+    ///
+    /// [`try_done`]: RateLimiterBuilder::try_done
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use youshallnotpass::{Error, RateLimiter};
+    ///
+    /// let limiter = RateLimiter::configure().block("A").try_done().unwrap();
+    ///
+    /// assert_eq!(limiter.consume(&"A", 1), Err(Error::Blocked));
+    /// ```
+    pub fn block(self, key: K) -> Self {
+        self.quota(key, Quota::blocked())
+    }
+
+    /// Allows every event of a `key`, e.g. of a health checker, regardless of
+    /// its policy, and without consuming any tokens.
+    ///
+    /// The key is checked before any of the buckets, including the global
+    /// one. Keys may be allowed, blocked, or limited again at runtime, see
+    /// [`RateLimiter::always_allow`]. If a key is both allowed and blocked, the
+    /// last one set wins.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .default_limit(1, Duration::from_secs(60))
+    ///     .always_allow("health-check")
+    ///     .done();
+    ///
+    /// assert!(limiter.consume(&"health-check", 100).is_ok());
+    /// assert!(limiter.consume(&"health-check", 100).is_ok());
+    /// ```
+    pub fn always_allow(mut self, key: K) -> Self {
+        self.bypasses.push((key, Bypass::Allow));
+        self
+    }
+
+    /// Blocks every event of a `key`, e.g. of a known abuser, regardless of
+    /// its policy.
+    ///
+    /// Unlike [`block`], the key is checked before any of the buckets, and
+    /// its policy is kept, so that the key may be limited by it again at
+    /// runtime, see [`RateLimiter::remove_bypass`]. See [`always_allow`] for
+    /// details.
+    ///
+    /// [`block`]: RateLimiterBuilder::block
+    /// [`always_allow`]: RateLimiterBuilder::always_allow
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::{Error, RateLimiter};
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("abuser", 10, Duration::from_secs(60))
+    ///     .always_block("abuser")
+    ///     .done();
+    ///
+    /// assert_eq!(limiter.consume(&"abuser", 1), Err(Error::Blocked));
+    ///
+    /// limiter.remove_bypass("abuser");
+    /// assert!(limiter.consume(&"abuser", 1).is_ok());
+    /// ```
+    pub fn always_block(mut self, key: K) -> Self {
+        self.bypasses.push((key, Bypass::Block));
+        self
+    }
+
+    /// Sets a limiting policy shared by several `keys`, which draw from a
+    /// single bucket, e.g. `"login"` and `"password-reset"` sharing the same
+    /// abuse budget.
+    ///
+    /// A key of the group with a policy of its own is limited by that policy
+    /// instead. Changing or removing the policy of a key at runtime takes only
+    /// that key out of the group, whichever of the `keys` it is, while the
+    /// rest of them keep sharing the bucket. Buckets of groups are listed
+    /// under every key of their group, e.g. by [`RateLimiter::handoff`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .group(["login", "password-reset"], 5, Duration::from_secs(60))
+    ///     .done();
+    ///
+    /// assert!(limiter.consume(&"login", 3).is_ok());
+    /// assert!(limiter.consume(&"password-reset", 2).is_ok());
+    /// assert!(limiter.consume(&"login", 1).is_err());
+    /// ```
+    pub fn group<I>(self, keys: I, limit: usize, interval: Duration) -> Self
+    where
+        I: IntoIterator<Item = K>,
+    {
+        self.group_quota(keys, Quota::new(limit, interval))
+    }
+
+    /// Sets a limiting policy shared by several `keys` in terms of a
+    /// [`Quota`].
+    ///
+    /// Same as [`group`], but allows to set the burst size independently of
+    /// the rate.
+    ///
+    /// [`group`]: RateLimiterBuilder::group
+    pub fn group_quota<I>(mut self, keys: I, quota: Quota) -> Self
+    where
+        I: IntoIterator<Item = K>,
+    {
+        let keys: Vec<K> = keys.into_iter().collect();
+        if !keys.is_empty() {
+            self.groups.push((keys, quota));
+        }
+        self
+    }
+
+    /// Sets a limiting policy for all keys matching a pattern (`key`), unless
+    /// they have a policy of their own.
+    ///
+    /// This is useful when keys cannot be enumerated in advance, e.g. paths of
+    /// REST APIs with path parameters. See [`KeyPattern`] for how keys are
+    /// matched. A key matching several patterns is limited by the most
+    /// specific one, and by the first one set of equally specific patterns.
+    ///
+    /// All keys matching a pattern share its bucket, so that the pattern limits
+    /// them together. Keys resolved by patterns are converted into owned ones
+    /// to be matched, which allocates unless cloning a key is cheap.
+    ///
+    /// The pattern is a key itself, so that it's limited same as any other
+    /// key, e.g. several policies set for a pattern are stacked.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .pattern(("GET", "/api/v1/*"), 2, Duration::from_secs(60))
+    ///     .pattern(("GET", "/api/v1/users/*"), 1, Duration::from_secs(60))
+    ///     .limit(("GET", "/api/v1/users/me"), 5, Duration::from_secs(60))
+    ///     .done();
+    ///
+    /// assert!(limiter.consume(&("GET", "/api/v1/users/1"), 1).is_ok());
+    /// assert!(limiter.consume(&("GET", "/api/v1/users/2"), 1).is_err());
+    /// assert!(limiter.consume(&("GET", "/api/v1/users/me"), 1).is_ok());
+    ///
+    /// assert!(limiter.consume(&("GET", "/api/v1/posts/1"), 2).is_ok());
+    /// assert!(limiter.consume(&("GET", "/api/v1/posts/2"), 1).is_err());
+    /// assert!(limiter.consume(&("PUT", "/api/v1/posts/2"), 1).is_ok());
+    /// ```
+    pub fn pattern(self, key: K, limit: usize, interval: Duration) -> Self
+    where
+        K: KeyPattern + Clone,
+    {
+        self.pattern_quota(key, Quota::new(limit, interval))
+    }
+
+    /// Sets a limiting policy for all keys matching a pattern (`key`) in terms
+    /// of a [`Quota`].
+    ///
+    /// Same as [`pattern`], but allows to set the burst size independently of
+    /// the rate.
+    ///
+    /// [`pattern`]: RateLimiterBuilder::pattern
+    pub fn pattern_quota(mut self, key: K, quota: Quota) -> Self
+    where
+        K: KeyPattern + Clone,
+    {
+        self.patterns
+            .get_or_insert_with(|| Patterns::new(K::match_key))
+            .keys
+            .push(key.clone());
+        self.quota(key, quota)
+    }
+
+    /// Sets a limiting policy for all string keys matching the regular
+    /// expression (`regex`), unless they have a policy of their own.
+    ///
+    /// This is useful to import rules matching URLs or user agents from
+    /// existing configurations, e.g. of web application firewalls. All the
+    /// expressions are compiled into a single [`RegexSet`], so that a key is
+    /// matched against all of them at once.
+    ///
+    /// A key is limited by the first policy found in the following order:
+    ///
+    /// 1. the policy set for the key itself, see [`limit`];
+    /// 2. the most specific pattern matching the key, see [`pattern`];
+    /// 3. the first expression matching the key in the order they're set;
+    /// 4. the default policy, see [`default_limit`].
+    ///
+    /// All keys matching an expression share its bucket, which isn't part of
+    /// a [`Handoff`]. Same as for patterns, the keys are converted into owned
+    /// ones to be matched.
+    ///
+    /// Available with the `regex` feature only.
+    ///
+    /// [`RegexSet`]: regex::RegexSet
+    /// [`limit`]: RateLimiterBuilder::limit
+    /// [`pattern`]: RateLimiterBuilder::pattern
+    /// [`default_limit`]: RateLimiterBuilder::default_limit
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use regex::Regex;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .regex_limit(Regex::new("(?i)bot").unwrap(), 1, Duration::from_secs(60))
+    ///     .regex_limit(Regex::new("^curl/").unwrap(), 2, Duration::from_secs(60))
+    ///     .done();
+    ///
+    /// assert!(limiter.consume(&"Googlebot/2.1", 1).is_ok());
+    /// assert!(limiter.consume(&"bingbot/2.0", 1).is_err());
+    /// assert!(limiter.consume(&"curl/8.0", 2).is_ok());
+    /// assert!(limiter.consume(&"Mozilla/5.0", 3).is_ok());
+    /// ```
+    #[cfg(feature = "regex")]
+    pub fn regex_limit(self, regex: Regex, limit: usize, interval: Duration) -> Self
+    where
+        K: AsRef<str>,
+    {
+        self.regex_quota(regex, Quota::new(limit, interval))
+    }
+
+    /// Sets a limiting policy for all string keys matching the regular
+    /// expression (`regex`) in terms of a [`Quota`].
+    ///
+    /// Same as [`regex_limit`], but allows to set the burst size
+    /// independently of the rate.
+    ///
+    /// Available with the `regex` feature only.
+    ///
+    /// [`regex_limit`]: RateLimiterBuilder::regex_limit
+    #[cfg(feature = "regex")]
+    pub fn regex_quota(mut self, regex: Regex, quota: Quota) -> Self
+    where
+        K: AsRef<str>,
+    {
+        self.regexes
+            .get_or_insert_with(|| (Vec::new(), K::as_ref))
+            .0
+            .push((regex, quota));
+        self
+    }
+
+    /// Sets a limiting policy for keys without a policy of their own.
+    ///
+    /// Every such key gets its own bucket on first use, so the keys are
+    /// limited independently of each other. Without a default policy, keys
+    /// without a policy are never limited.
+    ///
+    /// The buckets are never forgotten, so the number of distinct keys must be
+    /// bounded, e.g. by authenticating clients before limiting them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("admin", 100, Duration::from_secs(60))
+    ///     .default_limit(1, Duration::from_secs(60))
+    ///     .done();
+    ///
+    /// assert!(limiter.consume(&"admin", 2).is_ok());
+    ///
+    /// assert!(limiter.consume(&"alice", 1).is_ok());
+    /// assert!(limiter.consume(&"alice", 1).is_err());
+    /// assert!(limiter.consume(&"bob", 1).is_ok());
+    /// ```
+    pub fn default_limit(self, limit: usize, interval: Duration) -> Self
+    where
+        K: Clone,
+    {
+        self.default_quota(Quota::new(limit, interval))
+    }
+
+    /// Sets a limiting policy for keys without a policy of their own in terms
+    /// of a [`Quota`].
+    ///
+    /// Same as [`default_limit`], but allows to set the burst size
+    /// independently of the rate.
+    ///
+    /// [`default_limit`]: RateLimiterBuilder::default_limit
+    pub fn default_quota(mut self, quota: Quota) -> Self
+    where
+        K: Clone,
+    {
+        self.default = Some((quota, K::clone));
+        self
+    }
+
+    /// Sets a limiting policy for all events together, on top of the policies
+    /// of their keys.
+    ///
+    /// Every event is charged to a single global bucket, in addition to the
+    /// bucket of its key, if any, and is allowed only if both allow it. This
+    /// caps the load of the whole service regardless of how many keys share
+    /// it, e.g. when every tenant gets 100 requests per second, but the service
+    /// handles 2000 at most. A rejection consumes tokens from neither bucket.
+    ///
+    /// Functions managing the bucket of a key, such as [`RateLimiter::reset`],
+    /// [`RateLimiter::drain`], and [`RateLimiter::time_to_full`], leave the
+    /// global bucket be, and it isn't part of a [`Handoff`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("alice", 2, Duration::from_secs(60))
+    ///     .limit("bob", 2, Duration::from_secs(60))
+    ///     .global_limit(3, Duration::from_secs(60))
+    ///     .done();
+    ///
+    /// assert!(limiter.consume(&"alice", 2).is_ok());
+    /// assert!(limiter.consume(&"bob", 2).is_err());
+    /// assert!(limiter.consume(&"bob", 1).is_ok());
+    /// assert!(limiter.consume(&"carol", 1).is_err());
+    /// ```
+    pub fn global_limit(self, limit: usize, interval: Duration) -> Self {
+        self.global_quota(Quota::new(limit, interval))
+    }
+
+    /// Sets a limiting policy for all events together in terms of a
+    /// [`Quota`].
+    ///
+    /// Same as [`global_limit`], but allows to set the burst size
+    /// independently of the rate.
+    ///
+    /// [`global_limit`]: RateLimiterBuilder::global_limit
+    pub fn global_quota(mut self, quota: Quota) -> Self {
+        self.global = Some(quota);
+        self
+    }
+
+    /// Sets limiting policies for all keys of the `policies` set.
+    ///
+    /// Same as calling [`quota`] for every key of the set.
+    ///
+    /// [`quota`]: RateLimiterBuilder::quota
+    pub fn policies(mut self, policies: PolicySet<K>) -> Self
+    where
+        K: Eq + Hash,
+    {
+        self.limits.extend(policies);
+        self
+    }
+
+    /// Sets a `callback` to be invoked when a key chronically hits its limit.
+    ///
+    /// The fraction of rejected requests is tracked for every key with a
+    /// limiting policy over the last `windows` periods of `window` length.
+    /// Once it reaches the `threshold` (e.g. `0.5` for a half of requests),
+    /// the `callback` is invoked with the key and the fraction. It's not
+    /// invoked again for the same key until the fraction drops below the
+    /// threshold and then reaches it anew.
+    ///
+    /// The detection has no effect on limiting decisions. The `callback` is
+    /// invoked synchronously from consuming functions, and must not call back
+    /// into the same [`RateLimiter`]. It's invoked once the key is unlocked,
+    /// so a slow `callback` stalls only the request that has triggered it.
+    /// Keys idle for all the `windows` are forgotten as new keys come.
+    ///
+    /// The `callback` is owned by the limiter, and thus may not borrow from
+    /// the surrounding scope; use shared ownership (e.g. `Arc`) instead.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("A", 1, Duration::from_secs(60))
+    ///     .on_anomaly(Duration::from_secs(60), 5, 0.5, |key, fraction| {
+    ///         println!("{key} got {:.0}% of requests rejected", fraction * 100.0);
+    ///     })
+    ///     .done();
+    ///
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    /// assert!(limiter.consume(&"A", 1).is_err());
+    /// ```
+    pub fn on_anomaly<F>(
+        mut self,
+        window: Duration,
+        windows: usize,
+        threshold: f64,
+        callback: F,
+    ) -> Self
+    where
+        F: Fn(&K, f64) + Send + Sync + 'static,
+    {
+        self.anomalies = Some((window, windows, threshold, Arc::new(callback)));
+        self
+    }
+
+    /// Blocks a key for the `ban` duration once it's rejected `violations`
+    /// times within the `window`, e.g. to stop brute-force attempts rather
+    /// than merely throttle them.
+    ///
+    /// Rejections are counted for every key with a limiting policy, and the
+    /// ones of a banned key don't count towards the next ban, and neither do
+    /// the ones of requests exceeding the capacity, see
+    /// [`Error::ExceedsCapacity`]. Rejections are forgotten once the key is
+    /// banned, so that it's banned again only after as many new violations.
+    /// Keys that are always allowed are never banned, and a ban can be lifted
+    /// early via [`RateLimiter::pardon`]. Zero `violations` are the same as
+    /// one, i.e. a key is banned on its first rejection.
+    ///
+    /// Keys are tracked as long as they are banned, or have been rejected
+    /// within the `window`, so the memory used grows with the number of
+    /// distinct keys rejected recently.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::{Error, ManualClock, RateLimiter};
+    ///
+    /// let clock = ManualClock::new();
+    /// let limiter = RateLimiter::with_clock(clock.clone())
+    ///     .limit("login", 1, Duration::from_secs(1))
+    ///     .escalate_after(2, Duration::from_secs(60), Duration::from_secs(3600))
+    ///     .done();
+    ///
+    /// assert!(limiter.consume(&"login", 1).is_ok());
+    /// assert!(matches!(limiter.consume(&"login", 1), Err(Error::RetryAfter(_))));
+    /// assert!(matches!(limiter.consume(&"login", 1), Err(Error::RetryAfter(_))));
+    ///
+    /// clock.advance(Duration::from_secs(1));
+    /// assert_eq!(limiter.consume(&"login", 1), Err(Error::Blocked));
+    ///
+    /// clock.advance(Duration::from_secs(3600));
+    /// assert!(limiter.consume(&"login", 1).is_ok());
+    /// ```
+    pub fn escalate_after(mut self, violations: usize, window: Duration, ban: Duration) -> Self {
+        self.escalation = Some((violations, window, ban));
+        self
+    }
+
+    /// Sets a `callback` invoked on every decision to allow or reject an
+    /// event, e.g. to emit metrics or structured logs without wrapping every
+    /// call site.
+    ///
+    /// The `callback` is invoked with the key, the number of tokens requested,
+    /// and the outcome of the request, including the ones of keys that are
+    /// always allowed or blocked, and of keys without a limit. Every consuming
+    /// function reports its decisions, while [`RateLimiter::check`],
+    /// [`RateLimiter::admit`], and [`RateLimiter::refund`] don't, as they
+    /// never reject an event by consuming tokens.
+    ///
+    /// Same as for [`on_anomaly`], the `callback` is invoked synchronously,
+    /// must not call back into the same [`RateLimiter`], and may not borrow
+    /// from the surrounding scope. A key looked up by its borrowed form is
+    /// converted into an owned one to be reported.
+    ///
+    /// [`on_anomaly`]: RateLimiterBuilder::on_anomaly
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("A", 1, Duration::from_secs(60))
+    ///     .on_decision(|key, tokens, outcome| match outcome {
+    ///         Ok(()) => println!("{key} is allowed {tokens} tokens"),
+    ///         Err(error) => println!("{key} is denied {tokens} tokens: {error}"),
+    ///     })
+    ///     .done();
+    ///
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    /// assert!(limiter.consume(&"A", 1).is_err());
+    /// ```
+    pub fn on_decision<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&K, usize, Result<(), &Error>) + Send + Sync + 'static,
+    {
+        self.on_decision = Some(Arc::new(callback));
+        self
+    }
+
+    /// Counts allowed and rejected requests, and tokens consumed, per key, to
+    /// be queried via [`RateLimiter::stats`] and [`RateLimiter::stats_iter`],
+    /// e.g. for dashboards and capacity planning.
+    ///
+    /// The same decisions are counted as reported via [`on_decision`]. Every
+    /// key seen is counted, including the ones without a limit, and up to
+    /// 10000 keys are tracked, see [`track_stats_max_keys`] to change that.
+    ///
+    /// [`on_decision`]: RateLimiterBuilder::on_decision
+    /// [`track_stats_max_keys`]: RateLimiterBuilder::track_stats_max_keys
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("A", 2, Duration::from_secs(60))
+    ///     .track_stats()
+    ///     .done();
+    ///
+    /// assert!(limiter.consume(&"A", 2).is_ok());
+    /// assert!(limiter.consume(&"A", 1).is_err());
+    ///
+    /// let stats = limiter.stats(&"A").unwrap();
+    /// assert_eq!((stats.allowed(), stats.rejected(), stats.tokens()), (1, 1, 2));
+    /// ```
+    pub fn track_stats(self) -> Self
+    where
+        K: Clone,
+    {
+        self.track_stats_max_keys(DEFAULT_MAX_KEYS)
+    }
+
+    /// Same as [`track_stats`], but tracks up to `max_keys` keys, evicting
+    /// the counters of the least recently seen ones beyond that, the same way
+    /// [`KeyedRateLimiter::max_keys`] evicts buckets.
+    ///
+    /// [`track_stats`]: RateLimiterBuilder::track_stats
+    /// [`KeyedRateLimiter::max_keys`]: crate::KeyedRateLimiter::max_keys
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::<&str>::configure()
+    ///     .track_stats_max_keys(1)
+    ///     .done();
+    ///
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    /// assert!(limiter.consume(&"B", 1).is_ok());
+    /// assert!(limiter.stats(&"A").is_none());
+    /// assert_eq!(limiter.stats(&"B").unwrap().allowed(), 1);
+    /// ```
+    pub fn track_stats_max_keys(mut self, max_keys: usize) -> Self
+    where
+        K: Clone,
+    {
+        self.stats = Some((K::clone, max_keys));
+        self
+    }
+
+    /// Queues every rejected request to a `sink`, e.g. to keep an audit trail
+    /// of throttled and blocked entities.
+    ///
+    /// The same rejections are queued as reported via [`on_decision`]. The
+    /// sink receives them on a dedicated thread, and at most `capacity` of
+    /// them wait to be received, so that a slow sink doesn't stall consuming
+    /// functions. Rejections that don't fit into the queue are dropped, and
+    /// counted by [`RateLimiter::dropped_events`].
+    ///
+    /// [`on_decision`]: RateLimiterBuilder::on_decision
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::sync::mpsc;
+    /// use std::time::Duration;
+    /// use youshallnotpass::{Error, RateLimiter, RejectionEvent};
+    ///
+    /// let (sender, receiver) = mpsc::channel();
+    /// let limiter = RateLimiter::configure()
+    ///     .block("A")
+    ///     .event_sink(move |event: RejectionEvent<_>| sender.send(event).unwrap(), 1024)
+    ///     .done();
+    ///
+    /// assert!(limiter.consume(&"A", 1).is_err());
+    ///
+    /// let event = receiver.recv().unwrap();
+    /// assert_eq!((event.key(), event.tokens()), (&"A", 1));
+    /// assert_eq!(event.reason(), &Error::Blocked);
+    /// ```
+    pub fn event_sink<E: EventSink<K>>(mut self, sink: E, capacity: usize) -> Self
+    where
+        K: Send + 'static,
+    {
+        self.events = Some(EventQueue::spawn(sink, capacity));
+        self
+    }
+
+    /// Sets a random delay within the `range` to be added to
+    /// [`Error::RetryAfter`] durations returned by consuming functions.
+    ///
+    /// When many clients are rejected at the same time, they get identical
+    /// delays, and all come back at once. Jitter spreads their retries over
+    /// time. The limiter doesn't depend on any random number generator, and
+    /// draws uniformly distributed numbers from the `random` function instead.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::{Error, RateLimiter};
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("A", 1, Duration::from_secs(60))
+    ///     // e.g. `rand::random` in real code
+    ///     .jitter(Duration::ZERO..Duration::from_secs(10), || u64::MAX / 2)
+    ///     .done();
+    ///
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    /// assert!(matches!(
+    ///     limiter.consume(&"A", 1),
+    ///     Err(Error::RetryAfter(duration)) if duration > Duration::from_secs(60)
+    /// ));
+    /// ```
+    pub fn jitter<F>(mut self, range: Range<Duration>, random: F) -> Self
+    where
+        F: Fn() -> u64 + Send + Sync + 'static,
+    {
+        self.jitter = Some(Jitter::new(range, Arc::new(random)));
+        self
+    }
+
+    /// Sets a function normalizing keys before they're looked up, e.g. by
+    /// lowercasing or trimming them, stripping query strings from paths, or
+    /// mapping IPv4-mapped IPv6 addresses to IPv4 ones.
+    ///
+    /// Every function of the [`RateLimiter`] taking a key normalizes it first,
+    /// so that equivalent keys share a bucket without every caller having to
+    /// normalize them the same way. The keys of the configured policies,
+    /// including patterns, are normalized as well. A key passed by a borrowed
+    /// form is converted into an owned one to be normalized.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("Alice".to_string(), 1, Duration::from_secs(60))
+    ///     .normalize(|key: &String| key.trim().to_lowercase())
+    ///     .done();
+    ///
+    /// assert!(limiter.consume("alice", 1).is_ok());
+    /// assert!(limiter.consume(" ALICE ", 1).is_err());
+    /// ```
+    pub fn normalize<F>(mut self, normalize: F) -> Self
+    where
+        F: Fn(&K) -> K + Send + Sync + 'static,
+    {
+        self.normalize = Some(Arc::new(normalize));
+        self
+    }
+
+    /// Sets the `hasher` used to look buckets up by keys.
+    ///
+    /// By default, the hasher of [`HashMap`] is used, which resists HashDoS
+    /// attacks at the cost of speed. A faster hasher lowers the latency of
+    /// lookups when keys aren't controlled by untrusted parties, while a keyed
+    /// hasher protects the rate limiter when they are.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::hash_map::DefaultHasher;
+    /// use std::hash::BuildHasherDefault;
+    /// use std::time::Duration;
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("A", 1, Duration::from_secs(60))
+    ///     // e.g. `ahash::RandomState` in real code
+    ///     .hasher(BuildHasherDefault::<DefaultHasher>::default())
+    ///     .done();
+    ///
+    /// assert!(limiter.consume(&"A", 1).is_ok());
+    /// assert!(limiter.consume(&"A", 1).is_err());
+    /// ```
+    pub fn hasher<T>(self, hasher: T) -> RateLimiterBuilder<K, C, T> {
+        RateLimiterBuilder {
+            limits: self.limits,
+            groups: self.groups,
+            bypasses: self.bypasses,
+            default: self.default,
+            global: self.global,
+            patterns: self.patterns,
+            #[cfg(feature = "regex")]
+            regexes: self.regexes,
+            normalize: self.normalize,
+            anomalies: self.anomalies,
+            escalation: self.escalation,
+            on_decision: self.on_decision,
+            stats: self.stats,
+            events: self.events,
+            jitter: self.jitter,
+            hasher,
+            clock: self.clock,
+        }
+    }
+}
+
+impl<K: Eq + Hash, C: Clock + Clone, S: BuildHasher + Clone> RateLimiterBuilder<K, C, S> {
+    /// Constructs a [`RateLimiter`] instance with configured limiting policies.
+    ///
+    /// Once constructed, the policies of the `RateLimiter` instance can only
+    /// be changed one by one, see [`RateLimiter::add_limit`]. See [`try_done`]
+    /// to reject likely mistakes in the policies instead.
+    ///
+    /// [`try_done`]: RateLimiterBuilder::try_done
+    pub fn done(mut self) -> RateLimiter<K, C, S> {
+        self.normalize_keys();
+        self.build()
+    }
+
+    /// Same as [`done`], but rejects the policies that are likely mistakes,
+    /// see [`BuildError`] for details.
+    ///
+    /// This is useful for policies that come from a configuration, so that a
+    /// typo is reported at startup rather than silently blocking or stacking
+    /// limits.
+    ///
+    /// [`done`]: RateLimiterBuilder::done
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::{BuildError, RateLimiter};
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("A", 1, Duration::from_secs(60))
+    ///     .limit("A", 2, Duration::from_secs(60))
+    ///     .try_done();
+    /// assert_eq!(limiter.unwrap_err(), BuildError::DuplicateKey("A"));
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit("A", 0, Duration::from_secs(60))
+    ///     .try_done();
+    /// assert_eq!(limiter.unwrap_err(), BuildError::ZeroQuota(Some("A")));
+    /// ```
+    pub fn try_done(mut self) -> Result<RateLimiter<K, C, S>, BuildError<K>> {
+        self.normalize_keys();
+        self.validate()?;
+        Ok(self.build())
+    }
+
+    /// Normalizes the keys of the configured policies, including patterns,
+    /// see [`RateLimiterBuilder::normalize`].
+    fn normalize_keys(&mut self) {
+        let Some(normalize) = &self.normalize else {
+            return;
+        };
+        for (key, _) in &mut self.limits {
+            *key = normalize(key);
+        }
+        for key in self.groups.iter_mut().flat_map(|(keys, _)| keys) {
+            *key = normalize(key);
+        }
+        for (key, _) in &mut self.bypasses {
+            *key = normalize(key);
+        }
+        if let Some(patterns) = &mut self.patterns {
+            for key in &mut patterns.keys {
+                *key = normalize(key);
+            }
+        }
+    }
+
+    /// Reports the first policy that is likely a mistake, if any.
+    fn validate(&mut self) -> Result<(), BuildError<K>> {
+        fn check<K>(quota: &Quota) -> Option<fn(Option<K>) -> BuildError<K>> {
+            if quota.is_blocking() && *quota != Quota::blocked() {
+                Some(BuildError::ZeroQuota)
+            } else if quota.overflows() {
+                Some(BuildError::IntervalOverflow)
+            } else {
+                None
+            }
+        }
+
+        let keyless = self.default.iter().map(|(quota, _)| quota);
+        let keyless = keyless.chain(&self.global);
+        #[cfg(feature = "regex")]
+        let keyless = keyless.chain(
+            self.regexes
+                .iter()
+                .flat_map(|(policies, _)| policies.iter().map(|(_, quota)| quota)),
+        );
+        if let Some(error) = keyless.filter_map(check).next() {
+            return Err(error(None));
+        }
+
+        // keys are moved out of the builder on error, since it's discarded
+        let grouped = self
+            .groups
+            .iter()
+            .map(|(keys, _)| keys.len())
+            .sum::<usize>();
+        let mut seen = HashSet::with_capacity(self.limits.len() + grouped);
+        let invalid = self
+            .limits
+            .iter()
+            .position(|(key, quota)| !seen.insert(key) || check::<K>(quota).is_some());
+        if let Some(index) = invalid {
+            let (key, quota) = self.limits.swap_remove(index);
+            return Err(match check(&quota) {
+                Some(error) => error(Some(key)),
+                None => BuildError::DuplicateKey(key),
+            });
+        }
+        for index in 0..self.groups.len() {
+            let (keys, quota) = &self.groups[index];
+            if let Some(error) = check(quota) {
+                return Err(error(Some(self.groups.swap_remove(index).0.swap_remove(0))));
+            }
+            if let Some(position) = keys.iter().position(|key| !seen.insert(key)) {
+                let key = self.groups.swap_remove(index).0.swap_remove(position);
+                return Err(BuildError::DuplicateKey(key));
+            }
+        }
+        Ok(())
+    }
+
+    /// Constructs a [`RateLimiter`] instance with the keys of the policies
+    /// normalized already.
+    fn build(self) -> RateLimiter<K, C, S> {
+        let mut buckets = HashMap::with_capacity_and_hasher(self.limits.len(), self.hasher.clone());
+        let mut stacked: HashMap<K, Vec<_>, S> = HashMap::with_hasher(self.hasher.clone());
+        for (key, quota) in self.limits {
+            let bucket = TokenBucket::from_quota_with_clock(quota, self.clock.clone());
+            // the key is moved into either of the maps, so the entry API of
+            // the first one doesn't help
+            #[allow(clippy::map_entry)]
+            if buckets.contains_key(&key) {
+                stacked.entry(key).or_default().push(bucket);
+            } else {
+                buckets.insert(key, bucket);
+            }
+        }
+
+        let mut groups = HashMap::with_hasher(self.hasher.clone());
+        let mut group_buckets = Vec::with_capacity(self.groups.len());
+        for (index, (keys, quota)) in self.groups.into_iter().enumerate() {
+            groups.extend(keys.into_iter().map(|key| (key, index)));
+            group_buckets.push(TokenBucket::from_quota_with_clock(
+                quota,
+                self.clock.clone(),
+            ));
+        }
+        let bypasses = Bypasses::with_hasher(self.hasher.clone());
+        for (key, bypass) in self.bypasses {
+            bypasses.insert(key, bypass);
+        }
+        let stats = self.stats.map(|(clone_key, max_keys)| {
+            StatsTracker::with_hasher(clone_key, max_keys, self.hasher.clone())
+        });
+        let escalation = self
+            .escalation
+            .map(|policy| Escalation::with_hasher(policy, self.clock.clone(), self.hasher.clone()));
+        let anomalies = self
+            .anomalies
+            .map(|(window, windows, threshold, callback)| {
+                AnomalyDetector::with_hasher(
+                    (window, windows, threshold),
+                    callback,
+                    self.clock.clone(),
+                    self.hasher.clone(),
+                )
+            });
+
+        RateLimiter {
+            buckets,
+            stacked,
+            groups,
+            group_buckets: group_buckets.into_boxed_slice(),
+            bypasses,
+            patterns: self.patterns,
+            #[cfg(feature = "regex")]
+            regexes: self
+                .regexes
+                .map(|(policies, as_str)| RegexPolicies::new(policies, as_str, &self.clock)),
+            global: self
+                .global
+                .map(|quota| TokenBucket::from_quota_with_clock(quota, self.clock.clone())),
+            runtime: RuntimePolicies::with_hasher(self.hasher.clone()),
+            default: self.default.map(|(quota, clone_key)| {
+                DefaultPolicy::with_hasher(
+                    quota,
+                    clone_key,
+                    |quota, clock: &C| TokenBucket::from_quota_with_clock(quota, clock.clone()),
+                    self.clock.clone(),
+                    self.hasher,
+                )
+            }),
+            anomalies,
+            escalation,
+            on_decision: self.on_decision,
+            stats,
+            events: self.events,
+            jitter: self.jitter,
+            normalize: self.normalize,
+            clock: self.clock,
+        }
+    }
+
+    /// Constructs a [`DenseRateLimiter`] instance with configured limiting
+    /// policies.
+    ///
+    /// The buckets are stored in an array indexed by the keys, which avoids
+    /// hashing on every request. This is only suitable for keys that are small
+    /// integers, e.g. enum discriminants, as the array is as long as the
+    /// largest key.
+    ///
+    /// Only limits, quotas, groups of a single key, anomaly detection and
+    /// jitter are supported, so keys without a policy of their own are never
+    /// limited. Policies aren't stacked, and the last one set for a key wins.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any other option is set, e.g. a default or a global policy,
+    /// see [`try_done_dense`] to get it reported instead.
+    ///
+    /// [`try_done_dense`]: RateLimiterBuilder::try_done_dense
+    pub fn done_dense(self) -> DenseRateLimiter<K, C>
+    where
+        K: Copy + Into<usize>,
+    {
+        if let Some(option) = self.unsupported_dense() {
+            panic!("{} is not supported by the dense rate limiter", option);
+        }
+        self.build_dense()
+    }
+
+    /// Same as [`done_dense`], but reports the first option that isn't
+    /// supported instead of panicking, and rejects the policies that are
+    /// likely mistakes, including stacked ones, same as [`try_done`].
+    ///
+    /// [`done_dense`]: RateLimiterBuilder::done_dense
+    /// [`try_done`]: RateLimiterBuilder::try_done
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::{BuildError, RateLimiter};
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit(1_usize, 1, Duration::from_secs(60))
+    ///     .global_limit(10, Duration::from_secs(60))
+    ///     .try_done_dense();
+    /// assert_eq!(limiter.err(), Some(BuildError::Unsupported("global_limit")));
+    ///
+    /// let limiter = RateLimiter::configure()
+    ///     .limit(1_usize, 1, Duration::from_secs(60))
+    ///     .limit(1_usize, 2, Duration::from_secs(60))
+    ///     .try_done_dense();
+    /// assert_eq!(limiter.err(), Some(BuildError::DuplicateKey(1)));
+    /// ```
+    pub fn try_done_dense(mut self) -> Result<DenseRateLimiter<K, C>, BuildError<K>>
+    where
+        K: Copy + Into<usize>,
+    {
+        if let Some(option) = self.unsupported_dense() {
+            return Err(BuildError::Unsupported(option));
+        }
+        self.validate()?;
+        Ok(self.build_dense())
+    }
+
+    /// Returns the name of the builder method setting the first option that
+    /// the dense rate limiter doesn't support, if any.
+    fn unsupported_dense(&self) -> Option<&'static str> {
+        #[cfg(feature = "regex")]
+        let regexes = self.regexes.is_some();
+        #[cfg(not(feature = "regex"))]
+        let regexes = false;
+        let options = [
+            (
+                self.bypasses
+                    .iter()
+                    .any(|(_, bypass)| *bypass == Bypass::Allow),
+                "always_allow",
+            ),
+            (
+                self.bypasses
+                    .iter()
+                    .any(|(_, bypass)| *bypass == Bypass::Block),
+                "always_block",
+            ),
+            (self.groups.iter().any(|(keys, _)| keys.len() > 1), "group"),
+            (self.patterns.is_some(), "pattern"),
+            (regexes, "regex_limit"),
+            (self.default.is_some(), "default_limit"),
+            (self.global.is_some(), "global_limit"),
+            (self.escalation.is_some(), "escalate_after"),
+            (self.on_decision.is_some(), "on_decision"),
+            (self.stats.is_some(), "track_stats"),
+            (self.events.is_some(), "event_sink"),
+            (self.normalize.is_some(), "normalize"),
+        ];
+        options
+            .into_iter()
+            .find_map(|(set, option)| set.then_some(option))
+    }
+
+    /// Constructs a [`DenseRateLimiter`] instance with the supported policies.
+    fn build_dense(self) -> DenseRateLimiter<K, C>
+    where
+        K: Copy + Into<usize>,
+    {
+        let mut buckets = Vec::new();
+        let grouped = self
+            .groups
+            .into_iter()
+            .map(|(mut keys, quota)| (keys.swap_remove(0), quota));
+        for (key, quota) in grouped.chain(self.limits) {
+            let index = key.into();
+            if index >= buckets.len() {
+                buckets.resize_with(index + 1, || None);
+            }
+            buckets[index] = Some(TokenBucket::from_quota_with_clock(
+                quota,
+                self.clock.clone(),
+            ));
+        }
+
+        DenseRateLimiter::new(
+            buckets,
+            self.jitter,
+            self.anomalies
+                .map(|(window, windows, threshold, callback)| {
+                    AnomalyDetector::new(window, windows, threshold, callback, self.clock)
+                }),
+        )
+    }
+}
+
+/// Consumes `tokens` from the `bucket`, and from every of the `tiers` on top
+/// of it, at the given moment (`at`), or at the current one, so that either
+/// all of them are charged, or none is. Returns the least of the numbers of
+/// tokens remaining in them.
+#[inline]
+fn consume_stacked<C: Clock>(
+    bucket: &TokenBucket<C>,
+    tiers: Tiers<'_, C>,
+    tokens: usize,
+    at: Option<Instant>,
+) -> Result<u64, Error> {
+    if tiers.clone().next().is_none() {
+        return match at {
+            Some(at) => bucket.consume_at(tokens, at).map(|()| 0),
+            None => bucket.consume_remaining(tokens),
+        };
+    }
+    consume_all(iter::once(bucket).chain(tiers), tokens, at)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex, OnceLock};
+
+    #[test]
+    fn new() {
+        let limiter = RateLimiter::configure()
+            .limit("A", 3, Duration::from_secs(60))
+            .done();
+
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        // we don't mock time in this test case, so checking the retry-after delay would be unreliable
+        assert!(matches!(
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(_))
+        ));
+    }
+
+    #[test]
+    fn blocked_limit() {
+        let limiter = RateLimiter::configure()
+            .limit("A", 0, Duration::from_secs(60))
+            .done();
+
+        // using a limit of 0 blocks the given entity
+        assert_eq!(limiter.consume(&"A", 1), Err(Error::Blocked));
+        assert_eq!(limiter.consume(&"A", 1), Err(Error::Blocked));
+        assert_eq!(limiter.consume(&"A", 1), Err(Error::Blocked));
+    }
+
+    #[test]
+    fn blocked_duration() {
+        let limiter = RateLimiter::configure()
+            .limit("A", 42, Duration::from_secs(0))
+            .done();
+
+        assert_eq!(limiter.consume(&"A", 1), Err(Error::Blocked));
+        assert_eq!(limiter.consume(&"A", 1), Err(Error::Blocked));
+        assert_eq!(limiter.consume(&"A", 1), Err(Error::Blocked));
+    }
+
+    #[test]
+    fn capacity_is_one() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 1, Duration::from_secs(1))
+            .done();
+
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_secs(1)))
+        );
+
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_secs(1)))
+        );
+    }
+
+    #[test]
+    fn capacity_gt_one() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 3, Duration::from_secs(1))
+            .done();
+
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_nanos(333_333_332)))
+        );
+
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_nanos(333_333_332)))
+        );
+    }
+
+    #[test]
+    fn period_gt_one() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 1, Duration::from_secs(3))
+            .done();
+
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_secs(3)))
+        );
+
+        *now.lock().unwrap() += Duration::from_secs(2);
+        assert_eq!(
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_secs(1)))
+        );
+
+        *now.lock().unwrap() += Duration::from_secs(3);
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_secs(3)))
+        );
+    }
+
+    #[test]
+    fn consume_over_time() {
+        let t0 = Instant::now();
+        let now = Mutex::new(t0);
+        let clock = || *now.lock().unwrap();
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 4, Duration::from_secs(1))
+            .done();
+
+        // consume first token
+        *now.lock().unwrap() = t0;
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+
+        // consume second token
+        *now.lock().unwrap() = t0 + Duration::from_millis(50);
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+
+        // consume third & fourth tokens
+        *now.lock().unwrap() = t0 + Duration::from_millis(150);
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+
+        // ensure we are out of tokens
+        assert_eq!(
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_millis(100)))
+        );
+
+        // one token is not yet replenished
+        *now.lock().unwrap() = t0 + Duration::from_millis(249);
+        assert_eq!(
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_millis(1)))
+        );
+
+        // one token is replenished
+        *now.lock().unwrap() = t0 + Duration::from_millis(250);
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+
+        // ensure we are out of tokens again
+        assert_eq!(
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_millis(250)))
+        );
+
+        // two tokens are replenished
+        *now.lock().unwrap() = t0 + Duration::from_millis(750);
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_millis(250)))
+        );
+    }
+
+    #[test]
+    fn consume_gt_one() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 3, Duration::from_secs(1))
+            .done();
+
+        // consume all tokens at once
+        assert_eq!(limiter.consume(&"A", 3), Ok(()));
+        assert_eq!(
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_nanos(333_333_332)))
+        );
+
+        // sequentially consume tokens
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(limiter.consume(&"A", 2), Ok(()));
+        assert_eq!(
+            limiter.consume(&"A", 2),
+            Err(Error::RetryAfter(Duration::from_nanos(333_333_332)))
+        );
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_nanos(333_333_332)))
+        );
+
+        // two tokens are replenished
+        *now.lock().unwrap() += Duration::from_millis(700);
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_nanos(299_999_998)))
+        );
+    }
+
+    #[test]
+    fn multiple_buckets() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 2, Duration::from_secs(1))
+            .limit("B", 1, Duration::from_secs(2))
+            .done();
+
+        // consume tokens in A and B
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_millis(500)))
+        );
+        assert_eq!(limiter.consume(&"B", 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&"B", 1),
+            Err(Error::RetryAfter(Duration::from_secs(2)))
+        );
+
+        // tokens in A are replenished, but not in B
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_millis(500)))
+        );
+        assert_eq!(
+            limiter.consume(&"B", 1),
+            Err(Error::RetryAfter(Duration::from_secs(1)))
+        );
+
+        // tokens in A and B are replenished
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_millis(500)))
+        );
+        assert_eq!(limiter.consume(&"B", 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&"B", 1),
+            Err(Error::RetryAfter(Duration::from_secs(2)))
+        );
+    }
+
+    #[test]
+    fn compound_key() {
+        #[allow(clippy::upper_case_acronyms)]
+        #[derive(Clone, Eq, PartialEq, Hash)]
+        enum MyHttpVerb {
+            GET,
+            PUT,
+        }
+
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit((MyHttpVerb::PUT, "/foobar"), 1, Duration::from_secs(1))
+            .limit((MyHttpVerb::GET, "/foobar"), 3, Duration::from_secs(1))
+            .limit((MyHttpVerb::GET, "/spam"), 2, Duration::from_secs(1))
+            .done();
+
+        assert_eq!(limiter.consume(&(MyHttpVerb::GET, "/foobar"), 1), Ok(()));
+        assert_eq!(limiter.consume(&(MyHttpVerb::GET, "/foobar"), 1), Ok(()));
+        assert_eq!(limiter.consume(&(MyHttpVerb::GET, "/foobar"), 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&(MyHttpVerb::GET, "/foobar"), 1),
+            Err(Error::RetryAfter(Duration::from_nanos(333_333_332)))
+        );
+
+        assert_eq!(limiter.consume(&(MyHttpVerb::PUT, "/foobar"), 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&(MyHttpVerb::PUT, "/foobar"), 1),
+            Err(Error::RetryAfter(Duration::from_secs(1)))
+        );
+
+        assert_eq!(limiter.consume(&(MyHttpVerb::GET, "/spam"), 1), Ok(()));
+        assert_eq!(limiter.consume(&(MyHttpVerb::GET, "/spam"), 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&(MyHttpVerb::GET, "/spam"), 1),
+            Err(Error::RetryAfter(Duration::from_millis(500)))
+        );
+    }
+
+    #[test]
+    fn rekey() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let mut limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 2, Duration::from_secs(1))
+            .limit("B", 4, Duration::from_secs(1))
+            .done();
+
+        assert_eq!(limiter.consume(&"A", 2), Ok(()));
+        assert_eq!(limiter.consume(&"B", 1), Ok(()));
+
+        limiter.rekey(|key| match key {
+            "A" => "X",
+            key => key,
+        });
+
+        // the state of a renamed bucket is preserved
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&"X", 1),
+            Err(Error::RetryAfter(Duration::from_millis(500)))
+        );
+        assert_eq!(limiter.consume(&"B", 3), Ok(()));
+        assert_eq!(
+            limiter.consume(&"B", 1),
+            Err(Error::RetryAfter(Duration::from_millis(250)))
+        );
+    }
+
+    #[test]
+    fn rekey_collision() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let mut limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 4, Duration::from_secs(1))
+            .limit("B", 4, Duration::from_secs(1))
+            .limit("C", 4, Duration::from_secs(1))
+            .done();
+
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(limiter.consume(&"B", 2), Ok(()));
+        assert_eq!(limiter.consume(&"C", 3), Ok(()));
+
+        // consumed tokens are summed up, but never exceed the bucket capacity
+        limiter.rekey(|key| match key {
+            "A" | "B" => "AB",
+            _ => "CC",
+        });
+        assert_eq!(limiter.consume(&"AB", 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&"AB", 1),
+            Err(Error::RetryAfter(Duration::from_millis(250)))
+        );
+
+        limiter.rekey(|_| "ABC");
+        assert_eq!(
+            limiter.consume(&"ABC", 1),
+            Err(Error::RetryAfter(Duration::from_millis(250)))
+        );
+    }
+
+    #[test]
+    fn consume_speculative() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 1, Duration::from_secs(1))
+            .done();
+
+        let speculation = limiter.consume_speculative(&"A", 1, Duration::from_secs(1));
+        assert_eq!(
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_secs(1)))
+        );
+        drop(speculation);
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+
+        // keys without a policy are never limited
+        let speculation = limiter.consume_speculative(&"B", 1, Duration::from_secs(1));
+        assert!(speculation.unwrap().confirm());
+    }
+
+    #[test]
+    fn static_limiter() {
+        static LIMITER: OnceLock<RateLimiter<&str>> = OnceLock::new();
+        let limiter = LIMITER.get_or_init(|| {
+            RateLimiter::configure()
+                .limit("A", 1, Duration::from_secs(60))
+                .on_anomaly(Duration::from_secs(60), 1, 0.5, |_, _| {})
+                .done()
+        });
+
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert!(limiter.consume(&"A", 1).is_err());
+    }
+
+    #[test]
+    fn on_anomaly() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let anomalies = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&anomalies);
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 1, Duration::from_secs(1))
+            .limit("B", 1, Duration::from_secs(1))
+            .on_anomaly(Duration::from_secs(1), 2, 0.5, move |key, fraction| {
+                recorded.lock().unwrap().push((*key, fraction));
+            })
+            .done();
+
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert!(limiter.consume(&"A", 1).is_err());
+        assert!(limiter.consume(&"A", 1).is_err());
+        assert_eq!(limiter.consume(&"B", 1), Ok(()));
+        assert_eq!(limiter.consume(&"C", 1), Ok(()));
+
+        // the callback is invoked once the threshold is crossed
+        assert_eq!(*anomalies.lock().unwrap(), vec![("A", 0.5)]);
+
+        // the fraction is computed over the recent windows only
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        *now.lock().unwrap() += Duration::from_millis(500);
+        assert!(limiter.consume(&"A", 1).is_err());
+        assert_eq!(anomalies.lock().unwrap().len(), 1);
+        assert!(limiter.consume(&"A", 1).is_err());
+        assert_eq!(*anomalies.lock().unwrap(), vec![("A", 0.5), ("A", 0.5)]);
+    }
+
+    #[test]
+    fn on_decision() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let decisions = Arc::new(Mutex::new(Vec::new()));
+        let reported = Arc::clone(&decisions);
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 2, Duration::from_secs(1))
+            .always_block("B")
+            .normalize(|key: &&str| if *key == "a" { "A" } else { key })
+            .on_decision(move |key, tokens, outcome| {
+                reported
+                    .lock()
+                    .unwrap()
+                    .push((*key, tokens, outcome.map_err(Error::to_string)));
+            })
+            .done();
+
+        assert_eq!(limiter.consume(&"a", 1), Ok(()));
+        assert!(limiter.consume_detailed(&"A", 2).is_err());
+        limiter.consume_permit(&"A", 1).unwrap().commit();
+        assert!(limiter.consume_at(&"B", 1, clock()).is_err());
+        assert!(limiter
+            .consume_speculative(&"C", 3, Duration::from_secs(1))
+            .is_ok());
+
+        // neither checks, nor admissions, nor refunds are decisions
+        assert!(limiter.check(&"A", 1).is_err());
+        limiter.admit(&"A", 1);
+        limiter.refund(&"A", 1);
+
+        assert_eq!(
+            *decisions.lock().unwrap(),
+            vec![
+                ("A", 1, Ok(())),
+                ("A", 2, Err("Retry after 0.5 seconds".to_string())),
+                ("A", 1, Ok(())),
+                ("B", 1, Err("Entity is blocked".to_string())),
+                ("C", 3, Ok(())),
+            ]
+        );
+    }
+
+    #[test]
+    fn stats() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let mut limiter = RateLimiter::with_clock(&clock)
+            .limit("A".to_string(), 2, Duration::from_secs(1))
+            .limit("B".to_string(), 1, Duration::from_secs(1))
+            .always_block("C".to_string())
+            .normalize(|key: &String| key.to_uppercase())
+            .track_stats()
+            .done();
+        let counts = |stats: Stats| (stats.allowed(), stats.rejected(), stats.tokens());
+
+        assert_eq!(limiter.consume("a", 2), Ok(()));
+        assert!(limiter.consume("A", 1).is_err());
+        assert!(limiter.consume_at("B", 1, clock()).is_ok());
+        assert!(limiter.consume("c", 1).is_err());
+        assert_eq!(limiter.consume("D", 5), Ok(()));
+        assert!(limiter.check("B", 1).is_err());
+        assert!(limiter
+            .consume_speculative("b", 1, Duration::from_secs(1))
+            .is_err());
+
+        assert_eq!(limiter.stats("a").map(counts), Some((1, 1, 2)));
+        assert_eq!(limiter.stats("B").map(counts), Some((1, 1, 1)));
+        assert_eq!(limiter.stats("C").map(counts), Some((0, 1, 0)));
+        assert_eq!(limiter.stats("D").map(counts), Some((1, 0, 5)));
+        assert_eq!(limiter.stats("E"), None);
+        let mut all: Vec<_> = limiter
+            .stats_iter()
+            .map(|(key, stats)| (key, counts(stats)))
+            .collect();
+        all.sort();
+        assert_eq!(
+            all,
+            vec![
+                ("A".to_string(), (1, 1, 2)),
+                ("B".to_string(), (1, 1, 1)),
+                ("C".to_string(), (0, 1, 0)),
+                ("D".to_string(), (1, 0, 5)),
+            ]
+        );
+
+        // statistics follow their keys, and are summed up for merged ones
+        limiter.rekey(|key| if key == "B" { "A".to_string() } else { key });
+        assert_eq!(limiter.stats("A").map(counts), Some((2, 2, 3)));
+        assert_eq!(limiter.stats("B"), None);
+
+        // and start afresh for a clone
+        assert_eq!(limiter.clone().stats("A"), None);
+        assert_eq!(limiter.clone().stats_iter().count(), 0);
+
+        // nothing is counted unless tracked
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 1, Duration::from_secs(1))
+            .done();
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(limiter.stats(&"A"), None);
+        assert_eq!(limiter.stats_iter().count(), 0);
+
+        // an eighth of the keys is evicted at once, the least recently seen
+        // ones first
+        let limiter = RateLimiter::with_clock(&clock)
+            .track_stats_max_keys(8)
+            .done();
+        for key in ["A", "B", "C", "D", "E", "F", "G", "H", "A", "I"] {
+            assert_eq!(limiter.consume(&key, 1), Ok(()));
+        }
+        let mut keys: Vec<_> = limiter.stats_iter().map(|(key, _)| key).collect();
+        keys.sort();
+        assert_eq!(keys, ["A", "D", "E", "F", "G", "H", "I"]);
+        assert_eq!(limiter.stats(&"A").map(counts), Some((2, 0, 2)));
+    }
+
+    #[test]
+    fn event_sink() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("A".to_string(), 2, Duration::from_secs(1))
+            .block("B".to_string())
+            .normalize(|key: &String| key.to_uppercase())
+            .event_sink(move |event| sender.send(event).unwrap(), 16)
+            .done();
+
+        assert_eq!(limiter.consume("a", 2), Ok(()));
+        assert!(limiter.consume("A", 1).is_err());
+        assert!(limiter.consume_at("b", 3, clock()).is_err());
+        assert!(limiter.consume("C", 5).is_ok());
+
+        // only rejections are queued, under normalized keys
+        let events: Vec<_> = receiver.iter().take(2).collect();
+        assert_eq!(
+            events
+                .iter()
+                .map(|event| (event.key().as_str(), event.tokens(), event.reason()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("A", 1, &Error::RetryAfter(Duration::from_millis(500))),
+                ("B", 3, &Error::Blocked),
+            ]
+        );
+        assert!(events[0].timestamp() <= events[1].timestamp());
+
+        // a clone queues to the same sink
+        assert!(limiter.clone().consume("B", 1).is_err());
+        assert_eq!(receiver.recv().unwrap().tokens(), 1);
+        assert_eq!(limiter.dropped_events(), 0);
+
+        // the sink thread exits once the rate limiter is dropped
+        drop(limiter);
+        assert!(receiver.recv().is_err());
+    }
+
+    #[test]
+    fn escalate_after() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let advance = |by| *now.lock().unwrap() += by;
+        let mut limiter = RateLimiter::with_clock(&clock)
+            .limit("A".to_string(), 1, Duration::from_secs(1))
+            .limit("B".to_string(), 1, Duration::from_secs(1))
+            .always_allow("B".to_string())
+            .escalate_after(3, Duration::from_secs(10), Duration::from_secs(60))
+            .done();
+
+        // rejections that fall out of the window don't count
+        assert_eq!(limiter.consume("A", 1), Ok(()));
+        assert!(limiter.consume("A", 1).is_err());
+        assert!(limiter.consume("A", 1).is_err());
+        advance(Duration::from_secs(10));
+        assert_eq!(limiter.consume("A", 1), Ok(()));
+        assert!(limiter.consume("A", 1).is_err());
+        assert!(limiter.consume("A", 1).is_err());
+        assert!(matches!(
+            limiter.consume_at("A", 1, clock()),
+            Err(Error::RetryAfter(_))
+        ));
+
+        // the key is banned even once its bucket is refilled
+        advance(Duration::from_secs(1));
+        assert_eq!(limiter.check("A", 1), Err(Error::Blocked));
+        assert_eq!(limiter.consume("A", 1), Err(Error::Blocked));
+
+        // and its ban follows it, as well as to a clone
+        limiter.rekey(|key| key.to_lowercase());
+        assert_eq!(limiter.consume("a", 1), Err(Error::Blocked));
+        assert_eq!(limiter.clone().consume("a", 1), Err(Error::Blocked));
+
+        // rejections of a banned key don't extend the ban
+        advance(Duration::from_secs(59));
+        assert_eq!(limiter.consume("a", 1), Ok(()));
+        assert!(limiter.consume("a", 1).is_err());
+
+        // keys that are always allowed are never banned
+        for _ in 0..5 {
+            assert_eq!(limiter.consume("b", 1), Ok(()));
+        }
+
+        // a ban can be lifted early
+        assert!(limiter.consume("a", 1).is_err());
+        assert!(limiter.consume("a", 1).is_err());
+        assert_eq!(limiter.consume("a", 1), Err(Error::Blocked));
+        limiter.pardon("a");
+        assert!(matches!(limiter.consume("a", 1), Err(Error::RetryAfter(_))));
+
+        // requests exceeding the capacity aren't violations
+        for _ in 0..3 {
+            assert_eq!(limiter.consume("a", 2), Err(Error::ExceedsCapacity));
+        }
+        assert!(matches!(limiter.consume("a", 1), Err(Error::RetryAfter(_))));
+    }
+
+    #[test]
+    fn apply() {
+        let old: PolicySet<&str> = [
+            ("A", Quota::per_minute(1)),
+            ("B", Quota::per_minute(1)),
+            ("C", Quota::per_minute(1)),
+        ]
+        .into_iter()
+        .collect();
+        let mut limiter = RateLimiter::configure().policies(old.clone()).done();
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(limiter.consume(&"B", 1), Ok(()));
+        assert_eq!(limiter.consume(&"C", 1), Ok(()));
+
+        let mut new = old.clone();
+        new.insert("B", Quota::per_minute(2));
+        new.remove(&"C");
+        new.insert("D", Quota::per_minute(1));
+        limiter.apply(PolicySet::diff(&old, &new));
+
+        // untouched keys keep their state, and changed ones keep their tokens
+        assert!(limiter.consume(&"A", 1).is_err());
+        assert!(matches!(
+            limiter.consume(&"B", 1),
+            Err(Error::RetryAfter(duration)) if duration <= Duration::from_secs(30)
+        ));
+        assert_eq!(limiter.consume_remaining(&"C", 100), Ok(None));
+        assert_eq!(limiter.consume(&"D", 1), Ok(()));
+        assert!(limiter.consume(&"D", 1).is_err());
+    }
+
+    #[test]
+    fn jitter() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 1, Duration::from_secs(60))
+            .limit("B", 0, Duration::from_secs(60))
+            .jitter(Duration::from_secs(1)..Duration::from_secs(3), || {
+                u64::MAX / 2 + 1
+            })
+            .done();
+
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_secs(62)))
+        );
+        assert_eq!(
+            limiter.consume_at(&"A", 1, clock()),
+            Err(Error::RetryAfter(Duration::from_secs(62)))
+        );
+        assert!(matches!(
+            limiter.consume_permit(&"A", 1),
+            Err(Error::RetryAfter(duration)) if duration == Duration::from_secs(62)
+        ));
+
+        // introspection reports the actual delay, and other errors are intact
+        assert_eq!(
+            limiter.check(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_secs(60)))
+        );
+        assert_eq!(limiter.consume(&"B", 1), Err(Error::Blocked));
+    }
+
+    #[test]
+    fn debug_clone() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 2, Duration::from_secs(1))
+            .done();
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+
+        let clone = limiter.clone();
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(
+            format!("{:?}", limiter),
+            "RateLimiter { buckets: {\"A\": TokenBucket { capacity: 2, \
+             time_per_token: Some(500ms), available: 0, .. }}, .. }"
+        );
+        assert_eq!(clone.consume_remaining(&"A", 1), Ok(Some(0)));
+    }
+
+    #[test]
+    fn lint() {
+        let limiter = RateLimiter::configure()
+            .limit("A", 1, Duration::from_secs(1))
+            .limit("B", 1_000, Duration::from_secs(1))
+            .limit("C", 1_001, Duration::from_secs(1))
+            .limit("D", 0, Duration::from_secs(1))
+            .done();
+        assert_eq!(
+            limiter.lint(),
+            vec![Lint::SubMillisecondTokenTime {
+                key: &"C",
+                time_per_token: Duration::from_nanos(999_000),
+            }]
+        );
+
+        // a policy stacked on top of a stricter one has no effect
+        let limiter = RateLimiter::configure()
+            .limit("A", 1, Duration::from_secs(1))
+            .limit("A", 0, Duration::from_secs(1))
+            .done();
+        assert_eq!(limiter.lint(), vec![Lint::Shadowed { key: &"A" }]);
+        assert_eq!(limiter.consume(&"A", 1), Err(Error::Blocked));
+
+        // bursts above the global one are never allowed
+        let limiter = RateLimiter::configure()
+            .limit("A", 10, Duration::from_secs(1))
+            .limit("B", 5, Duration::from_secs(1))
+            .limit("C", 0, Duration::from_secs(1))
+            .global_limit(5, Duration::from_secs(1))
+            .done();
+        assert_eq!(
+            limiter.lint(),
+            vec![Lint::BurstAboveGlobal {
+                key: &"A",
+                burst: 10,
+                global_burst: 5,
+            }]
+        );
+        assert_eq!(limiter.consume(&"A", 6), Err(Error::ExceedsCapacity));
+
+        // a pattern always matched by an earlier, equally specific one
+        #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+        struct Caseless(&'static str);
+
+        impl KeyPattern for Caseless {
+            fn match_key(&self, key: &Self) -> Option<usize> {
+                let pattern = self.0.to_lowercase();
+                pattern.as_str().match_key(key.0.to_lowercase().as_str())
+            }
+        }
+
+        let limiter = RateLimiter::configure()
+            .pattern(Caseless("/API/*"), 1, Duration::from_secs(1))
+            .pattern(Caseless("/api/*"), 2, Duration::from_secs(1))
+            .pattern(Caseless("/api/*"), 3, Duration::from_secs(60))
+            .pattern(Caseless("/api/users/*"), 1, Duration::from_secs(1))
+            .done();
+        assert_eq!(
+            limiter.lint(),
+            vec![Lint::UnreachablePattern {
+                pattern: &Caseless("/api/*"),
+                by: &Caseless("/API/*"),
+            }]
+        );
+        assert_eq!(limiter.consume(&Caseless("/api/posts"), 1), Ok(()));
+        assert!(limiter.consume(&Caseless("/api/posts"), 1).is_err());
+    }
+
+    #[test]
+    fn consume_at() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 2, Duration::from_secs(1))
+            .done();
+
+        // events from different regions are charged against the same window
+        let start = *now.lock().unwrap();
+        assert_eq!(limiter.consume_at(&"A", 1, start), Ok(()));
+        assert_eq!(
+            limiter.consume_at(&"A", 1, start + Duration::from_millis(100)),
+            Ok(())
+        );
+        assert_eq!(
+            limiter.consume_at(&"A", 1, start + Duration::from_millis(200)),
+            Err(Error::RetryAfter(Duration::from_millis(300)))
+        );
+        assert_eq!(
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_millis(500)))
+        );
+
+        assert_eq!(limiter.consume_at(&"B", 1, start), Ok(()));
+    }
+
+    #[test]
+    fn default_limit() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let mut limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 3, Duration::from_secs(1))
+            .default_limit(1, Duration::from_secs(1))
+            .done();
+
+        // the explicit policy takes precedence over the default one
+        assert_eq!(limiter.consume(&"A", 3), Ok(()));
+
+        // every other key is limited on its own
+        assert_eq!(limiter.time_to_full(&"B"), Ok(Duration::ZERO));
+        assert_eq!(limiter.consume(&"B", 1), Ok(()));
+        assert_eq!(
+            limiter.consume(&"B", 1),
+            Err(Error::RetryAfter(Duration::from_secs(1)))
+        );
+        assert_eq!(limiter.time_to_full(&"B"), Ok(Duration::from_secs(1)));
+        assert_eq!(limiter.consume(&"C", 2), Err(Error::ExceedsCapacity));
+        assert_eq!(limiter.check(&"C", 1), Ok(()));
+
+        limiter.refund(&"B", 1);
+        let permit = limiter.consume_permit(&"B", 1).unwrap();
+        assert!(limiter.consume(&"B", 1).is_err());
+        drop(permit);
+        assert_eq!(limiter.consume(&"B", 1), Ok(()));
+
+        // the state of default buckets survives cloning and rekeying
+        let cloned = limiter.clone();
+        assert!(cloned.consume(&"B", 1).is_err());
+        limiter.rekey(|key| if key == "B" { "D" } else { key });
+        assert!(limiter.consume(&"D", 1).is_err());
+        assert_eq!(limiter.consume(&"B", 1), Ok(()));
+    }
+
+    #[test]
+    fn handoff() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let configure = || {
+            RateLimiter::with_clock(&clock)
+                .limit("A", 2, Duration::from_secs(60))
+                .limit("B", 2, Duration::from_secs(60))
+                .default_limit(1, Duration::from_secs(60))
+                .done()
+        };
+
+        let old = configure();
+        assert_eq!(old.consume(&"A", 2), Ok(()));
+        assert_eq!(old.consume(&"C", 1), Ok(()));
+        let handoff = old.handoff();
+        assert_eq!(handoff.len(), 3);
+
+        // keys limited by the default policy are warmed too, while untouched
+        // buckets stay full
+        let new = configure();
+        new.warm(handoff.clone());
+        assert!(new.consume(&"A", 1).is_err());
+        assert!(new.consume(&"C", 1).is_err());
+        assert_eq!(new.consume(&"B", 2), Ok(()));
+        assert_eq!(new.consume(&"D", 1), Ok(()));
+
+        // keys without a policy are ignored
+        let new = RateLimiter::with_clock(&clock)
+            .limit("A", 2, Duration::from_secs(60))
+            .done();
+        new.warm(handoff);
+        assert!(new.consume(&"A", 1).is_err());
+        assert_eq!(new.consume(&"C", 100), Ok(()));
+    }
+
+    #[test]
+    fn snapshot() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let configure = || {
+            RateLimiter::with_clock(&clock)
+                .limit("A", 2, Duration::from_secs(60))
+                .limit("A", 3, Duration::from_secs(60))
+                .global_limit(4, Duration::from_secs(60))
+                .done()
+        };
+
+        let old = configure();
+        assert_eq!(old.consume(&"A", 2), Ok(()));
+        assert_eq!(old.consume(&"B", 1), Ok(()));
+        let snapshot = old.snapshot();
+        assert_eq!(snapshot.handoff().len(), 2);
+
+        // the global bucket is restored along with the ones of keys
+        let new = configure();
+        new.restore(snapshot.clone());
+        assert!(new.consume(&"A", 1).is_err());
+        assert_eq!(new.consume(&"B", 1), Ok(()));
+        assert!(new.consume(&"B", 1).is_err());
+
+        // and is ignored if either instance has none
+        let new = RateLimiter::with_clock(&clock)
+            .limit("A", 2, Duration::from_secs(60))
+            .done();
+        new.restore(snapshot);
+        assert!(new.consume(&"A", 1).is_err());
+        assert_eq!(new.consume(&"B", 4), Ok(()));
+
+        let old = new;
+        let new = configure();
+        new.restore(old.snapshot());
+        assert!(new.consume(&"A", 1).is_err());
+        assert_eq!(new.consume(&"B", 4), Ok(()));
+    }
 
+    #[cfg(feature = "json")]
     #[test]
-    fn new() {
+    fn snapshot_version() {
         let limiter = RateLimiter::configure()
-            .limit("A", 3, Duration::from_secs(60))
+            .limit("A".to_string(), 2, Duration::from_secs(60))
+            .global_limit(4, Duration::from_secs(60))
             .done();
+        assert_eq!(limiter.consume("A", 2), Ok(()));
+        let snapshot = limiter.snapshot();
+        let json = serde_json::to_value(&snapshot).unwrap();
+        assert_eq!(json["version"], 1);
+        let decode = serde_json::from_value::<RateLimiterSnapshot<String>>;
+        assert_eq!(decode(json.clone()).unwrap(), snapshot);
 
-        assert_eq!(limiter.consume("A", 1), Ok(()));
-        assert_eq!(limiter.consume("A", 1), Ok(()));
-        assert_eq!(limiter.consume("A", 1), Ok(()));
-        // we don't mock time in this test case, so checking the retry-after delay would be unreliable
-        assert!(matches!(limiter.consume("A", 1), Err(Error::RetryAfter(_))));
+        // snapshots serialized before the version has been introduced are of
+        // the first one
+        let mut unversioned = json.clone();
+        unversioned.as_object_mut().unwrap().remove("version");
+        assert_eq!(decode(unversioned).unwrap(), snapshot);
+
+        // and the ones of an unknown version are rejected
+        let mut newer = json;
+        newer["version"] = 2.into();
+        assert!(decode(newer).is_err());
     }
 
     #[test]
-    fn blocked_limit() {
-        let limiter = RateLimiter::configure()
-            .limit("A", 0, Duration::from_secs(60))
+    fn add_remove_limit() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let mut limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 1, Duration::from_secs(1))
+            .limit("B", 1, Duration::from_secs(1))
             .done();
+        assert_eq!(limiter.consume(&"B", 1), Ok(()));
 
-        // using a limit of 0 blocks the given entity
-        assert_eq!(limiter.consume("A", 1), Err(Error::Blocked));
-        assert_eq!(limiter.consume("A", 1), Err(Error::Blocked));
-        assert_eq!(limiter.consume("A", 1), Err(Error::Blocked));
+        limiter.add_limit("C", 2, Duration::from_secs(1));
+        assert_eq!(limiter.consume(&"C", 2), Ok(()));
+        assert_eq!(
+            limiter.consume(&"C", 1),
+            Err(Error::RetryAfter(Duration::from_millis(500)))
+        );
+
+        // a replaced policy starts over with a full bucket
+        limiter.add_limit("A", 3, Duration::from_secs(1));
+        assert_eq!(limiter.consume(&"A", 3), Ok(()));
+        assert!(limiter.consume(&"A", 1).is_err());
+
+        limiter.remove_limit("A");
+        assert_eq!(limiter.consume(&"A", 100), Ok(()));
+        limiter.remove_limit("D");
+        assert_eq!(limiter.consume(&"D", 100), Ok(()));
+
+        // other keys keep their state
+        assert!(limiter.consume(&"B", 1).is_err());
+
+        // and so do the changed ones once merged by mutating functions
+        let cloned = limiter.clone();
+        limiter.rekey(|key| key);
+        for limiter in [&limiter, &cloned] {
+            assert_eq!(limiter.consume(&"A", 100), Ok(()));
+            assert!(limiter.consume(&"B", 1).is_err());
+            assert!(limiter.consume(&"C", 1).is_err());
+        }
     }
 
     #[test]
-    fn blocked_duration() {
-        let limiter = RateLimiter::configure()
-            .limit("A", 42, Duration::from_secs(0))
+    fn add_limit_default() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = RateLimiter::with_clock(&clock)
+            .default_limit(1, Duration::from_secs(1))
             .done();
 
-        assert_eq!(limiter.consume("A", 1), Err(Error::Blocked));
-        assert_eq!(limiter.consume("A", 1), Err(Error::Blocked));
-        assert_eq!(limiter.consume("A", 1), Err(Error::Blocked));
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        limiter.add_limit("A", 2, Duration::from_secs(1));
+        let permit = limiter.consume_permit(&"A", 2).unwrap();
+        assert!(limiter.consume(&"A", 1).is_err());
+        drop(permit);
+        assert_eq!(limiter.consume(&"A", 2), Ok(()));
+
+        // a removed key falls back to the default policy
+        limiter.remove_limit("A");
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert!(limiter.consume(&"A", 1).is_err());
     }
 
     #[test]
-    fn capacity_is_one() {
+    fn update_limit() {
         let now = Mutex::new(Instant::now());
         let clock = || *now.lock().unwrap();
-        let limiter = RateLimiter::with_timer(&clock)
-            .limit("A", 1, Duration::from_secs(1))
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 4, Duration::from_secs(4))
+            .limit("B", 2, Duration::from_secs(1))
+            .limit("B", 1, Duration::from_secs(60))
+            .default_limit(2, Duration::from_secs(1))
             .done();
 
-        assert_eq!(limiter.consume("A", 1), Ok(()));
+        // the tokens available are kept, and generated at the new rate
+        assert_eq!(limiter.consume(&"A", 2), Ok(()));
+        limiter.update_limit("A", 8, Duration::from_secs(4));
+        assert_eq!(limiter.consume(&"A", 2), Ok(()));
         assert_eq!(
-            limiter.consume("A", 1),
-            Err(Error::RetryAfter(Duration::from_secs(1)))
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_millis(500)))
         );
+        limiter.update_limit("A", 1, Duration::from_secs(1));
+        assert!(limiter.consume(&"A", 1).is_err());
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
 
+        // stacked limits are removed
+        assert_eq!(limiter.consume(&"B", 1), Ok(()));
+        limiter.update_limit("B", 3, Duration::from_secs(1));
+        assert_eq!(limiter.consume(&"B", 1), Ok(()));
+        assert!(limiter.consume(&"B", 1).is_err());
         *now.lock().unwrap() += Duration::from_secs(1);
-        assert_eq!(limiter.consume("A", 1), Ok(()));
-        assert_eq!(
-            limiter.consume("A", 1),
-            Err(Error::RetryAfter(Duration::from_secs(1)))
-        );
+        assert_eq!(limiter.consume(&"B", 3), Ok(()));
+
+        // keys limited by the default policy keep their state too, unless
+        // they haven't been seen yet
+        assert_eq!(limiter.consume(&"C", 2), Ok(()));
+        limiter.update_limit("C", 4, Duration::from_secs(1));
+        assert!(limiter.consume(&"C", 1).is_err());
+        limiter.update_limit("D", 4, Duration::from_secs(1));
+        assert_eq!(limiter.consume(&"D", 4), Ok(()));
     }
 
     #[test]
-    fn capacity_gt_one() {
+    fn hasher() {
+        #[derive(Clone, Default)]
+        struct CountingHasher(Arc<AtomicUsize>);
+
+        impl BuildHasher for CountingHasher {
+            type Hasher = DefaultHasher;
+
+            fn build_hasher(&self) -> DefaultHasher {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                DefaultHasher::new()
+            }
+        }
+
         let now = Mutex::new(Instant::now());
         let clock = || *now.lock().unwrap();
-        let limiter = RateLimiter::with_timer(&clock)
-            .limit("A", 3, Duration::from_secs(1))
+        let hasher = CountingHasher::default();
+        let mut limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 1, Duration::from_secs(1))
+            .default_limit(2, Duration::from_secs(1))
+            .hasher(hasher.clone())
             .done();
 
-        assert_eq!(limiter.consume("A", 1), Ok(()));
-        assert_eq!(limiter.consume("A", 1), Ok(()));
-        assert_eq!(limiter.consume("A", 1), Ok(()));
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert!(limiter.consume(&"A", 1).is_err());
+        assert_eq!(limiter.consume(&"B", 2), Ok(()));
+        assert!(limiter.consume(&"B", 1).is_err());
+        limiter.add_limit("C", 1, Duration::from_secs(1));
+        assert_eq!(limiter.consume(&"C", 1), Ok(()));
+        assert!(limiter.consume(&"C", 1).is_err());
+
+        // the hasher is kept for all the buckets, including rekeyed ones
+        limiter.rekey(|key| if key == "A" { "D" } else { key });
+        assert!(limiter.consume(&"D", 1).is_err());
+        assert!(limiter.consume(&"C", 1).is_err());
+        assert!(limiter.consume(&"B", 1).is_err());
+        assert!(hasher.0.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn stacked() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 2, Duration::from_secs(1))
+            .limit("A", 3, Duration::from_secs(60))
+            .done();
+        assert_eq!(limiter.lint(), vec![]);
+
+        assert_eq!(limiter.consume_remaining(&"A", 2), Ok(Some(0)));
         assert_eq!(
-            limiter.consume("A", 1),
-            Err(Error::RetryAfter(Duration::from_nanos(333_333_332)))
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_millis(500)))
         );
 
+        // the longest delay wins, and a rejection charges none of the tiers
         *now.lock().unwrap() += Duration::from_secs(1);
-        assert_eq!(limiter.consume("A", 1), Ok(()));
-        assert_eq!(limiter.consume("A", 1), Ok(()));
-        assert_eq!(limiter.consume("A", 1), Ok(()));
+        assert_eq!(limiter.consume_remaining(&"A", 1), Ok(Some(0)));
         assert_eq!(
-            limiter.consume("A", 1),
-            Err(Error::RetryAfter(Duration::from_nanos(333_333_332)))
+            limiter.consume(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_secs(19)))
+        );
+        assert_eq!(
+            limiter.check(&"A", 1),
+            Err(Error::RetryAfter(Duration::from_secs(19)))
         );
+        assert_eq!(limiter.consume(&"A", 4), Err(Error::ExceedsCapacity));
+
+        *now.lock().unwrap() += Duration::from_secs(20);
+        drop(limiter.consume_permit(&"A", 1).unwrap());
+        assert!(limiter
+            .consume_speculative(&"A", 1, Duration::from_secs(1))
+            .unwrap()
+            .confirm());
+        assert!(limiter.consume_permit(&"A", 1).is_err());
+        assert_eq!(limiter.time_to_full(&"A"), Ok(Duration::from_secs(59)));
+
+        // the state of every tier is handed off
+        let warm = RateLimiter::with_clock(&clock)
+            .limit("A", 2, Duration::from_secs(1))
+            .limit("A", 3, Duration::from_secs(60))
+            .done();
+        warm.warm(limiter.handoff());
+        assert_eq!(warm.handoff().len(), 2);
+        assert!(warm.check(&"A", 1).is_err());
+
+        limiter.reset(&"A");
+        assert_eq!(limiter.consume_remaining(&"A", 2), Ok(Some(0)));
     }
 
     #[test]
-    fn period_gt_one() {
+    fn stacked_concurrent() {
+        // the buckets of a key are locked together with the global one, so a
+        // request rejected by any of them never holds tokens of the others,
+        // whether it's a plain one, a permit or a speculation, and keys
+        // locking the global bucket first don't deadlock the rest; the limits
+        // of the keys add up to the global one, so that none of them is ever
+        // rejected by the global bucket unless the others leak its tokens
+        let limiter = RateLimiter::configure()
+            .limit("A", 6, Duration::from_secs(60))
+            .limit("A", 4, Duration::from_secs(60))
+            .limit("B", 46, Duration::from_secs(60))
+            .global_limit(50, Duration::from_secs(60))
+            .done();
+        let allowed = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..8)
+                .map(|thread| {
+                    let limiter = &limiter;
+                    let key = if thread % 2 == 0 { "A" } else { "B" };
+                    let timeout = Duration::from_secs(60);
+                    scope.spawn(move || {
+                        (0..30)
+                            .filter(|attempt| match attempt % 3 {
+                                0 => limiter.consume(&key, 1).is_ok(),
+                                1 => limiter.consume_permit(&key, 1).map(Permit::commit).is_ok(),
+                                _ => limiter
+                                    .consume_speculative(&key, 1, timeout)
+                                    .is_ok_and(Speculation::confirm),
+                            })
+                            .count()
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        let allowed_a: usize = allowed.iter().step_by(2).sum();
+        let allowed_b: usize = allowed.iter().skip(1).step_by(2).sum();
+        assert_eq!(allowed_a, 4);
+        assert_eq!(allowed_b, 46);
+        assert_eq!(limiter.consume_remaining(&"A", 0), Ok(Some(0)));
+    }
+
+    #[test]
+    fn global() {
         let now = Mutex::new(Instant::now());
         let clock = || *now.lock().unwrap();
-        let limiter = RateLimiter::with_timer(&clock)
-            .limit("A", 1, Duration::from_secs(3))
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 2, Duration::from_secs(1))
+            .limit("A", 3, Duration::from_secs(60))
+            .default_limit(2, Duration::from_secs(1))
+            .global_limit(4, Duration::from_secs(1))
             .done();
 
-        assert_eq!(limiter.consume("A", 1), Ok(()));
+        assert_eq!(limiter.consume_remaining(&"A", 2), Ok(Some(0)));
+        assert_eq!(limiter.consume_remaining(&"B", 1), Ok(Some(1)));
         assert_eq!(
-            limiter.consume("A", 1),
-            Err(Error::RetryAfter(Duration::from_secs(3)))
+            limiter.consume(&"C", 2),
+            Err(Error::RetryAfter(Duration::from_millis(250)))
         );
+        assert_eq!(limiter.check(&"C", 1), Ok(()));
 
-        *now.lock().unwrap() += Duration::from_secs(2);
-        assert_eq!(
-            limiter.consume("A", 1),
-            Err(Error::RetryAfter(Duration::from_secs(1)))
-        );
+        // a rejection by the global limit charges none of the key's buckets
+        *now.lock().unwrap() += Duration::from_millis(250);
+        assert_eq!(limiter.consume_remaining(&"C", 2), Ok(Some(0)));
+        *now.lock().unwrap() += Duration::from_millis(500);
+        assert!(limiter.consume_permit(&"C", 2).is_err());
+        let permit = limiter.consume_permit(&"B", 2).unwrap();
+        assert!(limiter.consume(&"D", 1).is_err());
+        drop(permit);
+        assert_eq!(limiter.consume(&"D", 1), Ok(()));
 
-        *now.lock().unwrap() += Duration::from_secs(3);
-        assert_eq!(limiter.consume("A", 1), Ok(()));
-        assert_eq!(
-            limiter.consume("A", 1),
-            Err(Error::RetryAfter(Duration::from_secs(3)))
-        );
+        // resetting a key leaves the global bucket be
+        limiter.reset(&"C");
+        assert_eq!(limiter.time_to_full(&"C"), Ok(Duration::ZERO));
+        assert!(limiter.consume(&"C", 2).is_err());
+        limiter.refund(&"C", 1);
+        assert_eq!(limiter.consume(&"C", 2), Ok(()));
+
+        // the global limit applies to keys without a policy as well
+        let limiter = RateLimiter::with_clock(&clock)
+            .global_limit(1, Duration::from_secs(1))
+            .done();
+        assert_eq!(limiter.consume_remaining(&"A", 1), Ok(Some(0)));
+        assert!(limiter.clone().consume(&"B", 1).is_err());
+        assert_eq!(limiter.admit(&"B", 1), QosClass::Red);
+
+        // and the class is the worst of all the buckets, none of which is
+        // charged by a request some of them can't admit
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 10, Duration::from_secs(1))
+            .global_limit(1, Duration::from_secs(1))
+            .done();
+        assert_eq!(limiter.admit(&"A", 1), QosClass::Yellow);
+        assert_eq!(limiter.admit(&"A", 1), QosClass::Red);
+        assert_eq!(limiter.state(&"A").unwrap().unwrap().tokens(), 9);
     }
 
     #[test]
-    fn consume_over_time() {
-        let t0 = Instant::now();
-        let now = Mutex::new(t0);
+    fn group() {
+        let now = Mutex::new(Instant::now());
         let clock = || *now.lock().unwrap();
-        let limiter = RateLimiter::with_timer(&clock)
-            .limit("A", 4, Duration::from_secs(1))
+        let mut limiter = RateLimiter::with_clock(&clock)
+            .group(["A", "B", "C", "E"], 3, Duration::from_secs(1))
+            .limit("C", 5, Duration::from_secs(1))
+            .default_limit(1, Duration::from_secs(1))
             .done();
 
-        // consume first token
-        *now.lock().unwrap() = t0;
-        assert_eq!(limiter.consume("A", 1), Ok(()));
+        // keys of a group share its bucket, unless they have a policy of
+        // their own
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert_eq!(limiter.consume_remaining(&"B", 1), Ok(Some(1)));
+        assert_eq!(limiter.consume(&"C", 5), Ok(()));
+        assert_eq!(limiter.consume(&"E", 1), Ok(()));
+        assert!(limiter.check(&"A", 1).is_err());
+        *now.lock().unwrap() += Duration::from_secs(1);
+        limiter.reset(&"B");
+        assert_eq!(limiter.consume(&"A", 3), Ok(()));
 
-        // consume second token
-        *now.lock().unwrap() = t0 + Duration::from_millis(50);
-        assert_eq!(limiter.consume("A", 1), Ok(()));
+        // a key removed from the group falls back to the default policy
+        limiter.remove_limit("B");
+        assert_eq!(limiter.consume(&"B", 1), Ok(()));
+        assert!(limiter.consume(&"B", 1).is_err());
 
-        // consume third & fourth tokens
-        *now.lock().unwrap() = t0 + Duration::from_millis(150);
-        assert_eq!(limiter.consume("A", 1), Ok(()));
-        assert_eq!(limiter.consume("A", 1), Ok(()));
+        limiter.rekey(|key| if key == "A" { "D" } else { key });
+        limiter.add_limit("B", 1, Duration::from_secs(1));
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(limiter.consume(&"D", 3), Ok(()));
+        assert!(limiter.consume(&"E", 1).is_err());
 
-        // ensure we are out of tokens
         assert_eq!(
-            limiter.consume("A", 1),
-            Err(Error::RetryAfter(Duration::from_millis(100)))
+            RateLimiter::configure()
+                .group(["A", "B"], 1, Duration::from_secs(1))
+                .group(["C", "B"], 1, Duration::from_secs(1))
+                .try_done()
+                .unwrap_err(),
+            BuildError::DuplicateKey("B")
         );
-
-        // one token is not yet replenished
-        *now.lock().unwrap() = t0 + Duration::from_millis(249);
         assert_eq!(
-            limiter.consume("A", 1),
-            Err(Error::RetryAfter(Duration::from_millis(1)))
+            RateLimiter::configure()
+                .group(["A", "B"], 0, Duration::from_secs(1))
+                .try_done()
+                .unwrap_err(),
+            BuildError::ZeroQuota(Some("A"))
         );
+    }
 
-        // one token is replenished
-        *now.lock().unwrap() = t0 + Duration::from_millis(250);
-        assert_eq!(limiter.consume("A", 1), Ok(()));
+    #[test]
+    fn group_runtime() {
+        let group = || {
+            RateLimiter::configure()
+                .group(["A", "B", "C"], 1, Duration::from_secs(60))
+                .done()
+        };
 
-        // ensure we are out of tokens again
-        assert_eq!(
-            limiter.consume("A", 1),
-            Err(Error::RetryAfter(Duration::from_millis(250)))
-        );
+        // removing the first key of a group leaves the others in it
+        let limiter = group();
+        limiter.remove_limit("A");
+        assert_eq!(limiter.consume(&"B", 1), Ok(()));
+        assert!(limiter.consume(&"C", 1).is_err());
+        assert_eq!(limiter.consume(&"A", 2), Ok(()));
 
-        // two tokens are replenished
-        *now.lock().unwrap() = t0 + Duration::from_millis(750);
-        assert_eq!(limiter.consume("A", 1), Ok(()));
-        assert_eq!(limiter.consume("A", 1), Ok(()));
-        assert_eq!(
-            limiter.consume("A", 1),
-            Err(Error::RetryAfter(Duration::from_millis(250)))
-        );
+        // and so does changing its policy, whichever key it is
+        for key in ["A", "B"] {
+            let limiter = group();
+            limiter.update_limit(key, 2, Duration::from_secs(60));
+            assert_eq!(limiter.consume(&key, 2), Ok(()));
+            assert_eq!(limiter.consume(&"C", 1), Ok(()));
+            assert!(limiter.consume(&"C", 1).is_err());
+
+            let limiter = group();
+            limiter.add_limit(key, 2, Duration::from_secs(60));
+            assert_eq!(limiter.consume(&key, 2), Ok(()));
+            assert_eq!(limiter.consume(&"C", 1), Ok(()));
+        }
+
+        let mut limiter = group();
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        limiter.apply(PolicyDiff {
+            added: Vec::new(),
+            removed: vec!["A"],
+            changed: Vec::new(),
+        });
+        assert!(limiter.consume(&"B", 1).is_err());
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+
+        // groups are handed off under every key still in them
+        let limiter = group();
+        limiter.remove_limit("A");
+        assert_eq!(limiter.consume(&"B", 1), Ok(()));
+        let mut keys: Vec<_> = limiter.handoff().iter().map(|(key, _)| *key).collect();
+        keys.sort();
+        assert_eq!(keys, ["B", "C"]);
+        let warmed = group();
+        warmed.warm(limiter.handoff());
+        assert!(warmed.consume(&"A", 1).is_err());
     }
 
     #[test]
-    fn consume_gt_one() {
+    fn bypass() {
         let now = Mutex::new(Instant::now());
         let clock = || *now.lock().unwrap();
-        let limiter = RateLimiter::with_timer(&clock)
-            .limit("A", 3, Duration::from_secs(1))
+        let mut limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 1, Duration::from_secs(1))
+            .limit("B", 1, Duration::from_secs(1))
+            .always_block("A")
+            .always_allow("A")
+            .always_block("B")
+            .global_limit(2, Duration::from_secs(1))
             .done();
 
-        // consume all tokens at once
-        assert_eq!(limiter.consume("A", 3), Ok(()));
+        // allowed keys bypass every bucket, including the global one
+        for _ in 0..3 {
+            assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        }
+        assert_eq!(limiter.consume_remaining(&"A", 1), Ok(None));
+        assert_eq!(limiter.consume_at(&"A", 1, clock()), Ok(()));
+        assert_eq!(limiter.admit(&"A", 10), QosClass::Green);
+        limiter.consume_permit(&"A", 1).unwrap().commit();
+        assert!(limiter
+            .consume_speculative(&"A", 1, Duration::from_secs(1))
+            .unwrap()
+            .confirm());
         assert_eq!(
-            limiter.consume("A", 1),
-            Err(Error::RetryAfter(Duration::from_nanos(333_333_332)))
+            limiter.consume_detailed(&"A", 1).unwrap().limit(),
+            usize::MAX
         );
+        assert_eq!(limiter.state(&"A").unwrap().unwrap().tokens(), 1);
 
-        // sequentially consume tokens
-        *now.lock().unwrap() += Duration::from_secs(1);
-        assert_eq!(limiter.consume("A", 2), Ok(()));
-        assert_eq!(
-            limiter.consume("A", 2),
-            Err(Error::RetryAfter(Duration::from_nanos(333_333_332)))
-        );
-        assert_eq!(limiter.consume("A", 1), Ok(()));
+        assert_eq!(limiter.consume(&"B", 1), Err(Error::Blocked));
+        assert_eq!(limiter.check(&"B", 1), Err(Error::Blocked));
+        assert_eq!(limiter.admit(&"B", 1), QosClass::Red);
+        assert!(limiter.consume_permit(&"B", 1).is_err());
+        assert!(limiter
+            .consume_speculative(&"B", 1, Duration::from_secs(1))
+            .is_err());
         assert_eq!(
-            limiter.consume("A", 1),
-            Err(Error::RetryAfter(Duration::from_nanos(333_333_332)))
+            limiter.consume_detailed(&"B", 1).unwrap_err().into_error(),
+            Error::Blocked
         );
 
-        // two tokens are replenished
-        *now.lock().unwrap() += Duration::from_millis(700);
-        assert_eq!(limiter.consume("A", 1), Ok(()));
-        assert_eq!(limiter.consume("A", 1), Ok(()));
+        // keys are allowed, blocked, and limited again at runtime
+        limiter.always_block("C");
+        assert_eq!(limiter.consume(&"C", 1), Err(Error::Blocked));
+        limiter.remove_bypass("A");
+        limiter.remove_bypass("B");
+        limiter.always_allow("C");
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        assert!(limiter.consume(&"A", 1).is_err());
+        assert_eq!(limiter.consume(&"C", 1), Ok(()));
+        assert_eq!(limiter.consume(&"B", 1), Ok(()));
+        assert!(limiter.consume(&"B", 1).is_err());
+
+        limiter.rekey(|key| if key == "C" { "D" } else { key });
+        assert!(limiter.consume(&"C", 1).is_err());
+        assert_eq!(limiter.clone().consume(&"D", 1), Ok(()));
+    }
+
+    #[test]
+    fn pattern() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let mut limiter = RateLimiter::with_clock(&clock)
+            .pattern("/api/*".to_string(), 2, Duration::from_secs(1))
+            .pattern("/api/*".to_string(), 3, Duration::from_secs(60))
+            .pattern("/api/users/*".to_string(), 1, Duration::from_secs(1))
+            .pattern("/api/users/*".to_string(), 5, Duration::from_secs(1))
+            .default_limit(1, Duration::from_secs(1))
+            .done();
+
+        // keys matching a pattern share its bucket, limits of which are stacked
+        assert_eq!(limiter.consume_remaining("/api/posts/1", 1), Ok(Some(1)));
+        assert_eq!(limiter.consume_remaining("/api/posts/2", 1), Ok(Some(0)));
+        assert!(limiter.check("/api/posts/3", 1).is_err());
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert!(limiter.consume("/api/posts/3", 2).is_err());
+        assert_eq!(limiter.consume("/api/posts/3", 1), Ok(()));
         assert_eq!(
-            limiter.consume("A", 1),
-            Err(Error::RetryAfter(Duration::from_nanos(299_999_998)))
+            limiter.time_to_full("/api/posts/4"),
+            Ok(Duration::from_secs(59))
         );
+
+        // the most specific pattern wins, and the first one of the equally
+        // specific ones
+        assert_eq!(limiter.consume("/api/users/1", 1), Ok(()));
+        assert!(limiter.consume("/api/users/2", 1).is_err());
+        assert_eq!(limiter.consume("/ap", 1), Ok(()));
+        assert!(limiter.consume("/ap", 1).is_err());
+
+        limiter.reset("/api/users/3");
+        assert_eq!(limiter.consume("/api/users/1", 1), Ok(()));
+
+        limiter.rekey(|key| key.replace("/api/", "/v2/"));
+        assert!(limiter.consume("/v2/users/1", 1).is_err());
+        assert_eq!(limiter.consume("/api/users/1", 1), Ok(()));
+        assert!(limiter.consume("/api/users/1", 1).is_err());
+
+        let warm = RateLimiter::with_clock(&clock)
+            .pattern("/v2/*".to_string(), 1, Duration::from_secs(1))
+            .default_limit(1, Duration::from_secs(1))
+            .done();
+        warm.warm(limiter.handoff());
+        assert!(warm.consume("/v2/users/1", 1).is_err());
+        assert!(warm.consume("/api/users/1", 1).is_err());
     }
 
     #[test]
-    fn multiple_buckets() {
+    fn introspection() {
         let now = Mutex::new(Instant::now());
         let clock = || *now.lock().unwrap();
-        let limiter = RateLimiter::with_timer(&clock)
+        let limiter = RateLimiter::with_clock(&clock)
             .limit("A", 2, Duration::from_secs(1))
-            .limit("B", 1, Duration::from_secs(2))
+            .limit("A", 4, Duration::from_secs(60))
+            .quota("B", Quota::per_minute(100).allow_burst(20))
+            .limit("C", 0, Duration::from_secs(1))
+            .limit("D", 1, Duration::from_secs(1))
+            .pattern("/api/*", 1, Duration::from_secs(1))
+            .default_limit(3, Duration::from_secs(3))
+            .global_limit(10, Duration::from_secs(1))
             .done();
+        limiter.add_limit("E", 1, Duration::from_secs(1));
+        limiter.remove_limit("D");
 
-        // consume tokens in A and B
-        assert_eq!(limiter.consume("A", 1), Ok(()));
-        assert_eq!(limiter.consume("A", 1), Ok(()));
+        let mut policies = limiter.policies();
+        policies.sort_by_key(|(key, quota)| (*key, quota.interval()));
         assert_eq!(
-            limiter.consume("A", 1),
-            Err(Error::RetryAfter(Duration::from_millis(500)))
+            policies,
+            vec![
+                ("/api/*", Quota::per_second(1)),
+                ("A", Quota::per_second(2)),
+                ("A", Quota::per_minute(4)),
+                ("B", Quota::new(20, Duration::from_secs(12))),
+                ("C", Quota::new(0, Duration::ZERO)),
+                ("E", Quota::per_second(1)),
+            ]
         );
-        assert_eq!(limiter.consume("B", 1), Ok(()));
+
+        // the strictest of the stacked limits is reported, but the global
+        // one is left out
+        assert_eq!(limiter.consume(&"A", 1), Ok(()));
+        *now.lock().unwrap() += Duration::from_secs(1);
+        let state = limiter.state(&"A").unwrap().unwrap();
+        assert_eq!(state.tokens(), 2);
+        assert_eq!(state.next_token_in(), Duration::ZERO);
+        assert_eq!(limiter.consume(&"A", 2), Ok(()));
+        let state = limiter.state(&"A").unwrap().unwrap();
+        assert_eq!(state.tokens(), 0);
+        assert_eq!(state.next_token_in(), Duration::from_millis(500));
+
+        assert_eq!(limiter.state(&"C"), Err(Error::Blocked));
+        assert_eq!(limiter.state(&"/api/users").unwrap().unwrap().tokens(), 1);
+        let state = limiter.state(&"F").unwrap().unwrap();
+        assert_eq!(state.tokens(), 3);
+        assert_eq!(state.next_token_in(), Duration::ZERO);
+        // the state isn't tracked until the key is seen
         assert_eq!(
-            limiter.consume("B", 1),
-            Err(Error::RetryAfter(Duration::from_secs(2)))
+            limiter
+                .handoff()
+                .iter()
+                .filter(|(key, _)| **key == "F")
+                .count(),
+            0
         );
+    }
 
-        // tokens in A are replenished, but not in B
-        *now.lock().unwrap() += Duration::from_secs(1);
-        assert_eq!(limiter.consume("A", 1), Ok(()));
+    #[test]
+    fn try_done() {
+        let limiter = RateLimiter::configure()
+            .limit("A".to_string(), 1, Duration::from_secs(1))
+            .block("B".to_string())
+            .pattern("/api/*".to_string(), 1, Duration::from_secs(1))
+            .default_quota(Quota::per_second(1).allow_burst(10))
+            .global_limit(10, Duration::from_secs(1))
+            .try_done()
+            .unwrap();
         assert_eq!(limiter.consume("A", 1), Ok(()));
+        assert_eq!(limiter.consume("B", 1), Err(Error::Blocked));
+
+        let configure = || {
+            RateLimiter::configure()
+                .limit("A".to_string(), 1, Duration::from_secs(1))
+                .limit("B".to_string(), 1, Duration::from_secs(1))
+        };
         assert_eq!(
-            limiter.consume("A", 1),
-            Err(Error::RetryAfter(Duration::from_millis(500)))
+            configure()
+                .limit("a".to_string(), 1, Duration::from_secs(1))
+                .normalize(|key| key.to_uppercase())
+                .try_done()
+                .unwrap_err(),
+            BuildError::DuplicateKey("A".to_string())
         );
         assert_eq!(
-            limiter.consume("B", 1),
-            Err(Error::RetryAfter(Duration::from_secs(1)))
+            configure()
+                .pattern("B".to_string(), 2, Duration::from_secs(1))
+                .try_done()
+                .unwrap_err(),
+            BuildError::DuplicateKey("B".to_string())
         );
-
-        // tokens in A and B are replenished
-        *now.lock().unwrap() += Duration::from_secs(1);
-        assert_eq!(limiter.consume("A", 1), Ok(()));
-        assert_eq!(limiter.consume("A", 1), Ok(()));
         assert_eq!(
-            limiter.consume("A", 1),
-            Err(Error::RetryAfter(Duration::from_millis(500)))
+            configure()
+                .quota("C".to_string(), Quota::per_second(1).allow_burst(0))
+                .try_done()
+                .unwrap_err(),
+            BuildError::ZeroQuota(Some("C".to_string()))
         );
-        assert_eq!(limiter.consume("B", 1), Ok(()));
         assert_eq!(
-            limiter.consume("B", 1),
-            Err(Error::RetryAfter(Duration::from_secs(2)))
+            configure()
+                .limit("C".to_string(), 1, Duration::ZERO)
+                .try_done()
+                .unwrap_err(),
+            BuildError::ZeroQuota(Some("C".to_string()))
+        );
+        assert_eq!(
+            configure()
+                .default_limit(0, Duration::from_secs(1))
+                .try_done()
+                .unwrap_err(),
+            BuildError::ZeroQuota(None)
+        );
+        assert_eq!(
+            configure()
+                .limit("C".to_string(), 1, Duration::MAX)
+                .try_done()
+                .unwrap_err(),
+            BuildError::IntervalOverflow(Some("C".to_string()))
+        );
+        assert_eq!(
+            configure()
+                .global_quota(Quota::per_second(1).allow_burst(usize::MAX))
+                .try_done()
+                .unwrap_err(),
+            BuildError::IntervalOverflow(None)
+        );
+        assert_eq!(
+            BuildError::ZeroQuota(Some("C")).to_string(),
+            "Policy for \"C\" blocks every request"
         );
     }
 
     #[test]
-    fn compound_key() {
-        #[derive(Eq, PartialEq, Hash)]
-        enum MyHttpVerb {
-            GET,
-            PUT,
-        }
-
+    fn consume_detailed() {
         let now = Mutex::new(Instant::now());
         let clock = || *now.lock().unwrap();
-        let limiter = RateLimiter::with_timer(&clock)
-            .limit((MyHttpVerb::PUT, "/foobar"), 1, Duration::from_secs(1))
-            .limit((MyHttpVerb::GET, "/foobar"), 3, Duration::from_secs(1))
-            .limit((MyHttpVerb::GET, "/spam"), 2, Duration::from_secs(1))
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("A", 4, Duration::from_secs(4))
+            .limit("A", 10, Duration::from_secs(60))
+            .limit("B", 0, Duration::from_secs(1))
+            .global_limit(5, Duration::from_secs(1))
             .done();
 
-        assert_eq!(limiter.consume((MyHttpVerb::GET, "/foobar"), 1), Ok(()));
-        assert_eq!(limiter.consume((MyHttpVerb::GET, "/foobar"), 1), Ok(()));
-        assert_eq!(limiter.consume((MyHttpVerb::GET, "/foobar"), 1), Ok(()));
-        assert_eq!(
-            limiter.consume((MyHttpVerb::GET, "/foobar"), 1),
-            Err(Error::RetryAfter(Duration::from_nanos(333_333_332)))
-        );
+        // the strictest of the limits is reported, be it stacked or global
+        let allowance = limiter.consume_detailed(&"A", 3).unwrap();
+        assert_eq!(allowance.limit(), 4);
+        assert_eq!(allowance.remaining(), 1);
+        assert_eq!(allowance.reset(), Duration::from_secs(3));
+        let allowance = limiter.consume_detailed(&"C", 1).unwrap();
+        assert_eq!(allowance.limit(), 5);
+        assert_eq!(allowance.remaining(), 1);
+        assert_eq!(allowance.reset(), Duration::from_millis(800));
+
+        let denial = limiter.consume_detailed(&"A", 2).unwrap_err();
+        assert_eq!(denial.error(), &Error::RetryAfter(Duration::from_secs(1)));
+        assert_eq!(denial.limit(), 4);
+        assert_eq!(denial.remaining(), 1);
+        assert_eq!(denial.reset(), Duration::from_secs(3));
+        assert_eq!(denial.to_string(), "Retry after 1.0 seconds");
 
-        assert_eq!(limiter.consume((MyHttpVerb::PUT, "/foobar"), 1), Ok(()));
+        let denial = limiter.consume_detailed(&"B", 1).unwrap_err();
+        assert_eq!(denial.into_error(), Error::Blocked);
         assert_eq!(
-            limiter.consume((MyHttpVerb::PUT, "/foobar"), 1),
-            Err(Error::RetryAfter(Duration::from_secs(1)))
+            limiter.consume_detailed(&"B", 1).unwrap_err().reset(),
+            Duration::MAX
         );
 
-        assert_eq!(limiter.consume((MyHttpVerb::GET, "/spam"), 1), Ok(()));
-        assert_eq!(limiter.consume((MyHttpVerb::GET, "/spam"), 1), Ok(()));
+        let limiter = RateLimiter::with_clock(&clock).done();
+        let allowance = limiter.consume_detailed(&"A", 1).unwrap();
+        assert_eq!(allowance.limit(), usize::MAX);
+        assert_eq!(allowance.remaining(), usize::MAX);
+        assert_eq!(allowance.reset(), Duration::ZERO);
+    }
+
+    #[test]
+    fn normalize() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("/API/Users".to_string(), 2, Duration::from_secs(1))
+            .pattern("/api/posts/*".to_string(), 1, Duration::from_secs(1))
+            .default_limit(1, Duration::from_secs(1))
+            .normalize(|key: &String| match key.split_once('?') {
+                Some((path, _)) => path.to_lowercase(),
+                None => key.to_lowercase(),
+            })
+            .done();
+
+        // equivalent keys share a bucket, whichever function is called
         assert_eq!(
-            limiter.consume((MyHttpVerb::GET, "/spam"), 1),
-            Err(Error::RetryAfter(Duration::from_millis(500)))
+            limiter.consume_remaining("/api/users?page=1", 1),
+            Ok(Some(1))
         );
+        assert!(limiter.check("/API/USERS", 2).is_err());
+        assert_eq!(limiter.consume("/Api/Users", 1), Ok(()));
+        assert!(limiter.consume("/api/users", 1).is_err());
+        limiter.refund("/API/users?page=2", 1);
+        assert_eq!(limiter.consume("/api/users", 1), Ok(()));
+
+        // so do keys matching a pattern, and keys limited by the default policy
+        assert_eq!(limiter.consume("/API/Posts/1", 1), Ok(()));
+        assert!(limiter.consume("/api/posts/2?draft", 1).is_err());
+        assert_eq!(limiter.consume("/Other", 1), Ok(()));
+        assert!(limiter.consume("/other?again", 1).is_err());
+
+        // keys of policies added at runtime are normalized too
+        limiter.add_limit("/Admin".to_string(), 1, Duration::from_secs(1));
+        assert_eq!(limiter.consume("/admin", 1), Ok(()));
+        assert!(limiter.consume("/ADMIN?x", 1).is_err());
+        limiter.remove_limit("/ADMIN".to_string());
+        assert_eq!(limiter.consume("/admin", 1), Ok(()));
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn regex() {
+        use regex::Regex;
+
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = RateLimiter::with_clock(&clock)
+            .limit("/api/login".to_string(), 3, Duration::from_secs(1))
+            .pattern("/api/users/*".to_string(), 2, Duration::from_secs(1))
+            .regex_limit(Regex::new("^/api/").unwrap(), 1, Duration::from_secs(1))
+            .regex_limit(Regex::new("/users/").unwrap(), 5, Duration::from_secs(1))
+            .default_limit(4, Duration::from_secs(1))
+            .done();
+
+        // own policies go first, then patterns, regular expressions in the
+        // order they're set, and the default policy
+        assert_eq!(limiter.consume_remaining("/api/login", 1), Ok(Some(2)));
+        assert_eq!(limiter.consume_remaining("/api/users/1", 1), Ok(Some(1)));
+        assert_eq!(limiter.consume_remaining("/api/posts/1", 1), Ok(Some(0)));
+        assert!(limiter.consume("/api/posts/2", 1).is_err());
+        assert_eq!(limiter.consume_remaining("/v2/users/1", 1), Ok(Some(4)));
+        assert_eq!(limiter.consume_remaining("/", 1), Ok(Some(3)));
+
+        let clone = limiter.clone();
+        limiter.refund("/api/posts/3", 1);
+        assert_eq!(limiter.consume("/api/posts/2", 1), Ok(()));
+        assert!(clone.consume("/api/posts/2", 1).is_err());
+
+        // buckets of expressions are never restored from the keys they match
+        let warm = RateLimiter::with_clock(&clock)
+            .regex_limit(Regex::new("^/api/").unwrap(), 1, Duration::from_secs(1))
+            .default_limit(1, Duration::from_secs(1))
+            .done();
+        warm.warm(limiter.handoff());
+        assert_eq!(warm.consume("/api/posts/1", 1), Ok(()));
+        assert!(warm.consume("/", 1).is_err());
+
+        // unlike snapshots, which restore them by their expressions
+        let restored = RateLimiter::with_clock(&clock)
+            .regex_limit(Regex::new("/users/").unwrap(), 5, Duration::from_secs(1))
+            .regex_limit(Regex::new("^/api/").unwrap(), 1, Duration::from_secs(1))
+            .done();
+        restored.restore(limiter.snapshot());
+        assert!(restored.consume("/api/posts/1", 1).is_err());
+        assert_eq!(restored.consume_remaining("/v2/users/1", 1), Ok(Some(3)));
     }
 }
@@ -0,0 +1,77 @@
+use regex::{Regex, RegexSet};
+
+use crate::clock::Clock;
+use crate::quota::Quota;
+use crate::token_bucket::{Snapshot, TokenBucket};
+
+/// Returns a key as a string to be matched, see [`RegexPolicies`].
+pub(crate) type AsStr<K> = fn(&K) -> &str;
+
+/// Quotas set for regular expressions, along with the function returning a
+/// key as a string, which is only known while the quotas are set.
+pub(crate) type RegexQuotas<K> = (Vec<(Regex, Quota)>, AsStr<K>);
+
+/// Policies keyed by regular expressions over string keys, see
+/// [`RateLimiterBuilder::regex_limit`].
+///
+/// The expressions are compiled into a single [`RegexSet`], so that a key is
+/// matched against all of them in a single pass.
+///
+/// [`RateLimiterBuilder::regex_limit`]: crate::RateLimiterBuilder::regex_limit
+pub(crate) struct RegexPolicies<K, C> {
+    set: RegexSet,
+    buckets: Vec<TokenBucket<C>>,
+    as_str: AsStr<K>,
+}
+
+impl<K, C: Clock + Clone> RegexPolicies<K, C> {
+    pub(crate) fn new(policies: Vec<(Regex, Quota)>, as_str: AsStr<K>, clock: &C) -> Self {
+        // every expression has been compiled on its own already
+        let set = RegexSet::new(policies.iter().map(|(regex, _)| regex.as_str()))
+            .expect("compiled expressions make a valid set");
+        let buckets = policies
+            .into_iter()
+            .map(|(_, quota)| TokenBucket::from_quota_with_clock(quota, clock.clone()))
+            .collect();
+        RegexPolicies {
+            set,
+            buckets,
+            as_str,
+        }
+    }
+}
+
+impl<K, C> RegexPolicies<K, C> {
+    /// Returns the bucket of the first expression matching the `key`, if any.
+    pub(crate) fn bucket(&self, key: &K) -> Option<&TokenBucket<C>> {
+        let index = self.set.matches((self.as_str)(key)).into_iter().next()?;
+        Some(&self.buckets[index])
+    }
+}
+
+impl<K, C: Clock> RegexPolicies<K, C> {
+    /// Returns every expression along with the state of its bucket.
+    pub(crate) fn snapshots(&self) -> Vec<(String, Snapshot)> {
+        let expressions = self.set.patterns().iter().cloned();
+        let snapshots = self.buckets.iter().map(TokenBucket::snapshot);
+        expressions.zip(snapshots).collect()
+    }
+
+    /// Restores the state of the bucket of the `expression`, if it's set.
+    pub(crate) fn restore(&self, expression: &str, snapshot: Snapshot) {
+        let index = self.set.patterns().iter().position(|set| set == expression);
+        if let Some(index) = index {
+            self.buckets[index].restore(snapshot);
+        }
+    }
+}
+
+impl<K, C: Clone> Clone for RegexPolicies<K, C> {
+    fn clone(&self) -> Self {
+        RegexPolicies {
+            set: self.set.clone(),
+            buckets: self.buckets.clone(),
+            as_str: self.as_str,
+        }
+    }
+}
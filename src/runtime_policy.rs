@@ -0,0 +1,89 @@
+use std::borrow::Borrow;
+use std::collections::hash_map::{Drain, RandomState};
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+
+use crate::token_bucket::TokenBucket;
+
+/// Buckets of the changed policies, keyed by their keys.
+pub(crate) type Policies<K, C, S> = HashMap<K, Option<Arc<TokenBucket<C>>>, S>;
+
+/// Limiting policies added or removed after a rate limiter is constructed.
+///
+/// The policies take precedence over the ones set at construction time. A key
+/// mapped to `None` has its policy removed. The buckets are shared, so that
+/// permits and speculations issued by them don't borrow the map they are
+/// stored in.
+pub(crate) struct RuntimePolicies<K, C, S = RandomState> {
+    /// Whether any policy has been changed, so that rate limiters that are
+    /// never changed at runtime don't pay for the lock.
+    changed: AtomicBool,
+    buckets: RwLock<Policies<K, C, S>>,
+}
+
+impl<K, C, S> RuntimePolicies<K, C, S> {
+    pub(crate) fn with_hasher(hasher: S) -> Self {
+        RuntimePolicies {
+            changed: AtomicBool::new(false),
+            buckets: RwLock::new(HashMap::with_hasher(hasher)),
+        }
+    }
+}
+
+impl<K: Eq + Hash, C, S: BuildHasher> RuntimePolicies<K, C, S> {
+    /// Return the bucket for `key` if its policy has been changed: `None` for
+    /// a key whose policy is unchanged, and `Some(None)` for a removed one.
+    #[inline]
+    pub(crate) fn get<Q>(&self, key: &Q) -> Option<Option<Arc<TokenBucket<C>>>>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        if !self.changed.load(Ordering::Acquire) {
+            return None;
+        }
+        self.buckets.read().unwrap().get(key).cloned()
+    }
+
+    /// Set the `bucket` for `key`, or remove its policy if `None`.
+    pub(crate) fn insert(&self, key: K, bucket: Option<TokenBucket<C>>) {
+        self.buckets
+            .write()
+            .unwrap()
+            .insert(key, bucket.map(Arc::new));
+        self.changed.store(true, Ordering::Release);
+    }
+
+    /// Forget the change of the policy for `key`, if any.
+    pub(crate) fn remove(&self, key: &K) {
+        self.buckets.write().unwrap().remove(key);
+    }
+
+    /// Return the changed policies, keyed by their keys.
+    pub(crate) fn read(&self) -> RwLockReadGuard<'_, Policies<K, C, S>> {
+        self.buckets.read().unwrap()
+    }
+
+    /// Take the changed policies out, so that they can be merged into the
+    /// policies set at construction time.
+    pub(crate) fn take(&mut self) -> Drain<'_, K, Option<Arc<TokenBucket<C>>>> {
+        *self.changed.get_mut() = false;
+        self.buckets.get_mut().unwrap().drain()
+    }
+}
+
+impl<K: Clone, C: Clone, S: Clone> Clone for RuntimePolicies<K, C, S> {
+    /// Clone the policies along with the state of every bucket.
+    fn clone(&self) -> Self {
+        let mut buckets = self.buckets.read().unwrap().clone();
+        for bucket in buckets.values_mut().flatten() {
+            *bucket = Arc::new(TokenBucket::clone(bucket));
+        }
+        RuntimePolicies {
+            changed: AtomicBool::new(self.changed.load(Ordering::Acquire)),
+            buckets: RwLock::new(buckets),
+        }
+    }
+}
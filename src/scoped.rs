@@ -0,0 +1,184 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::time::Duration;
+
+use crate::clock::{Clock, MonotonicClock};
+use crate::error::Error;
+use crate::policy::PolicySet;
+use crate::quota::Quota;
+use crate::rate_limiter::{RateLimiter, RateLimiterBuilder};
+
+/// A view over a [`RateLimiter`] keyed by `(prefix, key)` pairs, which
+/// operates on the keys of a single `prefix`.
+///
+/// Scopes allow library crates embedded in a larger application to share a
+/// single rate limiter without key collisions: every library registers its
+/// policies under its own prefix via [`RateLimiterBuilder::scope`], and
+/// consumes tokens via a view created by [`RateLimiter::scoped`]. The view is
+/// cheap to create, and forwards all operations to the parent limiter.
+///
+/// ```
+/// use std::time::Duration;
+/// use youshallnotpass::{PolicySet, Quota, RateLimiter};
+///
+/// fn auth_policies() -> PolicySet<&'static str> {
+///     [("login", Quota::per_minute(1))].into_iter().collect()
+/// }
+///
+/// fn admin_policies() -> PolicySet<&'static str> {
+///     [("login", Quota::per_minute(5))].into_iter().collect()
+/// }
+///
+/// let limiter = RateLimiter::configure()
+///     .scope("auth", auth_policies())
+///     .scope("admin", admin_policies())
+///     .done();
+///
+/// let auth = limiter.scoped("auth");
+/// assert!(auth.consume("login", 1).is_ok());
+/// assert!(auth.consume("login", 1).is_err());
+///
+/// // the same key in another scope has its own bucket
+/// assert!(limiter.scoped("admin").consume("login", 1).is_ok());
+/// ```
+pub struct Scoped<'l, P, Q, C = MonotonicClock, S = RandomState> {
+    limiter: &'l RateLimiter<(P, Q), C, S>,
+    prefix: P,
+}
+
+impl<P: Clone + Eq + Hash, Q: Eq + Hash, C: Clock, S: BuildHasher> Scoped<'_, P, Q, C, S> {
+    /// Same as [`RateLimiter::consume`], for the `key` within the scope.
+    #[inline]
+    pub fn consume(&self, key: Q, tokens: usize) -> Result<(), Error>
+    where
+        Q: Clone,
+    {
+        self.limiter.consume(&self.key(key), tokens)
+    }
+
+    /// Same as [`RateLimiter::consume_remaining`], for the `key` within the
+    /// scope.
+    pub fn consume_remaining(&self, key: Q, tokens: usize) -> Result<Option<u64>, Error>
+    where
+        Q: Clone,
+    {
+        self.limiter.consume_remaining(&self.key(key), tokens)
+    }
+
+    /// Same as [`RateLimiter::check`], for the `key` within the scope.
+    pub fn check(&self, key: Q, tokens: usize) -> Result<(), Error>
+    where
+        Q: Clone,
+    {
+        self.limiter.check(&self.key(key), tokens)
+    }
+
+    /// Same as [`RateLimiter::refund`], for the `key` within the scope.
+    pub fn refund(&self, key: Q, tokens: usize)
+    where
+        Q: Clone,
+    {
+        self.limiter.refund(&self.key(key), tokens)
+    }
+
+    /// Same as [`RateLimiter::reset`], for the `key` within the scope.
+    pub fn reset(&self, key: Q)
+    where
+        Q: Clone,
+    {
+        self.limiter.reset(&self.key(key))
+    }
+
+    /// Same as [`RateLimiter::drain`], for the `key` within the scope.
+    pub fn drain(&self, key: Q)
+    where
+        Q: Clone,
+    {
+        self.limiter.drain(&self.key(key))
+    }
+
+    /// Same as [`RateLimiter::time_to_full`], for the `key` within the scope.
+    pub fn time_to_full(&self, key: Q) -> Result<Duration, Error>
+    where
+        Q: Clone,
+    {
+        self.limiter.time_to_full(&self.key(key))
+    }
+
+    #[inline]
+    fn key(&self, key: Q) -> (P, Q) {
+        (self.prefix.clone(), key)
+    }
+}
+
+impl<P, Q, C, S> RateLimiter<(P, Q), C, S> {
+    /// Returns a view over the keys within the `prefix` scope.
+    ///
+    /// See [`Scoped`] for details.
+    pub fn scoped(&self, prefix: P) -> Scoped<'_, P, Q, C, S> {
+        Scoped {
+            limiter: self,
+            prefix,
+        }
+    }
+}
+
+impl<P: Clone, Q, C, S> RateLimiterBuilder<(P, Q), C, S> {
+    /// Sets limiting `policies` for the keys within the `prefix` scope.
+    ///
+    /// See [`Scoped`] for details.
+    pub fn scope(self, prefix: P, policies: PolicySet<Q>) -> Self
+    where
+        Q: Eq + Hash,
+    {
+        policies.into_iter().fold(self, |builder, (key, quota)| {
+            builder.quota((prefix.clone(), key), quota)
+        })
+    }
+
+    /// Sets a limiting policy for a `key` within the `prefix` scope in terms
+    /// of a [`Quota`].
+    pub fn scoped_quota(self, prefix: P, key: Q, quota: Quota) -> Self {
+        self.quota((prefix, key), quota)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    #[test]
+    fn scoped() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let limiter = RateLimiter::with_clock(&clock)
+            .scope("a", [("x", Quota::per_second(2))].into_iter().collect())
+            .scoped_quota("b", "x", Quota::per_second(1))
+            .done();
+
+        let a = limiter.scoped("a");
+        let b = limiter.scoped("b");
+        assert_eq!(a.consume_remaining("x", 1), Ok(Some(1)));
+        assert_eq!(b.consume_remaining("x", 1), Ok(Some(0)));
+        assert_eq!(
+            b.check("x", 1),
+            Err(Error::RetryAfter(Duration::from_secs(1)))
+        );
+        assert_eq!(a.check("x", 1), Ok(()));
+
+        // keys without a policy in the scope aren't limited
+        assert_eq!(a.consume_remaining("y", 100), Ok(None));
+        assert_eq!(limiter.scoped("c").consume_remaining("x", 100), Ok(None));
+
+        // operations are forwarded to the parent limiter
+        a.drain("x");
+        assert!(limiter.consume(&("a", "x"), 1).is_err());
+        assert_eq!(a.time_to_full("x"), Ok(Duration::from_secs(1)));
+        a.refund("x", 1);
+        assert_eq!(a.consume("x", 1), Ok(()));
+        b.reset("x");
+        assert_eq!(b.consume("x", 1), Ok(()));
+    }
+}
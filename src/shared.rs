@@ -0,0 +1,145 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use crate::clock::{Clock, MonotonicClock};
+use crate::rate_limiter::RateLimiter;
+
+/// A cheaply cloneable handle to a [`RateLimiter`], so that every worker or
+/// task charges the same buckets.
+///
+/// Unlike cloning a [`RateLimiter`], which copies the state of its buckets,
+/// cloning a handle only bumps a reference count, and every clone refers to
+/// the same instance. The handle dereferences to the rate limiter, so all the
+/// functions taking `&self` are available on it, and it converts into an
+/// [`Arc`], e.g. to be passed to the middlewares of the `tide` and `poem`
+/// features.
+///
+/// Functions taking `&mut self`, such as [`RateLimiter::add_limit`], aren't
+/// available on a shared rate limiter, see [`RateLimiter::update_limit`] and
+/// [`RateLimiter::always_block`] for their runtime counterparts.
+///
+/// ```
+/// use std::time::Duration;
+/// use youshallnotpass::RateLimiter;
+///
+/// let limiter = RateLimiter::configure()
+///     .limit("A", 2, Duration::from_secs(60))
+///     .done()
+///     .into_shared();
+///
+/// std::thread::scope(|scope| {
+///     for _ in 0..2 {
+///         let limiter = limiter.clone();
+///         scope.spawn(move || assert!(limiter.consume(&"A", 1).is_ok()));
+///     }
+/// });
+/// assert!(limiter.consume(&"A", 1).is_err());
+/// ```
+pub struct SharedRateLimiter<K, C = MonotonicClock, S = RandomState> {
+    limiter: Arc<RateLimiter<K, C, S>>,
+}
+
+impl<K, C, S> SharedRateLimiter<K, C, S> {
+    /// Create a handle to the `limiter`.
+    pub fn new(limiter: RateLimiter<K, C, S>) -> Self {
+        SharedRateLimiter {
+            limiter: Arc::new(limiter),
+        }
+    }
+
+    /// Return the [`Arc`] the handle refers to the rate limiter with.
+    pub fn as_arc(&self) -> &Arc<RateLimiter<K, C, S>> {
+        &self.limiter
+    }
+}
+
+impl<K, C, S> RateLimiter<K, C, S> {
+    /// Turns the rate limiter into a cheaply cloneable handle to be shared.
+    ///
+    /// See [`SharedRateLimiter`] for details.
+    pub fn into_shared(self) -> SharedRateLimiter<K, C, S> {
+        SharedRateLimiter::new(self)
+    }
+}
+
+impl<K, C, S> Clone for SharedRateLimiter<K, C, S> {
+    /// Returns another handle to the same rate limiter.
+    fn clone(&self) -> Self {
+        SharedRateLimiter {
+            limiter: Arc::clone(&self.limiter),
+        }
+    }
+}
+
+impl<K, C, S> Deref for SharedRateLimiter<K, C, S> {
+    type Target = RateLimiter<K, C, S>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.limiter
+    }
+}
+
+impl<K, C, S> From<RateLimiter<K, C, S>> for SharedRateLimiter<K, C, S> {
+    fn from(limiter: RateLimiter<K, C, S>) -> Self {
+        SharedRateLimiter::new(limiter)
+    }
+}
+
+impl<K, C, S> From<Arc<RateLimiter<K, C, S>>> for SharedRateLimiter<K, C, S> {
+    fn from(limiter: Arc<RateLimiter<K, C, S>>) -> Self {
+        SharedRateLimiter { limiter }
+    }
+}
+
+impl<K, C, S> From<SharedRateLimiter<K, C, S>> for Arc<RateLimiter<K, C, S>> {
+    fn from(shared: SharedRateLimiter<K, C, S>) -> Self {
+        shared.limiter
+    }
+}
+
+impl<K: fmt::Debug, C: Clock, S> fmt::Debug for SharedRateLimiter<K, C, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.limiter.fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::time::Duration;
+
+    #[test]
+    fn shared() {
+        let clock = ManualClock::new();
+        let limiter = RateLimiter::with_clock(clock.clone())
+            .limit("A", 4, Duration::from_secs(1))
+            .done()
+            .into_shared();
+
+        // every clone charges the same bucket
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                let limiter = limiter.clone();
+                scope.spawn(move || assert_eq!(limiter.consume(&"A", 1), Ok(())));
+            }
+        });
+        assert!(limiter.consume(&"A", 1).is_err());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(limiter.clone().consume(&"A", 4), Ok(()));
+
+        // and so does the arc it converts into
+        let arc: Arc<_> = limiter.clone().into();
+        assert!(Arc::ptr_eq(&arc, limiter.as_arc()));
+        let limiter = SharedRateLimiter::from(arc);
+        assert!(limiter.consume(&"A", 1).is_err());
+
+        // a clone of the rate limiter itself gets a copy of its buckets
+        let copy = RateLimiter::clone(&limiter);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(copy.consume(&"A", 4), Ok(()));
+        assert_eq!(limiter.consume(&"A", 4), Ok(()));
+    }
+}
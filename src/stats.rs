@@ -0,0 +1,199 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+
+use crate::default_policy::CloneKey;
+
+/// Counters of the decisions made for a key, see
+/// [`RateLimiterBuilder::track_stats`].
+///
+/// [`RateLimiterBuilder::track_stats`]: crate::RateLimiterBuilder::track_stats
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    allowed: u64,
+    rejected: u64,
+    tokens: u64,
+}
+
+impl Stats {
+    /// Return the number of requests allowed.
+    pub fn allowed(&self) -> u64 {
+        self.allowed
+    }
+
+    /// Return the number of requests rejected.
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+
+    /// Return the number of tokens consumed by the allowed requests.
+    pub fn tokens(&self) -> u64 {
+        self.tokens
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    allowed: AtomicU64,
+    rejected: AtomicU64,
+    tokens: AtomicU64,
+    /// The generation of the tracker the counters have been updated at last.
+    seen_at: AtomicU64,
+}
+
+impl Counters {
+    fn record(&self, tokens: usize, allowed: bool, generation: u64) {
+        self.seen_at.store(generation, Ordering::Relaxed);
+        if allowed {
+            self.allowed.fetch_add(1, Ordering::Relaxed);
+            self.tokens.fetch_add(tokens as u64, Ordering::Relaxed);
+        } else {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn stats(&self) -> Stats {
+        Stats {
+            allowed: self.allowed.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            tokens: self.tokens.load(Ordering::Relaxed),
+        }
+    }
+
+    fn absorb(&mut self, other: Counters) {
+        *self.allowed.get_mut() += other.allowed.into_inner();
+        *self.rejected.get_mut() += other.rejected.into_inner();
+        *self.tokens.get_mut() += other.tokens.into_inner();
+        let seen_at = self.seen_at.get_mut();
+        *seen_at = (*seen_at).max(other.seen_at.into_inner());
+    }
+}
+
+/// The number of keys tracked unless set otherwise, see
+/// [`RateLimiterBuilder::track_stats`].
+///
+/// [`RateLimiterBuilder::track_stats`]: crate::RateLimiterBuilder::track_stats
+pub(crate) const DEFAULT_MAX_KEYS: usize = 10_000;
+
+/// Counters of the decisions made for every key seen.
+///
+/// Counters of a key are updated under a shared lock, and the exclusive one is
+/// taken only the first time the key is seen. At most `max_keys` keys are
+/// tracked, and the least recently seen ones are evicted the same way the
+/// buckets of the default policy are.
+pub(crate) struct StatsTracker<K, S> {
+    counters: RwLock<HashMap<K, Counters, S>>,
+    max_keys: usize,
+    /// The number of keys seen first so far, which tells the keys seen since
+    /// the last one from the ones seen before, without a clock.
+    generation: AtomicU64,
+    /// Captured at build time, so that the rate limiter doesn't require
+    /// `K: Clone` to count decisions for its keys.
+    clone_key: CloneKey<K>,
+}
+
+impl<K, S> StatsTracker<K, S> {
+    pub(crate) fn with_hasher(clone_key: CloneKey<K>, max_keys: usize, hasher: S) -> Self {
+        StatsTracker {
+            counters: RwLock::new(HashMap::with_hasher(hasher)),
+            max_keys,
+            generation: AtomicU64::new(0),
+            clone_key,
+        }
+    }
+}
+
+impl<K: Eq + Hash, S: BuildHasher> StatsTracker<K, S> {
+    /// Count a request for `tokens` of `key`, and whether it's been `allowed`.
+    /// The `key` is looked up by its borrowed form, which is converted into an
+    /// owned one via `to_owned` only for a key seen first.
+    pub(crate) fn record<Q>(&self, key: &Q, to_owned: fn(&Q) -> K, tokens: usize, allowed: bool)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        if let Some(counters) = self.counters.read().unwrap().get(key) {
+            counters.record(tokens, allowed, self.generation.load(Ordering::Relaxed));
+            return;
+        }
+        let mut counters = self.counters.write().unwrap();
+        if !counters.contains_key(key) {
+            self.evict(&mut counters);
+        }
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed) + 1;
+        counters
+            .entry(to_owned(key))
+            .or_default()
+            .record(tokens, allowed, generation);
+    }
+
+    /// Make room for a new key, if `max_keys` are tracked already.
+    fn evict(&self, counters: &mut HashMap<K, Counters, S>) {
+        let len = counters.len();
+        let max_keys = match self.max_keys {
+            max_keys if len >= max_keys => max_keys.max(1),
+            _ => return,
+        };
+        // An eighth of the keys is evicted at once, same as the buckets of
+        // the default policy are, so that the cost of finding the least
+        // recently seen ones is amortized.
+        let count = (len + 1 + max_keys / 8 - max_keys).min(len);
+        if count == 0 {
+            return;
+        }
+        let mut seen_at: Vec<u64> = counters
+            .values()
+            .map(|counters| counters.seen_at.load(Ordering::Relaxed))
+            .collect();
+        let (_, &mut threshold, _) = seen_at.select_nth_unstable(count - 1);
+        let mut evicted = 0;
+        counters.retain(|_, counters| {
+            let evict = evicted < count && *counters.seen_at.get_mut() <= threshold;
+            evicted += usize::from(evict);
+            !evict
+        });
+    }
+
+    /// Return the counters of `key`, if it's been seen.
+    pub(crate) fn get<Q>(&self, key: &Q) -> Option<Stats>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.counters.read().unwrap().get(key).map(Counters::stats)
+    }
+
+    /// Return the counters of every key seen.
+    pub(crate) fn all(&self) -> Vec<(K, Stats)> {
+        let counters = self.counters.read().unwrap();
+        counters
+            .iter()
+            .map(|(key, counters)| ((self.clone_key)(key), counters.stats()))
+            .collect()
+    }
+
+    /// Rename every key with `f`, summing up the counters of the keys that
+    /// are renamed to the same one.
+    pub(crate) fn rekey<F: FnMut(K) -> K>(&mut self, mut f: F)
+    where
+        S: Clone,
+    {
+        let counters = self.counters.get_mut().unwrap();
+        let mut rekeyed: HashMap<K, Counters, S> =
+            HashMap::with_capacity_and_hasher(counters.len(), counters.hasher().clone());
+        for (key, counters) in counters.drain() {
+            rekeyed.entry(f(key)).or_default().absorb(counters);
+        }
+        *counters = rekeyed;
+    }
+}
+
+impl<K, S: Clone> Clone for StatsTracker<K, S> {
+    /// Clone the tracker, but not the counters, which start afresh.
+    fn clone(&self) -> Self {
+        let hasher = self.counters.read().unwrap().hasher().clone();
+        StatsTracker::with_hasher(self.clone_key, self.max_keys, hasher)
+    }
+}
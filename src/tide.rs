@@ -0,0 +1,180 @@
+//! Integration with the [tide](https://docs.rs/tide) web framework.
+//!
+//! The module is available behind the `tide` feature, and provides a
+//! middleware that rate limits incoming requests before they reach the
+//! endpoint.
+//!
+//! ```
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//! use youshallnotpass::tide::RateLimitMiddleware;
+//! use youshallnotpass::RateLimiter;
+//!
+//! let limiter = RateLimiter::configure()
+//!     .limit("/login".to_string(), 5, Duration::from_secs(60))
+//!     .done();
+//!
+//! let mut app = tide::new();
+//! app.with(RateLimitMiddleware::new(Arc::new(limiter), |req: &tide::Request<()>| {
+//!     req.url().path().to_string()
+//! }));
+//! ```
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+
+use tide::http::headers::RETRY_AFTER;
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+use crate::allowance::seconds;
+use crate::clock::{Clock, MonotonicClock};
+use crate::{Error, RateLimiter};
+
+/// Tide middleware consuming a token for every incoming request.
+///
+/// The `key` function maps a request to a key of the [`RateLimiter`], e.g. a
+/// path or a client address. Requests that are allowed by the limiter are
+/// passed down the middleware chain, while the rest are answered with
+/// `429 Too Many Requests`. If retrying makes sense, the response carries the
+/// `Retry-After` header with the number of seconds to wait.
+///
+/// Responses for keys with a limit carry the `RateLimit-Limit`,
+/// `RateLimit-Remaining` and `RateLimit-Reset` headers as well, both allowed
+/// and rejected ones, see [`RateLimiter::consume_detailed`].
+pub struct RateLimitMiddleware<K: 'static, F, C: 'static = MonotonicClock, S: 'static = RandomState>
+{
+    limiter: Arc<RateLimiter<K, C, S>>,
+    key: F,
+}
+
+impl<K, F, C, S> RateLimitMiddleware<K, F, C, S> {
+    /// Create a middleware consuming tokens from `limiter` for keys extracted
+    /// from requests by `key`.
+    ///
+    /// The `limiter` is either an [`Arc`], or a [`SharedRateLimiter`].
+    ///
+    /// [`SharedRateLimiter`]: crate::SharedRateLimiter
+    pub fn new(limiter: impl Into<Arc<RateLimiter<K, C, S>>>, key: F) -> Self {
+        RateLimitMiddleware {
+            limiter: limiter.into(),
+            key,
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State, K, F, C, S> Middleware<State> for RateLimitMiddleware<K, F, C, S>
+where
+    State: Clone + Send + Sync + 'static,
+    K: Eq + Hash + Clone + Send + Sync + 'static,
+    F: Fn(&Request<State>) -> K + Send + Sync + 'static,
+    C: Clock + Send + Sync + 'static,
+    S: BuildHasher + Send + Sync + 'static,
+{
+    async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let (mut response, usage) = match self.limiter.consume_detailed(&(self.key)(&request), 1) {
+            Ok(allowance) => (next.run(request).await, *allowance.usage()),
+            Err(denial) => {
+                let mut response = Response::new(StatusCode::TooManyRequests);
+                if let Error::RetryAfter(duration) = denial.error() {
+                    response.insert_header(RETRY_AFTER, seconds(*duration).to_string());
+                }
+                (response, *denial.usage())
+            }
+        };
+        for (name, value) in usage.headers() {
+            response.insert_header(name, value.to_string());
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use tide::http::{Method, Request as HttpRequest, Response as HttpResponse, Url};
+
+    use crate::ManualClock;
+
+    fn app(limiter: RateLimiter<String>) -> tide::Server<()> {
+        let mut app = tide::new();
+        app.with(RateLimitMiddleware::new(
+            Arc::new(limiter),
+            |req: &Request<()>| req.url().path().to_string(),
+        ));
+        app.at("/*").get(|_| async { Ok("ok") });
+        app
+    }
+
+    fn get(app: &tide::Server<()>, path: &str) -> HttpResponse {
+        let url = Url::parse("http://localhost").unwrap().join(path).unwrap();
+        async_std::task::block_on(app.respond(HttpRequest::new(Method::Get, url))).unwrap()
+    }
+
+    #[test]
+    fn retry_after() {
+        let app = app(RateLimiter::configure()
+            .limit("/a".to_string(), 1, Duration::from_secs(60))
+            .done());
+
+        assert_eq!(get(&app, "/a").status(), StatusCode::Ok);
+
+        let response = get(&app, "/a");
+        assert_eq!(response.status(), StatusCode::TooManyRequests);
+        let retry_after: u64 = response[RETRY_AFTER].as_str().parse().unwrap();
+        assert!(retry_after > 0 && retry_after <= 60);
+
+        // keys without a policy are never limited
+        assert_eq!(get(&app, "/b").status(), StatusCode::Ok);
+        assert_eq!(get(&app, "/b").status(), StatusCode::Ok);
+    }
+
+    #[test]
+    fn blocked() {
+        let app = app(RateLimiter::configure()
+            .limit("/a".to_string(), 0, Duration::from_secs(60))
+            .done());
+
+        let response = get(&app, "/a");
+        assert_eq!(response.status(), StatusCode::TooManyRequests);
+        assert!(response.header(RETRY_AFTER).is_none());
+        assert_eq!(response["ratelimit-limit"].as_str(), "0");
+        assert!(response.header("ratelimit-reset").is_none());
+    }
+
+    #[test]
+    fn headers() {
+        let clock = ManualClock::new();
+        let limiter = RateLimiter::with_clock(clock.clone())
+            .limit("/a".to_string(), 2, Duration::from_secs(60))
+            .done();
+        let mut app = tide::new();
+        app.with(RateLimitMiddleware::new(
+            Arc::new(limiter),
+            |req: &Request<()>| req.url().path().to_string(),
+        ));
+        app.at("/*").get(|_| async { Ok("ok") });
+        let headers = |response: &HttpResponse| {
+            ["ratelimit-limit", "ratelimit-remaining", "ratelimit-reset"]
+                .map(|name| response[name].as_str().to_string())
+        };
+
+        let response = get(&app, "/a");
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(headers(&response), ["2", "1", "30"]);
+
+        assert_eq!(get(&app, "/a").status(), StatusCode::Ok);
+        let response = get(&app, "/a");
+        assert_eq!(response.status(), StatusCode::TooManyRequests);
+        assert_eq!(headers(&response), ["2", "0", "60"]);
+        assert_eq!(response[RETRY_AFTER].as_str(), "30");
+
+        // keys without a policy have no limit to report
+        let response = get(&app, "/b");
+        assert!(response.header("ratelimit-limit").is_none());
+    }
+}
@@ -1,7 +1,15 @@
-use std::sync::Mutex;
-use std::time::{Duration, Instant};
+use std::collections::VecDeque;
+use std::fmt;
+use std::iter;
+use std::ops::Deref;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::error::Error;
+use crate::clock::{Clock, MonotonicClock};
+use crate::error::{DecodeError, Error};
+use crate::quota::{Penalty, Quota};
 
 /// Implementation of the [token bucket](https://en.wikipedia.org/wiki/Token_bucket)
 /// rate-limiting algorithm.
@@ -40,14 +48,95 @@ use crate::error::Error;
 /// ```
 ///
 /// Generated tokens can be consumed all at once or over time.
-pub struct TokenBucket<'a> {
-    time_per_token: usize,
+///
+/// The bucket owns its [`Clock`], which is the system monotonic clock unless
+/// specified otherwise, so it can be stored in a `'static` context.
+pub struct TokenBucket<C = MonotonicClock> {
+    time_per_token: u64,
+    /// The time it takes to refill an empty bucket up to its capacity.
     interval: Duration,
-    last_replenished_at: Mutex<Option<Instant>>,
-    clock: &'a (dyn Fn() -> Instant + Sync),
+    state: Mutex<State>,
+    /// The moment until which every request is known to be rejected, in
+    /// nanoseconds since `epoch`, or 0 if no such moment is known. Checking it
+    /// doesn't require taking the lock, which keeps rejections cheap for
+    /// exhausted buckets under heavy load.
+    denied_until: AtomicU64,
+    /// The moment the bucket was last used to admit requests, in nanoseconds
+    /// since `epoch`.
+    last_used_at: AtomicU64,
+    epoch: Instant,
+    /// The time since `epoch` over which the rate ramps up to the configured
+    /// one, or zero if the bucket starts at full speed.
+    warm_up: Duration,
+    penalty: Option<Penalty>,
+    /// Wakes up consumers waiting in the queue once its head changes.
+    waiting: Waiting,
+    clock: C,
+}
+
+/// Mutable state of a [`TokenBucket`].
+#[derive(Default)]
+struct State {
+    /// The moment up to which the generated tokens have been consumed, or `None`
+    /// if no tokens have been consumed yet.
+    last_replenished_at: Option<Instant>,
+
+    /// Tokens consumed speculatively, which are returned to the bucket unless
+    /// confirmed in time.
+    speculations: Vec<PendingSpeculation>,
+
+    /// Identifier to assign to the next speculation.
+    next_speculation_id: u64,
+
+    /// The moment of the first of consecutive rejections, and their number,
+    /// if the bucket penalizes them.
+    rejected: Option<(Instant, usize)>,
+
+    /// The moment until which every request is rejected as a penalty.
+    penalized_until: Option<Instant>,
+
+    /// Identifiers of consumers waiting for tokens, in arrival order. Only the
+    /// consumer at the head of the queue tries to consume tokens.
+    waiters: VecDeque<u64>,
+
+    /// Identifier to assign to the next waiting consumer.
+    next_waiter_id: u64,
+}
+
+/// Notifications of waiting consumers, both threads and tasks, which share
+/// the same queue.
+#[derive(Default)]
+struct Waiting {
+    threads: Condvar,
+    #[cfg(feature = "tokio")]
+    tasks: tokio::sync::Notify,
+}
+
+/// A place in the queue of waiting consumers, which is given up on drop.
+struct Waiter<'b, C> {
+    bucket: &'b TokenBucket<C>,
+    id: u64,
+}
+
+impl<C> Drop for Waiter<'_, C> {
+    fn drop(&mut self) {
+        let mut state = self.bucket.state.lock().unwrap();
+        state.waiters.retain(|&id| id != self.id);
+        drop(state);
+
+        self.bucket.waiting.threads.notify_all();
+        #[cfg(feature = "tokio")]
+        self.bucket.waiting.tasks.notify_waiters();
+    }
+}
+
+struct PendingSpeculation {
+    id: u64,
+    tokens: usize,
+    expires_at: Instant,
 }
 
-impl<'a> TokenBucket<'a> {
+impl TokenBucket {
     /// Create a new [`TokenBucket`] with `limit` tokens generated with a constant
     /// rate over the specified `interval` of time.
     ///
@@ -75,24 +164,116 @@ impl<'a> TokenBucket<'a> {
     /// assert!(matches!(bucket.consume(1), Err(Error::Blocked)));
     /// ```
     pub fn new(limit: usize, interval: Duration) -> Self {
-        TokenBucket::with_timer(limit, interval, &Instant::now)
+        TokenBucket::with_clock(limit, interval, MonotonicClock)
+    }
+
+    /// Create a new [`TokenBucket`] limiting requests to the given `quota`.
+    ///
+    /// ```
+    /// use youshallnotpass::{Quota, TokenBucket};
+    ///
+    /// // create a bucket that allows to consume 2 tokens every second
+    /// let bucket = TokenBucket::from_quota(Quota::per_second(2));
+    /// assert!(bucket.consume(2).is_ok());
+    /// assert!(bucket.consume(1).is_err());
+    /// ```
+    ///
+    /// Same as with [`TokenBucket::new()`], a quota of 0 tokens blocks a given
+    /// entity.
+    pub fn from_quota(quota: Quota) -> Self {
+        TokenBucket::from_quota_with_clock(quota, MonotonicClock)
+    }
+
+    /// Constructs a new [`TokenBucketBuilder`] object.
+    ///
+    /// Unlike [`TokenBucket::new()`], which uses the same `limit` for both the
+    /// capacity of the bucket and the number of tokens generated over the
+    /// interval, the builder allows to set them separately. This is useful
+    /// to allow short bursts of requests, while sustaining a lower rate.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::{TokenBucket, Error};
+    ///
+    /// // create a bucket that allows bursts of 100 tokens, refilled at 10/s
+    /// let bucket = TokenBucket::builder()
+    ///     .capacity(100)
+    ///     .refill(10, Duration::from_secs(1))
+    ///     .done();
+    /// assert!(bucket.consume(100).is_ok());
+    /// assert!(matches!(bucket.consume(1), Err(Error::RetryAfter(_))));
+    /// ```
+    pub fn builder() -> TokenBucketBuilder {
+        TokenBucket::builder_with_clock(MonotonicClock)
     }
 
+    /// Decode a bucket previously encoded with [`TokenBucket::to_bytes()`].
+    ///
+    /// Please see [`TokenBucket::to_bytes()`] for the caveats. In particular, the
+    /// input must be authenticated by the caller, as the decoder cannot detect
+    /// tampering.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        TokenBucket::from_bytes_with_clock(bytes, MonotonicClock)
+    }
+}
+
+impl<C: Clock> TokenBucket<C> {
     /// Same as [`TokenBucket::new()`], but allows to override the internal clock,
     /// which is mainly useful in tests.
-    pub(crate) fn with_timer(
-        limit: usize,
-        interval: Duration,
-        clock: &'a (dyn Fn() -> Instant + Sync),
-    ) -> Self {
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::{Error, ManualClock, TokenBucket};
+    ///
+    /// let clock = ManualClock::new();
+    /// let bucket = TokenBucket::with_clock(2, Duration::from_secs(30), clock.clone());
+    /// assert!(bucket.consume(2).is_ok());
+    /// assert_eq!(bucket.consume(1), Err(Error::RetryAfter(Duration::from_secs(15))));
+    ///
+    /// clock.advance(Duration::from_secs(15));
+    /// assert!(bucket.consume(1).is_ok());
+    /// ```
+    pub fn with_clock(limit: usize, interval: Duration, clock: C) -> Self {
+        TokenBucket::with_refill(limit, limit, interval, clock)
+    }
+
+    /// Same as [`TokenBucket::from_quota()`], but allows to override the
+    /// internal clock, which is mainly useful in tests along with a
+    /// [`ManualClock`](crate::ManualClock).
+    pub fn from_quota_with_clock(quota: Quota, clock: C) -> Self {
+        let mut bucket =
+            TokenBucket::with_refill(quota.burst(), quota.tokens(), quota.interval(), clock);
+        bucket.penalty = quota.penalty();
+        bucket
+    }
+
+    /// Create a new [`TokenBucket`] holding up to `capacity` tokens, which is
+    /// refilled with `tokens` every `interval` of time.
+    fn with_refill(capacity: usize, tokens: usize, interval: Duration, clock: C) -> Self {
+        let (time_per_token, interval) = refill_rate(capacity, tokens, interval);
+
         TokenBucket {
-            time_per_token: if limit > 0 {
-                interval.as_nanos() as usize / limit
-            } else {
-                0
-            },
+            time_per_token,
             interval,
-            last_replenished_at: Mutex::new(None),
+            state: Mutex::new(State::default()),
+            denied_until: AtomicU64::new(0),
+            last_used_at: AtomicU64::new(0),
+            epoch: clock.now(),
+            warm_up: Duration::ZERO,
+            penalty: None,
+            waiting: Waiting::default(),
+            clock,
+        }
+    }
+
+    /// Same as [`TokenBucket::builder()`], but allows to override the internal
+    /// clock, which is mainly useful in tests along with a [`ManualClock`](crate::ManualClock).
+    pub fn builder_with_clock(clock: C) -> TokenBucketBuilder<C> {
+        TokenBucketBuilder {
+            capacity: None,
+            refill: (0, Duration::ZERO),
+            warm_up: Duration::ZERO,
+            penalty: None,
             clock,
         }
     }
@@ -108,7 +289,11 @@ impl<'a> TokenBucket<'a> {
     /// same arguments again. Retrying the operation earlier will result in the same error.
     ///
     /// If the bucket has a limit of 0 tokens, [`Error::Blocked`] is always returned instead,
-    /// regardless of how much time the caller waits between attempts.
+    /// regardless of how much time the caller waits between attempts. Likewise, requests
+    /// for more tokens than the bucket can ever hold always result in
+    /// [`Error::ExceedsCapacity`].
+    ///
+    /// The function never allocates memory, so it's safe to use on hot paths.
     ///
     /// ```
     /// use std::time::Duration;
@@ -125,223 +310,3364 @@ impl<'a> TokenBucket<'a> {
     /// let bucket = TokenBucket::new(0, Duration::from_secs(60));
     /// assert!(matches!(bucket.consume(1), Err(Error::Blocked)));
     /// ```
+    #[inline]
     pub fn consume(&self, tokens: usize) -> Result<(), Error> {
+        self.consume_remaining(tokens).map(|_| ())
+    }
+
+    /// Same as [`TokenBucket::consume()`], but returns the number of tokens
+    /// remaining in the bucket after the consumption.
+    ///
+    /// This is useful to report the headroom left without a separate, racy
+    /// call to [`TokenBucket::available()`].
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::TokenBucket;
+    ///
+    /// let bucket = TokenBucket::new(3, Duration::from_secs(60));
+    /// assert_eq!(bucket.consume_remaining(1), Ok(2));
+    /// assert_eq!(bucket.consume_remaining(2), Ok(0));
+    /// assert!(bucket.consume_remaining(1).is_err());
+    /// ```
+    #[inline]
+    pub fn consume_remaining(&self, tokens: usize) -> Result<u64, Error> {
+        self.consume_remaining_at(tokens, None)
+    }
+
+    /// Same as [`TokenBucket::consume()`], but evaluates the bucket at the
+    /// given moment of time (`at`) instead of the current one.
+    ///
+    /// This is useful to replay recorded traffic, or to run deterministic
+    /// simulations. Mixing it with the functions using the current time on the
+    /// same bucket is possible, but rarely makes sense.
+    ///
+    /// ```
+    /// use std::time::{Duration, Instant};
+    /// use youshallnotpass::{TokenBucket, Error};
+    ///
+    /// let bucket = TokenBucket::new(1, Duration::from_secs(60));
+    /// let start = Instant::now();
+    ///
+    /// assert_eq!(bucket.consume_at(1, start), Ok(()));
+    /// assert_eq!(
+    ///     bucket.consume_at(1, start + Duration::from_secs(45)),
+    ///     Err(Error::RetryAfter(Duration::from_secs(15)))
+    /// );
+    /// assert_eq!(bucket.consume_at(1, start + Duration::from_secs(60)), Ok(()));
+    /// ```
+    pub fn consume_at(&self, tokens: usize, at: Instant) -> Result<(), Error> {
+        self.consume_remaining_at(tokens, Some(at)).map(|_| ())
+    }
+
+    /// Consume the specified number of `tokens` from the bucket, blocking the
+    /// current thread until they are available.
+    ///
+    /// This is a pacing API for batch jobs and command line tools, which would
+    /// otherwise sleep for [`Error::RetryAfter`] durations in a loop. Requests
+    /// that can never be admitted fail right away with [`Error::Blocked`] or
+    /// [`Error::ExceedsCapacity`].
+    ///
+    /// Consumers waiting on the same bucket are served in arrival order, both
+    /// threads and tasks waiting via [`TokenBucket::acquire()`], so a large
+    /// request is not starved by a stream of smaller ones that would fit
+    /// earlier. Only [`TokenBucket::consume()`] and friends, which never wait,
+    /// bypass the queue.
+    ///
+    /// The thread sleeps for real, so the bucket must be driven by a clock
+    /// that advances on its own, e.g. the default one.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::TokenBucket;
+    ///
+    /// let bucket = TokenBucket::new(100, Duration::from_secs(1));
+    /// for _ in 0..105 {
+    ///     // the last 5 iterations are paced at 100 per second
+    ///     bucket.consume_wait(1).unwrap();
+    /// }
+    /// ```
+    pub fn consume_wait(&self, tokens: usize) -> Result<(), Error> {
+        self.consume_wait_until(tokens, None)
+    }
+
+    /// Same as [`TokenBucket::consume_wait()`], but waits for no longer than
+    /// `max_wait`.
+    ///
+    /// If the tokens aren't going to be available in time, the function fails
+    /// right away with [`Error::RetryAfter`], rather than waiting in vain. The
+    /// same happens if the consumers queued ahead aren't served in time, in
+    /// which case the duration doesn't account for them.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::{Error, TokenBucket};
+    ///
+    /// let bucket = TokenBucket::new(1, Duration::from_secs(60));
+    /// assert!(bucket.consume_wait_timeout(1, Duration::from_secs(1)).is_ok());
+    /// assert!(matches!(
+    ///     bucket.consume_wait_timeout(1, Duration::from_secs(1)),
+    ///     Err(Error::RetryAfter(_))
+    /// ));
+    /// ```
+    pub fn consume_wait_timeout(&self, tokens: usize, max_wait: Duration) -> Result<(), Error> {
+        let deadline = self.clock.now().checked_add(max_wait);
+        self.consume_wait_until(tokens, deadline)
+    }
+
+    /// Consume the specified number of `tokens` from the bucket, waiting
+    /// asynchronously until they are available.
+    ///
+    /// Same as [`TokenBucket::consume_wait()`], but sleeps via
+    /// [`tokio::time::sleep`] instead of blocking the thread, and thus must be
+    /// awaited within a Tokio runtime.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::TokenBucket;
+    ///
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// let bucket = TokenBucket::new(100, Duration::from_secs(1));
+    /// for _ in 0..105 {
+    ///     bucket.acquire(1).await.unwrap();
+    /// }
+    /// # }
+    /// ```
+    #[cfg(feature = "tokio")]
+    pub async fn acquire(&self, tokens: usize) -> Result<(), Error> {
+        self.validate(tokens)?;
+        let waiter = self.enqueue();
+        loop {
+            loop {
+                // the notification must be subscribed to before checking the
+                // queue, so that a change in between is not missed
+                let notified = self.waiting.tasks.notified();
+                if self.is_head(&waiter) {
+                    break;
+                }
+                notified.await;
+            }
+
+            match self.consume(tokens) {
+                Err(Error::RetryAfter(delay)) => tokio::time::sleep(delay).await,
+                result => return result,
+            }
+        }
+    }
+
+    /// Consume `tokens`, sleeping until they are available, unless it takes
+    /// past the `deadline`.
+    fn consume_wait_until(&self, tokens: usize, deadline: Option<Instant>) -> Result<(), Error> {
+        self.validate(tokens)?;
+        let waiter = self.enqueue();
+        loop {
+            let mut state = self.state.lock().unwrap();
+            while state.waiters.front() != Some(&waiter.id) {
+                state = match deadline {
+                    Some(deadline) => {
+                        let timeout = deadline.saturating_duration_since(self.clock.now());
+                        if timeout.is_zero() {
+                            drop(state);
+                            return Err(Error::RetryAfter(self.queued_delay(tokens)));
+                        }
+                        self.waiting.threads.wait_timeout(state, timeout).unwrap().0
+                    }
+                    None => self.waiting.threads.wait(state).unwrap(),
+                };
+            }
+            drop(state);
+
+            match self.consume(tokens) {
+                Err(Error::RetryAfter(delay)) => {
+                    if let Some(deadline) = deadline {
+                        if self.clock.now() + delay > deadline {
+                            return Err(Error::RetryAfter(delay));
+                        }
+                    }
+                    std::thread::sleep(delay);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Fail right away if the specified number of `tokens` can never be
+    /// consumed, so that there is no point in waiting for them.
+    fn validate(&self, tokens: usize) -> Result<(), Error> {
+        if self.time_per_token == 0 {
+            Err(Error::Blocked)
+        } else if tokens as u128 > self.capacity() {
+            Err(Error::ExceedsCapacity)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Take a place at the end of the queue of waiting consumers.
+    fn enqueue(&self) -> Waiter<'_, C> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_waiter_id;
+        state.next_waiter_id += 1;
+        state.waiters.push_back(id);
+        Waiter { bucket: self, id }
+    }
+
+    /// Return `true` if the `waiter` is at the head of the queue.
+    #[cfg(feature = "tokio")]
+    fn is_head(&self, waiter: &Waiter<'_, C>) -> bool {
+        self.state.lock().unwrap().waiters.front() == Some(&waiter.id)
+    }
+
+    /// Return how long to wait before `tokens` are available, not counting
+    /// the consumers queued ahead.
+    fn queued_delay(&self, tokens: usize) -> Duration {
+        match self.check(tokens) {
+            Err(Error::RetryAfter(delay)) => delay,
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Check whether the specified number of `tokens` can be consumed from the
+    /// bucket, without consuming them.
+    ///
+    /// The function returns exactly what [`TokenBucket::consume()`] would have
+    /// returned, but never charges the bucket. This is useful to pre-validate
+    /// requests early, and charge them once they are actually executed.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::TokenBucket;
+    ///
+    /// let bucket = TokenBucket::new(1, Duration::from_secs(60));
+    /// assert!(bucket.check(1).is_ok());
+    /// assert!(bucket.check(1).is_ok());
+    /// assert!(bucket.consume(1).is_ok());
+    /// assert!(bucket.check(1).is_err());
+    /// ```
+    pub fn check(&self, tokens: usize) -> Result<(), Error> {
+        if self.time_per_token == 0 {
+            return Err(Error::Blocked);
+        }
+        if tokens as u128 > self.capacity() {
+            return Err(Error::ExceedsCapacity);
+        }
+
+        let now = self.clock.now();
+        if let Some(retry_after) = self.cached_denial(now, tokens) {
+            return Err(Error::RetryAfter(retry_after));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        self.expire_speculations(&mut state, now);
+        if let Some(retry_after) = self.penalty_delay(&state, now) {
+            return Err(Error::RetryAfter(retry_after));
+        }
+
+        let required_time = self.required_time(state.last_replenished_at, now, tokens);
+        if required_time > now {
+            Err(Error::RetryAfter(required_time - now))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Same as [`TokenBucket::consume_remaining()`], but at the given moment
+    /// `at`, if any, and at the current one otherwise.
+    #[inline]
+    fn consume_remaining_at(&self, tokens: usize, at: Option<Instant>) -> Result<u64, Error> {
+        let now = at.unwrap_or_else(|| self.clock.now());
+        self.touch(now);
         if self.time_per_token == 0 {
             return Err(Error::Blocked);
         }
+        if tokens as u128 > self.capacity() {
+            return Err(Error::ExceedsCapacity);
+        }
+
+        if let Some(retry_after) = self.cached_denial(now, tokens) {
+            return Err(Error::RetryAfter(retry_after));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        // the clock is read again once the bucket is locked, or else a thread
+        // that read it earlier could be charged after one that read it later,
+        // and be rejected until the time the latter one read
+        let now = at.unwrap_or_else(|| self.clock.now());
+        self.expire_speculations(&mut state, now);
+        if let Some(retry_after) = self.penalty_delay(&state, now) {
+            return Err(Error::RetryAfter(retry_after));
+        }
+
+        let required_time = self.required_time(state.last_replenished_at, now, tokens);
+        if required_time > now {
+            Err(Error::RetryAfter(self.reject(
+                &mut state,
+                now,
+                required_time - now,
+            )))
+        } else {
+            state.last_replenished_at = Some(required_time);
+            state.rejected = None;
+            Ok(self.available_tokens(&state, now) as u64)
+        }
+    }
+
+    /// Try to consume a provisional number of `tokens` from the bucket, to be
+    /// reconciled with the actual cost later via [`TokenBucket::settle()`].
+    ///
+    /// This is useful when the true cost of a request is only known once it
+    /// has finished, e.g. the number of rows scanned by a query. The request is
+    /// admitted based on its estimated cost, same as with [`TokenBucket::consume()`],
+    /// and the returned [`Reservation`] is settled with the measured cost.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::TokenBucket;
+    ///
+    /// let bucket = TokenBucket::new(3, Duration::from_secs(60));
+    ///
+    /// let reservation = bucket.reserve(1).unwrap();
+    /// // the request turned out to be more expensive than estimated
+    /// bucket.settle(reservation, 3);
+    /// assert!(bucket.consume(1).is_err());
+    /// ```
+    pub fn reserve(&self, tokens: usize) -> Result<Reservation, Error> {
+        self.consume(tokens).map(|()| Reservation { tokens })
+    }
+
+    /// Reconcile a `reservation` made via [`TokenBucket::reserve()`] with the
+    /// actual number of `tokens` the request has cost.
+    ///
+    /// If the actual cost is lower than the reserved one, the difference is
+    /// returned to the bucket, up to its capacity. If the actual cost is higher,
+    /// the difference is consumed unconditionally, which may push the bucket
+    /// into *debt*: no tokens can be consumed until the debt is paid off by
+    /// replenishment, and [`Error::RetryAfter`] accounts for that.
+    pub fn settle(&self, reservation: Reservation, tokens: usize) {
+        if self.time_per_token == 0 {
+            return;
+        }
+
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+
+        if tokens >= reservation.tokens {
+            let interval_start = now.checked_sub(self.interval).unwrap_or(now);
+            let last_replenished_at = state.last_replenished_at.unwrap_or(interval_start);
+            let debt = self.token_time(tokens - reservation.tokens);
+            self.forget_denial();
+            state.last_replenished_at =
+                Some(std::cmp::max(interval_start, last_replenished_at) + debt);
+        } else {
+            self.give_back(&mut state, now, reservation.tokens - tokens);
+        }
+    }
+
+    /// Return the specified number of `tokens` back to the bucket, e.g. when a
+    /// request is cancelled or fails before doing any real work.
+    ///
+    /// Refunded tokens never make the bucket exceed its capacity, so refunding
+    /// more tokens than consumed merely fills the bucket up.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::TokenBucket;
+    ///
+    /// let bucket = TokenBucket::new(1, Duration::from_secs(60));
+    /// assert!(bucket.consume(1).is_ok());
+    /// assert!(bucket.consume(1).is_err());
+    ///
+    /// bucket.refund(1);
+    /// assert!(bucket.consume(1).is_ok());
+    /// ```
+    pub fn refund(&self, tokens: usize) {
+        if self.time_per_token == 0 {
+            return;
+        }
+
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        self.give_back(&mut state, now, tokens);
+    }
+
+    /// Change the rate of the bucket to `limit` tokens generated with a constant
+    /// rate over the specified `interval` of time, same as if it was created
+    /// via [`TokenBucket::new()`].
+    ///
+    /// The tokens available in the bucket are preserved, up to the new
+    /// capacity, and so is the debt, if any. Pending speculations are refunded
+    /// at the new rate. A bucket that has been blocked becomes full once
+    /// unblocked.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::TokenBucket;
+    ///
+    /// let mut bucket = TokenBucket::new(10, Duration::from_secs(60));
+    /// assert!(bucket.consume(5).is_ok());
+    ///
+    /// // tighten the limit under load, keeping the tokens left
+    /// bucket.set_rate(3, Duration::from_secs(60));
+    /// assert!(bucket.consume(3).is_ok());
+    /// assert!(bucket.consume(1).is_err());
+    /// ```
+    pub fn set_rate(&mut self, limit: usize, interval: Duration) {
+        self.set_refill(limit, limit, interval);
+    }
+
+    /// Change the policy of the bucket to the `quota`, same as if it was
+    /// created via [`TokenBucket::from_quota()`], preserving its state as
+    /// [`TokenBucket::set_rate()`] does.
+    pub(crate) fn set_quota(&mut self, quota: Quota) {
+        self.set_refill(quota.burst(), quota.tokens(), quota.interval());
+        self.penalty = quota.penalty();
+    }
+
+    /// Change the bucket to hold up to `capacity` tokens, refilled with
+    /// `tokens` every `interval` of time, preserving its state.
+    fn set_refill(&mut self, capacity: usize, tokens: usize, interval: Duration) {
+        let (time_per_token, interval) = refill_rate(capacity, tokens, interval);
+        let now = self.clock.now();
+        let state = self.state.get_mut().unwrap();
+
+        if self.time_per_token == 0 || time_per_token == 0 {
+            state.last_replenished_at = None;
+        } else if let Some(last_replenished_at) = state.last_replenished_at {
+            let interval_start = now.checked_sub(self.interval).unwrap_or(now);
+            let replenished_at = std::cmp::max(interval_start, last_replenished_at);
+            let convert = |duration: Duration| {
+                let nanos =
+                    duration.as_nanos() * time_per_token as u128 / self.time_per_token as u128;
+                Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+            };
+
+            state.last_replenished_at = if replenished_at <= now {
+                let available = std::cmp::min(convert(now - replenished_at), interval);
+                now.checked_sub(available)
+            } else {
+                Some(now + convert(replenished_at - now))
+            };
+        }
+
+        self.time_per_token = time_per_token;
+        self.interval = interval;
+        self.denied_until.store(0, Ordering::Release);
+    }
+
+    /// Refill the bucket up to its capacity, e.g. to lift a limit after an
+    /// incident has been resolved. Any debt, or penalty, is forgiven as well.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::TokenBucket;
+    ///
+    /// let bucket = TokenBucket::new(2, Duration::from_secs(60));
+    /// assert!(bucket.consume(2).is_ok());
+    ///
+    /// bucket.reset();
+    /// assert!(bucket.consume(2).is_ok());
+    /// ```
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        self.forget_denial();
+        state.last_replenished_at = None;
+        state.rejected = None;
+        state.penalized_until = None;
+    }
+
+    /// Empty the bucket immediately, e.g. to throttle a misbehaving client.
+    /// New tokens are generated as usual afterwards.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::TokenBucket;
+    ///
+    /// let bucket = TokenBucket::new(2, Duration::from_secs(60));
+    ///
+    /// bucket.drain();
+    /// assert!(bucket.consume(1).is_err());
+    /// ```
+    pub fn drain(&self) {
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        self.forget_denial();
+        state.last_replenished_at = Some(
+            state
+                .last_replenished_at
+                .map_or(now, |last_replenished_at| {
+                    std::cmp::max(last_replenished_at, now)
+                }),
+        );
+    }
+
+    /// Return how long the bucket hasn't been used to admit requests, or has
+    /// existed if it has never been used.
+    ///
+    /// Every attempt to consume tokens counts as a use, whether it succeeds or
+    /// not, while introspection functions, such as [`TokenBucket::check()`],
+    /// do not. This is useful to evict buckets that have been idle for long.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::TokenBucket;
+    ///
+    /// let bucket = TokenBucket::new(2, Duration::from_secs(60));
+    /// assert!(bucket.consume(1).is_ok());
+    /// assert!(bucket.idle_for() < Duration::from_secs(60));
+    /// ```
+    pub fn idle_for(&self) -> Duration {
+        let now = self.clock.now();
+        let last_used_at =
+            self.epoch + Duration::from_nanos(self.last_used_at.load(Ordering::Relaxed));
+        now.saturating_duration_since(last_used_at)
+    }
 
-        let now = (self.clock)();
-        let mut lock = self.last_replenished_at.lock().unwrap();
+    /// Try to consume the specified number of `tokens` from the bucket, and
+    /// return a [`Permit`] that refunds them unless committed.
+    ///
+    /// This is useful to charge the limit only if an operation has actually
+    /// run: the permit is committed via [`Permit::commit()`] once it has, and
+    /// the tokens are returned to the bucket if the permit is dropped, e.g.
+    /// on an early return or a panic.
+    ///
+    /// Apart from that, the function behaves exactly as [`TokenBucket::consume()`].
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::TokenBucket;
+    ///
+    /// let bucket = TokenBucket::new(1, Duration::from_secs(60));
+    ///
+    /// // the operation failed before doing any work, so the token is refunded
+    /// let permit = bucket.consume_permit(1).unwrap();
+    /// drop(permit);
+    ///
+    /// let permit = bucket.consume_permit(1).unwrap();
+    /// permit.commit();
+    /// assert!(bucket.consume(1).is_err());
+    /// ```
+    pub fn consume_permit(&self, tokens: usize) -> Result<Permit<'_, C>, Error> {
+        self.consume(tokens).map(|()| Permit {
+            bucket: Some(BucketRef::Borrowed(self)),
+            stacked: Vec::new(),
+            tokens,
+        })
+    }
+
+    /// Try to consume the specified number of `tokens` from the bucket
+    /// speculatively, i.e. returning them to the bucket unless the consumption
+    /// is confirmed within `timeout`.
+    ///
+    /// This is useful when a request is admitted in a fast path, but may still
+    /// be dropped by a later component before it costs anything. The returned
+    /// [`Speculation`] must be confirmed via [`Speculation::confirm()`] once the
+    /// request is actually processed. If the speculation is dropped, or isn't
+    /// confirmed in time, the tokens are refunded.
+    ///
+    /// Apart from that, the function behaves exactly as [`TokenBucket::consume()`].
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::TokenBucket;
+    ///
+    /// let bucket = TokenBucket::new(1, Duration::from_secs(60));
+    ///
+    /// // the request is dropped before being processed, so the token is refunded
+    /// let speculation = bucket.consume_speculative(1, Duration::from_secs(1)).unwrap();
+    /// drop(speculation);
+    ///
+    /// let speculation = bucket.consume_speculative(1, Duration::from_secs(1)).unwrap();
+    /// assert!(speculation.confirm());
+    /// assert!(bucket.consume(1).is_err());
+    /// ```
+    pub fn consume_speculative(
+        &self,
+        tokens: usize,
+        timeout: Duration,
+    ) -> Result<Speculation<'_, C>, Error> {
+        if self.time_per_token == 0 {
+            return Err(Error::Blocked);
+        }
+        if tokens as u128 > self.capacity() {
+            return Err(Error::ExceedsCapacity);
+        }
+
+        let now = self.clock.now();
+        self.touch(now);
+        let mut state = self.state.lock().unwrap();
+        self.expire_speculations(&mut state, now);
+
+        let required_time = self.required_time(state.last_replenished_at, now, tokens);
+        if let Some(retry_after) = self.rejection(&mut state, now, required_time) {
+            return Err(Error::RetryAfter(retry_after));
+        }
+
+        let id = state.next_speculation_id;
+        state.next_speculation_id += 1;
+        state.last_replenished_at = Some(required_time);
+        state.rejected = None;
+        state.speculations.push(PendingSpeculation {
+            id,
+            tokens,
+            expires_at: now + timeout,
+        });
+
+        Ok(Speculation {
+            bucket: Some(BucketRef::Borrowed(self)),
+            stacked: Vec::new(),
+            id,
+        })
+    }
+
+    /// Return the number of tokens currently available in the bucket, and how
+    /// long it takes to generate the next one, without consuming anything.
+    ///
+    /// This is useful to report the state of the bucket, e.g. via
+    /// `X-RateLimit-Remaining` headers. If the bucket has a limit of 0 tokens,
+    /// [`Error::Blocked`] is returned instead.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::TokenBucket;
+    ///
+    /// let bucket = TokenBucket::new(3, Duration::from_secs(60));
+    /// assert!(bucket.consume(1).is_ok());
+    ///
+    /// let availability = bucket.available().unwrap();
+    /// assert_eq!(availability.tokens(), 2);
+    /// assert!(availability.next_token_in() > Duration::ZERO);
+    /// ```
+    pub fn available(&self) -> Result<Availability, Error> {
+        if self.time_per_token == 0 {
+            return Err(Error::Blocked);
+        }
+
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        self.expire_speculations(&mut state, now);
+
+        let tokens = self.available_tokens(&state, now);
+        let next_token_in = if tokens as u128 >= self.capacity() {
+            // the bucket is full, and no more tokens are generated
+            Duration::ZERO
+        } else {
+            self.required_time(state.last_replenished_at, now, tokens + 1) - now
+        };
+
+        Ok(Availability {
+            tokens,
+            next_token_in,
+        })
+    }
+
+    /// Return how long it takes to refill the bucket up to its capacity,
+    /// without consuming anything.
+    ///
+    /// This is useful to schedule resuming a paused job at full speed, rather
+    /// than trickling requests as soon as a single token is available. A full
+    /// bucket returns zero. If the bucket has a limit of 0 tokens, it's never
+    /// refilled, and [`Error::Blocked`] is returned instead.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::{ManualClock, TokenBucket};
+    ///
+    /// let bucket = TokenBucket::with_clock(4, Duration::from_secs(60), ManualClock::new());
+    /// assert_eq!(bucket.time_to_full(), Ok(Duration::ZERO));
+    ///
+    /// assert!(bucket.consume(2).is_ok());
+    /// assert_eq!(bucket.time_to_full(), Ok(Duration::from_secs(30)));
+    /// ```
+    pub fn time_to_full(&self) -> Result<Duration, Error> {
+        if self.time_per_token == 0 {
+            return Err(Error::Blocked);
+        }
+
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        self.expire_speculations(&mut state, now);
+        Ok(self.deficit(&state, now))
+    }
+
+    /// Consume as many tokens as available, but no more than `tokens`, and
+    /// return the number of tokens consumed.
+    ///
+    /// Unlike [`TokenBucket::consume()`], which is all-or-nothing, this is
+    /// useful to size a batch of work to the available budget. If no tokens
+    /// are available, or the bucket has a limit of 0 tokens, 0 is returned.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::TokenBucket;
+    ///
+    /// let bucket = TokenBucket::new(3, Duration::from_secs(60));
+    /// assert_eq!(bucket.consume_up_to(2), 2);
+    /// assert_eq!(bucket.consume_up_to(2), 1);
+    /// assert_eq!(bucket.consume_up_to(2), 0);
+    /// ```
+    pub fn consume_up_to(&self, tokens: usize) -> usize {
+        if self.time_per_token == 0 {
+            return 0;
+        }
+
+        let now = self.clock.now();
+        self.touch(now);
+        let mut state = self.state.lock().unwrap();
+        self.expire_speculations(&mut state, now);
+
+        if self.penalty_delay(&state, now).is_some() {
+            return 0;
+        }
+
+        let granted = std::cmp::min(tokens, self.available_tokens(&state, now));
+        if granted > 0 {
+            state.last_replenished_at =
+                Some(self.required_time(state.last_replenished_at, now, granted));
+            state.rejected = None;
+        } else if tokens > 0 {
+            let required_time = self.required_time(state.last_replenished_at, now, 1);
+            self.rejection(&mut state, now, required_time);
+        }
+        granted
+    }
+
+    /// Admit a request costing `tokens` without ever rejecting it, and return
+    /// its [`QosClass`] based on how full the bucket is.
+    ///
+    /// This is useful to degrade the service (e.g. serve a smaller page, or
+    /// cached content) instead of failing requests:
+    ///
+    /// * [`QosClass::Green`] means the tokens are consumed, and the bucket is
+    ///   still at least half full
+    ///
+    /// * [`QosClass::Yellow`] means the tokens are consumed, but the bucket is
+    ///   running low
+    ///
+    /// * [`QosClass::Red`] means the bucket doesn't have enough tokens, and
+    ///   nothing is consumed, same as if [`TokenBucket::consume()`] failed
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::{QosClass, TokenBucket};
+    ///
+    /// let bucket = TokenBucket::new(4, Duration::from_secs(60));
+    /// assert_eq!(bucket.admit(1), QosClass::Green);
+    /// assert_eq!(bucket.admit(1), QosClass::Green);
+    /// assert_eq!(bucket.admit(1), QosClass::Yellow);
+    /// assert_eq!(bucket.admit(2), QosClass::Red);
+    /// ```
+    pub fn admit(&self, tokens: usize) -> QosClass {
+        if self.time_per_token == 0 {
+            return QosClass::Red;
+        }
+
+        let now = self.clock.now();
+        self.touch(now);
+        let mut state = self.state.lock().unwrap();
+        self.expire_speculations(&mut state, now);
+
+        let required_time = self.required_time(state.last_replenished_at, now, tokens);
+        if self.rejection(&mut state, now, required_time).is_some() {
+            return QosClass::Red;
+        }
+        state.last_replenished_at = Some(required_time);
+        state.rejected = None;
+
+        if self.available_tokens(&state, now) as u128 * 2 >= self.capacity() {
+            QosClass::Green
+        } else {
+            QosClass::Yellow
+        }
+    }
+
+    /// Compose this bucket with `other` into a limiter that admits only what
+    /// both buckets allow.
+    ///
+    /// This is useful when several independent limits apply to the same
+    /// requests, e.g. an API limit and a hardware limit. Tokens are consumed
+    /// from both buckets atomically: either both buckets are charged, or
+    /// neither is. See [`Intersection::consume()`] for details.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::TokenBucket;
+    ///
+    /// let api = TokenBucket::new(3, Duration::from_secs(60));
+    /// let hardware = TokenBucket::new(2, Duration::from_secs(60));
+    ///
+    /// let both = api.intersect(&hardware);
+    /// assert!(both.consume(1).is_ok());
+    /// assert!(both.consume(1).is_ok());
+    /// assert!(both.consume(1).is_err());
+    ///
+    /// // the API bucket wasn't charged for the rejected request
+    /// assert!(api.consume(1).is_ok());
+    /// ```
+    pub fn intersect<'b>(&'b self, other: &'b TokenBucket<C>) -> Intersection<'b, C> {
+        Intersection {
+            first: self,
+            second: other,
+        }
+    }
+
+    /// Return the time at which `tokens` can be consumed from the bucket,
+    /// given the moment the bucket was `last_replenished_at`.
+    fn required_time(
+        &self,
+        last_replenished_at: Option<Instant>,
+        now: Instant,
+        tokens: usize,
+    ) -> Instant {
+        let interval_start = now.checked_sub(self.interval).unwrap_or(now);
+        let token_delay = self.warm_up_time(self.token_time(tokens), now);
+        let last_replenished_at = last_replenished_at.unwrap_or(interval_start);
+
+        std::cmp::max(interval_start, last_replenished_at) + token_delay
+    }
+
+    /// Return the specified number of `tokens` back to the bucket, up to its
+    /// capacity.
+    fn give_back(&self, state: &mut State, now: Instant, tokens: usize) {
+        self.forget_denial();
+        let interval_start = now.checked_sub(self.interval).unwrap_or(now);
+        let refund = self.token_time(tokens);
+
+        if let Some(last_replenished_at) = state.last_replenished_at {
+            state.last_replenished_at = Some(
+                last_replenished_at
+                    .checked_sub(refund)
+                    .map_or(interval_start, |refunded_at| {
+                        std::cmp::max(refunded_at, interval_start)
+                    }),
+            );
+        }
+    }
+
+    /// Return a quota limiting as the bucket does, which has the same burst
+    /// size and rate as the one the bucket has been created from, though not
+    /// necessarily the same number of tokens per interval.
+    pub(crate) fn quota(&self) -> Quota {
+        let quota = match self.time_per_token {
+            0 => Quota::new(0, self.interval),
+            _ => Quota::new(
+                usize::try_from(self.capacity()).unwrap_or(usize::MAX),
+                self.interval,
+            ),
+        };
+        match self.penalty {
+            Some(penalty) => quota.penalize(penalty.rejections, penalty.window, penalty.cooldown),
+            None => quota,
+        }
+    }
+
+    /// Return whether the bucket never admits more than `other` does, i.e. it
+    /// holds no more tokens, and generates them no faster.
+    pub(crate) fn is_stricter_than(&self, other: &TokenBucket<C>) -> bool {
+        self.time_per_token == 0
+            || (other.time_per_token != 0
+                && self.capacity() <= other.capacity()
+                && self.time_per_token >= other.time_per_token)
+    }
+
+    /// Return the time it takes to generate a single token, or `None` if the
+    /// bucket is blocked.
+    pub(crate) fn time_per_token(&self) -> Option<Duration> {
+        (self.time_per_token != 0).then(|| Duration::from_nanos(self.time_per_token))
+    }
+
+    /// Return the time it takes to generate the specified number of `tokens`.
+    fn token_time(&self, tokens: usize) -> Duration {
+        Duration::from_nanos((tokens as u64).saturating_mul(self.time_per_token))
+    }
+
+    /// Return how long it takes to generate tokens worth of `time` at `now`,
+    /// taking into account that the rate ramps up during the warm-up.
+    ///
+    /// The rate grows linearly from `1 / COLD_FACTOR` of the configured rate
+    /// right after creation up to the configured rate at the end of the
+    /// warm-up, and the time is scaled by the inverse of that.
+    fn warm_up_time(&self, time: Duration, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.epoch);
+        if elapsed >= self.warm_up {
+            return time;
+        }
+
+        let warm_up = self.warm_up.as_nanos();
+        let nanos = time.as_nanos() * COLD_FACTOR * warm_up
+            / (warm_up + (COLD_FACTOR - 1) * elapsed.as_nanos());
+        Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+    }
+
+    /// Remember that the bucket has been used at `now`.
+    #[inline]
+    fn touch(&self, now: Instant) {
+        let nanos = as_nanos(now.saturating_duration_since(self.epoch));
+        self.last_used_at.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    /// Return the maximum number of tokens the bucket can hold.
+    fn capacity(&self) -> u128 {
+        self.interval.as_nanos() / self.time_per_token as u128
+    }
+
+    /// Return how long it takes to refill the bucket up to its capacity at `now`.
+    fn deficit(&self, state: &State, now: Instant) -> Duration {
+        match state.last_replenished_at {
+            Some(last_replenished_at) => {
+                let interval_start = now.checked_sub(self.interval).unwrap_or(now);
+                (std::cmp::max(interval_start, last_replenished_at) + self.interval)
+                    .saturating_duration_since(now)
+            }
+            None => Duration::ZERO,
+        }
+    }
+
+    /// Return the number of tokens available in the bucket at `now`.
+    fn available_tokens(&self, state: &State, now: Instant) -> usize {
+        let interval_start = now.checked_sub(self.interval).unwrap_or(now);
+        let last_replenished_at = state.last_replenished_at.unwrap_or(interval_start);
+        let elapsed =
+            now.saturating_duration_since(std::cmp::max(interval_start, last_replenished_at));
+        usize::try_from(as_nanos(elapsed) / self.time_per_token).unwrap_or(usize::MAX)
+    }
+
+    /// Return how long a request for `tokens` has to wait, if the bucket is
+    /// known to reject every request at `now` without looking at its state.
+    #[inline]
+    fn cached_denial(&self, now: Instant, tokens: usize) -> Option<Duration> {
+        let denied_until = match self.denied_until.load(Ordering::Acquire) {
+            0 => return None,
+            nanos => self.epoch + Duration::from_nanos(nanos),
+        };
+        if tokens == 0 || denied_until <= now {
+            return None;
+        }
+
+        // no tokens are generated until `denied_until`, and the rest of them
+        // are generated one by one afterwards
+        let token_delay = self.token_time(tokens - 1);
+        Some(denied_until - now + token_delay)
+    }
+
+    /// Return how long the bucket keeps rejecting every request as a penalty
+    /// at `now`, if it does.
+    fn penalty_delay(&self, state: &State, now: Instant) -> Option<Duration> {
+        state
+            .penalized_until
+            .filter(|penalized_until| *penalized_until > now)
+            .map(|penalized_until| penalized_until - now)
+    }
+
+    /// Return how long a request that can be served at `required_time` has to
+    /// wait at `now`, if it does, either as a penalty, or as a rejection that
+    /// is recorded towards one.
+    fn rejection(
+        &self,
+        state: &mut State,
+        now: Instant,
+        required_time: Instant,
+    ) -> Option<Duration> {
+        if let Some(retry_after) = self.penalty_delay(state, now) {
+            return Some(retry_after);
+        }
+        (required_time > now).then(|| self.reject(state, now, required_time - now))
+    }
+
+    /// Record a rejection at `now` that has to wait for `retry_after`, and
+    /// return how long the request actually has to wait, taking into account
+    /// the penalty for consecutive rejections.
+    fn reject(&self, state: &mut State, now: Instant, retry_after: Duration) -> Duration {
+        let penalty = match self.penalty {
+            Some(penalty) => penalty,
+            None => {
+                self.cache_denial(state, now);
+                return retry_after;
+            }
+        };
+
+        // rejections are counted under the lock, so they cannot be cached
+        // until the penalty is imposed
+        let rejected = match state.rejected {
+            Some((since, rejections)) if now.saturating_duration_since(since) <= penalty.window => {
+                (since, rejections + 1)
+            }
+            _ => (now, 1),
+        };
+        if rejected.1 < penalty.rejections {
+            state.rejected = Some(rejected);
+            return retry_after;
+        }
+
+        state.rejected = None;
+        state.penalized_until = Some(now + penalty.cooldown);
+        self.cache_denial(state, now);
+        std::cmp::max(retry_after, penalty.cooldown)
+    }
+
+    /// Remember the moment until which every request is going to be rejected,
+    /// i.e. when the next token is generated or a speculation expires,
+    /// whichever comes first, unless the bucket is penalized for longer.
+    fn cache_denial(&self, state: &State, now: Instant) {
+        // tokens get cheaper as the warm-up goes, so the moment the next one
+        // is generated at cannot be known in advance
+        if now.saturating_duration_since(self.epoch) < self.warm_up {
+            return;
+        }
+
+        let next_token = self.required_time(state.last_replenished_at, now, 1);
+        let denied_until = state
+            .speculations
+            .iter()
+            .map(|speculation| speculation.expires_at)
+            .fold(next_token, std::cmp::min);
+        let denied_until = std::cmp::max(denied_until, state.penalized_until.unwrap_or(now));
+
+        if denied_until > now && denied_until > self.epoch {
+            let nanos = as_nanos(denied_until - self.epoch);
+            self.denied_until.store(nanos, Ordering::Release);
+        }
+    }
+
+    /// Forget the cached denial, as tokens may have been returned to the bucket.
+    fn forget_denial(&self) {
+        self.denied_until.store(0, Ordering::Release);
+    }
+
+    /// Refund tokens of the speculations that haven't been confirmed in time.
+    fn expire_speculations(&self, state: &mut State, now: Instant) {
+        while let Some(index) = state
+            .speculations
+            .iter()
+            .position(|speculation| speculation.expires_at <= now)
+        {
+            let speculation = state.speculations.swap_remove(index);
+            self.give_back(state, now, speculation.tokens);
+        }
+    }
+
+    /// Settle the speculation with the given `id`, either confirming the tokens
+    /// consumed or returning them back to the bucket.
+    ///
+    /// Return `false` if the speculation has already expired.
+    fn settle_speculation(&self, id: u64, confirmed: bool) -> bool {
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        self.expire_speculations(&mut state, now);
+
+        match state
+            .speculations
+            .iter()
+            .position(|speculation| speculation.id == id)
+        {
+            Some(index) => {
+                let speculation = state.speculations.swap_remove(index);
+                if !confirmed {
+                    self.give_back(&mut state, now, speculation.tokens);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Merge the state of `other` into this bucket by summing up the tokens
+    /// consumed from both buckets.
+    ///
+    /// Tokens consumed from `other` are converted according to the rate of this
+    /// bucket, and the result never exceeds the bucket capacity. Blocked buckets
+    /// have no meaningful state, so merging into or from them is a no-op.
+    pub(crate) fn absorb(&self, other: &TokenBucket<C>) {
+        if self.time_per_token == 0 || other.time_per_token == 0 {
+            return;
+        }
+
+        let now = self.clock.now();
+        let consumed = other.consumed(now).as_nanos() * self.time_per_token as u128
+            / other.time_per_token as u128;
+
+        let mut state = self.state.lock().unwrap();
+        self.forget_denial();
+        let interval_start = now.checked_sub(self.interval).unwrap_or(now);
+        let last_replenished_at = state.last_replenished_at.unwrap_or(interval_start);
+        let merged = std::cmp::max(interval_start, last_replenished_at)
+            + Duration::from_nanos(u64::try_from(consumed).unwrap_or(u64::MAX));
+        state.last_replenished_at = Some(std::cmp::min(merged, now));
+    }
+
+    /// Return the amount of time worth of tokens consumed from the bucket at `now`.
+    fn consumed(&self, now: Instant) -> Duration {
+        let interval_start = now.checked_sub(self.interval).unwrap_or(now);
+        self.state
+            .lock()
+            .unwrap()
+            .last_replenished_at
+            .map(|last_replenished_at| {
+                last_replenished_at.saturating_duration_since(interval_start)
+            })
+            .unwrap_or_default()
+    }
+
+    /// Encode the bucket configuration and its current state into a compact,
+    /// versioned binary representation.
+    ///
+    /// The encoding is meant for handing a bucket over to another process,
+    /// e.g. by sticking it into a cookie or a JWT claim, and can be turned back
+    /// into a bucket via [`TokenBucket::from_bytes()`].
+    ///
+    /// The bucket state is stored relative to the moment of encoding, because
+    /// monotonic clocks of different processes (or hosts) are not comparable.
+    /// The time spent in transit is therefore not accounted for, and the decoded
+    /// bucket continues exactly from where the encoded one stopped, including
+    /// the debt, if any, see [`TokenBucket::settle()`].
+    ///
+    /// The encoding is neither encrypted nor signed. Anyone who can modify the
+    /// bytes can grant themselves a full bucket or a more generous limit, so the
+    /// output must be signed (e.g. with HMAC) before it leaves the trusted
+    /// boundary, and verified before it's decoded.
+    ///
+    /// The encoding is forward compatible: bytes produced by a given version of
+    /// this crate can be decoded by any later version, so buckets survive
+    /// upgrades of the processes they are handed over between. Decoding bytes
+    /// produced by a newer, unknown version of the encoding fails with
+    /// [`DecodeError::UnsupportedVersion`] rather than guessing.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::TokenBucket;
+    ///
+    /// let bucket = TokenBucket::new(2, Duration::from_secs(60));
+    /// assert!(bucket.consume(2).is_ok());
+    ///
+    /// let bucket = TokenBucket::from_bytes(&bucket.to_bytes()).unwrap();
+    /// assert!(bucket.consume(1).is_err());
+    /// ```
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let now = self.clock.now();
+        let state = self.state.lock().unwrap();
+        let deficit = self.deficit(&state, now);
+
+        let mut bytes = Vec::with_capacity(ENCODED_LEN);
+        bytes.push(ENCODING_VERSION);
+        bytes.push(CLOCK_MODE_MONOTONIC);
+        bytes.extend_from_slice(&as_nanos(self.interval).to_be_bytes());
+        bytes.extend_from_slice(&self.time_per_token.to_be_bytes());
+        bytes.extend_from_slice(&as_nanos(deficit).to_be_bytes());
+        bytes
+    }
+
+    /// Capture the state of the bucket, so that it can be restored via
+    /// [`TokenBucket::restore()`] later, e.g. after a service restart.
+    ///
+    /// Unlike [`TokenBucket::to_bytes()`], the snapshot doesn't carry the rate
+    /// of the bucket, and is anchored to the wall clock: the time between
+    /// taking and restoring the snapshot is accounted for, same as if the
+    /// bucket had been replenished all along. With the `serde` feature enabled,
+    /// [`Snapshot`] can be serialized with any serde format.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::TokenBucket;
+    ///
+    /// let bucket = TokenBucket::new(2, Duration::from_secs(60));
+    /// assert!(bucket.consume(2).is_ok());
+    /// let snapshot = bucket.snapshot();
+    ///
+    /// // ...restart...
+    ///
+    /// let bucket = TokenBucket::new(2, Duration::from_secs(60));
+    /// bucket.restore(snapshot);
+    /// assert!(bucket.consume(1).is_err());
+    /// ```
+    pub fn snapshot(&self) -> Snapshot {
+        let now = self.clock.now();
+        let state = self.state.lock().unwrap();
+
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            taken_at: SystemTime::now(),
+            deficit: self.deficit(&state, now),
+        }
+    }
+
+    /// Restore the state of the bucket from a `snapshot` taken via
+    /// [`TokenBucket::snapshot()`].
+    ///
+    /// The tokens generated since the snapshot has been taken, according to
+    /// the wall clock, are added to the bucket. If the wall clock has gone
+    /// backwards, the snapshot is restored as is. Pending speculations are
+    /// left intact.
+    pub fn restore(&self, snapshot: Snapshot) {
+        let downtime = SystemTime::now()
+            .duration_since(snapshot.taken_at)
+            .unwrap_or(Duration::ZERO);
+        let deficit = snapshot.deficit.saturating_sub(downtime);
+
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        self.forget_denial();
+        state.last_replenished_at = if deficit.is_zero() {
+            None
+        } else {
+            Some((now + deficit).checked_sub(self.interval).unwrap_or(now))
+        };
+    }
+
+    /// Same as [`TokenBucket::from_bytes()`], but allows to override the internal
+    /// clock, which is mainly useful in tests along with a [`ManualClock`](crate::ManualClock).
+    pub fn from_bytes_with_clock(bytes: &[u8], clock: C) -> Result<Self, DecodeError> {
+        let decoded = match bytes.first() {
+            None => return Err(DecodeError::Truncated),
+            Some(1) => decode_v1(bytes)?,
+            Some(2) => decode_v2(bytes)?,
+            Some(&version) => return Err(DecodeError::UnsupportedVersion(version)),
+        };
+
+        // same as for a restored snapshot, a bucket without a deficit is full,
+        // and that's exactly what a bucket that has never been replenished
+        // looks like
+        let now = clock.now();
+        let last_replenished_at = (!decoded.deficit.is_zero()).then(|| {
+            now.checked_add(decoded.deficit)
+                .and_then(|full_at| full_at.checked_sub(decoded.interval))
+                .unwrap_or(now)
+        });
+
+        Ok(TokenBucket {
+            time_per_token: decoded.time_per_token,
+            interval: decoded.interval,
+            state: Mutex::new(State {
+                last_replenished_at,
+                ..State::default()
+            }),
+            denied_until: AtomicU64::new(0),
+            last_used_at: AtomicU64::new(0),
+            epoch: now,
+            warm_up: Duration::ZERO,
+            penalty: None,
+            waiting: Waiting::default(),
+            clock,
+        })
+    }
+}
+
+impl<C: Clock> fmt::Debug for TokenBucket<C> {
+    /// Shows the configured rate, and the approximate number of tokens
+    /// available in the bucket.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (capacity, available) = if self.time_per_token == 0 {
+            (0, 0)
+        } else {
+            let now = self.clock.now();
+            let state = self.state.lock().unwrap();
+            (self.capacity(), self.available_tokens(&state, now))
+        };
+
+        f.debug_struct("TokenBucket")
+            .field("capacity", &capacity)
+            .field("time_per_token", &self.time_per_token())
+            .field("available", &available)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<C: Clone> Clone for TokenBucket<C> {
+    /// Clones the configuration of the bucket along with the number of tokens
+    /// available. The clone is independent of the original bucket afterwards.
+    ///
+    /// Tokens of pending speculations remain consumed in the clone, as it
+    /// cannot be told whether the speculations are going to be confirmed.
+    fn clone(&self) -> Self {
+        let state = self.state.lock().unwrap();
+        TokenBucket {
+            time_per_token: self.time_per_token,
+            interval: self.interval,
+            state: Mutex::new(State {
+                last_replenished_at: state.last_replenished_at,
+                rejected: state.rejected,
+                penalized_until: state.penalized_until,
+                ..State::default()
+            }),
+            denied_until: AtomicU64::new(0),
+            last_used_at: AtomicU64::new(self.last_used_at.load(Ordering::Relaxed)),
+            epoch: self.epoch,
+            warm_up: self.warm_up,
+            penalty: self.penalty,
+            waiting: Waiting::default(),
+            clock: self.clock.clone(),
+        }
+    }
+}
+
+/// State of a [`TokenBucket`] captured via [`TokenBucket::snapshot()`].
+///
+/// Serialized snapshots carry the version of their layout, so that the ones
+/// taken by an older version of this crate are migrated on deserialization,
+/// and the ones taken by a newer, unknown version are rejected rather than
+/// guessed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "VersionedSnapshot"))]
+pub struct Snapshot {
+    /// The version of the layout of the snapshot.
+    version: u32,
+
+    /// The wall clock time the snapshot has been taken at.
+    taken_at: SystemTime,
+
+    /// The time it takes to refill the bucket up to its capacity, including
+    /// the debt, if any.
+    deficit: Duration,
+}
+
+impl Snapshot {
+    /// The length of the binary encoding of a snapshot.
+    #[cfg(all(unix, feature = "uds"))]
+    pub(crate) const ENCODED_LEN: usize = 8 + 8;
+
+    /// Encode the snapshot as the number of nanoseconds since the Unix epoch
+    /// it has been taken at, followed by the deficit in nanoseconds.
+    #[cfg(all(unix, feature = "uds"))]
+    pub(crate) fn to_bytes(&self) -> [u8; Snapshot::ENCODED_LEN] {
+        let taken_at = self
+            .taken_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO);
+        let mut bytes = [0; Snapshot::ENCODED_LEN];
+        bytes[..8].copy_from_slice(&as_nanos(taken_at).to_be_bytes());
+        bytes[8..].copy_from_slice(&as_nanos(self.deficit).to_be_bytes());
+        bytes
+    }
+
+    /// Decode a snapshot encoded via [`Snapshot::to_bytes()`].
+    #[cfg(all(unix, feature = "uds"))]
+    pub(crate) fn from_bytes(bytes: [u8; Snapshot::ENCODED_LEN]) -> Snapshot {
+        let (taken_at, deficit) = bytes.split_at(8);
+        let taken_at = u64::from_be_bytes(taken_at.try_into().unwrap());
+        let deficit = u64::from_be_bytes(deficit.try_into().unwrap());
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            taken_at: SystemTime::UNIX_EPOCH + Duration::from_nanos(taken_at),
+            deficit: Duration::from_nanos(deficit),
+        }
+    }
+}
+
+/// Version of the layout of a serialized [`Snapshot`].
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// A [`Snapshot`] of any version, as deserialized, before it's migrated to
+/// what the current version expects.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct VersionedSnapshot {
+    /// Snapshots serialized before the version has been introduced are of
+    /// the first one.
+    #[serde(default = "snapshot_v1")]
+    version: u32,
+    taken_at: SystemTime,
+    deficit: Duration,
+}
+
+#[cfg(feature = "serde")]
+fn snapshot_v1() -> u32 {
+    1
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<VersionedSnapshot> for Snapshot {
+    type Error = String;
+
+    fn try_from(snapshot: VersionedSnapshot) -> Result<Self, Self::Error> {
+        match snapshot.version {
+            1 => Ok(migrate_snapshot_v1(snapshot)),
+            version => Err(format!("unsupported snapshot version {}", version)),
+        }
+    }
+}
+
+/// The first version is the current one, so there is nothing to migrate.
+#[cfg(feature = "serde")]
+fn migrate_snapshot_v1(snapshot: VersionedSnapshot) -> Snapshot {
+    Snapshot {
+        version: SNAPSHOT_VERSION,
+        taken_at: snapshot.taken_at,
+        deficit: snapshot.deficit,
+    }
+}
+
+/// The builder exposes ability to configure a [`TokenBucket`] instance with
+/// the capacity set independently of the refill rate.
+pub struct TokenBucketBuilder<C = MonotonicClock> {
+    capacity: Option<usize>,
+    refill: (usize, Duration),
+    warm_up: Duration,
+    penalty: Option<Penalty>,
+    clock: C,
+}
+
+impl<C: Clock> TokenBucketBuilder<C> {
+    /// Sets the maximum number of tokens the bucket can hold, i.e. the size of
+    /// the largest burst allowed.
+    ///
+    /// If not set, the capacity is equal to the number of tokens generated
+    /// per refill interval.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /// Sets the refill rate: `tokens` are generated with a constant rate over
+    /// the specified `interval` of time.
+    ///
+    /// Unless set, no tokens are generated, and the bucket is blocked.
+    pub fn refill(mut self, tokens: usize, interval: Duration) -> Self {
+        self.refill = (tokens, interval);
+        self
+    }
+
+    /// Sets the penalty for consecutive rejections: after `rejections` of them
+    /// within the `window`, every request is rejected for the `cooldown`
+    /// period. See [`Quota::penalize()`] for details.
+    pub fn penalize(mut self, rejections: usize, window: Duration, cooldown: Duration) -> Self {
+        self.penalty = Some(Penalty {
+            rejections,
+            window,
+            cooldown,
+        });
+        self
+    }
+
+    /// Sets the warm-up `period`, over which the rate of a newly created
+    /// bucket ramps up to the configured one.
+    ///
+    /// A warming up bucket starts empty rather than full, and its rate grows
+    /// linearly from a third of the configured rate up to the configured rate
+    /// by the end of the `period`. This protects backends from a cold client
+    /// consuming a full burst right on startup. Delays reported during the
+    /// warm-up are computed at the current rate, and thus may be longer than
+    /// necessary.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use youshallnotpass::{Error, ManualClock, TokenBucket};
+    ///
+    /// let clock = ManualClock::new();
+    /// let bucket = TokenBucket::builder_with_clock(clock.clone())
+    ///     .refill(10, Duration::from_secs(1))
+    ///     .warm_up(Duration::from_secs(10))
+    ///     .done();
+    /// assert_eq!(bucket.consume(1), Err(Error::RetryAfter(Duration::from_millis(300))));
+    ///
+    /// clock.advance(Duration::from_secs(10));
+    /// assert!(bucket.consume(10).is_ok());
+    /// ```
+    pub fn warm_up(mut self, period: Duration) -> Self {
+        self.warm_up = period;
+        self
+    }
+
+    /// Constructs a [`TokenBucket`] instance with the configured capacity and
+    /// refill rate.
+    ///
+    /// Specifying the capacity, or the refill rate, of 0 has a meaning of
+    /// blocking a given entity, same as for [`TokenBucket::new()`].
+    pub fn done(self) -> TokenBucket<C> {
+        let (tokens, interval) = self.refill;
+        let mut bucket = TokenBucket::with_refill(
+            self.capacity.unwrap_or(tokens),
+            tokens,
+            interval,
+            self.clock,
+        );
+        bucket.penalty = self.penalty;
+        if !self.warm_up.is_zero() {
+            bucket.warm_up = self.warm_up;
+            bucket.state.get_mut().unwrap().last_replenished_at = Some(bucket.epoch);
+        }
+        bucket
+    }
+}
+
+/// Tokens provisionally consumed from a bucket, awaiting the actual cost.
+///
+/// Created by [`TokenBucket::reserve()`], and must be passed to
+/// [`TokenBucket::settle()`] of the same bucket once the cost is known.
+#[must_use = "a reservation must be settled with the actual cost"]
+#[derive(Debug)]
+pub struct Reservation {
+    tokens: usize,
+}
+
+impl Reservation {
+    /// Return the number of tokens reserved.
+    pub fn tokens(&self) -> usize {
+        self.tokens
+    }
+}
+
+/// Quality of service class assigned to a request by [`TokenBucket::admit()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum QosClass {
+    /// The request is within the limit, with plenty of budget left.
+    Green,
+
+    /// The request is within the limit, but the budget is running low.
+    Yellow,
+
+    /// The request exceeds the limit.
+    Red,
+}
+
+/// A snapshot of tokens available in a bucket.
+///
+/// Created by [`TokenBucket::available()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Availability {
+    tokens: usize,
+    next_token_in: Duration,
+}
+
+impl Availability {
+    /// Return the number of tokens that can be consumed right away.
+    pub fn tokens(&self) -> usize {
+        self.tokens
+    }
+
+    /// Return how long it takes to generate the next token, or zero if the
+    /// bucket is full.
+    pub fn next_token_in(&self) -> Duration {
+        self.next_token_in
+    }
+
+    /// Return the availability of whichever of two buckets admits fewer
+    /// tokens right away, which is the one limiting requests both of them are
+    /// charged for.
+    pub(crate) fn scarcer(self, other: Availability) -> Availability {
+        std::cmp::min_by_key(self, other, |availability| {
+            (
+                availability.tokens,
+                std::cmp::Reverse(availability.next_token_in),
+            )
+        })
+    }
+}
+
+/// A reference to a bucket, either borrowed or shared with the map it's
+/// stored in, e.g. when the map is behind a lock.
+pub(crate) enum BucketRef<'b, C> {
+    Borrowed(&'b TokenBucket<C>),
+    Shared(Arc<TokenBucket<C>>),
+}
+
+impl<C> Deref for BucketRef<'_, C> {
+    type Target = TokenBucket<C>;
+
+    fn deref(&self) -> &TokenBucket<C> {
+        match self {
+            BucketRef::Borrowed(bucket) => bucket,
+            BucketRef::Shared(bucket) => bucket,
+        }
+    }
+}
+
+/// Tokens consumed speculatively, awaiting confirmation.
+///
+/// Created by [`TokenBucket::consume_speculative()`]. If the speculation is
+/// dropped without being confirmed, the tokens are returned to the bucket.
+#[must_use = "dropping a speculation refunds its tokens"]
+pub struct Speculation<'b, C: Clock = MonotonicClock> {
+    bucket: Option<BucketRef<'b, C>>,
+    /// Speculations of the limits stacked on top of the bucket.
+    stacked: Vec<Speculation<'b, C>>,
+    id: u64,
+}
+
+impl<'b, C: Clock> Speculation<'b, C> {
+    /// Create a speculation that has nothing to confirm, e.g. for requests that
+    /// aren't limited at all.
+    pub(crate) fn unlimited() -> Self {
+        Speculation {
+            bucket: None,
+            stacked: Vec::new(),
+            id: 0,
+        }
+    }
+
+    /// Consume `tokens` speculatively from the `bucket` and every one of the
+    /// `stacked` buckets at once, so that either all of them are charged, or
+    /// none is, see [`TokenBucket::consume_speculative()`].
+    pub(crate) fn consume<I>(
+        bucket: BucketRef<'b, C>,
+        stacked: I,
+        tokens: usize,
+        timeout: Duration,
+    ) -> Result<Self, Error>
+    where
+        I: Iterator<Item = &'b TokenBucket<C>> + Clone,
+    {
+        let buckets = iter::once(&*bucket).chain(stacked.clone().map(shorten));
+        validate(buckets.clone(), tokens)?;
+        let mut ids = Vec::new();
+        consume_locked(
+            buckets,
+            None,
+            None,
+            tokens,
+            None,
+            &mut |bucket, state, now| {
+                let id = state.next_speculation_id;
+                state.next_speculation_id += 1;
+                state.speculations.push(PendingSpeculation {
+                    id,
+                    tokens,
+                    expires_at: now + timeout,
+                });
+                ids.push((bucket as *const TokenBucket<C>, id));
+            },
+        )
+        .map_err(Error::RetryAfter)?;
+
+        let id = |bucket: &TokenBucket<C>| {
+            ids.iter()
+                .find(|(address, _)| ptr::eq(*address, bucket))
+                .map_or(0, |&(_, id)| id)
+        };
+        Ok(Speculation {
+            id: id(&bucket),
+            stacked: stacked
+                .map(|stacked| Speculation {
+                    id: id(stacked),
+                    bucket: Some(BucketRef::Borrowed(stacked)),
+                    stacked: Vec::new(),
+                })
+                .collect(),
+            bucket: Some(bucket),
+        })
+    }
+
+    /// Confirm the speculatively consumed tokens, so they are never refunded.
+    ///
+    /// Return `false` if the confirmation came too late, and the tokens have
+    /// already been returned to the bucket.
+    pub fn confirm(mut self) -> bool {
+        let mut confirmed = true;
+        for speculation in std::mem::take(&mut self.stacked) {
+            confirmed &= speculation.confirm();
+        }
+        match self.bucket.take() {
+            Some(bucket) => bucket.settle_speculation(self.id, true) && confirmed,
+            None => confirmed,
+        }
+    }
+}
+
+impl<'b, C: Clock> Drop for Speculation<'b, C> {
+    fn drop(&mut self) {
+        if let Some(bucket) = self.bucket.take() {
+            bucket.settle_speculation(self.id, false);
+        }
+    }
+}
+
+/// Tokens consumed from a bucket, refunded unless committed.
+///
+/// Created by [`TokenBucket::consume_permit()`]. If the permit is dropped
+/// without being committed, the tokens are returned to the bucket.
+#[must_use = "dropping a permit refunds its tokens"]
+pub struct Permit<'b, C: Clock = MonotonicClock> {
+    bucket: Option<BucketRef<'b, C>>,
+    /// Permits of the limits stacked on top of the bucket.
+    stacked: Vec<Permit<'b, C>>,
+    tokens: usize,
+}
+
+impl<'b, C: Clock> Permit<'b, C> {
+    /// Create a permit that has nothing to refund, e.g. for requests that
+    /// aren't limited at all.
+    pub(crate) fn unlimited(tokens: usize) -> Self {
+        Permit {
+            bucket: None,
+            stacked: Vec::new(),
+            tokens,
+        }
+    }
+
+    /// Make the permit hold the shared `bucket` it has been created by, so
+    /// that it outlives the borrow.
+    pub(crate) fn shared<'a>(mut self, bucket: Arc<TokenBucket<C>>) -> Permit<'a, C> {
+        debug_assert!(self.stacked.is_empty(), "shared buckets aren't stacked");
+        Permit {
+            bucket: self.bucket.take().map(|_| BucketRef::Shared(bucket)),
+            stacked: Vec::new(),
+            tokens: self.tokens,
+        }
+    }
+
+    /// Consume `tokens` from the `bucket` and every one of the `stacked`
+    /// buckets at once, so that either all of them are charged, or none is,
+    /// see [`TokenBucket::consume_permit()`].
+    pub(crate) fn consume<I>(
+        bucket: BucketRef<'b, C>,
+        stacked: I,
+        tokens: usize,
+    ) -> Result<Self, Error>
+    where
+        I: Iterator<Item = &'b TokenBucket<C>> + Clone,
+    {
+        consume_all(
+            iter::once(&*bucket).chain(stacked.clone().map(shorten)),
+            tokens,
+            None,
+        )?;
+        Ok(Permit {
+            bucket: Some(bucket),
+            stacked: stacked
+                .map(|stacked| Permit {
+                    bucket: Some(BucketRef::Borrowed(stacked)),
+                    stacked: Vec::new(),
+                    tokens,
+                })
+                .collect(),
+            tokens,
+        })
+    }
+
+    /// Return the number of tokens held by the permit.
+    pub fn tokens(&self) -> usize {
+        self.tokens
+    }
+
+    /// Commit the consumed tokens, so they are never refunded.
+    pub fn commit(mut self) {
+        self.bucket = None;
+        for permit in std::mem::take(&mut self.stacked) {
+            permit.commit();
+        }
+    }
+
+    /// Return the consumed tokens to the bucket right away. This is the same
+    /// as dropping the permit, but more explicit.
+    pub fn rollback(self) {}
+}
+
+impl<'b, C: Clock> Drop for Permit<'b, C> {
+    fn drop(&mut self) {
+        if let Some(bucket) = self.bucket.take() {
+            bucket.refund(self.tokens);
+        }
+    }
+}
+
+/// Composition of two token buckets admitting only what both of them allow.
+///
+/// Created by [`TokenBucket::intersect()`].
+pub struct Intersection<'b, C = MonotonicClock> {
+    first: &'b TokenBucket<C>,
+    second: &'b TokenBucket<C>,
+}
+
+impl<'b, C: Clock> Intersection<'b, C> {
+    /// Try to consume the specified number of `tokens` from both buckets.
+    ///
+    /// If both buckets have the sufficient number of tokens available, they are
+    /// *consumed* from both buckets and `Ok(())` is returned.
+    ///
+    /// Otherwise, neither bucket is modified. If any of the buckets is blocked,
+    /// [`Error::Blocked`] is returned. Otherwise, [`Error::RetryAfter`] specifies
+    /// the longest of the delays required by the buckets.
+    pub fn consume(&self, tokens: usize) -> Result<(), Error> {
+        consume_all([self.first, self.second].into_iter(), tokens, None).map(|_| ())
+    }
+}
+
+/// Consume `tokens` from every one of the `buckets` at once, so that either
+/// all of them are charged, or none is, and return the least of the numbers
+/// of tokens remaining in them.
+///
+/// Every bucket is evaluated `at` the given moment, or at the current time of
+/// its own clock. A bucket listed several times is charged once.
+pub(crate) fn consume_all<'b, C, I>(
+    buckets: I,
+    tokens: usize,
+    at: Option<Instant>,
+) -> Result<u64, Error>
+where
+    C: Clock + 'b,
+    I: Iterator<Item = &'b TokenBucket<C>> + Clone,
+{
+    validate(buckets.clone(), tokens)?;
+    let mut remaining = u64::MAX;
+    consume_locked(
+        buckets,
+        None,
+        None,
+        tokens,
+        at,
+        &mut |bucket, state, now| {
+            remaining = remaining.min(bucket.available_tokens(state, now) as u64);
+        },
+    )
+    .map_err(Error::RetryAfter)?;
+    Ok(remaining)
+}
+
+/// Admit `tokens` from every one of the `buckets` at once, same as
+/// [`TokenBucket::admit()`] does, and return the worst of their classes, or
+/// the reason the request is [`QosClass::Red`].
+///
+/// Nothing is consumed from either bucket if any of them doesn't have enough
+/// tokens.
+pub(crate) fn admit_all<'b, C, I>(buckets: I, tokens: usize) -> Result<QosClass, Error>
+where
+    C: Clock + 'b,
+    I: Iterator<Item = &'b TokenBucket<C>> + Clone,
+{
+    validate(buckets.clone(), tokens)?;
+    let mut class = QosClass::Green;
+    let charged = consume_locked(
+        buckets,
+        None,
+        None,
+        tokens,
+        None,
+        &mut |bucket, state, now| {
+            if (bucket.available_tokens(state, now) as u128) * 2 < bucket.capacity() {
+                class = QosClass::Yellow;
+            }
+        },
+    );
+    charged.map(|()| class).map_err(Error::RetryAfter)
+}
+
+/// Shorten the borrow of a stacked `bucket` to the one of the bucket it's
+/// stacked on, so that both can be charged together.
+fn shorten<'a, 'b: 'a, C>(bucket: &'b TokenBucket<C>) -> &'a TokenBucket<C> {
+    bucket
+}
+
+/// Return the strictest of the errors of the `buckets` that can never admit
+/// `tokens`, whatever their state, if any.
+fn validate<'b, C, I>(buckets: I, tokens: usize) -> Result<(), Error>
+where
+    C: Clock + 'b,
+    I: Iterator<Item = &'b TokenBucket<C>>,
+{
+    let invalid = buckets.filter_map(|bucket| {
+        if bucket.time_per_token == 0 {
+            Some(Error::Blocked)
+        } else if tokens as u128 > bucket.capacity() {
+            Some(Error::ExceedsCapacity)
+        } else {
+            None
+        }
+    });
+    match invalid.reduce(Error::strictest) {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
+}
+
+/// Lock the first of the `buckets` in the order of their addresses that comes
+/// `after` the ones locked already, and then the rest of them, recursively,
+/// so that the locks are held until every bucket has been evaluated. The
+/// buckets are charged only if neither of them, nor any of the ones locked
+/// already, has a `delay`, and each one is passed to `charged` while still
+/// locked.
+///
+/// The buckets are always locked in the same order, so that requests charging
+/// the same buckets listed in different order cannot deadlock each other.
+fn consume_locked<'b, C, I, F>(
+    buckets: I,
+    after: Option<*const TokenBucket<C>>,
+    delay: Option<Duration>,
+    tokens: usize,
+    at: Option<Instant>,
+    charged: &mut F,
+) -> Result<(), Duration>
+where
+    C: Clock + 'b,
+    I: Iterator<Item = &'b TokenBucket<C>> + Clone,
+    F: FnMut(&'b TokenBucket<C>, &mut State, Instant),
+{
+    let address = |bucket: &&TokenBucket<C>| *bucket as *const TokenBucket<C>;
+    let next = buckets
+        .clone()
+        .filter(|bucket| after.is_none_or(|after| address(bucket) > after))
+        .min_by_key(address);
+    let Some(bucket) = next else {
+        return delay.map_or(Ok(()), Err);
+    };
+
+    let mut state = bucket.state.lock().unwrap();
+    let now = at.unwrap_or_else(|| bucket.clock.now());
+    bucket.touch(now);
+    bucket.expire_speculations(&mut state, now);
+    let required_time = bucket.required_time(state.last_replenished_at, now, tokens);
+
+    // each bucket counts only the rejections it makes itself towards its
+    // penalty
+    let delay = delay.max(bucket.rejection(&mut state, now, required_time));
+    consume_locked(buckets, Some(address(&bucket)), delay, tokens, at, charged)?;
+    state.last_replenished_at = Some(required_time);
+    state.rejected = None;
+    charged(bucket, &mut state, now);
+    Ok(())
+}
+
+/// How many times slower than configured the rate of a warming up bucket is
+/// right after creation.
+const COLD_FACTOR: u128 = 3;
+
+/// Version of the binary encoding produced by [`TokenBucket::to_bytes()`].
+///
+/// The first version stored the time elapsed since the bucket has been
+/// replenished, which cannot represent a debt, so the second one stores the
+/// deficit instead, same as a [`Snapshot`].
+const ENCODING_VERSION: u8 = 2;
+
+/// The bucket state is encoded relative to the moment of encoding.
+const CLOCK_MODE_MONOTONIC: u8 = 0;
+
+/// Version, clock mode, interval, time per token, and either elapsed time
+/// (v1) or deficit (v2).
+const ENCODED_LEN: usize = 1 + 1 + 8 + 8 + 8;
+
+/// Elapsed time marker for buckets that have never been replenished (v1).
+const NEVER_REPLENISHED: u64 = u64::MAX;
+
+/// Version-independent representation of an encoded bucket.
+///
+/// Every supported encoding version has a decoder producing this structure,
+/// and that's where data written by older versions is migrated to what the
+/// current version expects.
+struct Decoded {
+    interval: Duration,
+    time_per_token: u64,
+    deficit: Duration,
+}
+
+fn decode_v1(bytes: &[u8]) -> Result<Decoded, DecodeError> {
+    let [interval, time_per_token, elapsed] = decode_fields(bytes)?;
+    let interval = Duration::from_nanos(interval);
+
+    // once a whole interval has passed the bucket is full again, same as if it
+    // has never been replenished
+    let deficit = match elapsed {
+        NEVER_REPLENISHED => Duration::ZERO,
+        elapsed => interval.saturating_sub(Duration::from_nanos(elapsed)),
+    };
+    Ok(Decoded {
+        interval,
+        time_per_token,
+        deficit,
+    })
+}
+
+fn decode_v2(bytes: &[u8]) -> Result<Decoded, DecodeError> {
+    let [interval, time_per_token, deficit] = decode_fields(bytes)?;
+    Ok(Decoded {
+        interval: Duration::from_nanos(interval),
+        time_per_token,
+        deficit: Duration::from_nanos(deficit),
+    })
+}
+
+/// Decode the fields following the version, which are laid out the same way
+/// by every version so far.
+fn decode_fields(bytes: &[u8]) -> Result<[u64; 3], DecodeError> {
+    match bytes.get(1) {
+        None => return Err(DecodeError::Truncated),
+        Some(&CLOCK_MODE_MONOTONIC) => {}
+        Some(&mode) => return Err(DecodeError::UnsupportedClockMode(mode)),
+    }
+    if bytes.len() < ENCODED_LEN {
+        return Err(DecodeError::Truncated);
+    }
+
+    let read_u64 = |offset: usize| {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(&bytes[offset..offset + 8]);
+        u64::from_be_bytes(buf)
+    };
+    Ok([read_u64(2), read_u64(10), read_u64(18)])
+}
+
+/// Return the time it takes to generate a single token, and to refill an empty
+/// bucket up to its `capacity`, when `tokens` are generated every `interval`.
+fn refill_rate(capacity: usize, tokens: usize, interval: Duration) -> (u64, Duration) {
+    // All the internal math is done in u64 nanoseconds regardless of the
+    // platform, so intervals are capped at ~584 years, which is as good as
+    // never. The product of two u64 values always fits into u128.
+    let interval = Duration::from_nanos(as_nanos(interval));
+    let time_per_token = if capacity == 0 {
+        0
+    } else {
+        as_nanos(interval).checked_div(tokens as u64).unwrap_or(0)
+    };
+    let burst = (interval.as_nanos() * capacity as u128)
+        .checked_div(tokens as u128)
+        .unwrap_or(0);
+
+    (
+        time_per_token,
+        Duration::from_nanos(u64::try_from(burst).unwrap_or(u64::MAX)),
+    )
+}
+
+fn as_nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::clock::ManualClock;
+
+    #[test]
+    fn new() {
+        let bucket = TokenBucket::new(3, Duration::from_secs(60));
+
+        assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(bucket.consume(1), Ok(()));
+        // we don't mock time in this test case, so checking the retry-after delay would be unreliable
+        assert!(matches!(bucket.consume(1), Err(Error::RetryAfter(_))));
+    }
+
+    #[test]
+    fn blocked_limit() {
+        let bucket = TokenBucket::new(0, Duration::from_secs(60));
+
+        // tokens are not being added to the bucket; the entity is effectively blocked,
+        // and retries are useless
+        assert_eq!(bucket.consume(1), Err(Error::Blocked));
+        assert_eq!(bucket.consume(1), Err(Error::Blocked));
+        assert_eq!(bucket.consume(1), Err(Error::Blocked));
+    }
+
+    #[test]
+    fn blocked_duration() {
+        let bucket = TokenBucket::new(42, Duration::from_secs(0));
+
+        // tokens are not being added to the bucket; the entity is effectively blocked,
+        // and retries are useless
+        assert_eq!(bucket.consume(1), Err(Error::Blocked));
+        assert_eq!(bucket.consume(1), Err(Error::Blocked));
+        assert_eq!(bucket.consume(1), Err(Error::Blocked));
+    }
+
+    #[test]
+    fn capacity_is_one() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::with_clock(1, Duration::from_secs(1), &clock);
+
+        assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_secs(1)))
+        );
+
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_secs(1)))
+        );
+    }
+
+    #[test]
+    fn capacity_gt_one() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::with_clock(3, Duration::from_secs(1), &clock);
+
+        assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_nanos(333_333_332)))
+        );
+
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_nanos(333_333_332)))
+        );
+    }
+
+    #[test]
+    fn period_gt_one() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::with_clock(1, Duration::from_secs(3), &clock);
+
+        assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_secs(3)))
+        );
+
+        *now.lock().unwrap() += Duration::from_secs(2);
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_secs(1)))
+        );
+
+        *now.lock().unwrap() += Duration::from_secs(3);
+        assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_secs(3)))
+        );
+    }
+
+    #[test]
+    fn consume_over_time() {
+        let t0 = Instant::now();
+        let now = Mutex::new(t0);
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::with_clock(4, Duration::from_secs(1), &clock);
+
+        // consume first token
+        *now.lock().unwrap() = t0;
+        assert_eq!(bucket.consume(1), Ok(()));
+
+        // consume second token
+        *now.lock().unwrap() = t0 + Duration::from_millis(50);
+        assert_eq!(bucket.consume(1), Ok(()));
+
+        // consume third & fourth tokens
+        *now.lock().unwrap() = t0 + Duration::from_millis(150);
+        assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(bucket.consume(1), Ok(()));
+
+        // ensure we are out of tokens
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_millis(100)))
+        );
+
+        // one token is not yet replenished
+        *now.lock().unwrap() = t0 + Duration::from_millis(249);
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_millis(1)))
+        );
+
+        // one token is replenished
+        *now.lock().unwrap() = t0 + Duration::from_millis(250);
+        assert_eq!(bucket.consume(1), Ok(()));
+
+        // ensure we are out of tokens again
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_millis(250)))
+        );
+
+        // two tokens are replenished
+        *now.lock().unwrap() = t0 + Duration::from_millis(750);
+        assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_millis(250)))
+        );
+    }
+
+    #[test]
+    fn consume_gt_one() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::with_clock(3, Duration::from_secs(1), &clock);
+
+        // consume all tokens at once
+        assert_eq!(bucket.consume(3), Ok(()));
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_nanos(333_333_332)))
+        );
+
+        // sequentially consume tokens
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(bucket.consume(2), Ok(()));
+        assert_eq!(
+            bucket.consume(2),
+            Err(Error::RetryAfter(Duration::from_nanos(333_333_332)))
+        );
+        assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_nanos(333_333_332)))
+        );
+
+        // two tokens are replenished
+        *now.lock().unwrap() += Duration::from_millis(700);
+        assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_nanos(299_999_998)))
+        );
+    }
+
+    #[test]
+    fn to_bytes_from_bytes() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::with_clock(3, Duration::from_secs(3), &clock);
+
+        // a bucket that has never been used is decoded full
+        let decoded = TokenBucket::from_bytes_with_clock(&bucket.to_bytes(), &clock).unwrap();
+        assert_eq!(decoded.consume(3), Ok(()));
+
+        // consumed tokens stay consumed after a handoff
+        assert_eq!(bucket.consume(2), Ok(()));
+        *now.lock().unwrap() += Duration::from_millis(500);
+        let decoded = TokenBucket::from_bytes_with_clock(&bucket.to_bytes(), &clock).unwrap();
+        assert_eq!(decoded.consume(1), Ok(()));
+        assert_eq!(
+            decoded.consume(1),
+            Err(Error::RetryAfter(Duration::from_millis(500)))
+        );
+
+        // and so does the debt of a settled bucket
+        let bucket = TokenBucket::with_clock(3, Duration::from_secs(3), &clock);
+        let reservation = bucket.reserve(1).unwrap();
+        bucket.settle(reservation, 5);
+        let decoded = TokenBucket::from_bytes_with_clock(&bucket.to_bytes(), &clock).unwrap();
+        assert_eq!(
+            decoded.consume(1),
+            Err(Error::RetryAfter(Duration::from_secs(3)))
+        );
+        *now.lock().unwrap() += Duration::from_secs(3);
+        assert_eq!(decoded.consume(1), Ok(()));
+        assert!(decoded.consume(1).is_err());
+
+        // blocked buckets remain blocked
+        let bucket = TokenBucket::with_clock(0, Duration::from_secs(3), &clock);
+        let decoded = TokenBucket::from_bytes_with_clock(&bucket.to_bytes(), &clock).unwrap();
+        assert_eq!(decoded.consume(1), Err(Error::Blocked));
+    }
+
+    #[test]
+    fn from_bytes_invalid() {
+        let bytes = TokenBucket::new(3, Duration::from_secs(3)).to_bytes();
+
+        assert!(matches!(
+            TokenBucket::from_bytes(&[]),
+            Err(DecodeError::Truncated)
+        ));
+        assert!(matches!(
+            TokenBucket::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(DecodeError::Truncated)
+        ));
+        assert!(matches!(
+            TokenBucket::from_bytes(&[42, 0]),
+            Err(DecodeError::UnsupportedVersion(42))
+        ));
+        assert!(matches!(
+            TokenBucket::from_bytes(&[bytes[0], 42]),
+            Err(DecodeError::UnsupportedClockMode(42))
+        ));
+    }
+
+    #[test]
+    fn from_bytes_v1() {
+        // these bytes were produced by the very first version of the encoding, and
+        // must be decodable by every later version of the crate
+        #[rustfmt::skip]
+        let bytes = [
+            1, 0,
+            0, 0, 0, 0, 0xb2, 0xd0, 0x5e, 0x00,
+            0, 0, 0, 0, 0x3b, 0x9a, 0xca, 0x00,
+            0, 0, 0, 0, 0x95, 0x02, 0xf9, 0x00,
+        ];
+
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::from_bytes_with_clock(&bytes, &clock).unwrap();
+
+        assert_eq!(bucket.consume(2), Ok(()));
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_millis(500)))
+        );
+    }
+
+    #[test]
+    fn intersect() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let a = TokenBucket::with_clock(3, Duration::from_secs(3), &clock);
+        let b = TokenBucket::with_clock(2, Duration::from_secs(1), &clock);
+        let both = a.intersect(&b);
+
+        assert_eq!(both.consume(2), Ok(()));
+        assert_eq!(
+            both.consume(1),
+            Err(Error::RetryAfter(Duration::from_millis(500)))
+        );
+
+        // requests exceeding the smaller capacity can never be admitted, and
+        // neither bucket is charged
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(both.consume(3), Err(Error::ExceedsCapacity));
+        assert_eq!(both.consume(2), Ok(()));
+
+        // the longest delay is reported
+        assert_eq!(
+            both.consume(1),
+            Err(Error::RetryAfter(Duration::from_millis(1_000)))
+        );
+
+        // the order of composition doesn't matter
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(b.intersect(&a).consume(1), Ok(()));
+        assert_eq!(a.consume(1), Err(Error::RetryAfter(Duration::from_secs(1))));
+        assert_eq!(b.consume(1), Ok(()));
+    }
+
+    #[test]
+    fn intersect_blocked() {
+        let a = TokenBucket::new(3, Duration::from_secs(3));
+        let b = TokenBucket::new(0, Duration::from_secs(1));
+
+        assert_eq!(a.intersect(&b).consume(1), Err(Error::Blocked));
+        assert_eq!(b.intersect(&a).consume(1), Err(Error::Blocked));
+        assert_eq!(a.consume(3), Ok(()));
+    }
+
+    #[test]
+    fn intersect_self() {
+        let a = TokenBucket::new(2, Duration::from_secs(60));
+
+        assert_eq!(a.intersect(&a).consume(1), Ok(()));
+        assert_eq!(a.intersect(&a).consume(1), Ok(()));
+        assert!(matches!(
+            a.intersect(&a).consume(1),
+            Err(Error::RetryAfter(_))
+        ));
+    }
+
+    #[test]
+    fn reserve_settle() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::with_clock(4, Duration::from_secs(1), &clock);
+
+        // the actual cost is lower than reserved, so the difference is returned
+        let reservation = bucket.reserve(3).unwrap();
+        assert_eq!(reservation.tokens(), 3);
+        bucket.settle(reservation, 1);
+        assert_eq!(bucket.consume(3), Ok(()));
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_millis(250)))
+        );
+
+        // the actual cost is higher than reserved, so the bucket goes into debt
+        *now.lock().unwrap() += Duration::from_secs(1);
+        let reservation = bucket.reserve(1).unwrap();
+        bucket.settle(reservation, 6);
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_millis(750)))
+        );
+
+        // the debt is paid off by replenishment
+        *now.lock().unwrap() += Duration::from_millis(750);
+        assert_eq!(bucket.consume(1), Ok(()));
+    }
+
+    #[test]
+    fn settle_refund_respects_capacity() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::with_clock(2, Duration::from_secs(1), &clock);
+
+        let reservation = bucket.reserve(2).unwrap();
+        *now.lock().unwrap() += Duration::from_secs(1);
+        bucket.settle(reservation, 0);
+
+        assert_eq!(bucket.consume(2), Ok(()));
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_millis(500)))
+        );
+    }
+
+    #[test]
+    fn reserve_blocked() {
+        let bucket = TokenBucket::new(0, Duration::from_secs(1));
+
+        assert!(matches!(bucket.reserve(1), Err(Error::Blocked)));
+    }
+
+    #[test]
+    fn consume_speculative() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::with_clock(2, Duration::from_secs(1), &clock);
+
+        // confirmed tokens stay consumed
+        let speculation = bucket
+            .consume_speculative(1, Duration::from_secs(1))
+            .unwrap();
+        assert!(speculation.confirm());
+        *now.lock().unwrap() += Duration::from_secs(2);
+        assert_eq!(bucket.consume(2), Ok(()));
+
+        // dropped speculations are refunded
+        *now.lock().unwrap() += Duration::from_secs(1);
+        let speculation = bucket
+            .consume_speculative(2, Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_millis(500)))
+        );
+        drop(speculation);
+        assert_eq!(bucket.consume(2), Ok(()));
+    }
+
+    #[test]
+    fn consume_speculative_timeout() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::with_clock(2, Duration::from_secs(10), &clock);
+
+        let speculation = bucket
+            .consume_speculative(2, Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_secs(5)))
+        );
+
+        // the speculation hasn't been confirmed in time, so the tokens are refunded
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(bucket.consume(2), Ok(()));
+        assert!(!speculation.confirm());
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_secs(5)))
+        );
+    }
+
+    #[test]
+    fn consume_speculative_blocked() {
+        let bucket = TokenBucket::new(0, Duration::from_secs(1));
+
+        assert!(matches!(
+            bucket.consume_speculative(1, Duration::from_secs(1)),
+            Err(Error::Blocked)
+        ));
+    }
+
+    #[test]
+    fn penalize() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::builder_with_clock(&clock)
+            .refill(1, Duration::from_secs(10))
+            .penalize(3, Duration::from_secs(1), Duration::from_secs(60))
+            .done();
+
+        assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_secs(10)))
+        );
+
+        // rejections outside of the window start counting anew
+        *now.lock().unwrap() += Duration::from_secs(2);
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_secs(8)))
+        );
+        *now.lock().unwrap() += Duration::from_millis(500);
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_millis(7500)))
+        );
+
+        // the third consecutive rejection within the window is penalized
+        *now.lock().unwrap() += Duration::from_millis(500);
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_secs(60)))
+        );
+
+        // tokens available during the cooldown cannot be consumed
+        *now.lock().unwrap() += Duration::from_secs(30);
+        assert_eq!(
+            bucket.check(1),
+            Err(Error::RetryAfter(Duration::from_secs(30)))
+        );
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_secs(30)))
+        );
+
+        *now.lock().unwrap() += Duration::from_secs(30);
+        assert_eq!(bucket.consume(1), Ok(()));
+
+        // a successful request breaks the streak of rejections, and resetting
+        // the bucket lifts the penalty
+        for _ in 0..3 {
+            assert!(bucket.consume(1).is_err());
+        }
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_secs(60)))
+        );
+        bucket.reset();
+        assert_eq!(bucket.consume(1), Ok(()));
+    }
+
+    /// Bucket of a token per 10 seconds, penalized for 30 seconds after two
+    /// rejections in a row, and drained already.
+    fn penalized<C: Clock>(clock: C) -> TokenBucket<C> {
+        let bucket = TokenBucket::builder_with_clock(clock)
+            .refill(1, Duration::from_secs(10))
+            .penalize(2, Duration::from_secs(60), Duration::from_secs(30))
+            .done();
+        assert_eq!(bucket.consume(1), Ok(()));
+        bucket
+    }
+
+    #[test]
+    fn penalize_consume_up_to() {
+        let clock = ManualClock::new();
+        let bucket = penalized(clock.clone());
+
+        assert_eq!(bucket.consume_up_to(1), 0);
+        assert_eq!(bucket.consume_up_to(1), 0);
+        clock.advance(Duration::from_secs(10));
+        assert!(bucket.check(1).is_err());
+        assert_eq!(bucket.consume_up_to(1), 0);
+
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(bucket.consume_up_to(1), 1);
+    }
+
+    #[test]
+    fn penalize_admit() {
+        let clock = ManualClock::new();
+        let bucket = penalized(clock.clone());
+
+        assert_eq!(bucket.admit(1), QosClass::Red);
+        assert_eq!(bucket.admit(1), QosClass::Red);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(bucket.admit(1), QosClass::Red);
+
+        clock.advance(Duration::from_secs(20));
+        assert_ne!(bucket.admit(1), QosClass::Red);
+    }
+
+    #[test]
+    fn penalize_consume_speculative() {
+        let clock = ManualClock::new();
+        let bucket = penalized(clock.clone());
+        let timeout = Duration::from_secs(60);
+
+        assert!(bucket.consume_speculative(1, timeout).is_err());
+        assert!(matches!(
+            bucket.consume_speculative(1, timeout),
+            Err(Error::RetryAfter(delay)) if delay == Duration::from_secs(30)
+        ));
+        clock.advance(Duration::from_secs(10));
+        assert!(matches!(
+            bucket.consume_speculative(1, timeout),
+            Err(Error::RetryAfter(delay)) if delay == Duration::from_secs(20)
+        ));
+
+        clock.advance(Duration::from_secs(20));
+        assert!(bucket.consume_speculative(1, timeout).unwrap().confirm());
+    }
+
+    #[test]
+    fn penalize_intersection() {
+        let clock = ManualClock::new();
+        let bucket = penalized(clock.clone());
+        let other = TokenBucket::with_clock(10, Duration::from_secs(10), clock.clone());
+        let both = bucket.intersect(&other);
+
+        assert!(both.consume(1).is_err());
+        assert_eq!(
+            both.consume(1),
+            Err(Error::RetryAfter(Duration::from_secs(30)))
+        );
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(
+            both.consume(1),
+            Err(Error::RetryAfter(Duration::from_secs(20)))
+        );
+
+        // the other bucket isn't penalized for the rejections of the first one
+        assert_eq!(other.consume(1), Ok(()));
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(both.consume(1), Ok(()));
+    }
+
+    #[test]
+    fn consume_wait() {
+        // sleeping requires a real clock, so durations are kept short
+        let bucket = TokenBucket::new(1, Duration::from_millis(50));
+        assert_eq!(bucket.consume_wait(1), Ok(()));
+
+        let started_at = Instant::now();
+        assert_eq!(bucket.consume_wait(1), Ok(()));
+        assert!(started_at.elapsed() >= Duration::from_millis(40));
+
+        // requests that can never be admitted fail without waiting
+        assert_eq!(bucket.consume_wait(2), Err(Error::ExceedsCapacity));
+        let bucket = TokenBucket::new(0, Duration::from_millis(50));
+        assert_eq!(bucket.consume_wait(1), Err(Error::Blocked));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(start_paused = true)]
+    async fn acquire() {
+        // the clock follows the paused time of the runtime, which advances
+        // instantly whenever all tasks are asleep
+        let clock = || tokio::time::Instant::now().into_std();
+        let bucket = TokenBucket::with_clock(2, Duration::from_secs(60), clock);
+        let started_at = clock();
+
+        assert_eq!(bucket.acquire(2).await, Ok(()));
+        assert_eq!(clock(), started_at);
+        assert_eq!(bucket.acquire(1).await, Ok(()));
+        assert_eq!(clock() - started_at, Duration::from_secs(30));
+
+        assert_eq!(bucket.acquire(3).await, Err(Error::ExceedsCapacity));
+        let bucket = TokenBucket::with_clock(0, Duration::from_secs(60), clock);
+        assert_eq!(bucket.acquire(1).await, Err(Error::Blocked));
+    }
+
+    #[test]
+    fn consume_wait_fifo() {
+        let bucket = TokenBucket::new(2, Duration::from_millis(100));
+        bucket.consume(2).unwrap();
+
+        let served = Mutex::new(Vec::new());
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                bucket.consume_wait(2).unwrap();
+                served.lock().unwrap().push(2);
+            });
+            // make sure the large request is queued first
+            while bucket.state.lock().unwrap().waiters.is_empty() {
+                std::thread::yield_now();
+            }
+            scope.spawn(|| {
+                bucket.consume_wait(1).unwrap();
+                served.lock().unwrap().push(1);
+            });
+        });
+
+        // the small request would fit earlier, but it's served in order
+        assert_eq!(*served.lock().unwrap(), [2, 1]);
+        assert!(bucket.state.lock().unwrap().waiters.is_empty());
+    }
+
+    #[test]
+    fn consume_wait_timeout_queued() {
+        let bucket = TokenBucket::new(2, Duration::from_millis(100));
+        bucket.consume(2).unwrap();
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| bucket.consume_wait(2).unwrap());
+            while bucket.state.lock().unwrap().waiters.is_empty() {
+                std::thread::yield_now();
+            }
+
+            // the waiter queued ahead isn't served in time, and giving up
+            // leaves the queue
+            assert!(matches!(
+                bucket.consume_wait_timeout(1, Duration::from_millis(10)),
+                Err(Error::RetryAfter(_))
+            ));
+            assert_eq!(bucket.state.lock().unwrap().waiters.len(), 1);
+        });
+        assert!(bucket.state.lock().unwrap().waiters.is_empty());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(start_paused = true)]
+    async fn acquire_fifo() {
+        let clock = || tokio::time::Instant::now().into_std();
+        let bucket = TokenBucket::with_clock(2, Duration::from_secs(60), clock);
+        let started_at = clock();
+        bucket.consume(2).unwrap();
+
+        let served = Mutex::new(Vec::new());
+        let large = async {
+            bucket.acquire(2).await.unwrap();
+            served.lock().unwrap().push((2, clock() - started_at));
+        };
+        let small = async {
+            bucket.acquire(1).await.unwrap();
+            served.lock().unwrap().push((1, clock() - started_at));
+        };
+        tokio::join!(large, small);
+
+        assert_eq!(
+            *served.lock().unwrap(),
+            [(2, Duration::from_secs(60)), (1, Duration::from_secs(90))]
+        );
+
+        // a cancelled waiter leaves the queue
+        let cancelled = tokio::time::timeout(Duration::from_secs(1), bucket.acquire(2)).await;
+        assert!(cancelled.is_err());
+        assert!(bucket.state.lock().unwrap().waiters.is_empty());
+    }
+
+    #[test]
+    fn consume_wait_timeout() {
+        let bucket = TokenBucket::new(1, Duration::from_millis(50));
+        assert_eq!(
+            bucket.consume_wait_timeout(1, Duration::from_millis(100)),
+            Ok(())
+        );
+
+        // the tokens cannot be available in time, so it fails right away
+        let started_at = Instant::now();
+        assert!(matches!(
+            bucket.consume_wait_timeout(1, Duration::from_millis(10)),
+            Err(Error::RetryAfter(_))
+        ));
+        assert!(started_at.elapsed() < Duration::from_millis(40));
+
+        assert_eq!(
+            bucket.consume_wait_timeout(1, Duration::from_millis(100)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn debug() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::with_clock(4, Duration::from_secs(1), &clock);
+        assert_eq!(bucket.consume(3), Ok(()));
+        assert_eq!(
+            format!("{:?}", bucket),
+            "TokenBucket { capacity: 4, time_per_token: Some(250ms), available: 1, .. }"
+        );
+
+        let bucket = TokenBucket::new(0, Duration::from_secs(1));
+        assert_eq!(
+            format!("{:?}", bucket),
+            "TokenBucket { capacity: 0, time_per_token: None, available: 0, .. }"
+        );
+    }
+
+    #[test]
+    fn clone() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::with_clock(4, Duration::from_secs(1), &clock);
+        assert_eq!(bucket.consume(2), Ok(()));
+        let speculation = bucket.consume_speculative(1, Duration::from_secs(1));
+
+        // the clone starts with the same number of tokens, counting the pending
+        // speculation as consumed, and is independent afterwards
+        let clone = bucket.clone();
+        drop(speculation);
+        assert_eq!(clone.consume_remaining(1), Ok(0));
+        assert!(clone.consume(1).is_err());
+        assert_eq!(bucket.consume_remaining(2), Ok(0));
+    }
+
+    #[test]
+    fn warm_up() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::builder_with_clock(&clock)
+            .refill(10, Duration::from_secs(1))
+            .warm_up(Duration::from_secs(1))
+            .done();
+
+        // the bucket starts empty, and at a third of the rate
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_millis(300)))
+        );
+
+        // the rate has grown by the time the token is generated
+        *now.lock().unwrap() += Duration::from_millis(300);
+        assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_millis(75)))
+        );
+
+        // denials aren't cached during the warm-up, as tokens get cheaper
+        *now.lock().unwrap() += Duration::from_millis(100);
+        assert_eq!(bucket.consume(1), Ok(()));
+
+        // once warmed up, the bucket behaves as configured
+        *now.lock().unwrap() += Duration::from_secs(2);
+        assert_eq!(bucket.consume(10), Ok(()));
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_millis(100)))
+        );
+    }
+
+    #[test]
+    fn builder() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::builder_with_clock(&clock)
+            .capacity(100)
+            .refill(10, Duration::from_secs(1))
+            .done();
+
+        // a burst of up to the capacity is allowed at once
+        assert_eq!(bucket.consume(100), Ok(()));
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_millis(100)))
+        );
 
-        let interval_start = now.checked_sub(self.interval).unwrap_or(now);
-        let token_delay = Duration::from_nanos((tokens * self.time_per_token) as u64);
-        let last_replenished_at = lock.unwrap_or(interval_start);
+        // but the bucket is refilled at the sustained rate only
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(bucket.consume(10), Ok(()));
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_millis(100)))
+        );
 
-        let required_time = std::cmp::max(interval_start, last_replenished_at) + token_delay;
-        if required_time > now {
-            Err(Error::RetryAfter(required_time - now))
-        } else {
-            *lock = Some(required_time);
-            Ok(())
-        }
+        // and once idle long enough, is full again
+        *now.lock().unwrap() += Duration::from_secs(60);
+        assert_eq!(bucket.consume(100), Ok(()));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn builder_blocked() {
+        let bucket = TokenBucket::builder().capacity(100).done();
+        assert_eq!(bucket.consume(1), Err(Error::Blocked));
 
-    use std::sync::Mutex;
+        let bucket = TokenBucket::builder()
+            .capacity(0)
+            .refill(10, Duration::from_secs(1))
+            .done();
+        assert_eq!(bucket.consume(1), Err(Error::Blocked));
+
+        // the capacity defaults to the number of tokens per refill interval
+        let bucket = TokenBucket::builder()
+            .refill(2, Duration::from_secs(60))
+            .done();
+        assert_eq!(bucket.consume(2), Ok(()));
+        assert!(matches!(bucket.consume(1), Err(Error::RetryAfter(_))));
+    }
 
     #[test]
-    fn new() {
-        let bucket = TokenBucket::new(3, Duration::from_secs(60));
+    fn cached_denial() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::with_clock(2, Duration::from_secs(10), &clock);
 
+        assert_eq!(bucket.consume(2), Ok(()));
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_secs(5)))
+        );
+
+        // subsequent rejections are served from the cache, and still report
+        // the exact delay for the number of tokens requested
+        let state = bucket.state.lock().unwrap();
+        assert_eq!(
+            bucket.consume(2),
+            Err(Error::RetryAfter(Duration::from_secs(10)))
+        );
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_secs(4)))
+        );
+        drop(state);
+
+        // once the cached denial expires, the bucket state is used again
+        *now.lock().unwrap() += Duration::from_secs(4);
         assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(bucket.consume(0), Ok(()));
+    }
+
+    #[test]
+    fn cached_denial_refund() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::with_clock(2, Duration::from_secs(10), &clock);
+
+        let reservation = bucket.reserve(2).unwrap();
+        assert!(matches!(bucket.consume(1), Err(Error::RetryAfter(_))));
+
+        // refunded tokens must not be hidden by the cached denial
+        bucket.settle(reservation, 1);
         assert_eq!(bucket.consume(1), Ok(()));
-        assert_eq!(bucket.consume(1), Ok(()));
-        // we don't mock time in this test case, so checking the retry-after delay would be unreliable
         assert!(matches!(bucket.consume(1), Err(Error::RetryAfter(_))));
+
+        // neither must the tokens of expired speculations
+        *now.lock().unwrap() += Duration::from_secs(10);
+        let _speculation = bucket
+            .consume_speculative(2, Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_secs(5)))
+        );
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(bucket.consume(2), Ok(()));
     }
 
     #[test]
-    fn blocked_limit() {
-        let bucket = TokenBucket::new(0, Duration::from_secs(60));
+    fn available() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::with_clock(4, Duration::from_secs(1), &clock);
 
-        // tokens are not being added to the bucket; the entity is effectively blocked,
-        // and retries are useless
-        assert_eq!(bucket.consume(1), Err(Error::Blocked));
-        assert_eq!(bucket.consume(1), Err(Error::Blocked));
-        assert_eq!(bucket.consume(1), Err(Error::Blocked));
+        let availability = bucket.available().unwrap();
+        assert_eq!(availability.tokens(), 4);
+        assert_eq!(availability.next_token_in(), Duration::ZERO);
+
+        assert_eq!(bucket.consume(3), Ok(()));
+        let availability = bucket.available().unwrap();
+        assert_eq!(availability.tokens(), 1);
+        assert_eq!(availability.next_token_in(), Duration::from_millis(250));
+
+        *now.lock().unwrap() += Duration::from_millis(300);
+        let availability = bucket.available().unwrap();
+        assert_eq!(availability.tokens(), 2);
+        assert_eq!(availability.next_token_in(), Duration::from_millis(200));
+
+        // nothing is consumed by the introspection
+        assert_eq!(bucket.consume(2), Ok(()));
+        assert_eq!(bucket.available().unwrap().tokens(), 0);
+
+        let bucket = TokenBucket::new(0, Duration::from_secs(1));
+        assert_eq!(bucket.available(), Err(Error::Blocked));
     }
 
     #[test]
-    fn blocked_duration() {
-        let bucket = TokenBucket::new(42, Duration::from_secs(0));
+    fn time_to_full() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::with_clock(4, Duration::from_secs(1), &clock);
+        assert_eq!(bucket.time_to_full(), Ok(Duration::ZERO));
 
-        // tokens are not being added to the bucket; the entity is effectively blocked,
-        // and retries are useless
-        assert_eq!(bucket.consume(1), Err(Error::Blocked));
-        assert_eq!(bucket.consume(1), Err(Error::Blocked));
-        assert_eq!(bucket.consume(1), Err(Error::Blocked));
+        assert_eq!(bucket.consume(3), Ok(()));
+        assert_eq!(bucket.time_to_full(), Ok(Duration::from_millis(750)));
+
+        *now.lock().unwrap() += Duration::from_millis(500);
+        assert_eq!(bucket.time_to_full(), Ok(Duration::from_millis(250)));
+
+        *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(bucket.time_to_full(), Ok(Duration::ZERO));
+
+        // speculatively consumed tokens count until they are refunded
+        let speculation = bucket.consume_speculative(2, Duration::from_secs(1));
+        assert!(speculation.is_ok());
+        assert_eq!(bucket.time_to_full(), Ok(Duration::from_millis(500)));
+        drop(speculation);
+        assert_eq!(bucket.time_to_full(), Ok(Duration::ZERO));
+
+        let bucket = TokenBucket::new(0, Duration::from_secs(1));
+        assert_eq!(bucket.time_to_full(), Err(Error::Blocked));
     }
 
     #[test]
-    fn capacity_is_one() {
+    fn consume_up_to() {
         let now = Mutex::new(Instant::now());
         let clock = || *now.lock().unwrap();
-        let bucket = TokenBucket::with_timer(1, Duration::from_secs(1), &clock);
+        let bucket = TokenBucket::with_clock(4, Duration::from_secs(1), &clock);
 
-        assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(bucket.consume_up_to(3), 3);
+        assert_eq!(bucket.consume_up_to(3), 1);
+        assert_eq!(bucket.consume_up_to(3), 0);
+
+        *now.lock().unwrap() += Duration::from_millis(600);
+        assert_eq!(bucket.consume_up_to(3), 2);
         assert_eq!(
             bucket.consume(1),
-            Err(Error::RetryAfter(Duration::from_secs(1)))
+            Err(Error::RetryAfter(Duration::from_millis(150)))
         );
 
+        let bucket = TokenBucket::new(0, Duration::from_secs(1));
+        assert_eq!(bucket.consume_up_to(3), 0);
+    }
+
+    #[test]
+    fn admit() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::with_clock(4, Duration::from_secs(1), &clock);
+
+        assert_eq!(bucket.admit(2), QosClass::Green);
+        assert_eq!(bucket.admit(1), QosClass::Yellow);
+        assert_eq!(bucket.admit(2), QosClass::Red);
+        // rejected requests consume nothing
+        assert_eq!(bucket.admit(1), QosClass::Yellow);
+        assert_eq!(bucket.admit(1), QosClass::Red);
+
         *now.lock().unwrap() += Duration::from_secs(1);
+        assert_eq!(bucket.admit(1), QosClass::Green);
+
+        let bucket = TokenBucket::new(0, Duration::from_secs(1));
+        assert_eq!(bucket.admit(1), QosClass::Red);
+    }
+
+    #[test]
+    fn consume_remaining() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::with_clock(4, Duration::from_secs(1), &clock);
+
+        assert_eq!(bucket.consume_remaining(1), Ok(3));
+        assert_eq!(bucket.consume_remaining(3), Ok(0));
+        assert_eq!(
+            bucket.consume_remaining(1),
+            Err(Error::RetryAfter(Duration::from_millis(250)))
+        );
+
+        *now.lock().unwrap() += Duration::from_millis(600);
+        assert_eq!(bucket.consume_remaining(1), Ok(1));
+
+        let bucket = TokenBucket::new(0, Duration::from_secs(1));
+        assert_eq!(bucket.consume_remaining(1), Err(Error::Blocked));
+    }
+
+    #[test]
+    fn refund() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::with_clock(4, Duration::from_secs(1), &clock);
+
+        // refunding a full bucket has no effect
+        bucket.refund(2);
+        assert_eq!(bucket.consume(4), Ok(()));
+        assert!(matches!(bucket.consume(1), Err(Error::RetryAfter(_))));
+
+        bucket.refund(1);
         assert_eq!(bucket.consume(1), Ok(()));
         assert_eq!(
             bucket.consume(1),
-            Err(Error::RetryAfter(Duration::from_secs(1)))
+            Err(Error::RetryAfter(Duration::from_millis(250)))
         );
+
+        // refunds never exceed the bucket capacity
+        *now.lock().unwrap() += Duration::from_millis(500);
+        bucket.refund(10);
+        assert_eq!(bucket.consume_remaining(4), Ok(0));
     }
 
     #[test]
-    fn capacity_gt_one() {
+    fn consume_permit() {
         let now = Mutex::new(Instant::now());
         let clock = || *now.lock().unwrap();
-        let bucket = TokenBucket::with_timer(3, Duration::from_secs(1), &clock);
+        let bucket = TokenBucket::with_clock(2, Duration::from_secs(1), &clock);
 
-        assert_eq!(bucket.consume(1), Ok(()));
-        assert_eq!(bucket.consume(1), Ok(()));
-        assert_eq!(bucket.consume(1), Ok(()));
+        let permit = bucket.consume_permit(2).unwrap();
+        assert_eq!(permit.tokens(), 2);
         assert_eq!(
             bucket.consume(1),
-            Err(Error::RetryAfter(Duration::from_nanos(333_333_332)))
+            Err(Error::RetryAfter(Duration::from_millis(500)))
         );
 
-        *now.lock().unwrap() += Duration::from_secs(1);
-        assert_eq!(bucket.consume(1), Ok(()));
-        assert_eq!(bucket.consume(1), Ok(()));
+        // dropped permits are refunded, committed ones are not
+        permit.rollback();
+        bucket.consume_permit(1).unwrap().commit();
+        drop(bucket.consume_permit(1).unwrap());
         assert_eq!(bucket.consume(1), Ok(()));
         assert_eq!(
             bucket.consume(1),
-            Err(Error::RetryAfter(Duration::from_nanos(333_333_332)))
+            Err(Error::RetryAfter(Duration::from_millis(500)))
         );
+
+        let bucket = TokenBucket::new(0, Duration::from_secs(1));
+        assert!(matches!(bucket.consume_permit(1), Err(Error::Blocked)));
     }
 
     #[test]
-    fn period_gt_one() {
+    fn consume_at() {
+        let start = Instant::now();
+        let bucket = TokenBucket::new(4, Duration::from_secs(1));
+
+        assert_eq!(bucket.consume_at(4, start), Ok(()));
+        assert_eq!(
+            bucket.consume_at(1, start + Duration::from_millis(100)),
+            Err(Error::RetryAfter(Duration::from_millis(150)))
+        );
+        assert_eq!(
+            bucket.consume_at(2, start + Duration::from_millis(500)),
+            Ok(())
+        );
+        assert_eq!(
+            bucket.consume_at(2, start + Duration::from_millis(500)),
+            Err(Error::RetryAfter(Duration::from_millis(500)))
+        );
+
+        let bucket = TokenBucket::new(0, Duration::from_secs(1));
+        assert_eq!(bucket.consume_at(1, start), Err(Error::Blocked));
+    }
+
+    #[test]
+    fn exceeds_capacity() {
+        let bucket = TokenBucket::new(3, Duration::from_secs(1));
+        assert_eq!(bucket.consume(4), Err(Error::ExceedsCapacity));
+        assert!(matches!(
+            bucket.consume_speculative(4, Duration::from_secs(1)),
+            Err(Error::ExceedsCapacity)
+        ));
+        assert_eq!(bucket.consume(3), Ok(()));
+
+        // even while the bucket is empty, such requests are never retryable
+        assert_eq!(bucket.consume(4), Err(Error::ExceedsCapacity));
+
+        let bucket = TokenBucket::builder()
+            .capacity(10)
+            .refill(1, Duration::from_secs(1))
+            .done();
+        assert_eq!(bucket.consume(11), Err(Error::ExceedsCapacity));
+        assert_eq!(bucket.consume(10), Ok(()));
+    }
+
+    #[test]
+    fn check() {
         let now = Mutex::new(Instant::now());
         let clock = || *now.lock().unwrap();
-        let bucket = TokenBucket::with_timer(1, Duration::from_secs(3), &clock);
+        let bucket = TokenBucket::with_clock(2, Duration::from_secs(1), &clock);
+
+        assert_eq!(bucket.check(2), Ok(()));
+        assert_eq!(bucket.check(2), Ok(()));
+        assert_eq!(bucket.check(3), Err(Error::ExceedsCapacity));
 
         assert_eq!(bucket.consume(1), Ok(()));
         assert_eq!(
-            bucket.consume(1),
-            Err(Error::RetryAfter(Duration::from_secs(3)))
+            bucket.check(2),
+            Err(Error::RetryAfter(Duration::from_millis(500)))
+        );
+        assert_eq!(bucket.check(1), Ok(()));
+        assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(
+            bucket.check(1),
+            Err(Error::RetryAfter(Duration::from_millis(500)))
         );
 
-        *now.lock().unwrap() += Duration::from_secs(2);
+        let bucket = TokenBucket::new(0, Duration::from_secs(1));
+        assert_eq!(bucket.check(1), Err(Error::Blocked));
+    }
+
+    #[test]
+    fn reset_drain() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::with_clock(2, Duration::from_secs(1), &clock);
+
+        bucket.drain();
         assert_eq!(
             bucket.consume(1),
-            Err(Error::RetryAfter(Duration::from_secs(1)))
+            Err(Error::RetryAfter(Duration::from_millis(500)))
         );
-
-        *now.lock().unwrap() += Duration::from_secs(3);
+        *now.lock().unwrap() += Duration::from_millis(500);
         assert_eq!(bucket.consume(1), Ok(()));
+
+        bucket.reset();
+        assert_eq!(bucket.consume(2), Ok(()));
+
+        // debt is forgiven on reset, but kept on drain
+        bucket.settle(Reservation { tokens: 0 }, 2);
+        bucket.drain();
         assert_eq!(
             bucket.consume(1),
-            Err(Error::RetryAfter(Duration::from_secs(3)))
+            Err(Error::RetryAfter(Duration::from_millis(1_500)))
         );
+        bucket.reset();
+        assert_eq!(bucket.consume(2), Ok(()));
     }
 
     #[test]
-    fn consume_over_time() {
-        let t0 = Instant::now();
-        let now = Mutex::new(t0);
+    fn idle_for() {
+        let now = Mutex::new(Instant::now());
         let clock = || *now.lock().unwrap();
-        let bucket = TokenBucket::with_timer(4, Duration::from_secs(1), &clock);
+        let bucket = TokenBucket::with_clock(1, Duration::from_secs(1), &clock);
 
-        // consume first token
-        *now.lock().unwrap() = t0;
-        assert_eq!(bucket.consume(1), Ok(()));
+        *now.lock().unwrap() += Duration::from_secs(3);
+        assert_eq!(bucket.idle_for(), Duration::from_secs(3));
 
-        // consume second token
-        *now.lock().unwrap() = t0 + Duration::from_millis(50);
         assert_eq!(bucket.consume(1), Ok(()));
+        assert_eq!(bucket.idle_for(), Duration::ZERO);
 
-        // consume third & fourth tokens
-        *now.lock().unwrap() = t0 + Duration::from_millis(150);
-        assert_eq!(bucket.consume(1), Ok(()));
-        assert_eq!(bucket.consume(1), Ok(()));
+        // rejected attempts count as a use, introspection doesn't
+        *now.lock().unwrap() += Duration::from_millis(500);
+        assert!(bucket.consume(1).is_err());
+        *now.lock().unwrap() += Duration::from_millis(500);
+        assert_eq!(bucket.check(1), Ok(()));
+        assert_eq!(bucket.idle_for(), Duration::from_millis(500));
 
-        // ensure we are out of tokens
-        assert_eq!(
-            bucket.consume(1),
-            Err(Error::RetryAfter(Duration::from_millis(100)))
-        );
+        // replaying older traffic doesn't make the bucket look idle
+        let start = *now.lock().unwrap() - Duration::from_secs(10);
+        assert!(bucket.consume_at(1, start).is_err());
+        assert_eq!(bucket.idle_for(), Duration::from_millis(500));
+    }
 
-        // one token is not yet replenished
-        *now.lock().unwrap() = t0 + Duration::from_millis(249);
+    #[test]
+    fn from_quota() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket =
+            TokenBucket::from_quota_with_clock(Quota::per_minute(60).allow_burst(5), &clock);
+
+        assert_eq!(bucket.consume(5), Ok(()));
         assert_eq!(
             bucket.consume(1),
-            Err(Error::RetryAfter(Duration::from_millis(1)))
+            Err(Error::RetryAfter(Duration::from_secs(1)))
         );
 
-        // one token is replenished
-        *now.lock().unwrap() = t0 + Duration::from_millis(250);
-        assert_eq!(bucket.consume(1), Ok(()));
+        // the bucket never accumulates more than the burst size
+        *now.lock().unwrap() += Duration::from_secs(60);
+        assert_eq!(bucket.consume(6), Err(Error::ExceedsCapacity));
+        assert_eq!(bucket.consume_remaining(5), Ok(0));
 
-        // ensure we are out of tokens again
+        let bucket = TokenBucket::from_quota(Quota::per_second(0));
+        assert_eq!(bucket.consume(1), Err(Error::Blocked));
+    }
+
+    #[test]
+    fn long_interval() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+
+        // a token time exceeding u32::MAX nanoseconds must not be truncated
+        let bucket = TokenBucket::with_clock(1, Duration::from_secs(10), &clock);
+        assert_eq!(bucket.consume(1), Ok(()));
         assert_eq!(
             bucket.consume(1),
-            Err(Error::RetryAfter(Duration::from_millis(250)))
+            Err(Error::RetryAfter(Duration::from_secs(10)))
         );
 
-        // two tokens are replenished
-        *now.lock().unwrap() = t0 + Duration::from_millis(750);
-        assert_eq!(bucket.consume(1), Ok(()));
-        assert_eq!(bucket.consume(1), Ok(()));
+        // intervals beyond u64 nanoseconds saturate instead of overflowing
+        let bucket = TokenBucket::with_clock(2, Duration::from_secs(u64::MAX), &clock);
+        assert_eq!(bucket.consume(2), Ok(()));
         assert_eq!(
             bucket.consume(1),
-            Err(Error::RetryAfter(Duration::from_millis(250)))
+            Err(Error::RetryAfter(Duration::from_nanos(u64::MAX / 2 - 1)))
         );
+        assert_eq!(bucket.consume(usize::MAX), Err(Error::ExceedsCapacity));
     }
 
     #[test]
-    fn consume_gt_one() {
+    fn set_rate() {
         let now = Mutex::new(Instant::now());
         let clock = || *now.lock().unwrap();
-        let bucket = TokenBucket::with_timer(3, Duration::from_secs(1), &clock);
+        let mut bucket = TokenBucket::with_clock(4, Duration::from_secs(1), &clock);
 
-        // consume all tokens at once
+        // available tokens are preserved when loosening the limit
         assert_eq!(bucket.consume(3), Ok(()));
+        bucket.set_rate(8, Duration::from_secs(1));
+        assert_eq!(bucket.consume_remaining(1), Ok(0));
         assert_eq!(
             bucket.consume(1),
-            Err(Error::RetryAfter(Duration::from_nanos(333_333_332)))
+            Err(Error::RetryAfter(Duration::from_millis(125)))
         );
 
-        // sequentially consume tokens
+        // and capped at the new capacity when tightening it
         *now.lock().unwrap() += Duration::from_secs(1);
-        assert_eq!(bucket.consume(2), Ok(()));
+        bucket.set_rate(2, Duration::from_secs(1));
+        assert_eq!(bucket.consume(3), Err(Error::ExceedsCapacity));
+        assert_eq!(bucket.consume_remaining(2), Ok(0));
+
+        // debt is converted to the new rate
+        bucket.settle(Reservation { tokens: 0 }, 1);
+        bucket.set_rate(4, Duration::from_secs(1));
         assert_eq!(
-            bucket.consume(2),
-            Err(Error::RetryAfter(Duration::from_nanos(333_333_332)))
+            bucket.consume(1),
+            Err(Error::RetryAfter(Duration::from_millis(500)))
         );
-        assert_eq!(bucket.consume(1), Ok(()));
+
+        // blocking and unblocking starts over with a full bucket
+        bucket.set_rate(0, Duration::from_secs(1));
+        assert_eq!(bucket.consume(1), Err(Error::Blocked));
+        bucket.set_rate(2, Duration::from_secs(1));
+        assert_eq!(bucket.consume(2), Ok(()));
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let now = Mutex::new(Instant::now());
+        let clock = || *now.lock().unwrap();
+        let bucket = TokenBucket::with_clock(4, Duration::from_secs(4), &clock);
+
+        assert_eq!(bucket.consume(3), Ok(()));
+        let snapshot = bucket.snapshot();
+        assert_eq!(snapshot.deficit, Duration::from_secs(3));
+
+        let restored = TokenBucket::with_clock(4, Duration::from_secs(4), &clock);
+        restored.restore(snapshot.clone());
+        assert_eq!(restored.consume_remaining(1), Ok(0));
+
+        // the time passed since the snapshot was taken is accounted for
+        let restored = TokenBucket::with_clock(4, Duration::from_secs(4), &clock);
+        restored.restore(Snapshot {
+            taken_at: snapshot.taken_at - Duration::from_secs(2),
+            ..snapshot
+        });
+        assert_eq!(restored.consume_remaining(1), Ok(2));
+
+        // and so is the debt
+        bucket.settle(Reservation { tokens: 0 }, 3);
+        let snapshot = bucket.snapshot();
+        assert_eq!(snapshot.deficit, Duration::from_secs(6));
+        let restored = TokenBucket::with_clock(4, Duration::from_secs(4), &clock);
+        restored.restore(snapshot);
+        // the wall clock keeps ticking in this test, so the delay is approximate
+        assert!(matches!(
+            restored.consume(1),
+            Err(Error::RetryAfter(delay)) if delay > Duration::from_millis(2_900) && delay <= Duration::from_secs(3)
+        ));
+
+        // a full bucket is restored as full
+        let restored = TokenBucket::with_clock(4, Duration::from_secs(4), &clock);
+        restored.restore(TokenBucket::with_clock(4, Duration::from_secs(4), &clock).snapshot());
+        assert_eq!(restored.consume(4), Ok(()));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn snapshot_version() {
+        let bucket = TokenBucket::new(4, Duration::from_secs(4));
+        assert_eq!(bucket.consume(3), Ok(()));
+        let snapshot = bucket.snapshot();
+        let json = serde_json::to_value(&snapshot).unwrap();
+        assert_eq!(json["version"], 1);
         assert_eq!(
-            bucket.consume(1),
-            Err(Error::RetryAfter(Duration::from_nanos(333_333_332)))
+            serde_json::from_value::<Snapshot>(json.clone()).unwrap(),
+            snapshot
         );
 
-        // two tokens are replenished
-        *now.lock().unwrap() += Duration::from_millis(700);
-        assert_eq!(bucket.consume(1), Ok(()));
-        assert_eq!(bucket.consume(1), Ok(()));
+        // snapshots serialized before the version has been introduced are of
+        // the first one
+        let mut unversioned = json.clone();
+        unversioned.as_object_mut().unwrap().remove("version");
         assert_eq!(
-            bucket.consume(1),
-            Err(Error::RetryAfter(Duration::from_nanos(299_999_998)))
+            serde_json::from_value::<Snapshot>(unversioned).unwrap(),
+            snapshot
         );
+
+        // and the ones of an unknown version are rejected
+        let mut newer = json;
+        newer["version"] = 2.into();
+        assert!(serde_json::from_value::<Snapshot>(newer).is_err());
     }
 }
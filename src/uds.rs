@@ -0,0 +1,383 @@
+//! Rate limiter shared by processes on the same host over a Unix domain socket.
+//!
+//! The module is available behind the `uds` feature on Unix platforms, and is
+//! meant for process groups that need a common limit, e.g. workers of a
+//! pre-fork server written in different languages. One process runs a
+//! [`Server`] owning the [`RateLimiter`], while others consume tokens via a
+//! [`Client`]. The limiting is advisory: nothing stops a process from skipping
+//! the check.
+//!
+//! ```
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//! use youshallnotpass::uds::{Client, Server};
+//! use youshallnotpass::RateLimiter;
+//!
+//! # let dir = std::env::temp_dir().join(format!("ysnp-doc-{}", std::process::id()));
+//! # std::fs::create_dir_all(&dir).unwrap();
+//! # let path = dir.join("limiter.sock");
+//! let limiter = RateLimiter::configure()
+//!     .limit("/login".to_string(), 1, Duration::from_secs(60))
+//!     .done();
+//! let server = Server::bind(&path, Arc::new(limiter)).unwrap();
+//! std::thread::spawn(move || server.run());
+//!
+//! let mut client = Client::connect(&path).unwrap();
+//! assert!(client.consume("/login", 1).unwrap().is_ok());
+//! assert!(client.consume("/login", 1).unwrap().is_err());
+//! # std::fs::remove_dir_all(&dir).unwrap();
+//! ```
+//!
+//! The protocol is simple enough to be implemented in any language. Every
+//! message is a frame prefixed with its length as a big-endian `u32`:
+//!
+//! * a request consists of the number of tokens as a big-endian `u64`,
+//!   followed by the UTF-8 encoded key
+//!
+//! * a response consists of a status byte, followed by a big-endian `u64`
+//!   number of nanoseconds to retry after: `0` if the tokens are consumed,
+//!   `1` if the request should be retried after the delay, `2` if the key is
+//!   blocked and `3` if the request exceeds the capacity
+//!
+//! Requests are answered in order, one at a time per connection. Malformed
+//! requests close the connection.
+//!
+//! An empty request asks for a [`Handoff`] of the state of every bucket, so
+//! that a new instance can start warm during a rolling deploy, see
+//! [`Client::handoff()`]. The response consists of a version byte, which is
+//! `1`, followed by an entry per bucket: the length of the key as a
+//! big-endian `u32`, the UTF-8 encoded key, and the [`Snapshot`] of the
+//! bucket as two big-endian `u64` numbers of nanoseconds, the first one since
+//! the Unix epoch the snapshot has been taken at, and the second one it takes
+//! to refill the bucket.
+//!
+//! [`Snapshot`]: crate::Snapshot
+
+use std::io::{self, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{Error, Handoff, RateLimiter, Snapshot};
+
+/// The maximum length of a request frame, which bounds the length of keys.
+const MAX_FRAME_LEN: u32 = 64 * 1024;
+
+/// Version of the encoding of handoff responses.
+const HANDOFF_VERSION: u8 = 1;
+
+const STATUS_OK: u8 = 0;
+const STATUS_RETRY_AFTER: u8 = 1;
+const STATUS_BLOCKED: u8 = 2;
+const STATUS_EXCEEDS_CAPACITY: u8 = 3;
+
+/// Server answering requests of [`Client`]s with a shared [`RateLimiter`].
+pub struct Server {
+    listener: UnixListener,
+    limiter: Arc<RateLimiter<String>>,
+}
+
+impl Server {
+    /// Create a server listening on the socket at `path`, which must not
+    /// exist yet. The `limiter` is either an [`Arc`], or a
+    /// [`SharedRateLimiter`](crate::SharedRateLimiter).
+    pub fn bind(
+        path: impl AsRef<Path>,
+        limiter: impl Into<Arc<RateLimiter<String>>>,
+    ) -> io::Result<Self> {
+        Ok(Server {
+            listener: UnixListener::bind(path)?,
+            limiter: limiter.into(),
+        })
+    }
+
+    /// Accept connections and serve each of them on its own thread.
+    ///
+    /// The function blocks the current thread, and returns only if accepting
+    /// a connection fails.
+    pub fn run(&self) -> io::Result<()> {
+        loop {
+            let (stream, _) = self.listener.accept()?;
+            let limiter = Arc::clone(&self.limiter);
+            std::thread::spawn(move || serve(stream, &limiter));
+        }
+    }
+}
+
+/// Answer requests coming over the `stream` until it's closed.
+fn serve(mut stream: UnixStream, limiter: &RateLimiter<String>) -> io::Result<()> {
+    loop {
+        let request = match read_frame(&mut stream, MAX_FRAME_LEN) {
+            Ok(request) => request,
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(error) => return Err(error),
+        };
+        if request.is_empty() {
+            write_frame(&mut stream, &encode_handoff(limiter.handoff()))?;
+            continue;
+        }
+        let (tokens, key) = decode_request(&request)?;
+
+        let (status, delay) = match limiter.consume(key, tokens) {
+            Ok(()) => (STATUS_OK, Duration::ZERO),
+            Err(Error::RetryAfter(delay)) => (STATUS_RETRY_AFTER, delay),
+            Err(Error::Blocked) => (STATUS_BLOCKED, Duration::ZERO),
+            Err(Error::ExceedsCapacity) => (STATUS_EXCEEDS_CAPACITY, Duration::ZERO),
+        };
+        let mut response = [0; 9];
+        response[0] = status;
+        response[1..].copy_from_slice(&as_nanos(delay).to_be_bytes());
+        write_frame(&mut stream, &response)?;
+    }
+}
+
+/// Client consuming tokens from a [`RateLimiter`] owned by a [`Server`].
+pub struct Client {
+    stream: UnixStream,
+}
+
+impl Client {
+    /// Connect to the server listening on the socket at `path`.
+    pub fn connect(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Client {
+            stream: UnixStream::connect(path)?,
+        })
+    }
+
+    /// Try to consume the specified number of `tokens` for a given event
+    /// (`key`).
+    ///
+    /// Same as [`RateLimiter::consume`], except that the outer result
+    /// reports failures to talk to the server.
+    pub fn consume(&mut self, key: &str, tokens: usize) -> io::Result<Result<(), Error>> {
+        let mut request = Vec::with_capacity(8 + key.len());
+        request.extend_from_slice(&(tokens as u64).to_be_bytes());
+        request.extend_from_slice(key.as_bytes());
+        write_frame(&mut self.stream, &request)?;
+
+        let response = read_frame(&mut self.stream, 9)?;
+        let (&status, delay) = response.split_first().ok_or_else(invalid_data)?;
+        let delay = Duration::from_nanos(u64::from_be_bytes(
+            delay.try_into().map_err(|_| invalid_data())?,
+        ));
+        match status {
+            STATUS_OK => Ok(Ok(())),
+            STATUS_RETRY_AFTER => Ok(Err(Error::RetryAfter(delay))),
+            STATUS_BLOCKED => Ok(Err(Error::Blocked)),
+            STATUS_EXCEEDS_CAPACITY => Ok(Err(Error::ExceedsCapacity)),
+            _ => Err(invalid_data()),
+        }
+    }
+
+    /// Request the state of every bucket of the server's rate limiter.
+    ///
+    /// This is meant for rolling deploys: a new instance connects to the old
+    /// one, and passes the handoff to [`RateLimiter::warm`] before serving
+    /// requests itself.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use std::time::Duration;
+    /// use youshallnotpass::uds::{Client, Server};
+    /// use youshallnotpass::RateLimiter;
+    ///
+    /// # let dir = std::env::temp_dir().join(format!("ysnp-doc-handoff-{}", std::process::id()));
+    /// # std::fs::create_dir_all(&dir).unwrap();
+    /// # let path = dir.join("limiter.sock");
+    /// let configure = || {
+    ///     RateLimiter::configure()
+    ///         .limit("/login".to_string(), 1, Duration::from_secs(60))
+    ///         .done()
+    /// };
+    ///
+    /// let old = Arc::new(configure());
+    /// assert!(old.consume(&"/login".to_string(), 1).is_ok());
+    /// let server = Server::bind(&path, Arc::clone(&old)).unwrap();
+    /// std::thread::spawn(move || server.run());
+    ///
+    /// let new = configure();
+    /// new.warm(Client::connect(&path).unwrap().handoff().unwrap());
+    /// assert!(new.consume(&"/login".to_string(), 1).is_err());
+    /// # std::fs::remove_dir_all(&dir).unwrap();
+    /// ```
+    pub fn handoff(&mut self) -> io::Result<Handoff<String>> {
+        write_frame(&mut self.stream, &[])?;
+        decode_handoff(&read_frame(&mut self.stream, u32::MAX)?)
+    }
+}
+
+/// Split a request into the number of tokens and the key.
+fn decode_request(request: &[u8]) -> io::Result<(usize, &str)> {
+    if request.len() < 8 {
+        return Err(invalid_data());
+    }
+    let (tokens, key) = request.split_at(8);
+    let tokens = u64::from_be_bytes(tokens.try_into().unwrap());
+    let key = std::str::from_utf8(key).map_err(|_| invalid_data())?;
+    Ok((usize::try_from(tokens).unwrap_or(usize::MAX), key))
+}
+
+fn encode_handoff(handoff: Handoff<String>) -> Vec<u8> {
+    let mut bytes = vec![HANDOFF_VERSION];
+    for (key, snapshot) in handoff {
+        bytes.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(key.as_bytes());
+        bytes.extend_from_slice(&snapshot.to_bytes());
+    }
+    bytes
+}
+
+fn decode_handoff(bytes: &[u8]) -> io::Result<Handoff<String>> {
+    let mut bytes = match bytes.split_first() {
+        Some((&HANDOFF_VERSION, rest)) => rest,
+        _ => return Err(invalid_data()),
+    };
+
+    let mut buckets = Vec::new();
+    while !bytes.is_empty() {
+        let (len, rest) = split_array::<4>(bytes)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if rest.len() < len {
+            return Err(invalid_data());
+        }
+        let (key, rest) = rest.split_at(len);
+        let key = std::str::from_utf8(key).map_err(|_| invalid_data())?;
+        let (snapshot, rest) = split_array::<{ Snapshot::ENCODED_LEN }>(rest)?;
+        buckets.push((key.to_string(), Snapshot::from_bytes(snapshot)));
+        bytes = rest;
+    }
+    Ok(buckets.into_iter().collect())
+}
+
+/// Split off the first `N` bytes, failing if there are fewer of them.
+fn split_array<const N: usize>(bytes: &[u8]) -> io::Result<([u8; N], &[u8])> {
+    if bytes.len() < N {
+        return Err(invalid_data());
+    }
+    let (head, rest) = bytes.split_at(N);
+    Ok((head.try_into().unwrap(), rest))
+}
+
+fn read_frame(stream: &mut UnixStream, max_len: u32) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len > max_len {
+        return Err(invalid_data());
+    }
+
+    let mut frame = vec![0; len as usize];
+    stream.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+fn write_frame(stream: &mut UnixStream, frame: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(frame.len()).map_err(|_| invalid_data())?;
+    let mut buf = Vec::with_capacity(4 + frame.len());
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(frame);
+    stream.write_all(&buf)
+}
+
+fn invalid_data() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed frame")
+}
+
+fn as_nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn socket_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ysnp-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("limiter.sock")
+    }
+
+    #[test]
+    fn consume() {
+        let path = socket_path("consume");
+        let limiter = RateLimiter::configure()
+            .limit("a".to_string(), 2, Duration::from_secs(60))
+            .limit("b".to_string(), 0, Duration::from_secs(60))
+            .done();
+        let server = Server::bind(&path, Arc::new(limiter)).unwrap();
+        std::thread::spawn(move || server.run());
+
+        let mut first = Client::connect(&path).unwrap();
+        let mut second = Client::connect(&path).unwrap();
+        assert_eq!(first.consume("a", 1).unwrap(), Ok(()));
+        assert_eq!(second.consume("a", 1).unwrap(), Ok(()));
+
+        // both clients share the same bucket
+        assert!(matches!(
+            first.consume("a", 1).unwrap(),
+            Err(Error::RetryAfter(delay)) if delay > Duration::from_secs(29)
+        ));
+        assert_eq!(second.consume("a", 3).unwrap(), Err(Error::ExceedsCapacity));
+        assert_eq!(second.consume("b", 1).unwrap(), Err(Error::Blocked));
+        assert_eq!(second.consume("c", 100).unwrap(), Ok(()));
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn malformed() {
+        let path = socket_path("malformed");
+        let server = Server::bind(&path, Arc::new(RateLimiter::configure().done())).unwrap();
+        std::thread::spawn(move || server.run());
+
+        // a request too short to hold the number of tokens closes the
+        // connection
+        let mut stream = UnixStream::connect(&path).unwrap();
+        write_frame(&mut stream, &[1, 2, 3]).unwrap();
+        assert_eq!(
+            read_frame(&mut stream, 9).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+
+        // other connections are unaffected
+        let mut client = Client::connect(&path).unwrap();
+        assert_eq!(client.consume("a", 1).unwrap(), Ok(()));
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn handoff() {
+        let path = socket_path("handoff");
+        let limiter = RateLimiter::configure()
+            .limit("a".to_string(), 2, Duration::from_secs(60))
+            .limit("ключ".to_string(), 2, Duration::from_secs(60))
+            .done();
+        assert_eq!(limiter.consume(&"a".to_string(), 1), Ok(()));
+        assert_eq!(limiter.consume(&"ключ".to_string(), 2), Ok(()));
+        let server = Server::bind(&path, Arc::new(limiter)).unwrap();
+        std::thread::spawn(move || server.run());
+
+        // the connection is still usable for consuming afterwards
+        let mut client = Client::connect(&path).unwrap();
+        let handoff = client.handoff().unwrap();
+        assert_eq!(client.consume("a", 1).unwrap(), Ok(()));
+
+        let mut keys: Vec<_> = handoff.iter().map(|(key, _)| key.as_str()).collect();
+        keys.sort();
+        assert_eq!(keys, ["a", "ключ"]);
+        assert_eq!(
+            decode_handoff(&encode_handoff(handoff.clone())).unwrap(),
+            handoff
+        );
+
+        assert!(decode_handoff(&[]).is_err());
+        assert!(decode_handoff(&[2]).is_err());
+        assert!(decode_handoff(&[HANDOFF_VERSION, 0, 0, 0, 5, b'a']).is_err());
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}